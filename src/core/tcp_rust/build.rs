@@ -16,6 +16,19 @@ fn main() {
 
     println!("cargo:rustc-link-search={}/build", lwip_dir.display());
 
+    // The layout of ip_addr_t, and which of ip4_*/ip6_* exist, follow the
+    // address families lwipopts.h enables (opt.h defaults: IPv4 only)
+    let lwipopts = unix_lib_include.join("lwipopts.h");
+    println!("cargo:rerun-if-changed={}", lwipopts.display());
+    let opts = std::fs::read_to_string(&lwipopts).expect("Couldn't read lwipopts.h");
+    println!("cargo:rustc-check-cfg=cfg(lwip_ipv4, lwip_ipv6)");
+    if lwipopt(&opts, "LWIP_IPV4").unwrap_or(true) {
+        println!("cargo:rustc-cfg=lwip_ipv4");
+    }
+    if lwipopt(&opts, "LWIP_IPV6").unwrap_or(false) {
+        println!("cargo:rustc-cfg=lwip_ipv6");
+    }
+
     // Generate bindings for lwIP C headers
     let bindings = bindgen::Builder::default()
         .header("wrapper.h")
@@ -45,6 +58,9 @@ fn main() {
         .allowlist_function("ip_output_if")
         .allowlist_function("ip4_output_if")
         .allowlist_function("ip6_output_if")
+        .allowlist_function("ip4_route")
+        .allowlist_function("ip6_route")
+        .allowlist_function("netif_get_by_index")
         .allowlist_function("ip_chksum_pseudo")
         .allowlist_function("sys_timeout")
         .allowlist_function("sys_untimeout")
@@ -67,8 +83,10 @@ fn main() {
         .allowlist_var("tcp_bound_pcbs")
         .allowlist_var("tcp_pcb_lists")
         .allowlist_var("tcp_ticks")
+        .allowlist_var("ip_data")
         .allowlist_var("PBUF_.*")
         .allowlist_var("IP_PROTO_TCP")
+        .allowlist_var("MEMP_NUM_TCP_PCB")
         // TCP is now pure Rust - no need for C bindings
         // Generate with useful derivations
         .derive_debug(true)
@@ -84,3 +102,14 @@ fn main() {
         .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write bindings!");
 }
+
+/// Whether lwipopts.h turns `name` on, if it defines it at all
+fn lwipopt(opts: &str, name: &str) -> Option<bool> {
+    opts.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("#define") || words.next() != Some(name) {
+            return None;
+        }
+        Some(words.next().map_or(true, |value| value.trim_matches(|c| c == '(' || c == ')') != "0"))
+    })
+}
@@ -0,0 +1,81 @@
+//! Challenge ACK Rate Limiting
+//!
+//! RFC 5961 section 7: challenge ACKs are capped per time window, across
+//! all connections, so a blind attacker cannot use them to probe for the
+//! right sequence numbers or to amplify traffic.
+
+use crate::tcp_proto;
+
+/// Token bucket refilled once every `TCP_CHALLENGE_ACK_WINDOW` ticks
+pub struct ChallengeAckLimiter {
+    window_start: u32, // tcp_ticks at which the current window began
+    sent: u32,         // Challenge ACKs allowed in the current window
+}
+
+impl ChallengeAckLimiter {
+    pub const fn new() -> Self {
+        Self { window_start: 0, sent: 0 }
+    }
+
+    /// Take a token at time `now`; false means the budget is spent
+    pub fn allow(&mut self, now: u32) -> bool {
+        if now.wrapping_sub(self.window_start) >= tcp_proto::TCP_CHALLENGE_ACK_WINDOW {
+            self.window_start = now;
+            self.sent = 0;
+        }
+
+        if self.sent >= tcp_proto::TCP_CHALLENGE_ACK_LIMIT {
+            return false;
+        }
+
+        self.sent += 1;
+        true
+    }
+}
+
+impl Default for ChallengeAckLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Shared by every PCB, like tcp_ticks
+static mut LIMITER: ChallengeAckLimiter = ChallengeAckLimiter::new();
+
+/// May a challenge ACK go out now?
+pub fn challenge_ack_allowed() -> bool {
+    unsafe {
+        let now = crate::tcp_ticks;
+        (*core::ptr::addr_of_mut!(LIMITER)).allow(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChallengeAckLimiter;
+    use crate::tcp_proto;
+
+    #[test]
+    fn test_budget_exhausts_then_refills() {
+        let mut limiter = ChallengeAckLimiter::new();
+        for _ in 0..tcp_proto::TCP_CHALLENGE_ACK_LIMIT {
+            assert!(limiter.allow(100));
+        }
+        assert!(!limiter.allow(100));
+        assert!(!limiter.allow(100 + tcp_proto::TCP_CHALLENGE_ACK_WINDOW - 1));
+
+        // Next window
+        assert!(limiter.allow(100 + tcp_proto::TCP_CHALLENGE_ACK_WINDOW));
+    }
+
+    #[test]
+    fn test_window_survives_tick_wraparound() {
+        let mut limiter = ChallengeAckLimiter::new();
+        let start = u32::MAX - 1;
+        for _ in 0..tcp_proto::TCP_CHALLENGE_ACK_LIMIT {
+            assert!(limiter.allow(start));
+        }
+        assert!(!limiter.allow(start));
+        assert!(limiter.allow(start.wrapping_add(tcp_proto::TCP_CHALLENGE_ACK_WINDOW)));
+    }
+}
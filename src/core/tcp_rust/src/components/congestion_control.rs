@@ -1,27 +1,161 @@
 //! Congestion Control Component
 //!
-//! Manages congestion window and slow start threshold.
+//! Manages congestion window and slow start threshold. The window
+//! arithmetic lives behind the `CongestionControl` trait so algorithms can
+//! be swapped per connection; Reno is the default.
 
 use crate::components::ConnectionManagementState;
+use crate::tcp_proto;
 use crate::tcp_types::TcpSegment;
 
+/// Congestion window and slow start threshold, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionWindow {
+    pub cwnd: u32,
+    pub ssthresh: u32,
+}
+
+/// Congestion control algorithm
+///
+/// The component owns the window and calls into the algorithm on each
+/// congestion event; the algorithm decides the new cwnd and ssthresh.
+pub trait CongestionControl {
+    /// Window once the handshake completes
+    ///
+    /// RFC 5681: IW = min(4*MSS, max(2*MSS, 4380 bytes))
+    fn initial_cwnd(&self, mss: u16) -> u32 {
+        let mss = mss as u32;
+        core::cmp::min(4 * mss, core::cmp::max(2 * mss, 4380))
+    }
+
+    /// `acked` bytes of new data acknowledged outside fast recovery, at
+    /// tcp_ticks `now` with a smoothed RTT of `srtt` ms
+    fn on_ack(&mut self, win: &mut CongestionWindow, mss: u16, acked: u32, now: u32, srtt: u32);
+
+    /// Third duplicate ACK: enter fast recovery
+    fn on_loss(&mut self, win: &mut CongestionWindow, mss: u16, flight: u32);
+
+    /// Every further duplicate ACK during fast recovery
+    fn on_dupack(&mut self, win: &mut CongestionWindow, mss: u16) {
+        win.cwnd = win.cwnd.saturating_add(mss as u32);
+    }
+
+    /// New ACK ends fast recovery, deflate the window
+    fn on_recovery_exit(&mut self, win: &mut CongestionWindow) {
+        win.cwnd = win.ssthresh;
+    }
+
+    /// ECE received: congestion without loss
+    ///
+    /// RFC 3168 §6.1.2: halve the window as for a loss, minus the
+    /// retransmission and fast recovery.
+    fn on_ecn(&mut self, win: &mut CongestionWindow, mss: u16) {
+        win.ssthresh = core::cmp::max(win.cwnd / 2, 2 * mss as u32);
+        win.cwnd = win.ssthresh;
+    }
+
+    /// Retransmission timeout
+    fn on_timeout(&mut self, win: &mut CongestionWindow, mss: u16, flight: u32);
+
+    /// Window the sender may have outstanding
+    fn cwnd(&self, win: &CongestionWindow) -> u32 {
+        win.cwnd
+    }
+
+    /// Connection reset or aborted: drop any per-connection history
+    fn reset(&mut self) {}
+}
+
+/// Slow start increase for an ACK of `acked` bytes (RFC 3465)
+///
+/// Counting bytes rather than ACKs keeps a delayed-ACK receiver from
+/// halving the growth; the cap bounds the burst a stretch ACK allows.
+pub fn slow_start_increase(acked: u32, mss: u16) -> u32 {
+    core::cmp::min(acked, (tcp_proto::TCP_ABC_LIMIT as u32) * mss as u32)
+}
+
+/// ssthresh = max(FlightSize/2, 2*MSS)
+fn half_flight(flight: u32, mss: u16) -> u32 {
+    core::cmp::max(flight / 2, 2 * mss as u32)
+}
+
+/// RFC 5681 slow start, congestion avoidance and fast recovery
+#[derive(Debug, Default)]
+pub struct RenoCongestionControl;
+
+impl CongestionControl for RenoCongestionControl {
+    /// Below ssthresh cwnd grows by the bytes acknowledged, up to
+    /// TCP_ABC_LIMIT MSS per ACK (RFC 3465), above it by MSS*MSS/cwnd,
+    /// i.e. roughly one MSS per round trip.
+    fn on_ack(&mut self, win: &mut CongestionWindow, mss: u16, acked: u32, _now: u32, _srtt: u32) {
+        let increase = if win.cwnd < win.ssthresh {
+            slow_start_increase(acked, mss)
+        } else {
+            let mss = mss as u32;
+            core::cmp::max(mss * mss / core::cmp::max(win.cwnd, 1), 1)
+        };
+        win.cwnd = win.cwnd.saturating_add(increase);
+    }
+
+    /// RFC 5681 §3.2: ssthresh = max(FlightSize/2, 2*MSS), cwnd = ssthresh + 3*MSS
+    fn on_loss(&mut self, win: &mut CongestionWindow, mss: u16, flight: u32) {
+        win.ssthresh = half_flight(flight, mss);
+        win.cwnd = win.ssthresh.saturating_add(3 * mss as u32);
+    }
+
+    /// RFC 5681 §3.1: ssthresh = max(FlightSize/2, 2*MSS), cwnd = 1*MSS
+    fn on_timeout(&mut self, win: &mut CongestionWindow, mss: u16, flight: u32) {
+        win.ssthresh = half_flight(flight, mss);
+        win.cwnd = mss as u32;
+    }
+}
+
 /// Congestion Control State
 ///
 /// Manages congestion window and slow start threshold.
 /// Only CC event handlers can write to this state.
 pub struct CongestionControlState {
-    pub cwnd: u16,       // Congestion Window
-    pub ssthresh: u16,   // Slow Start Threshold
+    pub cwnd: u32,       // Congestion Window
+    pub ssthresh: u32,   // Slow Start Threshold
+    algorithm: Box<dyn CongestionControl>,
 }
 
 impl CongestionControlState {
     pub fn new() -> Self {
+        Self::with_algorithm(Box::new(RenoCongestionControl))
+    }
+
+    /// Start out with a specific algorithm instead of Reno
+    pub fn with_algorithm(algorithm: Box<dyn CongestionControl>) -> Self {
         Self {
             cwnd: 0,
             ssthresh: 0xFFFF,   // Initial ssthresh is large
+            algorithm,
         }
     }
 
+    /// Swap the algorithm; the current window carries over
+    pub fn set_algorithm(&mut self, algorithm: Box<dyn CongestionControl>) {
+        self.algorithm = algorithm;
+    }
+
+    /// Window the sender may have outstanding, as decided by the algorithm
+    pub fn window(&self) -> u32 {
+        self.algorithm.cwnd(&self.current())
+    }
+
+    fn current(&self) -> CongestionWindow {
+        CongestionWindow { cwnd: self.cwnd, ssthresh: self.ssthresh }
+    }
+
+    /// Run one algorithm callback against the window
+    fn update(&mut self, f: impl FnOnce(&mut dyn CongestionControl, &mut CongestionWindow)) {
+        let mut win = self.current();
+        f(self.algorithm.as_mut(), &mut win);
+        self.cwnd = win.cwnd;
+        self.ssthresh = win.ssthresh;
+    }
+
     // ------------------------------------------------------------------------
     // Connection Setup (Handshake)
     // ------------------------------------------------------------------------
@@ -32,9 +166,7 @@ impl CongestionControlState {
         conn_mgmt: &ConnectionManagementState,
     ) -> Result<(), &'static str> {
         // Initialize congestion control
-        // RFC 5681: IW = min(4*MSS, max(2*MSS, 4380 bytes))
-        let mss = conn_mgmt.mss as u16;
-        self.cwnd = core::cmp::min(4 * mss, core::cmp::max(2 * mss, 4380));
+        self.cwnd = self.algorithm.initial_cwnd(conn_mgmt.mss);
 
         // ssthresh is already initialized to 0xFFFF in TcpConnectionState::new()
 
@@ -46,9 +178,16 @@ impl CongestionControlState {
         &mut self,
         conn_mgmt: &ConnectionManagementState,
     ) -> Result<(), &'static str> {
-        // RFC 5681: IW = min(4*MSS, max(2*MSS, 4380 bytes))
-        let mss = conn_mgmt.mss as u16;
-        self.cwnd = core::cmp::min(4 * mss, core::cmp::max(2 * mss, 4380));
+        self.cwnd = self.algorithm.initial_cwnd(conn_mgmt.mss);
+        Ok(())
+    }
+
+    /// SYN_SENT → SYN_RCVD: Initialize cwnd (simultaneous open)
+    pub fn on_syn_in_synsent(
+        &mut self,
+        conn_mgmt: &ConnectionManagementState,
+    ) -> Result<(), &'static str> {
+        self.cwnd = self.algorithm.initial_cwnd(conn_mgmt.mss);
         Ok(())
     }
 
@@ -106,6 +245,17 @@ impl CongestionControlState {
         Ok(()) // No cwnd change
     }
 
+    /// TIME_WAIT → SYN_RCVD: The new incarnation learns the path afresh
+    pub fn on_syn_in_timewait(
+        &mut self,
+        conn_mgmt: &ConnectionManagementState,
+    ) -> Result<(), &'static str> {
+        self.ssthresh = 0xFFFF;
+        self.algorithm.reset();
+
+        self.on_syn_in_listen(conn_mgmt)
+    }
+
     // ------------------------------------------------------------------------
     // Reset Handling
     // ------------------------------------------------------------------------
@@ -114,6 +264,8 @@ impl CongestionControlState {
     pub fn on_rst(&mut self) -> Result<(), &'static str> {
         // Reset congestion control state
         self.cwnd = 0;
+        self.ssthresh = 0xFFFF;
+        self.algorithm.reset();
 
         Ok(())
     }
@@ -122,6 +274,8 @@ impl CongestionControlState {
     pub fn on_abort(&mut self) -> Result<(), &'static str> {
         // Reset congestion control state
         self.cwnd = 0;
+        self.ssthresh = 0xFFFF;
+        self.algorithm.reset();
 
         Ok(())
     }
@@ -137,8 +291,7 @@ impl CongestionControlState {
     ) -> Result<(), &'static str> {
         // Initialize congestion window to 1 MSS for active open
         // (will be expanded after SYN+ACK received per RFC 5681)
-        let mss = conn_mgmt.mss as u16;
-        self.cwnd = mss;
+        self.cwnd = conn_mgmt.mss as u32;
 
         Ok(())
     }
@@ -148,22 +301,67 @@ impl CongestionControlState {
     // ------------------------------------------------------------------------
 
     /// ESTABLISHED: Update cwnd based on ACK (slow start / congestion avoidance)
-    pub fn on_ack_in_established(&mut self, _seg: &TcpSegment, _bytes_acked: u16) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - update cwnd based on ACK")
+    ///
+    /// `bytes_acked` is how far the ACK moved lastack.
+    pub fn on_ack_in_established(
+        &mut self,
+        _seg: &TcpSegment,
+        conn_mgmt: &ConnectionManagementState,
+        bytes_acked: u32,
+        now: u32,
+        srtt: u32,
+    ) -> Result<(), &'static str> {
+        let mss = conn_mgmt.mss;
+        self.update(|cc, win| cc.on_ack(win, mss, bytes_acked, now, srtt));
+
+        Ok(())
+    }
+
+    /// ESTABLISHED: Handle duplicate ACK (fast retransmit / fast recovery)
+    ///
+    /// The third duplicate is a loss signal; every further one lets the
+    /// algorithm inflate the window.
+    pub fn on_dupack_in_established(
+        &mut self,
+        conn_mgmt: &ConnectionManagementState,
+        dupacks: u8,
+        flight: u32,
+    ) -> Result<(), &'static str> {
+        let mss = conn_mgmt.mss;
+
+        if dupacks == 3 {
+            self.update(|cc, win| cc.on_loss(win, mss, flight));
+        } else if dupacks > 3 {
+            self.update(|cc, win| cc.on_dupack(win, mss));
+        }
+
+        Ok(())
     }
 
-    /// ESTABLISHED: Handle duplicate ACK (fast retransmit)
-    pub fn on_dupack_in_established(&mut self) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - fast retransmit logic")
+    /// ESTABLISHED: New ACK ends fast recovery, deflate the window
+    pub fn on_recovery_exit(&mut self) -> Result<(), &'static str> {
+        self.update(|cc, win| cc.on_recovery_exit(win));
+
+        Ok(())
     }
 
-    /// ESTABLISHED: Handle timeout (congestion event)
-    pub fn on_timeout_in_established(&mut self) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - reduce cwnd on timeout")
+    /// ESTABLISHED: Peer echoed congestion experienced
+    pub fn on_ecn_echo(&mut self, conn_mgmt: &ConnectionManagementState) -> Result<(), &'static str> {
+        let mss = conn_mgmt.mss;
+        self.update(|cc, win| cc.on_ecn(win, mss));
+
+        Ok(())
     }
 
-    /// CLOSE_WAIT: Update cwnd based on ACK
-    pub fn on_ack_in_closewait(&mut self, _seg: &TcpSegment, _bytes_acked: u16) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - update cwnd")
+    /// ESTABLISHED: Handle timeout (congestion event)
+    pub fn on_timeout_in_established(
+        &mut self,
+        conn_mgmt: &ConnectionManagementState,
+        flight: u32,
+    ) -> Result<(), &'static str> {
+        let mss = conn_mgmt.mss;
+        self.update(|cc, win| cc.on_timeout(win, mss, flight));
+
+        Ok(())
     }
 }
@@ -3,7 +3,10 @@
 //! This component owns the TCP state machine and all connection lifecycle data.
 
 use crate::ffi;
-use crate::state::TcpState;
+use crate::ip_addr;
+use crate::state::{TcpEvent, TcpState};
+use crate::tcp_proto;
+use crate::tcp_types::{DataAction, TcpSegment, TimerAction};
 
 /// Connection Management State
 ///
@@ -13,6 +16,9 @@ pub struct ConnectionManagementState {
     /* Connection Identifier (Tuple) */
     pub local_ip: ffi::ip_addr_t,
     pub remote_ip: ffi::ip_addr_t,
+    pub ip_type: u8,            // IPADDR_TYPE_*: which address pair is in use
+    pub local_ip6: [u32; 4],    // IPv6 endpoints, network byte order words
+    pub remote_ip6: [u32; 4],
     pub local_port: u16,
     pub remote_port: u16,
 
@@ -27,17 +33,28 @@ pub struct ConnectionManagementState {
     pub keep_intvl: u32,
     pub keep_cnt: u32,
     pub keep_cnt_sent: u8,
+    pub time_wait_ticks: u32, // Slow ticks left in TIME_WAIT (2MSL timer)
 
     /* Static Connection Parameters & Options */
-    pub mss: u16,
+    pub mss: u16,           // Negotiated MSS: min(ours, peer's)
+    pub eff_mss: u16,       // Payload bytes per segment after path and option overhead
     pub so_options: u8,
     pub tos: u8,
     pub ttl: u8,
     pub prio: u8,
     pub flags: u16, // tcpflags_t
+    pub ecn_enabled: bool,  // ECN negotiated on the handshake (RFC 3168)
+    #[cfg(feature = "md5")]
+    pub md5_key: Option<Vec<u8>>, // Shared secret signing every segment (RFC 2385)
 
     /* Network Interface */
-    pub netif_idx: u8,
+    pub netif_idx: u8,      // Interface segments must leave by (NETIF_NO_INDEX = routed)
+    pub mtu: u16,           // MTU of the outgoing interface (0 = unknown)
+
+    /* Listen Backlog (listeners only) */
+    pub backlog: u8,          // Most half-open connections allowed at once
+    pub accepts_pending: u8,  // Children spawned but not yet established
+    pub opened_passively: bool, // Reached SYN_RCVD from LISTEN (RST returns there)
 }
 
 impl ConnectionManagementState {
@@ -45,6 +62,9 @@ impl ConnectionManagementState {
         Self {
             local_ip: unsafe { core::mem::zeroed() },
             remote_ip: unsafe { core::mem::zeroed() },
+            ip_type: tcp_proto::IPADDR_TYPE_V4,
+            local_ip6: [0; 4],
+            remote_ip6: [0; 4],
             local_port: 0,
             remote_port: 0,
             state: TcpState::Closed,
@@ -55,52 +75,93 @@ impl ConnectionManagementState {
             keep_intvl: 75000,  // TCP_KEEPINTVL_DEFAULT
             keep_cnt: 9,        // TCP_KEEPCNT_DEFAULT
             keep_cnt_sent: 0,
-            mss: 536,           // Default MSS
+            time_wait_ticks: 0,
+            mss: tcp_proto::TCP_MSS, // Default MSS
+            eff_mss: tcp_proto::TCP_MSS,
             so_options: 0,
             tos: 0,
-            ttl: 255,
-            prio: 64,           // TCP_PRIO_NORMAL
+            ttl: tcp_proto::TCP_TTL,
+            prio: tcp_proto::TCP_PRIO_NORMAL,
             flags: 0,
-            netif_idx: 0,
+            ecn_enabled: false,
+            #[cfg(feature = "md5")]
+            md5_key: None,
+            netif_idx: tcp_proto::NETIF_NO_INDEX,
+            mtu: 0,
+            backlog: tcp_proto::TCP_DEFAULT_LISTEN_BACKLOG,
+            accepts_pending: 0,
+            opened_passively: false,
         }
     }
 
+    /// Where `event` takes us from the current state
+    ///
+    /// Every transition is checked against the table in `TcpState::next_state`
+    /// so an illegal event fails the same way everywhere and leaves the
+    /// state untouched.
+    fn next_state(&self, event: TcpEvent) -> Result<TcpState, &'static str> {
+        self.state.next_state(event).ok_or("Invalid state transition")
+    }
+
+    /// `next_state` for handlers that only apply in state `from`
+    fn next_state_from(&self, from: TcpState, event: TcpEvent) -> Result<TcpState, &'static str> {
+        if self.state != from {
+            return Err("Invalid state transition");
+        }
+        self.next_state(event)
+    }
+
     // ------------------------------------------------------------------------
     // Connection Setup (Handshake)
     // ------------------------------------------------------------------------
 
     /// LISTEN → SYN_RCVD: Handle incoming SYN
-    /// Store remote endpoint and transition state
+    /// Store remote endpoint, negotiate options and transition state
     pub fn on_syn_in_listen(
         &mut self,
         remote_ip: ffi::ip_addr_t,
         remote_port: u16,
+        seg: &TcpSegment,
     ) -> Result<(), &'static str> {
-        // Validate we're in LISTEN state
-        if self.state != TcpState::Listen {
-            return Err("Not in LISTEN state");
-        }
+        let next = self.next_state(TcpEvent::RcvSyn)?;
 
         // Store remote endpoint
         self.remote_ip = remote_ip;
         self.remote_port = remote_port;
 
+        self.negotiate_options(seg);
+        self.opened_passively = true;
+
         // Transition to SYN_RCVD
-        self.state = TcpState::SynRcvd;
+        self.state = next;
+
+        Ok(())
+    }
+
+    /// SYN_SENT → SYN_RCVD: Peer's SYN crossed ours (simultaneous open)
+    ///
+    /// Both ends opened actively, so a RST here closes rather than going
+    /// back to LISTEN.
+    pub fn on_syn_in_synsent(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        let next = self.next_state(TcpEvent::RcvSyn)?;
+
+        self.negotiate_options(seg);
+
+        // Transition to SYN_RCVD
+        self.state = next;
 
         Ok(())
     }
 
     /// SYN_SENT → ESTABLISHED: Handle incoming SYN+ACK (active open)
-    /// Transition to ESTABLISHED
-    pub fn on_synack_in_synsent(&mut self) -> Result<(), &'static str> {
-        // Validate we're in SYN_SENT state
-        if self.state != TcpState::SynSent {
-            return Err("Not in SYN_SENT state");
-        }
+    /// Settle the options our SYN offered and transition to ESTABLISHED
+    pub fn on_synack_in_synsent(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        let next = self.next_state(TcpEvent::RcvSynAck)?;
+
+        self.negotiate_options(seg);
 
         // Transition to ESTABLISHED
-        self.state = TcpState::Established;
+        self.state = next;
 
         Ok(())
     }
@@ -108,120 +169,249 @@ impl ConnectionManagementState {
     /// SYN_RCVD → ESTABLISHED: Handle ACK of our SYN (passive open)
     /// Transition to ESTABLISHED
     pub fn on_ack_in_synrcvd(&mut self) -> Result<(), &'static str> {
-        // Validate we're in SYN_RCVD state
-        if self.state != TcpState::SynRcvd {
-            return Err("Not in SYN_RCVD state");
-        }
-
         // Transition to ESTABLISHED
-        self.state = TcpState::Established;
+        self.state = self.next_state(TcpEvent::RcvAckOfSyn)?;
 
         Ok(())
     }
 
+    /// Apply the options of the peer's SYN or SYN+ACK
+    ///
+    /// The MSS is clamped to the peer's; without the option we keep the
+    /// default. Timestamps (RFC 7323) stay on only if the peer's SYN
+    /// carries them too, in which case every segment will carry the option.
+    /// SACK (RFC 2018) likewise needs SACK-permitted from both sides.
+    fn negotiate_options(&mut self, seg: &TcpSegment) {
+        if let Some(peer_mss) = seg.options.mss {
+            if peer_mss > 0 {
+                self.mss = core::cmp::min(self.mss, peer_mss);
+            }
+        }
+
+        if seg.options.ts.is_some() {
+            self.flags |= tcp_proto::TF_TIMESTAMP;
+        } else {
+            self.flags &= !tcp_proto::TF_TIMESTAMP;
+        }
+
+        if seg.options.sack_perm {
+            self.flags |= tcp_proto::TF_SACK;
+        } else {
+            self.flags &= !tcp_proto::TF_SACK;
+        }
+
+        if seg.options.ws.is_some() && self.window_scale_fits() {
+            self.flags |= tcp_proto::TF_WND_SCALE;
+        } else {
+            self.flags &= !tcp_proto::TF_WND_SCALE;
+        }
+
+        // RFC 3168 §6.1.1: an ECN-setup SYN carries ECE and CWR, the
+        // SYN-ACK accepting it only ECE
+        self.ecn_enabled = if seg.flags.ack {
+            seg.flags.ece && !seg.flags.cwr
+        } else {
+            seg.flags.ece && seg.flags.cwr
+        };
+
+        self.eff_mss = self.eff_send_mss();
+    }
+
+    /// Is there room for the window scale option on our SYN?
+    ///
+    /// Not next to an MD5 signature; scaling is then off, whatever the peer offers.
+    pub fn window_scale_fits(&self) -> bool {
+        #[cfg(feature = "md5")]
+        if self.md5_key.is_some() {
+            return false;
+        }
+        true
+    }
+
+    /// Does this connection run over IPv6?
+    pub fn is_ip6(&self) -> bool {
+        self.ip_type == tcp_proto::IPADDR_TYPE_V6
+    }
+
+    /// Does a segment from `remote_ip`:`remote_port` to
+    /// `local_ip`:`local_port` belong to this connection?
+    pub fn matches(
+        &self,
+        local_ip: ffi::ip_addr_t,
+        local_port: u16,
+        remote_ip: ffi::ip_addr_t,
+        remote_port: u16,
+    ) -> bool {
+        if self.local_port != local_port || self.remote_port != remote_port {
+            return false;
+        }
+        if self.is_ip6() {
+            ip_addr::is_v6(&local_ip)
+                && self.local_ip6 == ip_addr::ip6(&local_ip)
+                && self.remote_ip6 == ip_addr::ip6(&remote_ip)
+        } else {
+            ip_addr::eq(&self.local_ip, &local_ip) && ip_addr::eq(&self.remote_ip, &remote_ip)
+        }
+    }
+
+    /// Effective send MSS (lwIP's tcp_eff_send_mss plus option overhead)
+    ///
+    /// The negotiated MSS, limited by what fits through the outgoing
+    /// interface, minus the options every segment carries.
+    fn eff_send_mss(&self) -> u16 {
+        let mut mss = self.mss as usize;
+
+        if self.mtu > 0 {
+            let ip_hlen = if self.is_ip6() { tcp_proto::IP6_HLEN } else { tcp_proto::IP_HLEN };
+            let mtu_mss = (self.mtu as usize).saturating_sub(ip_hlen + tcp_proto::TCP_HLEN);
+            mss = core::cmp::min(mss, mtu_mss);
+        }
+
+        if self.flags & tcp_proto::TF_TIMESTAMP != 0 {
+            // Timestamp option padded to a 32-bit boundary
+            mss = mss.saturating_sub(tcp_proto::TCP_OPT_LEN_TS_PADDED);
+        }
+
+        mss as u16
+    }
+
     // ------------------------------------------------------------------------
     // Connection Teardown (Close)
     // ------------------------------------------------------------------------
 
     /// ESTABLISHED → FIN_WAIT_1: Application initiates close
     pub fn on_close_in_established(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::Established {
-            return Err("Not in ESTABLISHED state");
-        }
-
         // Transition to FIN_WAIT_1
-        self.state = TcpState::FinWait1;
+        self.state = self.next_state_from(TcpState::Established, TcpEvent::Close)?;
 
         Ok(())
     }
 
     /// CLOSE_WAIT → LAST_ACK: Application closes after receiving peer's FIN
     pub fn on_close_in_closewait(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::CloseWait {
-            return Err("Not in CLOSE_WAIT state");
-        }
-
         // Transition to LAST_ACK
-        self.state = TcpState::LastAck;
+        self.state = self.next_state_from(TcpState::CloseWait, TcpEvent::Close)?;
 
         Ok(())
     }
 
     /// ESTABLISHED → CLOSE_WAIT: Receive FIN from peer (passive close)
     pub fn on_fin_in_established(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::Established {
-            return Err("Not in ESTABLISHED state");
-        }
-
         // Transition to CLOSE_WAIT
-        self.state = TcpState::CloseWait;
+        self.state = self.next_state_from(TcpState::Established, TcpEvent::RcvFin)?;
 
         Ok(())
     }
 
     /// FIN_WAIT_1 → FIN_WAIT_2: ACK of our FIN received
     pub fn on_ack_in_finwait1(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::FinWait1 {
-            return Err("Not in FIN_WAIT_1 state");
-        }
-
         // Transition to FIN_WAIT_2
-        self.state = TcpState::FinWait2;
+        self.state = self.next_state_from(TcpState::FinWait1, TcpEvent::RcvAckOfFin)?;
 
         Ok(())
     }
 
     /// FIN_WAIT_1 → CLOSING: Receive FIN (simultaneous close)
     pub fn on_fin_in_finwait1(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::FinWait1 {
-            return Err("Not in FIN_WAIT_1 state");
-        }
-
         // Transition to CLOSING (simultaneous close)
-        self.state = TcpState::Closing;
+        self.state = self.next_state_from(TcpState::FinWait1, TcpEvent::RcvFin)?;
 
         Ok(())
     }
 
     /// FIN_WAIT_2 → TIME_WAIT: Receive FIN
     pub fn on_fin_in_finwait2(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::FinWait2 {
-            return Err("Not in FIN_WAIT_2 state");
-        }
-
         // Transition to TIME_WAIT
-        self.state = TcpState::TimeWait;
+        self.state = self.next_state_from(TcpState::FinWait2, TcpEvent::RcvFin)?;
+        self.time_wait_ticks = tcp_proto::TCP_TIME_WAIT_TICKS;
 
         Ok(())
     }
 
     /// CLOSING → TIME_WAIT: ACK of our FIN received (simultaneous close)
     pub fn on_ack_in_closing(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::Closing {
-            return Err("Not in CLOSING state");
-        }
-
         // Transition to TIME_WAIT
-        self.state = TcpState::TimeWait;
+        self.state = self.next_state_from(TcpState::Closing, TcpEvent::RcvAckOfFin)?;
+        self.time_wait_ticks = tcp_proto::TCP_TIME_WAIT_TICKS;
 
         Ok(())
     }
 
     /// LAST_ACK → CLOSED: ACK of our FIN received (passive close complete)
     pub fn on_ack_in_lastack(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::LastAck {
-            return Err("Not in LAST_ACK state");
-        }
-
         // Transition to CLOSED
-        self.state = TcpState::Closed;
+        self.state = self.next_state_from(TcpState::LastAck, TcpEvent::RcvAckOfFin)?;
 
         Ok(())
     }
 
     /// TIME_WAIT → CLOSED: 2MSL timer expires
     pub fn on_timewait_timeout(&mut self) -> Result<(), &'static str> {
-        unimplemented!("TODO: Implement 2MSL timeout handling")
+        self.state = self.next_state(TcpEvent::TimeWaitTimeout)?;
+
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+    // Fast Recovery
+    // ------------------------------------------------------------------------
+
+    /// ESTABLISHED: Third duplicate ACK, enter fast recovery
+    pub fn on_fast_retransmit(&mut self) -> Result<(), &'static str> {
+        self.flags |= tcp_proto::TF_INFR;
+
+        Ok(())
+    }
+
+    /// Retransmission timeout: fast recovery is abandoned
+    pub fn on_rto_timeout(&mut self) -> Result<(), &'static str> {
+        self.flags &= !tcp_proto::TF_INFR;
+
+        Ok(())
+    }
+
+    /// ESTABLISHED: New data acknowledged, leave fast recovery
+    pub fn on_recovery_exit(&mut self) -> Result<(), &'static str> {
+        self.flags &= !tcp_proto::TF_INFR;
+
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+    // Explicit Congestion Notification
+    // ------------------------------------------------------------------------
+
+    /// ESTABLISHED: Receiver side of ECN
+    ///
+    /// CE in the IP header is echoed with ECE on every ACK until the sender
+    /// confirms its window reduction with CWR. The first CE is ACKed at once.
+    pub fn on_ecn_marks(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        if !self.ecn_enabled {
+            return Ok(());
+        }
+
+        if seg.flags.cwr {
+            self.flags &= !tcp_proto::TF_ECN_ECHO;
+        }
+        if seg.ce && self.flags & tcp_proto::TF_ECN_ECHO == 0 {
+            self.flags |= tcp_proto::TF_ECN_ECHO | tcp_proto::TF_ACK_NOW;
+        }
+
+        Ok(())
+    }
+
+    /// ESTABLISHED: Window reduced for an ECE, announce it with CWR
+    pub fn on_ecn_echo(&mut self) -> Result<(), &'static str> {
+        self.flags |= tcp_proto::TF_ECN_CWR;
+
+        Ok(())
+    }
+
+    /// CWR went out on a data segment
+    pub fn on_cwr_sent(&mut self) -> Result<(), &'static str> {
+        self.flags &= !tcp_proto::TF_ECN_CWR;
+
+        Ok(())
     }
 
     // ------------------------------------------------------------------------
@@ -229,10 +419,25 @@ impl ConnectionManagementState {
     // ------------------------------------------------------------------------
 
     /// ANY → CLOSED: Receive RST or send RST
+    ///
+    /// A passively opened connection still in SYN_RCVD goes back to LISTEN
+    /// and forgets the peer (RFC 793, p. 70).
     pub fn on_rst(&mut self) -> Result<(), &'static str> {
-        // Transition to CLOSED
-        self.state = TcpState::Closed;
-        // TODO: Clean up resources (timers, etc.)
+        if self.state == TcpState::SynRcvd && self.opened_passively {
+            self.state = TcpState::Listen;
+            self.remote_ip = unsafe { core::mem::zeroed() };
+            self.remote_port = 0;
+            self.opened_passively = false;
+        } else {
+            self.state = self.next_state(TcpEvent::RcvRst)?;
+        }
+
+        // Drop pending ACKs and leave fast recovery / RTO retransmission
+        self.flags &= !(tcp_proto::TF_ACK_DELAY
+            | tcp_proto::TF_ACK_NOW
+            | tcp_proto::TF_INFR
+            | tcp_proto::TF_RTO);
+        self.keep_cnt_sent = 0;
 
         Ok(())
     }
@@ -240,7 +445,7 @@ impl ConnectionManagementState {
     /// ANY → CLOSED: Abort connection (send RST)
     pub fn on_abort(&mut self) -> Result<(), &'static str> {
         // Immediately close
-        self.state = TcpState::Closed;
+        self.state = self.next_state(TcpEvent::Abort)?;
 
         Ok(())
     }
@@ -255,12 +460,10 @@ impl ConnectionManagementState {
         local_ip: ffi::ip_addr_t,
         local_port: u16,
     ) -> Result<u16, &'static str> {
-        if self.state != TcpState::Closed {
-            return Err("Can only bind in CLOSED state");
-        }
+        self.next_state(TcpEvent::Bind)?;
 
         if local_port == 0 {
-            return Err("Port 0 not yet supported - provide explicit port");
+            return Err("Port 0 - pick an ephemeral port first");
         }
 
         self.local_ip = local_ip;
@@ -268,17 +471,181 @@ impl ConnectionManagementState {
         Ok(local_port)
     }
 
+    /// Any state: send only through interface `netif_idx` (tcp_bind_netif)
+    ///
+    /// NETIF_NO_INDEX goes back to routing each segment.
+    pub fn on_bind_netif(&mut self, netif_idx: u8) {
+        self.netif_idx = netif_idx;
+    }
+
     /// CLOSED → LISTEN: Start listening for connections
-    pub fn on_listen(&mut self) -> Result<(), &'static str> {
-        if self.state != TcpState::Closed {
-            return Err("Can only listen from CLOSED state");
-        }
+    pub fn on_listen(&mut self, backlog: u8) -> Result<(), &'static str> {
+        let next = self.next_state(TcpEvent::PassiveOpen)?;
 
         if self.local_port == 0 {
             return Err("Must bind to port before listening");
         }
 
-        self.state = TcpState::Listen;
+        self.state = next;
+        // A backlog of 0 still admits one connection, as in lwIP
+        self.backlog = core::cmp::max(backlog, 1);
+        self.accepts_pending = 0;
+        Ok(())
+    }
+
+    /// LISTEN: Reserve a backlog slot for a new child connection
+    pub fn on_backlog_reserve(&mut self) -> Result<(), &'static str> {
+        if self.state != TcpState::Listen {
+            return Err("Not in LISTEN state");
+        }
+        if self.accepts_pending >= self.backlog {
+            return Err("Listen backlog full");
+        }
+
+        self.accepts_pending += 1;
+        Ok(())
+    }
+
+    /// LISTEN: The application delays accepting an established child,
+    /// which takes a backlog slot again (tcp_backlog_delayed)
+    ///
+    /// Unlike a new SYN this is not refused when the backlog is full; it
+    /// only keeps further SYNs out.
+    pub fn on_backlog_delayed(&mut self) -> Result<(), &'static str> {
+        self.accepts_pending = self.accepts_pending.checked_add(1).ok_or("Listen backlog counter overflow")?;
+
+        Ok(())
+    }
+
+    /// LISTEN: A child established or went away, free its backlog slot
+    pub fn on_backlog_release(&mut self) -> Result<(), &'static str> {
+        self.accepts_pending = self.accepts_pending.saturating_sub(1);
+
+        Ok(())
+    }
+
+    /// Set or clear a socket option bit (SOF_*)
+    pub fn set_so_option(&mut self, opt: u8, on: bool) -> Result<(), &'static str> {
+        let known = tcp_proto::SOF_REUSEADDR | tcp_proto::SOF_KEEPALIVE | tcp_proto::SOF_BROADCAST;
+        if opt == 0 || opt & !known != 0 {
+            return Err("Unknown socket option");
+        }
+
+        if on {
+            self.so_options |= opt;
+        } else {
+            self.so_options &= !opt;
+        }
+        Ok(())
+    }
+
+    /// Set the TCP-MD5 key, or remove it with an empty one
+    #[cfg(feature = "md5")]
+    pub fn set_md5_key(&mut self, key: &[u8]) -> Result<(), &'static str> {
+        if key.len() > tcp_proto::TCP_MD5_MAX_KEY_LEN {
+            return Err("MD5 key too long");
+        }
+
+        self.md5_key = if key.is_empty() { None } else { Some(key.to_vec()) };
+        Ok(())
+    }
+
+    /// Poll the application every `interval` slow ticks; 0 never polls
+    pub fn set_poll_interval(&mut self, interval: u8) -> Result<(), &'static str> {
+        self.pollinterval = interval;
+        self.polltmr = 0;
+        Ok(())
+    }
+
+    /// Slow timer: is the poll callback due?
+    ///
+    /// Only open connections are polled; a listener or a connection in
+    /// TIME_WAIT has nothing left for the application to do.
+    pub fn on_poll_tmr(&mut self) -> bool {
+        if self.pollinterval == 0 || matches!(self.state, TcpState::Listen | TcpState::TimeWait) {
+            return false;
+        }
+
+        self.polltmr = self.polltmr.saturating_add(1);
+        if self.polltmr < self.pollinterval {
+            return false;
+        }
+        self.polltmr = 0;
+        true
+    }
+
+    /// Slow timer in TIME_WAIT: has 2MSL elapsed?
+    pub fn on_time_wait_tmr(&mut self) -> bool {
+        self.time_wait_ticks = self.time_wait_ticks.saturating_sub(1);
+        self.time_wait_ticks == 0
+    }
+
+    /// Any segment for this connection: it is alive, restart the idle clock
+    pub fn on_segment_received(&mut self, now: u32) -> Result<(), &'static str> {
+        self.tmr = now;
+        self.keep_cnt_sent = 0;
+        Ok(())
+    }
+
+    /// Slow timer: probe an idle connection, give up after keep_cnt probes
+    ///
+    /// Only runs with SOF_KEEPALIVE set, on ESTABLISHED and CLOSE_WAIT
+    /// connections. `now` is tcp_ticks; keep_* are in milliseconds.
+    pub fn on_keepalive_tmr(&mut self, now: u32) -> TimerAction {
+        if self.so_options & tcp_proto::SOF_KEEPALIVE == 0
+            || !matches!(self.state, TcpState::Established | TcpState::CloseWait)
+        {
+            return TimerAction::None;
+        }
+
+        let idle = now.wrapping_sub(self.tmr);
+        let ticks = |ms: u32| ms / tcp_proto::TCP_SLOW_INTERVAL;
+        let give_up = self.keep_idle.saturating_add(self.keep_cnt.saturating_mul(self.keep_intvl));
+        let next_probe = self.keep_idle.saturating_add((self.keep_cnt_sent as u32).saturating_mul(self.keep_intvl));
+
+        if idle > ticks(give_up) {
+            TimerAction::Abort
+        } else if idle > ticks(next_probe) {
+            self.keep_cnt_sent = self.keep_cnt_sent.saturating_add(1);
+            TimerAction::KeepAlive
+        } else {
+            TimerAction::None
+        }
+    }
+
+    /// New child of `listener`: take over its endpoint and socket options
+    ///
+    /// The child starts in LISTEN so the SYN can be run through it, and
+    /// holds a backlog slot (TF_BACKLOGPEND) until it is established.
+    pub fn on_spawn_from_listener(&mut self, listener: &ConnectionManagementState) -> Result<(), &'static str> {
+        let next = self.next_state(TcpEvent::PassiveOpen)?;
+
+        self.local_ip = listener.local_ip;
+        self.ip_type = listener.ip_type;
+        self.local_ip6 = listener.local_ip6;
+        self.local_port = listener.local_port;
+        self.so_options = listener.so_options & tcp_proto::SOF_INHERITED;
+        self.tos = listener.tos;
+        self.ttl = listener.ttl;
+        self.prio = listener.prio;
+        self.keep_idle = listener.keep_idle;
+        self.keep_intvl = listener.keep_intvl;
+        self.keep_cnt = listener.keep_cnt;
+        self.netif_idx = listener.netif_idx;
+        #[cfg(feature = "md5")]
+        {
+            self.md5_key = listener.md5_key.clone();
+        }
+
+        self.flags |= tcp_proto::TF_BACKLOGPEND;
+        self.state = next;
+        Ok(())
+    }
+
+    /// Child established: its backlog slot has been handed back
+    pub fn on_backlog_accepted(&mut self) -> Result<(), &'static str> {
+        self.flags &= !tcp_proto::TF_BACKLOGPEND;
+
         Ok(())
     }
 
@@ -288,16 +655,31 @@ impl ConnectionManagementState {
         remote_ip: ffi::ip_addr_t,
         remote_port: u16,
     ) -> Result<(), &'static str> {
-        if self.state != TcpState::Closed {
-            return Err("Can only connect from CLOSED state");
-        }
+        let next = self.next_state(TcpEvent::ActiveOpen)?;
 
         // Store remote endpoint
         self.remote_ip = remote_ip;
         self.remote_port = remote_port;
 
+        // Our SYN offers timestamps, SACK and window scaling; the SYN+ACK
+        // decides
+        self.flags |= tcp_proto::TF_TIMESTAMP | tcp_proto::TF_SACK | tcp_proto::TF_WND_SCALE;
+
         // Transition to SYN_SENT
-        self.state = TcpState::SynSent;
+        self.state = next;
+
+        Ok(())
+    }
+
+    /// Receive direction shut down (tcp_shutdown with shut_rx)
+    ///
+    /// Incoming data is still ACKed but no longer handed to the application.
+    pub fn on_shutdown_rx(&mut self) -> Result<(), &'static str> {
+        if self.state == TcpState::Listen {
+            return Err("Cannot shut down a listening connection");
+        }
+
+        self.flags |= tcp_proto::TF_RXCLOSED;
 
         Ok(())
     }
@@ -306,46 +688,87 @@ impl ConnectionManagementState {
     /// Returns: Ok(true) if FIN should be sent, Ok(false) if already closing/closed
     pub fn on_close(&mut self) -> Result<bool, &'static str> {
         match self.state {
-            TcpState::Closed => Ok(false),
-            TcpState::Listen => {
-                self.state = TcpState::Closed;
-                Ok(false)
-            }
-            TcpState::SynSent | TcpState::SynRcvd => {
-                self.state = TcpState::Closed;
-                Ok(false)
-            }
-            TcpState::Established => {
-                self.state = TcpState::FinWait1;
-                Ok(true)
-            }
-            TcpState::CloseWait => {
-                self.state = TcpState::LastAck;
-                Ok(true)
-            }
-            _ => {
-                // Already closing (FinWait1, FinWait2, Closing, LastAck, TimeWait)
-                Ok(false)
-            }
+            TcpState::Closed
+            | TcpState::FinWait1
+            | TcpState::FinWait2
+            | TcpState::Closing
+            | TcpState::LastAck
+            | TcpState::TimeWait => return Ok(false), // Already closed or closing
+            _ => {}
+        }
+
+        self.state = self.next_state(TcpEvent::Close)?;
+
+        // Only a synchronized connection has a FIN to send
+        let send_fin = matches!(self.state, TcpState::FinWait1 | TcpState::LastAck);
+        if send_fin {
+            self.flags |= tcp_proto::TF_FIN;
         }
+        Ok(send_fin)
     }
 
     // ------------------------------------------------------------------------
     // No-op handlers (Connection Management doesn't change in these states)
     // ------------------------------------------------------------------------
 
-    /// ESTABLISHED: Handle data/ACK (no state transition)
-    pub fn on_data_in_established(&mut self) -> Result<(), &'static str> {
-        Ok(()) // No state change for data in ESTABLISHED
+    /// ESTABLISHED: Data received, schedule the ACK (no state transition)
+    ///
+    /// A single in-order segment only sets TF_ACK_DELAY; the second one
+    /// while an ACK is pending asks for an immediate ACK (RFC 1122 4.2.3.2).
+    /// Out-of-order or duplicate data is ACKed at once, the duplicate ACK
+    /// drives the peer's fast retransmit (RFC 5681 4.2). So is a PSH
+    /// segment: the peer has flushed its write and is likely waiting on us.
+    pub fn on_data_in_established(&mut self, seg: &TcpSegment, action: &DataAction) -> Result<(), &'static str> {
+        if *action != DataAction::InOrder || seg.flags.psh {
+            self.flags |= tcp_proto::TF_ACK_NOW;
+        } else if self.flags & tcp_proto::TF_ACK_DELAY != 0 {
+            self.flags &= !tcp_proto::TF_ACK_DELAY;
+            self.flags |= tcp_proto::TF_ACK_NOW;
+        } else {
+            self.flags |= tcp_proto::TF_ACK_DELAY;
+        }
+
+        Ok(())
     }
 
-    /// CLOSE_WAIT: Handle ACK (no state transition)
-    pub fn on_ack_in_closewait(&mut self) -> Result<(), &'static str> {
-        Ok(()) // No state change for ACK in CLOSE_WAIT
+    /// An ACK is going out: nothing is pending any more
+    pub fn on_ack_sent(&mut self) -> Result<(), &'static str> {
+        self.flags &= !(tcp_proto::TF_ACK_DELAY | tcp_proto::TF_ACK_NOW);
+
+        Ok(())
     }
 
     /// TIME_WAIT: Handle retransmitted FIN (no state transition)
     pub fn on_fin_in_timewait(&mut self) -> Result<(), &'static str> {
-        Ok(()) // Remain in TIME_WAIT, restart 2MSL timer
+        // Remain in TIME_WAIT, restart 2MSL timer
+        self.state = self.next_state_from(TcpState::TimeWait, TcpEvent::RcvFin)?;
+        self.time_wait_ticks = tcp_proto::TCP_TIME_WAIT_TICKS;
+
+        Ok(())
+    }
+
+    /// TIME_WAIT → SYN_RCVD: A new SYN reopens the connection
+    ///
+    /// Same endpoints, but nothing negotiated by the old incarnation
+    /// carries over; only the application's TF_NODELAY is kept.
+    pub fn on_syn_in_timewait(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        let next = self.next_state_from(TcpState::TimeWait, TcpEvent::RcvSyn)?;
+
+        self.flags &= tcp_proto::TF_NODELAY;
+        self.mss = tcp_proto::TCP_MSS;
+        self.keep_cnt_sent = 0;
+        self.negotiate_options(seg);
+        self.opened_passively = true;
+
+        self.state = next;
+        Ok(())
+    }
+
+    /// SYN_RCVD: A listener took over this connection, which now holds one
+    /// of its backlog slots
+    pub fn on_backlog_pending(&mut self) -> Result<(), &'static str> {
+        self.flags |= tcp_proto::TF_BACKLOGPEND;
+
+        Ok(())
     }
 }
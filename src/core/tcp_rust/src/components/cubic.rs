@@ -0,0 +1,117 @@
+//! CUBIC Congestion Control
+//!
+//! RFC 8312: after a loss the window follows W(t) = C*(t - K)^3 + W_max,
+//! concave while it climbs back to the previous maximum and convex once it
+//! probes beyond it. A Reno estimate keeps it at least as aggressive as
+//! standard TCP on short-RTT paths.
+
+use crate::components::{slow_start_increase, CongestionControl, CongestionWindow};
+use crate::tcp_proto;
+
+/// Scaling constant C, in segments per second cubed
+const CUBIC_C: f64 = 0.4;
+/// Multiplicative decrease factor
+const CUBIC_BETA: f64 = 0.7;
+/// Reno-equivalent additive increase, per RTT, for the TCP-friendly region
+const CUBIC_ALPHA: f64 = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+
+/// Per-connection CUBIC state; windows are counted in segments
+#[derive(Debug, Default)]
+pub struct CubicCongestionControl {
+    pub w_max: f64,               // Window just before the last reduction
+    pub k: f64,                   // Seconds until W(t) reaches w_max again
+    pub epoch_start: Option<u32>, // tcp_ticks of the first ACK after a reduction
+    origin: f64,                  // Plateau of the cubic curve for this epoch
+    w_est: f64,                   // Reno estimate for the TCP-friendly region
+}
+
+impl CubicCongestionControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// W(t) for `t` seconds into the current epoch
+    pub fn w_cubic(&self, t: f64) -> f64 {
+        CUBIC_C * (t - self.k).powi(3) + self.origin
+    }
+
+    /// Congestion event: remember where it happened and back off by beta
+    ///
+    /// With fast convergence a loss below the previous maximum lowers that
+    /// maximum further, releasing bandwidth to newer flows sooner.
+    fn reduce(&mut self, win: &mut CongestionWindow, mss: u16) {
+        let cwnd = win.cwnd as f64 / mss as f64;
+        self.w_max = if cwnd < self.w_max {
+            cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.epoch_start = None;
+
+        let reduced = (win.cwnd as f64 * CUBIC_BETA) as u32;
+        win.ssthresh = core::cmp::max(reduced, 2 * mss as u32);
+    }
+
+    /// First ACK of a new epoch: anchor the curve at the current window
+    fn start_epoch(&mut self, cwnd: f64, now: u32) {
+        self.epoch_start = Some(now);
+        if cwnd < self.w_max {
+            self.k = ((self.w_max - cwnd) / CUBIC_C).cbrt();
+            self.origin = self.w_max;
+        } else {
+            self.k = 0.0;
+            self.origin = cwnd;
+        }
+        self.w_est = cwnd;
+    }
+}
+
+impl CongestionControl for CubicCongestionControl {
+    /// Slow start below ssthresh, otherwise move cwnd towards W(t + RTT)
+    fn on_ack(&mut self, win: &mut CongestionWindow, mss: u16, acked: u32, now: u32, srtt: u32) {
+        if win.cwnd < win.ssthresh {
+            let increase = slow_start_increase(acked, mss);
+            win.cwnd = win.cwnd.saturating_add(increase);
+            return;
+        }
+
+        let cwnd = win.cwnd as f64 / mss as f64;
+        if self.epoch_start.is_none() {
+            self.start_epoch(cwnd, now);
+        }
+        let ticks = now.wrapping_sub(self.epoch_start.unwrap_or(now));
+        let t = (ticks as f64 * tcp_proto::TCP_SLOW_INTERVAL as f64 + srtt as f64) / 1000.0;
+
+        // Reno would have grown by alpha segments per RTT, i.e. alpha/cwnd per ACK
+        self.w_est += CUBIC_ALPHA / cwnd;
+
+        let target = self.w_cubic(t).max(self.w_est);
+        if target > cwnd {
+            let increase = (target - cwnd) / cwnd * mss as f64;
+            let next = win.cwnd as f64 + increase.max(1.0);
+            win.cwnd = next.min(u32::MAX as f64) as u32;
+        }
+    }
+
+    /// RFC 8312 §4.5: ssthresh = cwnd * beta, then fast recovery as usual
+    fn on_loss(&mut self, win: &mut CongestionWindow, mss: u16, _flight: u32) {
+        self.reduce(win, mss);
+        win.cwnd = win.ssthresh.saturating_add(3 * mss as u32);
+    }
+
+    /// RFC 8312 §4.6: ECN is a congestion event like loss, without recovery
+    fn on_ecn(&mut self, win: &mut CongestionWindow, mss: u16) {
+        self.reduce(win, mss);
+        win.cwnd = win.ssthresh;
+    }
+
+    /// RFC 8312 §4.7: same reduction, restart from one segment
+    fn on_timeout(&mut self, win: &mut CongestionWindow, mss: u16, _flight: u32) {
+        self.reduce(win, mss);
+        win.cwnd = mss as u32;
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
@@ -3,6 +3,7 @@
 //! Manages receive and send windows.
 
 use crate::components::ConnectionManagementState;
+use crate::tcp_proto;
 use crate::tcp_types::TcpSegment;
 
 /// Flow Control State
@@ -11,19 +12,22 @@ use crate::tcp_types::TcpSegment;
 /// Only FC event handlers can write to this state.
 pub struct FlowControlState {
     /* Peer's Receive Window */
-    pub snd_wnd: u16,          // Window the remote peer advertised
-    pub snd_wnd_max: u16,      // Maximum window we've seen from peer
+    pub snd_wnd: u32,          // Window the remote peer advertised, scaled
+    pub snd_wnd_max: u32,      // Maximum window we've seen from peer
     pub snd_wl1: u32,          // For validating window updates
     pub snd_wl2: u32,          // For validating window updates
 
     /* Our Receive Window */
-    pub rcv_wnd: u16,          // Our available receive buffer space
-    pub rcv_ann_wnd: u16,      // Window we will advertise
+    pub rcv_buf: u32,          // Receive buffer size, the most rcv_wnd can reach
+    pub rcv_wnd: u32,          // Our available receive buffer space
+    pub rcv_ann_wnd: u32,      // Window we will advertise, before scaling
     pub rcv_ann_right_edge: u32, // Right edge of advertised window
+    pub rcv_wnd_cap: u32,      // Most we advertise, u32::MAX for no cap
+    pub rcv_mss: u16,          // MSS the receiver SWS threshold is measured in
 
     /* Window Scaling */
-    pub snd_scale: u8,         // Scale factor for our advertisements
-    pub rcv_scale: u8,         // Scale factor for peer's advertisements
+    pub snd_scale: u8,         // Shift applied to the peer's advertisements
+    pub rcv_scale: u8,         // Shift applied to our advertisements
 
     /* Zero Window Probing */
     pub persist_cnt: u8,
@@ -38,9 +42,12 @@ impl FlowControlState {
             snd_wnd_max: 0,
             snd_wl1: 0,
             snd_wl2: 0,
+            rcv_buf: tcp_proto::TCP_WND as u32,
             rcv_wnd: 0,
             rcv_ann_wnd: 0,
             rcv_ann_right_edge: 0,
+            rcv_wnd_cap: u32::MAX,
+            rcv_mss: tcp_proto::TCP_MSS,
             snd_scale: 0,
             rcv_scale: 0,
             persist_cnt: 0,
@@ -57,33 +64,79 @@ impl FlowControlState {
     pub fn on_syn_in_listen(
         &mut self,
         seg: &TcpSegment,
-        _conn_mgmt: &ConnectionManagementState,
+        conn_mgmt: &ConnectionManagementState,
     ) -> Result<(), &'static str> {
-        // Store peer's advertised window
-        self.snd_wnd = seg.wnd;
-        self.snd_wnd_max = seg.wnd;
+        // Store peer's advertised window; a SYN's is never scaled
+        self.snd_wnd = seg.wnd as u32;
+        self.snd_wnd_max = seg.wnd as u32;
+        self.negotiate_scale(seg, conn_mgmt);
+        // One before the SYN, so the first ACK always updates the window
+        self.snd_wl1 = seg.seqno.wrapping_sub(1);
 
         // Initialize our receive window
-        // TODO: Base this on actual buffer size
-        self.rcv_wnd = 4096;
-        self.rcv_ann_wnd = self.rcv_wnd;
+        self.rcv_wnd = self.rcv_buf;
+        self.rcv_ann_wnd = self.capped(self.rcv_wnd);
+        self.rcv_ann_right_edge = seg.seqno.wrapping_add(1).wrapping_add(self.rcv_ann_wnd);
+
+        Ok(())
+    }
+
+    /// SYN_SENT → SYN_RCVD: Store peer's advertised window (simultaneous open)
+    pub fn on_syn_in_synsent(
+        &mut self,
+        seg: &TcpSegment,
+        conn_mgmt: &ConnectionManagementState,
+    ) -> Result<(), &'static str> {
+        // Store peer's advertised window; a SYN's is never scaled
+        self.snd_wnd = seg.wnd as u32;
+        self.snd_wnd_max = seg.wnd as u32;
+        self.negotiate_scale(seg, conn_mgmt);
+        self.snd_wl1 = seg.seqno.wrapping_sub(1);
+        self.rcv_ann_right_edge = seg.seqno.wrapping_add(1).wrapping_add(self.rcv_ann_wnd);
 
         Ok(())
     }
 
     /// SYN_SENT → ESTABLISHED: Store peer's advertised window
-    pub fn on_synack_in_synsent(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
-        // Store peer's advertised window
-        self.snd_wnd = seg.wnd;
-        self.snd_wnd_max = seg.wnd;
+    pub fn on_synack_in_synsent(
+        &mut self,
+        seg: &TcpSegment,
+        conn_mgmt: &ConnectionManagementState,
+    ) -> Result<(), &'static str> {
+        // Store peer's advertised window; a SYN's is never scaled
+        self.snd_wnd = seg.wnd as u32;
+        self.snd_wnd_max = seg.wnd as u32;
+        self.negotiate_scale(seg, conn_mgmt);
+        self.snd_wl1 = seg.seqno.wrapping_sub(1);
+        self.snd_wl2 = seg.ackno;
+        self.rcv_ann_right_edge = seg.seqno.wrapping_add(1).wrapping_add(self.rcv_ann_wnd);
 
         Ok(())
     }
 
+    /// Window scaling is on only if both SYNs carried the option
+    /// (RFC 7323, 2.2): an active open offers it unless an MD5 signature
+    /// leaves no room, a passive one answers only an offer
+    fn negotiate_scale(&mut self, seg: &TcpSegment, conn_mgmt: &ConnectionManagementState) {
+        match seg.options.ws {
+            Some(shift) if conn_mgmt.window_scale_fits() => {
+                self.snd_scale = shift;
+                self.rcv_scale = tcp_proto::TCP_RCV_SCALE;
+            }
+            _ => {
+                self.snd_scale = 0;
+                self.rcv_scale = 0;
+            }
+        }
+    }
+
     /// SYN_RCVD → ESTABLISHED: Update peer's window
     pub fn on_ack_in_synrcvd(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
         // Update peer's advertised window
-        self.snd_wnd = seg.wnd;
+        self.snd_wnd = self.scaled(seg.wnd);
+        self.snd_wnd_max = core::cmp::max(self.snd_wnd_max, self.snd_wnd);
+        self.snd_wl1 = seg.seqno;
+        self.snd_wl2 = seg.ackno;
 
         Ok(())
     }
@@ -137,6 +190,19 @@ impl FlowControlState {
         Ok(()) // No window change
     }
 
+    /// TIME_WAIT → SYN_RCVD: Fresh windows for the new incarnation
+    pub fn on_syn_in_timewait(
+        &mut self,
+        seg: &TcpSegment,
+        conn_mgmt: &ConnectionManagementState,
+    ) -> Result<(), &'static str> {
+        let rcv_buf = self.rcv_buf;
+        *self = Self::new();
+        self.rcv_buf = rcv_buf;
+
+        self.on_syn_in_listen(seg, conn_mgmt)
+    }
+
     // ------------------------------------------------------------------------
     // Reset Handling
     // ------------------------------------------------------------------------
@@ -147,6 +213,11 @@ impl FlowControlState {
         self.snd_wnd = 0;
         self.rcv_wnd = 0;
 
+        // Stop the persist timer
+        self.persist_cnt = 0;
+        self.persist_backoff = 0;
+        self.persist_probe = 0;
+
         Ok(())
     }
 
@@ -156,6 +227,11 @@ impl FlowControlState {
         self.snd_wnd = 0;
         self.rcv_wnd = 0;
 
+        // Stop the persist timer
+        self.persist_cnt = 0;
+        self.persist_backoff = 0;
+        self.persist_probe = 0;
+
         Ok(())
     }
 
@@ -166,8 +242,8 @@ impl FlowControlState {
     /// CLOSED → SYN_SENT: Initialize our receive window for active open
     pub fn on_connect(&mut self) -> Result<(), &'static str> {
         // Initialize our receive window
-        self.rcv_wnd = 4096;
-        self.rcv_ann_wnd = self.rcv_wnd;
+        self.rcv_wnd = self.rcv_buf;
+        self.rcv_ann_wnd = self.capped(self.rcv_wnd);
 
         Ok(())
     }
@@ -176,18 +252,217 @@ impl FlowControlState {
     // Data Path (Future - for ESTABLISHED state)
     // ------------------------------------------------------------------------
 
-    /// ESTABLISHED: Update windows based on incoming segment
-    pub fn on_data_in_established(&mut self, _seg: &TcpSegment) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - update snd_wnd, rcv_wnd")
+    /// ESTABLISHED: `accepted` in-order bytes took up receive buffer
+    ///
+    /// `rcv_nxt` is the value after the data was accepted.
+    pub fn on_data_in_established(
+        &mut self,
+        accepted: u32,
+        rcv_nxt: u32,
+        conn_mgmt: &ConnectionManagementState,
+    ) -> Result<(), &'static str> {
+        self.rcv_wnd = self.rcv_wnd.saturating_sub(accepted);
+        self.rcv_mss = conn_mgmt.mss;
+        self.update_rcv_ann_wnd(rcv_nxt);
+
+        Ok(())
+    }
+
+    /// Application consumed `freed` bytes (tcp_recved): refund the window
+    ///
+    /// Refunding more than was taken is clamped, so the window never grows
+    /// past `rcv_buf`. Returns the window to advertise once the right edge
+    /// moved far enough to be worth a window update, None while receiver
+    /// SWS avoidance holds it back.
+    pub fn recv_window_update(&mut self, freed: u16) -> Option<u16> {
+        let outstanding = self.rcv_buf.saturating_sub(self.rcv_wnd);
+        self.rcv_wnd += core::cmp::min(freed as u32, outstanding);
+
+        // What is left of the advertised window ends at its right edge
+        let rcv_nxt = self.rcv_ann_right_edge.wrapping_sub(self.rcv_ann_wnd);
+        match self.update_rcv_ann_wnd(rcv_nxt) {
+            0 => None,
+            _ => Some(self.advertised_window()),
+        }
+    }
+
+    /// Cap the window we advertise at `bytes`, 0 lifting the cap
+    ///
+    /// Rate-limits the peer below what the buffer would allow. A lower cap
+    /// takes effect as the peer fills the window already advertised; it
+    /// is never taken back.
+    pub fn set_max_rcv_wnd(&mut self, bytes: u32) -> Result<(), &'static str> {
+        self.rcv_wnd_cap = if bytes == 0 { u32::MAX } else { bytes };
+
+        Ok(())
+    }
+
+    /// `wnd` limited by the advertisement cap
+    fn capped(&self, wnd: u32) -> u32 {
+        core::cmp::min(wnd, self.rcv_wnd_cap)
+    }
+
+    /// Receiver SWS avoidance (RFC 1122, 4.2.3.3)
+    ///
+    /// The right edge only advances once it can move by min(MSS, half the
+    /// largest window we would advertise); until then the window we
+    /// advertise shrinks as data arrives, but the right edge itself never
+    /// moves left. Returns how far the edge advanced.
+    fn update_rcv_ann_wnd(&mut self, rcv_nxt: u32) -> u32 {
+        let wnd = self.capped(self.rcv_wnd);
+        let new_right_edge = rcv_nxt.wrapping_add(wnd);
+        let threshold = core::cmp::min(self.rcv_mss as u32, self.capped(self.rcv_buf) / 2);
+
+        if (new_right_edge.wrapping_sub(self.rcv_ann_right_edge.wrapping_add(threshold)) as i32) >= 0 {
+            let advanced = new_right_edge.wrapping_sub(self.rcv_ann_right_edge);
+            self.rcv_ann_wnd = wnd;
+            self.rcv_ann_right_edge = new_right_edge;
+            return advanced;
+        }
+
+        // Keep the old edge: advertise what is left up to it
+        let left = self.rcv_ann_right_edge.wrapping_sub(rcv_nxt) as i32;
+        self.rcv_ann_wnd = left.max(0) as u32;
+        0
+    }
+
+    /// The peer's advertised `wnd` in bytes
+    fn scaled(&self, wnd: u16) -> u32 {
+        (wnd as u32) << self.snd_scale
+    }
+
+    /// Bytes the peer's window lets us have in flight
+    pub fn effective_snd_wnd(&self) -> u32 {
+        self.snd_wnd
+    }
+
+    /// Window field for an outgoing non-SYN segment
+    ///
+    /// Shifting rounds down, so we never offer more than we have.
+    pub fn advertised_window(&self) -> u16 {
+        (self.rcv_ann_wnd >> self.rcv_scale).min(u16::MAX as u32) as u16
+    }
+
+    /// Is `seg` at least as new as the last window update? (RFC 793, p. 72)
+    fn updates_window(&self, seg: &TcpSegment) -> bool {
+        let newer_seq = (seg.seqno.wrapping_sub(self.snd_wl1) as i32) > 0;
+        let newer_ack = seg.seqno == self.snd_wl1 && (seg.ackno.wrapping_sub(self.snd_wl2) as i32) >= 0;
+        newer_seq || newer_ack
+    }
+
+    /// Will `seg` change the send window?
+    pub fn is_window_update(&self, seg: &TcpSegment) -> bool {
+        self.updates_window(seg) && self.scaled(seg.wnd) != self.snd_wnd
     }
 
     /// ESTABLISHED: Update send window from ACK
-    pub fn on_ack_in_established(&mut self, _seg: &TcpSegment, _bytes_acked: u16) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - update snd_wnd")
+    ///
+    /// Only a segment at least as new as the last window update counts, so
+    /// a reordered one cannot take back a window the peer has reopened
+    /// after shrinking it. A window that opens again stops the persist
+    /// timer.
+    pub fn on_ack_in_established(&mut self, seg: &TcpSegment, _bytes_acked: u16) -> Result<(), &'static str> {
+        if !self.updates_window(seg) {
+            return Ok(());
+        }
+
+        self.snd_wnd = self.scaled(seg.wnd);
+        self.snd_wnd_max = core::cmp::max(self.snd_wnd_max, self.snd_wnd);
+        self.snd_wl1 = seg.seqno;
+        self.snd_wl2 = seg.ackno;
+
+        if self.snd_wnd > 0 {
+            self.persist_cnt = 0;
+            self.persist_backoff = 0;
+            self.persist_probe = 0;
+        }
+
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+    // Zero Window Probing (Persist Timer)
+    // ------------------------------------------------------------------------
+
+    /// Peer window closed with data waiting: start the persist timer
+    pub fn on_zero_window(&mut self) -> Result<(), &'static str> {
+        if self.persist_backoff == 0 {
+            self.persist_cnt = 0;
+            self.persist_backoff = 1;
+            self.persist_probe = 0;
+        }
+
+        Ok(())
     }
 
-    /// CLOSE_WAIT: Update send window from ACK
-    pub fn on_ack_in_closewait(&mut self, _seg: &TcpSegment, _bytes_acked: u16) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - update snd_wnd")
+    /// Slow timer tick while persisting: true when a probe is due
+    ///
+    /// Each probe moves one step along TCP_PERSIST_BACKOFF, staying on
+    /// the last step once reached.
+    pub fn on_persist_tick(&mut self) -> bool {
+        if self.persist_backoff == 0 {
+            return false;
+        }
+
+        self.persist_cnt = self.persist_cnt.saturating_add(1);
+        if self.persist_cnt < tcp_proto::TCP_PERSIST_BACKOFF[self.persist_backoff as usize - 1] {
+            return false;
+        }
+
+        self.persist_cnt = 0;
+        if (self.persist_backoff as usize) < tcp_proto::TCP_PERSIST_BACKOFF.len() {
+            self.persist_backoff += 1;
+        }
+        self.persist_probe = self.persist_probe.saturating_add(1);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlowControlState;
+    use crate::tcp_proto;
+
+    /// 4000 bytes of a TCP_WND buffer received at RCV.NXT 2001, none read
+    fn filled() -> FlowControlState {
+        let mut fc = FlowControlState::new();
+        fc.rcv_wnd = tcp_proto::TCP_WND as u32 - 4000;
+        fc.rcv_ann_wnd = fc.rcv_wnd;
+        fc.rcv_ann_right_edge = 2001 + fc.rcv_wnd;
+        fc
+    }
+
+    #[test]
+    fn test_small_frees_held_back_until_threshold() {
+        let mut fc = filled();
+        let edge = fc.rcv_ann_right_edge;
+        let threshold = (tcp_proto::TCP_MSS as u32).min(tcp_proto::TCP_WND as u32 / 2);
+
+        let mut freed = 0;
+        let wnd = loop {
+            freed += 100;
+            match fc.recv_window_update(100) {
+                Some(wnd) => break wnd,
+                None => {
+                    assert!(freed < threshold);
+                    assert_eq!(fc.rcv_ann_right_edge, edge);
+                }
+            }
+        };
+        assert!(freed >= threshold);
+        assert_eq!(wnd as u32, tcp_proto::TCP_WND as u32 - 4000 + freed);
+        assert_eq!(fc.rcv_ann_right_edge, edge + freed);
+    }
+
+    #[test]
+    fn test_over_refund_clamped_to_buffer() {
+        let mut fc = filled();
+
+        assert_eq!(fc.recv_window_update(u16::MAX), Some(tcp_proto::TCP_WND));
+        assert_eq!(fc.rcv_wnd, fc.rcv_buf);
+
+        // Nothing outstanding: nothing more to advertise
+        assert_eq!(fc.recv_window_update(1000), None);
+        assert_eq!(fc.rcv_wnd, fc.rcv_buf);
     }
 }
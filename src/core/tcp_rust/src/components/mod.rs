@@ -11,11 +11,15 @@ mod connection_mgmt;
 mod rod;
 mod flow_control;
 mod congestion_control;
+mod cubic;
 
 pub use connection_mgmt::ConnectionManagementState;
-pub use rod::ReliableOrderedDeliveryState;
+pub use rod::{OoseqQueue, OoseqSegment, ReliableOrderedDeliveryState, UnackedQueue, UnackedSegment};
 pub use flow_control::FlowControlState;
-pub use congestion_control::CongestionControlState;
+pub use congestion_control::{
+    slow_start_increase, CongestionControl, CongestionControlState, CongestionWindow, RenoCongestionControl,
+};
+pub use cubic::CubicCongestionControl;
 
 /// Demultiplexing State
 ///
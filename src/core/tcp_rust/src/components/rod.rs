@@ -2,7 +2,267 @@
 //!
 //! Handles sequence numbers, ACKs, retransmissions, and buffering.
 
-use crate::tcp_types::TcpSegment;
+use std::collections::VecDeque;
+
+use crate::send_queue::SendQueue;
+use crate::tcp_proto;
+use crate::tcp_types::{DataAction, SegmentDesc, TcpSegment, TxSegment};
+
+/// A transmitted segment that has not been acknowledged yet
+pub struct UnackedSegment {
+    pub seqno: u32,
+    pub flags: u8,     // TCP header flags the segment was sent with
+    pub data: Vec<u8>, // Payload, kept for retransmission
+    pub sent: u32,     // tcp_ticks of the last (re)transmission
+    pub nrtx: u8,      // Times this segment has been retransmitted
+}
+
+impl UnackedSegment {
+    pub fn new(seqno: u32, flags: u8, data: Vec<u8>) -> Self {
+        Self { seqno, flags, data, sent: 0, nrtx: 0 }
+    }
+
+    /// Sequence space consumed by this segment (payload plus SYN/FIN)
+    pub fn seq_len(&self) -> u32 {
+        let syn_fin = (self.flags & (tcp_proto::TCP_SYN | tcp_proto::TCP_FIN) != 0) as u32;
+        self.data.len() as u32 + syn_fin
+    }
+}
+
+/// Retransmission queue: sent segments awaiting acknowledgment, oldest first
+#[derive(Default)]
+pub struct UnackedQueue {
+    segs: VecDeque<UnackedSegment>,
+}
+
+impl UnackedQueue {
+    pub fn new() -> Self {
+        Self { segs: VecDeque::new() }
+    }
+
+    pub fn push(&mut self, seg: UnackedSegment) {
+        self.segs.push_back(seg);
+    }
+
+    /// Oldest unacknowledged segment
+    pub fn front(&self) -> Option<&UnackedSegment> {
+        self.segs.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.segs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segs.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &UnackedSegment> {
+        self.segs.iter()
+    }
+
+    /// Remove everything a cumulative ACK covers
+    ///
+    /// Segments wholly below `ackno` are dropped; one it ends inside is
+    /// trimmed to the unacknowledged rest, so only that goes out again.
+    /// Returns the payload bytes freed; SYN and FIN are not counted.
+    pub fn ack_upto(&mut self, ackno: u32) -> usize {
+        let mut freed = 0;
+        while let Some(seg) = self.segs.front_mut() {
+            let end = seg.seqno.wrapping_add(seg.seq_len());
+            if ReliableOrderedDeliveryState::seq_leq(end, ackno) {
+                freed += seg.data.len();
+                self.segs.pop_front();
+                continue;
+            }
+
+            if !ReliableOrderedDeliveryState::seq_gt(ackno, seg.seqno) {
+                break;
+            }
+
+            // Partial ACK: the SYN goes first, then payload bytes
+            let mut covered = ackno.wrapping_sub(seg.seqno);
+            if seg.flags & tcp_proto::TCP_SYN != 0 {
+                seg.flags &= !tcp_proto::TCP_SYN;
+                covered -= 1;
+            }
+            let covered = (covered as usize).min(seg.data.len());
+            seg.data.drain(..covered);
+            seg.seqno = ackno;
+            freed += covered;
+            break;
+        }
+        freed
+    }
+
+    /// Drop every queued segment
+    pub fn clear(&mut self) {
+        self.segs.clear();
+    }
+}
+
+/// Received data beyond rcv_nxt, waiting for the gap before it to fill
+pub struct OoseqSegment {
+    pub seqno: u32,
+    pub data: Vec<u8>,
+    pub push: bool, // PSH was set on the segment ending here
+}
+
+impl OoseqSegment {
+    fn end(&self) -> u32 {
+        self.seqno.wrapping_add(self.data.len() as u32)
+    }
+}
+
+/// Out-of-order reassembly queue, sorted by sequence number
+///
+/// Queued segments never overlap. Memory is bounded by
+/// TCP_OOSEQ_MAX_BYTES; the furthest data is dropped first.
+#[derive(Default)]
+pub struct OoseqQueue {
+    segs: VecDeque<OoseqSegment>,
+    recent: VecDeque<u32>, // Start of the latest arrivals, newest first, for SACK
+}
+
+impl OoseqQueue {
+    pub fn new() -> Self {
+        Self { segs: VecDeque::new(), recent: VecDeque::new() }
+    }
+
+    /// Queue `data` starting at `seqno`, keeping only bytes not held yet
+    ///
+    /// `push` belongs to the last byte of `data`, so it goes with
+    /// whichever queued segment ends up holding that byte.
+    pub fn insert(&mut self, seqno: u32, data: &[u8], push: bool) {
+        self.recent.retain(|&s| s != seqno);
+        self.recent.push_front(seqno);
+        self.recent.truncate(tcp_proto::TCP_SACK_MAX_BLOCKS);
+
+        let mut seqno = seqno;
+        let mut data = data;
+        let mut idx = 0;
+
+        while !data.is_empty() {
+            let end = seqno.wrapping_add(data.len() as u32);
+
+            let Some((seg_start, seg_end)) = self.segs.get(idx).map(|seg| (seg.seqno, seg.end())) else {
+                self.segs.push_back(OoseqSegment { seqno, data: data.to_vec(), push });
+                break;
+            };
+
+            if ReliableOrderedDeliveryState::seq_leq(seg_end, seqno) {
+                // Entirely before us, look further
+                idx += 1;
+            } else if ReliableOrderedDeliveryState::seq_leq(end, seg_start) {
+                // Entirely before the queued segment: fits in the gap
+                self.segs.insert(idx, OoseqSegment { seqno, data: data.to_vec(), push });
+                break;
+            } else if ReliableOrderedDeliveryState::seq_gt(seg_start, seqno) {
+                // Overlaps the queued segment: keep the part in front of it
+                let head = seg_start.wrapping_sub(seqno) as usize;
+                self.segs.insert(idx, OoseqSegment { seqno, data: data[..head].to_vec(), push: false });
+                idx += 1;
+                seqno = seg_start;
+                data = &data[head..];
+            } else {
+                // Starts inside the queued segment: skip what it already holds
+                let skip = (seg_end.wrapping_sub(seqno) as usize).min(data.len());
+                if skip == data.len() {
+                    self.segs[idx].push |= push;
+                }
+                seqno = seqno.wrapping_add(skip as u32);
+                data = &data[skip..];
+                idx += 1;
+            }
+        }
+
+        while self.bytes() > tcp_proto::TCP_OOSEQ_MAX_BYTES {
+            self.segs.pop_back();
+        }
+    }
+
+    /// Remove and return the data that continues from `rcv_nxt`, and
+    /// whether the last segment taken had PSH set
+    ///
+    /// Segments that now lie entirely below `rcv_nxt` are discarded.
+    pub fn take_contiguous(&mut self, rcv_nxt: u32) -> (Vec<u8>, bool) {
+        let mut out = Vec::new();
+        let mut push = false;
+        let mut next = rcv_nxt;
+
+        while let Some(seg) = self.segs.front() {
+            if ReliableOrderedDeliveryState::seq_gt(seg.seqno, next) {
+                break;
+            }
+            let seg = self.segs.pop_front().unwrap();
+            if ReliableOrderedDeliveryState::seq_gt(seg.end(), next) {
+                let skip = next.wrapping_sub(seg.seqno) as usize;
+                out.extend_from_slice(&seg.data[skip..]);
+                next = seg.end();
+                push = seg.push;
+            }
+        }
+
+        (out, push)
+    }
+
+    /// Contiguous queued ranges as SACK blocks, at most `max`
+    ///
+    /// RFC 2018, section 4: the block holding the latest arrival comes
+    /// first, then those of earlier arrivals, then the remaining ranges
+    /// from the highest down.
+    pub fn sack_blocks(&self, max: usize) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        for seg in &self.segs {
+            match ranges.last_mut() {
+                Some(last) if last.1 == seg.seqno => last.1 = seg.end(),
+                _ => ranges.push((seg.seqno, seg.end())),
+            }
+        }
+
+        let mut blocks = Vec::with_capacity(max);
+        let holding = |seqno: u32| {
+            ranges.iter().copied().find(|&(left, right)| {
+                ReliableOrderedDeliveryState::seq_leq(left, seqno)
+                    && ReliableOrderedDeliveryState::seq_lt(seqno, right)
+            })
+        };
+        let recent = self.recent.iter().filter_map(|&seqno| holding(seqno));
+        for block in recent.chain(ranges.iter().rev().copied()) {
+            if blocks.len() == max {
+                break;
+            }
+            if !blocks.contains(&block) {
+                blocks.push(block);
+            }
+        }
+        blocks
+    }
+
+    /// Lowest queued sequence number
+    pub fn front(&self) -> Option<&OoseqSegment> {
+        self.segs.front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.segs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segs.is_empty()
+    }
+
+    /// Total number of queued bytes
+    pub fn bytes(&self) -> usize {
+        self.segs.iter().map(|seg| seg.data.len()).sum()
+    }
+
+    /// Drop every queued segment
+    pub fn clear(&mut self) {
+        self.segs.clear();
+        self.recent.clear();
+    }
+}
 
 /// Reliable Ordered Delivery State
 ///
@@ -20,26 +280,39 @@ pub struct ReliableOrderedDeliveryState {
 
     /* Send Buffer Management */
     pub snd_lbb: u32,      // Sequence number of next byte to be buffered
-    pub snd_buf: u16,      // Available space in send buffer (simplified for now)
-    pub snd_queuelen: u16, // Number of pbufs in send queues
+    pub snd_buf: u16,      // Free space in the send buffer (bytes)
+    pub snd_queuelen: u16, // Segments on unsent and unacked (lwIP's pbuf count)
     pub bytes_acked: u16,  // Bytes acknowledged in current round
+    pub acked: u32,        // Application bytes acked, not yet reported to the sent callback
+    pub unsent: SendQueue,     // Written by the application, not yet sent
+    pub unacked: UnackedQueue, // Sent but not yet acknowledged
+    pub sacked: Vec<(u32, u32)>, // Ranges above lastack the peer SACKed, sorted, disjoint
+    pub ooseq: OoseqQueue,     // Received beyond rcv_nxt, awaiting reassembly
+    pub rcv_ready: Vec<u8>,    // In-order data not yet passed to the application
+    pub rcv_fin: bool,         // FIN received, EOF not yet passed to the application
+    pub rcv_push: bool,        // The last byte of rcv_ready came with PSH
+    pub fin_pending: bool,     // Our FIN is queued behind unsent data, not sent yet
 
     /* Retransmission Timer & RTT Estimation */
-    pub rtime: i16,        // Retransmission timer countdown
+    pub rtime: i16,        // Retransmission timer countdown (slow ticks, 0 = stopped)
     pub rttest: u32,       // RTT measurement start time
     pub rtseq: u32,        // Sequence number being timed for RTT
-    pub sa: i16,           // Smoothed RTT
-    pub sv: i16,           // RTT variance
-    pub rto: i16,          // Retransmission Timeout value
+    pub sa: i32,           // Smoothed RTT, scaled by 8 (ms)
+    pub sv: i32,           // RTT mean deviation, scaled by 4 (ms)
+    pub rto: u32,          // Retransmission Timeout value (ms)
     pub nrtx: u8,          // Number of retransmissions
 
     /* Fast Retransmit / Recovery State */
     pub dupacks: u8,       // Duplicate ACK counter
     pub rto_end: u32,      // End of RTO recovery
+    pub ecn_recover: u32,  // snd_nxt at the last ECN window reduction
 
     /* TCP Timestamps */
     pub ts_lastacksent: u32,
     pub ts_recent: u32,
+
+    /* RFC 5961 */
+    pub last_challenge_tick: Option<u32>, // tcp_ticks of our last challenge ACK
 }
 
 impl ReliableOrderedDeliveryState {
@@ -51,20 +324,31 @@ impl ReliableOrderedDeliveryState {
             iss: 0,
             irs: 0,
             snd_lbb: 0,
-            snd_buf: 0,
+            snd_buf: tcp_proto::TCP_SND_BUF,
             snd_queuelen: 0,
             bytes_acked: 0,
+            acked: 0,
+            unsent: SendQueue::new(),
+            unacked: UnackedQueue::new(),
+            sacked: Vec::new(),
+            ooseq: OoseqQueue::new(),
+            rcv_ready: Vec::new(),
+            rcv_fin: false,
+            rcv_push: false,
+            fin_pending: false,
             rtime: 0,
             rttest: 0,
             rtseq: 0,
             sa: 0,
-            sv: 0,
-            rto: 3000,          // Default RTO: 3 seconds
+            sv: tcp_proto::TCP_RTO_INITIAL as i32, // rto = sa/8 + sv until the first sample
+            rto: tcp_proto::TCP_RTO_INITIAL,
             nrtx: 0,
             dupacks: 0,
             rto_end: 0,
+            ecn_recover: 0,
             ts_lastacksent: 0,
             ts_recent: 0,
+            last_challenge_tick: None,
         }
     }
 
@@ -73,31 +357,26 @@ impl ReliableOrderedDeliveryState {
     // ------------------------------------------------------------------------
 
     /// LISTEN → SYN_RCVD: Initialize sequence numbers from incoming SYN
-    pub fn on_syn_in_listen(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+    ///
+    /// Our side starts at `iss`, see tcp_iss::generate_iss.
+    pub fn on_syn_in_listen(&mut self, seg: &TcpSegment, iss: u32) -> Result<(), &'static str> {
         // Store peer's initial sequence number
         self.irs = seg.seqno;
         self.rcv_nxt = seg.seqno.wrapping_add(1);
+        self.ts_recent = seg.options.ts.map_or(0, |(tsval, _)| tsval);
 
-        // Generate our initial sequence number (ISS)
-        // TODO: Use proper ISS generation per RFC 6528 (currently simplified)
-        self.iss = Self::generate_iss();
-        self.snd_nxt = self.iss;
-        self.snd_lbb = self.iss;
-        self.lastack = self.iss;
+        self.start_from_iss(iss);
 
         Ok(())
     }
 
-    /// Generate Initial Sequence Number (ISS)
-    ///
-    /// TODO: Implement proper ISS generation per RFC 6528
-    /// For now, use a simple counter
-    fn generate_iss() -> u32 {
-        unsafe {
-            static mut ISS_COUNTER: u32 = 0;
-            ISS_COUNTER = ISS_COUNTER.wrapping_add(1);
-            ISS_COUNTER
-        }
+    /// Our side of the sequence space begins at `iss`
+    fn start_from_iss(&mut self, iss: u32) {
+        self.iss = iss;
+        self.snd_nxt = iss;
+        self.snd_lbb = iss.wrapping_add(1); // Data follows our SYN
+        self.lastack = iss;
+        self.ecn_recover = iss;
     }
 
     /// SYN_SENT → ESTABLISHED: Process SYN+ACK, update sequence numbers
@@ -110,6 +389,7 @@ impl ReliableOrderedDeliveryState {
         // Store peer's initial sequence number
         self.irs = seg.seqno;
         self.rcv_nxt = seg.seqno.wrapping_add(1);
+        self.ts_recent = seg.options.ts.map_or(0, |(tsval, _)| tsval);
 
         // Update our sequence number (SYN is now ACKed)
         self.snd_nxt = self.iss.wrapping_add(1);
@@ -118,6 +398,17 @@ impl ReliableOrderedDeliveryState {
         Ok(())
     }
 
+    /// SYN_SENT → SYN_RCVD: Peer's SYN crossed ours (simultaneous open)
+    ///
+    /// Our ISS stays; the SYN+ACK we answer with repeats our SYN.
+    pub fn on_syn_in_synsent(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        self.irs = seg.seqno;
+        self.rcv_nxt = seg.seqno.wrapping_add(1);
+        self.ts_recent = seg.options.ts.map_or(0, |(tsval, _)| tsval);
+
+        Ok(())
+    }
+
     /// SYN_RCVD → ESTABLISHED: Process ACK of our SYN
     pub fn on_ack_in_synrcvd(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
         // Validate ACK is for our SYN
@@ -136,35 +427,53 @@ impl ReliableOrderedDeliveryState {
     // Connection Teardown (Close)
     // ------------------------------------------------------------------------
 
-    /// ESTABLISHED → FIN_WAIT_1: Prepare to send FIN (no rcv_nxt change)
+    /// ESTABLISHED → FIN_WAIT_1: Queue our FIN behind the unsent data
+    ///
+    /// The FIN takes the sequence number after the last buffered byte;
+    /// tcp_output sends it once everything in front of it is out.
     pub fn on_close_in_established(&mut self) -> Result<(), &'static str> {
-        unimplemented!("TODO: Implement - may need to mark FIN pending")
+        self.queue_fin();
+        Ok(())
     }
 
-    /// CLOSE_WAIT → LAST_ACK: Prepare to send FIN
+    /// CLOSE_WAIT → LAST_ACK: Queue our FIN behind the unsent data
     pub fn on_close_in_closewait(&mut self) -> Result<(), &'static str> {
-        unimplemented!("TODO: Implement - may need to mark FIN pending")
+        self.queue_fin();
+        Ok(())
+    }
+
+    fn queue_fin(&mut self) {
+        self.snd_lbb = self.snd_lbb.wrapping_add(1);
+        self.fin_pending = true;
+    }
+
+    /// Does the FIN of `seg` come next, right after its data?
+    ///
+    /// Call after the segment's data went through on_data_in_established.
+    /// A FIN beyond a gap is not taken; the peer resends it.
+    pub fn fin_in_order(&self, seg: &TcpSegment) -> bool {
+        seg.flags.fin && seg.seqno.wrapping_add(seg.payload_len as u32) == self.rcv_nxt
     }
 
     /// ESTABLISHED → CLOSE_WAIT: Process FIN, advance rcv_nxt
+    ///
+    /// The FIN may ride on data, it follows the last payload byte.
     pub fn on_fin_in_established(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
-        // Validate sequence number
-        if seg.seqno != self.rcv_nxt {
+        if !self.fin_in_order(seg) {
             return Err("Invalid sequence number for FIN");
         }
 
         // FIN consumes one sequence number
         self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        self.rcv_fin = true;
 
         Ok(())
     }
 
     /// FIN_WAIT_1 → FIN_WAIT_2: Process ACK of our FIN
     pub fn on_ack_in_finwait1(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
-        // Check if this ACKs our FIN
-        // FIN consumes one sequence number, so ACK should be snd_nxt + 1
-        let expected_ack = self.snd_nxt.wrapping_add(1);
-        if seg.ackno != expected_ack {
+        // Our FIN is the last thing buffered, so its ACK is snd_lbb
+        if seg.ackno != self.snd_lbb {
             return Err("ACK doesn't acknowledge our FIN");
         }
 
@@ -175,36 +484,36 @@ impl ReliableOrderedDeliveryState {
 
     /// FIN_WAIT_1 → CLOSING: Process FIN (simultaneous close)
     pub fn on_fin_in_finwait1(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
-        // Validate sequence number
-        if seg.seqno != self.rcv_nxt {
+        // The FIN follows any data the segment carried
+        if !self.fin_in_order(seg) {
             return Err("Invalid sequence number for FIN");
         }
 
         // FIN consumes one sequence number
         self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        self.rcv_fin = true;
 
         Ok(())
     }
 
     /// FIN_WAIT_2 → TIME_WAIT: Process FIN
     pub fn on_fin_in_finwait2(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
-        // Validate sequence number
-        if seg.seqno != self.rcv_nxt {
+        // The FIN follows any data the segment carried
+        if !self.fin_in_order(seg) {
             return Err("Invalid sequence number for FIN");
         }
 
         // FIN consumes one sequence number
         self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        self.rcv_fin = true;
 
         Ok(())
     }
 
     /// CLOSING → TIME_WAIT: Process ACK of our FIN
     pub fn on_ack_in_closing(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
-        // Check if this ACKs our FIN
-        // FIN consumes one sequence number, so ACK should be snd_nxt + 1
-        let expected_ack = self.snd_nxt.wrapping_add(1);
-        if seg.ackno != expected_ack {
+        // Our FIN is the last thing buffered, so its ACK is snd_lbb
+        if seg.ackno != self.snd_lbb {
             return Err("ACK doesn't acknowledge our FIN");
         }
 
@@ -215,10 +524,8 @@ impl ReliableOrderedDeliveryState {
 
     /// LAST_ACK → CLOSED: Process ACK of our FIN
     pub fn on_ack_in_lastack(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
-        // Check if this ACKs our FIN
-        // FIN consumes one sequence number, so ACK should be snd_nxt + 1
-        let expected_ack = self.snd_nxt.wrapping_add(1);
-        if seg.ackno != expected_ack {
+        // Our FIN is the last thing buffered, so its ACK is snd_lbb
+        if seg.ackno != self.snd_lbb {
             return Err("ACK doesn't acknowledge our FIN");
         }
 
@@ -228,45 +535,117 @@ impl ReliableOrderedDeliveryState {
     }
 
     /// TIME_WAIT: Process retransmitted FIN (no sequence change)
-    pub fn on_fin_in_timewait(&mut self, _seg: &TcpSegment) -> Result<(), &'static str> {
-        unimplemented!("TODO: Implement - validate sequence number")
+    pub fn on_fin_in_timewait(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        // The peer's FIN was already consumed; a retransmission ends there
+        let fin_seqno = seg.seqno.wrapping_add(seg.payload_len as u32);
+        if fin_seqno.wrapping_add(1) != self.rcv_nxt {
+            return Err("FIN is not a retransmission");
+        }
+
+        Ok(())
+    }
+
+    /// TIME_WAIT → SYN_RCVD: A new SYN reopens the connection (RFC 1122, 4.2.2.13)
+    ///
+    /// Only a SYN beyond everything the old incarnation received may do
+    /// so. Everything starts over, with our ISS past the old snd_nxt so
+    /// stray segments of the old connection cannot land in the new one.
+    pub fn on_syn_in_timewait(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        if !Self::seq_gt(seg.seqno, self.rcv_nxt) {
+            return Err("Stale SYN in TIME_WAIT");
+        }
+
+        let old_snd_nxt = self.snd_nxt;
+        *self = Self::new();
+        self.on_syn_in_listen(seg, old_snd_nxt.wrapping_add(tcp_proto::TCP_ISS_INCR))?;
+
+        Ok(())
     }
 
     // ------------------------------------------------------------------------
     // Reset Handling
     // ------------------------------------------------------------------------
 
-    /// ANY → CLOSED: Reset sequence numbers
+    /// ANY → CLOSED: Reset sequence numbers, free queues and stop timers
+    ///
+    /// A RST may arrive mid-recovery (fast retransmit or RTO backoff), so
+    /// the teardown must not depend on which recovery phase we were in.
     pub fn on_rst(&mut self) -> Result<(), &'static str> {
         // Clear sequence numbers
         self.snd_nxt = 0;
         self.rcv_nxt = 0;
         self.lastack = 0;
 
+        self.purge();
+
         Ok(())
     }
 
     /// ANY → CLOSED: Abort connection
+    ///
+    /// Every queue is freed and the whole send buffer is available again.
     pub fn on_abort(&mut self) -> Result<(), &'static str> {
         // Clear sequence numbers
         self.snd_nxt = 0;
         self.rcv_nxt = 0;
         self.lastack = 0;
 
+        self.purge();
+        self.snd_buf = tcp_proto::TCP_SND_BUF;
+
         Ok(())
     }
 
+    /// Free queued segments and cancel retransmission/recovery state
+    ///
+    /// Equivalent to lwIP's tcp_pcb_purge()
+    fn purge(&mut self) {
+        self.unsent.clear();
+        self.unacked.clear();
+        self.sacked.clear();
+        self.ooseq.clear();
+        self.rcv_ready.clear();
+        self.rcv_fin = false;
+        self.rcv_push = false;
+        self.fin_pending = false;
+        self.snd_queuelen = 0;
+        self.bytes_acked = 0;
+        self.acked = 0;
+
+        // Stop the retransmission timer and any RTT measurement
+        self.rtime = 0;
+        self.rttest = 0;
+        self.nrtx = 0;
+
+        // Leave fast retransmit / RTO recovery
+        self.dupacks = 0;
+        self.rto_end = 0;
+    }
+
     // ------------------------------------------------------------------------
     // API-Initiated State Changes
     // ------------------------------------------------------------------------
 
-    /// CLOSED → SYN_SENT: Generate ISS for active open
-    pub fn on_connect(&mut self) -> Result<(), &'static str> {
-        // Generate our ISS
-        self.iss = Self::generate_iss();
+    /// CLOSED → SYN_SENT: Start our sequence space at `iss` for active open
+    pub fn on_connect(&mut self, iss: u32) -> Result<(), &'static str> {
+        self.iss = iss;
         self.snd_nxt = self.iss;
-        self.snd_lbb = self.iss.wrapping_sub(1);
+        self.snd_lbb = self.iss.wrapping_add(1); // Data follows our SYN
         self.lastack = self.iss.wrapping_sub(1);
+        self.ecn_recover = self.iss;
+
+        Ok(())
+    }
+
+    /// SYN_SENT: The 4-tuple was last used by our connection now in
+    /// TIME_WAIT, whose sequence space ended at `old_snd_nxt`
+    ///
+    /// The ISS must lie beyond it (RFC 6191) so the peer cannot take
+    /// stray segments of the old incarnation for new ones.
+    pub fn on_connect_over_timewait(&mut self, old_snd_nxt: u32) -> Result<(), &'static str> {
+        if !Self::seq_gt(self.iss, old_snd_nxt) {
+            self.on_connect(old_snd_nxt.wrapping_add(tcp_proto::TCP_ISS_INCR))?;
+        }
 
         Ok(())
     }
@@ -276,52 +655,506 @@ impl ReliableOrderedDeliveryState {
     // ------------------------------------------------------------------------
 
     /// ESTABLISHED: Process incoming data segment
-    pub fn on_data_in_established(&mut self, _seg: &TcpSegment) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - update rcv_nxt")
+    ///
+    /// In-order data advances rcv_nxt, together with any queued data it
+    /// makes contiguous. Data beyond rcv_nxt goes to the ooseq queue and
+    /// leaves rcv_nxt pointing at the gap.
+    ///
+    /// rcv_push follows the PSH bit of the segment that supplied the last
+    /// byte, the queued one when the gap filled.
+    ///
+    /// Only what fits in `rcv_wnd` is taken. A zero window takes nothing,
+    /// so the byte of a zero window probe is left for the peer to send
+    /// again with the data that follows it.
+    pub fn on_data_in_established(&mut self, seg: &TcpSegment, rcv_wnd: u32) -> Result<DataAction, &'static str> {
+        if Self::seq_gt(seg.seqno, self.rcv_nxt) {
+            self.ooseq.insert(seg.seqno, &seg.payload, seg.flags.psh);
+            return Ok(DataAction::OutOfOrder);
+        }
+
+        // Skip any part we already have
+        let mut end = seg.seqno.wrapping_add(seg.payload_len as u32);
+        if !Self::seq_gt(end, self.rcv_nxt) {
+            return Ok(DataAction::Duplicate);
+        }
+        let right_edge = self.rcv_nxt.wrapping_add(rcv_wnd);
+        if Self::seq_gt(end, right_edge) {
+            end = right_edge;
+        }
+        if end == self.rcv_nxt {
+            return Ok(DataAction::Refused);
+        }
+
+        let skip = self.rcv_nxt.wrapping_sub(seg.seqno) as usize;
+        let take = end.wrapping_sub(self.rcv_nxt) as usize;
+        if let Some(new) = seg.payload.get(skip..) {
+            self.rcv_ready.extend_from_slice(&new[..take.min(new.len())]);
+        }
+        self.rcv_nxt = end;
+        self.rcv_push = seg.flags.psh;
+
+        let (filled, push) = self.ooseq.take_contiguous(self.rcv_nxt);
+        if !filled.is_empty() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(filled.len() as u32);
+            self.rcv_ready.extend_from_slice(&filled);
+            self.rcv_push = push;
+        }
+
+        Ok(DataAction::InOrder)
+    }
+
+    /// Acknowledged byte count handed to the application
+    ///
+    /// Returns the bytes acked since the last report and clears the count.
+    pub fn on_sent_reported(&mut self) -> u32 {
+        core::mem::take(&mut self.acked)
+    }
+
+    /// The application refused `data` (and the EOF after it, if `fin`):
+    /// hold it for the next delivery, ahead of anything received since
+    ///
+    /// `push` is its PSH indication, which stays with it unless newer
+    /// data now ends rcv_ready.
+    pub fn on_refused(&mut self, mut data: Vec<u8>, fin: bool, push: bool) {
+        if self.rcv_ready.is_empty() {
+            self.rcv_push = push;
+        }
+        data.extend_from_slice(&self.rcv_ready);
+        self.rcv_ready = data;
+        self.rcv_fin |= fin;
+    }
+
+    /// Received data and EOF handed to the application
+    ///
+    /// Returns the pending in-order bytes and whether the FIN is still to
+    /// be reported; both are cleared, as is rcv_push.
+    pub fn on_deliver(&mut self) -> (Vec<u8>, bool) {
+        self.rcv_push = false;
+        let data = core::mem::take(&mut self.rcv_ready);
+        let fin = core::mem::replace(&mut self.rcv_fin, false);
+        (data, fin)
+    }
+
+    /// Drop out-of-order data (tcp_free_ooseq, memory pressure)
+    pub fn on_free_ooseq(&mut self) -> Result<(), &'static str> {
+        self.ooseq.clear();
+
+        Ok(())
     }
 
     /// ESTABLISHED: Process ACK of our data
-    pub fn on_ack_in_established(&mut self, _seg: &TcpSegment) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - update lastack")
+    ///
+    /// Advances lastack, frees acknowledged segments, takes an RTT sample
+    /// and restarts (or stops) the retransmission timer. `now` is tcp_ticks.
+    /// Duplicate ACKs only bump `dupacks`; a new ACK clears it.
+    pub fn on_ack_in_established(&mut self, seg: &TcpSegment, now: u32) -> Result<(), &'static str> {
+        let ackno = seg.ackno;
+        if ackno == self.lastack {
+            // Duplicate ACK: no data on it and we still have data in flight
+            if seg.payload_len == 0 && !self.unacked.is_empty() {
+                self.dupacks = self.dupacks.saturating_add(1);
+            }
+            return Ok(());
+        }
+        if !Self::seq_gt(ackno, self.lastack) {
+            return Ok(()); // Old ACK
+        }
+        self.dupacks = 0;
+
+        // RTT sample, only if the timed segment is covered. Retransmissions
+        // clear rttest, so they are never sampled (Karn's algorithm).
+        if self.rttest != 0 && Self::seq_gt(ackno, self.rtseq) {
+            let m = now.wrapping_sub(self.rttest).wrapping_mul(tcp_proto::TCP_SLOW_INTERVAL);
+            self.update_rtt(m as i32);
+            self.rttest = 0;
+        }
+
+        self.lastack = ackno;
+        let queued = self.unacked.len();
+        let freed = self.unacked.ack_upto(ackno);
+        self.acked = self.acked.saturating_add(freed as u32);
+        // Acknowledged data leaves the send buffer
+        self.snd_buf = (self.snd_buf as usize + freed).min(tcp_proto::TCP_SND_BUF as usize) as u16;
+        self.snd_queuelen = self.snd_queuelen.saturating_sub((queued - self.unacked.len()) as u16);
+
+        // New data acked: forget the backoff
+        self.nrtx = 0;
+        self.rto = self.computed_rto();
+
+        if self.unacked.is_empty() {
+            self.rtime = 0;
+        } else {
+            self.arm_rtimer();
+        }
+
+        Ok(())
+    }
+
+    /// Record the peer's SACK blocks (RFC 2018) in the scoreboard
+    ///
+    /// Blocks must lie within (lastack, snd_nxt]; anything else, D-SACKs
+    /// included, is ignored. Ranges the cumulative ACK has passed are
+    /// dropped, so call this after on_ack_in_established.
+    pub fn on_sack(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        for &(left, right) in seg.options.sack_blocks() {
+            if !Self::seq_lt(left, right)
+                || !Self::seq_gt(right, self.lastack)
+                || Self::seq_gt(right, self.snd_nxt)
+            {
+                continue;
+            }
+            let left = if Self::seq_lt(left, self.lastack) { self.lastack } else { left };
+            self.insert_sacked(left, right);
+        }
+
+        let lastack = self.lastack;
+        self.sacked.retain(|&(_, right)| Self::seq_gt(right, lastack));
+        if let Some(first) = self.sacked.first_mut() {
+            if Self::seq_lt(first.0, lastack) {
+                first.0 = lastack;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Merge [left, right) into the scoreboard
+    fn insert_sacked(&mut self, mut left: u32, mut right: u32) {
+        let mut merged = Vec::with_capacity(self.sacked.len() + 1);
+        let mut placed = false;
+
+        for &(l, r) in &self.sacked {
+            if Self::seq_lt(r, left) {
+                merged.push((l, r));
+            } else if Self::seq_lt(right, l) {
+                if !placed {
+                    merged.push((left, right));
+                    placed = true;
+                }
+                merged.push((l, r));
+            } else {
+                // Overlapping or adjacent: absorb it
+                if Self::seq_lt(l, left) {
+                    left = l;
+                }
+                if Self::seq_gt(r, right) {
+                    right = r;
+                }
+            }
+        }
+        if !placed {
+            merged.push((left, right));
+        }
+
+        self.sacked = merged;
+    }
+
+    /// SACKed ranges above lastack, in sequence order
+    pub fn sacked_blocks(&self) -> &[(u32, u32)] {
+        &self.sacked
+    }
+
+    /// Has the peer already SACKed all of [seqno, seqno + len)?
+    pub fn is_sacked(&self, seqno: u32, len: u32) -> bool {
+        let end = seqno.wrapping_add(len);
+        self.sacked
+            .iter()
+            .any(|&(left, right)| Self::seq_leq(left, seqno) && Self::seq_leq(end, right))
+    }
+
+    /// Application write: queue data behind everything already buffered
+    ///
+    /// Data fills up the last unsent segment before new ones of at most
+    /// `mss` bytes are started; snd_queuelen counts those segments. Unless
+    /// `apiflags` has TCP_WRITE_FLAG_MORE, the write ends in a pushed
+    /// segment that later writes do not join.
+    /// Fails without queuing anything if the send buffer can't hold all of it.
+    pub fn on_write(&mut self, data: &[u8], mss: u16, apiflags: u8) -> Result<(), &'static str> {
+        if data.len() > self.snd_buf as usize {
+            return Err("Insufficient send buffer space");
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let push = apiflags & tcp_proto::TCP_WRITE_FLAG_MORE == 0;
+        let added = self.unsent.append(self.snd_lbb, data, mss as usize, push);
+        self.snd_lbb = self.snd_lbb.wrapping_add(data.len() as u32);
+        self.snd_buf -= data.len() as u16;
+        self.snd_queuelen = self.snd_queuelen.saturating_add(added as u16);
+
+        Ok(())
+    }
+
+    /// Output: move the planned segment from unsent onto the unacked queue
+    ///
+    /// Returns the segment to transmit. An empty segment is a bare FIN,
+    /// sent once the send queue has drained. `now` is tcp_ticks.
+    pub fn on_output(&mut self, desc: &SegmentDesc, now: u32) -> Result<TxSegment, &'static str> {
+        let tx = if desc.len == 0 {
+            let fin_seqno = self.snd_lbb.wrapping_sub(1);
+            if !self.fin_pending || !self.unsent.is_empty() || desc.seqno != fin_seqno {
+                return Err("No FIN to send");
+            }
+            // The bare FIN is one more queued segment
+            self.snd_queuelen = self.snd_queuelen.saturating_add(1);
+            TxSegment { seqno: desc.seqno, flags: desc.flags, data: Vec::new() }
+        } else {
+            if self.unsent.front().map(|seg| seg.seqno) != Some(desc.seqno) {
+                return Err("Segment is not at the head of the send queue");
+            }
+
+            let chunks = self.unsent.len();
+            let chunk = self.unsent.split_front(desc.len).ok_or("Nothing to send")?;
+            if self.unsent.len() == chunks {
+                // Split: the remainder stays queued as a segment of its own
+                self.snd_queuelen = self.snd_queuelen.saturating_add(1);
+            }
+            TxSegment {
+                seqno: chunk.seqno,
+                flags: desc.flags,
+                data: chunk.data,
+            }
+        };
+
+        if tx.flags & tcp_proto::TCP_FIN != 0 {
+            self.fin_pending = false;
+        }
+
+        self.on_segment_sent(UnackedSegment::new(tx.seqno, tx.flags, tx.data.clone()), now)?;
+
+        Ok(tx)
+    }
+
+    /// Zero window probe: make sure one byte is in flight to probe with
+    ///
+    /// The first probe moves a byte from unsent onto unacked; later probes
+    /// resend it. Probes are not timed for RTT.
+    pub fn on_zero_window_probe(&mut self, now: u32) -> Result<(), &'static str> {
+        if !self.unacked.is_empty() {
+            return Ok(());
+        }
+
+        let seqno = self.unsent.front().ok_or("Nothing to probe with")?.seqno;
+        let timing = self.rttest != 0;
+        self.on_output(&SegmentDesc { seqno, len: 1, flags: tcp_proto::TCP_ACK }, now)?;
+        if !timing {
+            self.rttest = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Segment handed to IP: queue it for retransmission and start the timer
+    ///
+    /// Also starts an RTT measurement if none is running. `now` is tcp_ticks.
+    pub fn on_segment_sent(&mut self, seg: UnackedSegment, now: u32) -> Result<(), &'static str> {
+        if self.rttest == 0 && seg.seq_len() > 0 {
+            self.rttest = now;
+            self.rtseq = seg.seqno;
+        }
+
+        let end = seg.seqno.wrapping_add(seg.seq_len());
+        if Self::seq_gt(end, self.snd_nxt) {
+            self.snd_nxt = end;
+        }
+        self.unacked.push(UnackedSegment { sent: now, ..seg });
+
+        if self.rtime == 0 {
+            self.arm_rtimer();
+        }
+
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+    // Retransmission Timer
+    // ------------------------------------------------------------------------
+
+    /// Slow timer tick: count down the retransmission timer
+    ///
+    /// Returns true if the timer expired with unacked data outstanding.
+    pub fn on_slowtmr(&mut self) -> bool {
+        if self.rtime <= 0 {
+            return false;
+        }
+        self.rtime -= 1;
+        self.rtime == 0 && !self.unacked.is_empty()
+    }
+
+    /// The segment a retransmission resends: the oldest unacknowledged one
+    ///
+    /// Anything SACKed lies above it, or the cumulative ACK would cover it.
+    pub fn retransmit_candidate(&self) -> Option<&UnackedSegment> {
+        self.unacked.front()
+    }
+
+    /// Stamp the candidate as resent at `now`
+    fn mark_retransmitted(&mut self, now: u32) {
+        if let Some(seg) = self.unacked.segs.front_mut() {
+            seg.sent = now;
+            seg.nrtx = seg.nrtx.saturating_add(1);
+        }
+    }
+
+    /// RTO expired: back off and restart the timer for the resend
+    pub fn on_rto_timeout(&mut self, now: u32) -> Result<(), &'static str> {
+        self.mark_retransmitted(now);
+        self.nrtx = self.nrtx.saturating_add(1);
+        self.rto = core::cmp::min(self.rto.saturating_mul(2), tcp_proto::TCP_RTO_MAX);
+
+        // Karn's algorithm: never time a retransmitted segment
+        self.rttest = 0;
+
+        // A timeout ends any fast retransmit in progress
+        self.dupacks = 0;
+
+        self.arm_rtimer();
+
+        Ok(())
     }
 
-    /// CLOSE_WAIT: Process ACK (connection closing but still receiving)
-    pub fn on_ack_in_closewait(&mut self, _seg: &TcpSegment) -> Result<(), &'static str> {
-        unimplemented!("TODO: Future data path - update lastack")
+    /// The duplicate just counted moved the peer's window: take it back
+    ///
+    /// RFC 5681, 2: a duplicate ACK leaves the advertised window unchanged,
+    /// so a pure window update is no loss signal.
+    pub fn on_window_update_ack(&mut self) -> Result<(), &'static str> {
+        self.dupacks = self.dupacks.saturating_sub(1);
+
+        Ok(())
+    }
+
+    /// Third duplicate ACK: the retransmit candidate is being resent
+    pub fn on_fast_retransmit(&mut self, now: u32) -> Result<(), &'static str> {
+        self.mark_retransmitted(now);
+
+        // Karn's algorithm applies to fast retransmits as well
+        self.rttest = 0;
+
+        Ok(())
+    }
+
+    /// Does an ECE on this ACK call for a window reduction?
+    ///
+    /// RFC 3168 §6.1.2: at most once per window of data, so only an ACK
+    /// covering data sent after the last reduction counts.
+    pub fn ecn_response_due(&self, ackno: u32) -> bool {
+        Self::seq_gt(ackno, self.ecn_recover)
+    }
+
+    /// ECE answered with a window reduction: ignore further ECEs until
+    /// everything sent so far is acknowledged
+    pub fn on_ecn_echo(&mut self) -> Result<(), &'static str> {
+        self.ecn_recover = self.snd_nxt;
+
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+    // Timestamps (RFC 7323)
+    // ------------------------------------------------------------------------
+
+    /// Accepted segment: remember its TSval for echoing
+    ///
+    /// Only a segment starting at or before rcv_nxt may move ts_recent,
+    /// and never backwards, so reordered or future data can't poison it.
+    pub fn on_timestamp(&mut self, seg: &TcpSegment) -> Result<(), &'static str> {
+        let Some((tsval, _)) = seg.options.ts else {
+            return Ok(());
+        };
+
+        if Self::seq_leq(seg.seqno, self.rcv_nxt) && !Self::seq_lt(tsval, self.ts_recent) {
+            self.ts_recent = tsval;
+        }
+
+        Ok(())
+    }
+
+    /// New data acknowledged with a timestamp echo: take an RTT sample
+    ///
+    /// The echoed TSval is one of our tcp_ticks values, so every ACK can
+    /// be timed, retransmissions included. Ends any rttest measurement.
+    pub fn on_ts_echo(&mut self, tsecr: u32, now: u32) -> Result<(), &'static str> {
+        let m = now.wrapping_sub(tsecr).wrapping_mul(tcp_proto::TCP_SLOW_INTERVAL);
+        self.update_rtt(m as i32);
+        self.rttest = 0;
+
+        Ok(())
+    }
+
+    // ------------------------------------------------------------------------
+    // RTT Estimation (Jacobson/Karels)
+    // ------------------------------------------------------------------------
+
+    /// Fold an RTT sample `m` (ms) into the smoothed estimators
+    ///
+    /// sa holds 8*SRTT and sv holds 4*RTTVAR, so the classic
+    /// SRTT += err/8 and RTTVAR += (|err| - RTTVAR)/4 become plain adds.
+    /// The first sample sets SRTT = m and RTTVAR = m/2 (RFC 6298, 2.2).
+    fn update_rtt(&mut self, m: i32) {
+        if self.sa == 0 {
+            self.sa = m << 3;
+            self.sv = m << 1;
+            self.rto = self.computed_rto();
+            return;
+        }
+
+        let mut err = m - (self.sa >> 3);
+        self.sa += err;
+        if err < 0 {
+            err = -err;
+        }
+        err -= self.sv >> 2;
+        self.sv += err;
+
+        self.rto = self.computed_rto();
+    }
+
+    /// RTO = SRTT + 4*RTTVAR, clamped to [TCP_RTO_MIN, TCP_RTO_MAX]
+    fn computed_rto(&self) -> u32 {
+        let rto = ((self.sa >> 3) + self.sv).max(0) as u32;
+        rto.clamp(tcp_proto::TCP_RTO_MIN, tcp_proto::TCP_RTO_MAX)
+    }
+
+    /// Start the retransmission timer from the current RTO
+    fn arm_rtimer(&mut self) {
+        let ticks = self.rto.div_ceil(tcp_proto::TCP_SLOW_INTERVAL).max(1);
+        self.rtime = core::cmp::min(ticks, i16::MAX as u32) as i16;
     }
 
     // ------------------------------------------------------------------------
     // Validation Helpers (Read-only)
     // ------------------------------------------------------------------------
 
-    /// Validate sequence number (RFC 793)
+    /// Validate sequence number (RFC 793, p. 69)
+    ///
+    /// A segment is acceptable if any part of it falls inside
+    /// [RCV.NXT, RCV.NXT + RCV.WND). SYN and FIN count as one octet each.
+    /// With a zero window only a segment starting exactly at RCV.NXT is
+    /// let through, so ACKs and RSTs still get processed.
     pub fn validate_sequence_number(
         &self,
         seg: &TcpSegment,
-        rcv_wnd: u16,
+        rcv_wnd: u32,
     ) -> bool {
         let seqno = seg.seqno;
         let rcv_nxt = self.rcv_nxt;
 
-        // Special case: zero window
         if rcv_wnd == 0 {
             return seqno == rcv_nxt;
         }
 
-        // Check if sequence number is within receive window
-        // Valid if: RCV.NXT <= SEG.SEQ < RCV.NXT + RCV.WND
-        let seg_end = seqno.wrapping_add(seg.payload_len as u32);
-
-        // Check if segment overlaps with receive window
-        let seq_acceptable = Self::seq_in_window(seqno, rcv_nxt, rcv_wnd)
-            || (seg.payload_len > 0 && Self::seq_in_window(seg_end.wrapping_sub(1), rcv_nxt, rcv_wnd));
+        let seg_len = seg.payload_len as u32 + seg.flags.syn as u32 + seg.flags.fin as u32;
+        if seg_len == 0 {
+            return Self::seq_in_window(seqno, rcv_nxt, rcv_wnd);
+        }
 
-        seq_acceptable
+        let seg_last = seqno.wrapping_add(seg_len - 1);
+        Self::seq_in_window(seqno, rcv_nxt, rcv_wnd) || Self::seq_in_window(seg_last, rcv_nxt, rcv_wnd)
     }
 
     /// Validate ACK field (RFC 5961)
-    pub fn validate_ack(&self, _seg: &TcpSegment) -> crate::tcp_types::AckValidation {
-        let seg = _seg;
+    pub fn validate_ack(&self, seg: &TcpSegment) -> crate::tcp_types::AckValidation {
         let ackno = seg.ackno;
         let snd_una = self.lastack;
         let snd_nxt = self.snd_nxt;
@@ -340,16 +1173,43 @@ impl ReliableOrderedDeliveryState {
         }
     }
 
-    /// Validate RST segment (RFC 5961)
-    pub fn validate_rst(&self, _seg: &TcpSegment, _rcv_wnd: u16) -> crate::tcp_types::RstValidation {
-        let seg = _seg;
-        // Check if sequence number is in window
-        if self.validate_sequence_number(seg, _rcv_wnd) {
-            // In window - accept the RST
+    /// PAWS (RFC 7323, section 5): is this segment's TSval older than
+    /// ts_recent?
+    ///
+    /// Such a segment is a duplicate from an earlier incarnation of the
+    /// sequence space, even if it lies inside the window.
+    pub fn paws_reject(&self, seg: &TcpSegment) -> bool {
+        match seg.options.ts {
+            Some((tsval, _)) => Self::seq_lt(tsval, self.ts_recent),
+            None => false,
+        }
+    }
+
+    /// May this connection send a challenge ACK at `now` (tcp_ticks)?
+    ///
+    /// One per connection every TCP_CHALLENGE_ACK_WINDOW ticks; false means
+    /// this connection challenged the peer too recently.
+    pub fn challenge_ack_due(&self, now: u32) -> bool {
+        self.last_challenge_tick
+            .is_none_or(|last| now.wrapping_sub(last) >= tcp_proto::TCP_CHALLENGE_ACK_WINDOW)
+    }
+
+    /// A challenge ACK goes out at `now`
+    pub fn on_challenge_ack(&mut self, now: u32) {
+        self.last_challenge_tick = Some(now);
+    }
+
+    /// Validate RST segment (RFC 5961, section 3.2)
+    ///
+    /// Only an exact match on RCV.NXT resets the connection. Anything else
+    /// inside the window gets a challenge ACK; outside it is dropped.
+    pub fn validate_rst(&self, seg: &TcpSegment, rcv_wnd: u32) -> crate::tcp_types::RstValidation {
+        if seg.seqno == self.rcv_nxt {
             crate::tcp_types::RstValidation::Valid
-        } else {
-            // Out of window - send challenge ACK per RFC 5961
+        } else if Self::seq_in_window(seg.seqno, self.rcv_nxt, rcv_wnd) {
             crate::tcp_types::RstValidation::Challenge
+        } else {
+            crate::tcp_types::RstValidation::Invalid
         }
     }
 
@@ -358,9 +1218,9 @@ impl ReliableOrderedDeliveryState {
     // ------------------------------------------------------------------------
 
     /// Check if a sequence number is within the window
-    fn seq_in_window(seq: u32, rcv_nxt: u32, rcv_wnd: u16) -> bool {
+    fn seq_in_window(seq: u32, rcv_nxt: u32, rcv_wnd: u32) -> bool {
         let diff = seq.wrapping_sub(rcv_nxt);
-        diff < rcv_wnd as u32
+        diff < rcv_wnd
     }
 
     /// Sequence number less than (handles wraparound)
@@ -0,0 +1,159 @@
+//! lwIP ip_addr_t Access
+//!
+//! What ip_addr_t is depends on lwipopts: ip4_addr_t with only IPv4,
+//! ip6_addr_t with only IPv6, and with both a union of the two (`u_addr`)
+//! tagged with its family (`type_`). build.rs sets the `lwip_ipv4` and
+//! `lwip_ipv6` cfgs to match, and the rest of the crate reads and builds
+//! addresses only through here.
+
+use crate::ffi;
+#[cfg(all(lwip_ipv4, lwip_ipv6))]
+use crate::tcp_proto;
+
+/// Does `addr` hold an IPv6 address?
+#[cfg(all(lwip_ipv4, lwip_ipv6))]
+pub fn is_v6(addr: &ffi::ip_addr_t) -> bool {
+    addr.type_ == tcp_proto::IPADDR_TYPE_V6
+}
+
+#[cfg(not(all(lwip_ipv4, lwip_ipv6)))]
+pub fn is_v6(_addr: &ffi::ip_addr_t) -> bool {
+    cfg!(lwip_ipv6)
+}
+
+/// The IPv4 address, in network byte order; 0 for an IPv6 address
+pub fn ip4(addr: &ffi::ip_addr_t) -> u32 {
+    #[cfg(all(lwip_ipv4, lwip_ipv6))]
+    {
+        if is_v6(addr) {
+            0
+        } else {
+            // SAFETY: anything but an IPv6 address is held as IPv4
+            unsafe { addr.u_addr.ip4.addr }
+        }
+    }
+    #[cfg(not(lwip_ipv6))]
+    {
+        addr.addr
+    }
+    #[cfg(all(not(lwip_ipv4), lwip_ipv6))]
+    {
+        0
+    }
+}
+
+/// The IPv6 address, as network byte order words; unspecified for IPv4
+pub fn ip6(addr: &ffi::ip_addr_t) -> [u32; 4] {
+    #[cfg(all(lwip_ipv4, lwip_ipv6))]
+    {
+        if is_v6(addr) {
+            // SAFETY: the tag says the union holds IPv6
+            unsafe { addr.u_addr.ip6.addr }
+        } else {
+            [0; 4]
+        }
+    }
+    #[cfg(not(lwip_ipv6))]
+    {
+        [0; 4]
+    }
+    #[cfg(all(not(lwip_ipv4), lwip_ipv6))]
+    {
+        addr.addr
+    }
+}
+
+/// The IPv4 member of `addr`, as ip4_route and ip4_output_if take it
+#[cfg(lwip_ipv4)]
+pub fn ip_2_ip4(addr: &ffi::ip_addr_t) -> &ffi::ip4_addr_t {
+    #[cfg(lwip_ipv6)]
+    {
+        // SAFETY: both members are plain words, any bit pattern is valid
+        unsafe { &addr.u_addr.ip4 }
+    }
+    #[cfg(not(lwip_ipv6))]
+    {
+        addr
+    }
+}
+
+/// Is `addr` the unspecified address of its family?
+pub fn is_any(addr: &ffi::ip_addr_t) -> bool {
+    ip4(addr) == 0 && ip6(addr) == [0; 4]
+}
+
+/// Do `a` and `b` hold the same address of the same family?
+pub fn eq(a: &ffi::ip_addr_t, b: &ffi::ip_addr_t) -> bool {
+    is_v6(a) == is_v6(b) && ip4(a) == ip4(b) && ip6(a) == ip6(b)
+}
+
+/// An IPv4 address from network byte order
+///
+/// Without IPv4 support only the unspecified address can be expressed,
+/// which comes out as the IPv6 one.
+pub fn from_ip4(addr: u32) -> ffi::ip_addr_t {
+    #[cfg(all(lwip_ipv4, lwip_ipv6))]
+    {
+        let mut ip = ffi::ip_addr_t::default();
+        ip.u_addr.ip4 = ffi::ip4_addr_t { addr };
+        ip.type_ = tcp_proto::IPADDR_TYPE_V4;
+        ip
+    }
+    #[cfg(not(lwip_ipv6))]
+    {
+        ffi::ip_addr_t { addr }
+    }
+    #[cfg(all(not(lwip_ipv4), lwip_ipv6))]
+    {
+        ffi::ip_addr_t::default()
+    }
+}
+
+/// An IPv6 address from network byte order words, without a zone
+#[cfg(lwip_ipv6)]
+pub fn from_ip6(addr: [u32; 4]) -> ffi::ip_addr_t {
+    let ip6 = ffi::ip6_addr_t { addr, ..Default::default() };
+    #[cfg(lwip_ipv4)]
+    {
+        let mut ip = ffi::ip_addr_t::default();
+        ip.u_addr.ip6 = ip6;
+        ip.type_ = tcp_proto::IPADDR_TYPE_V6;
+        ip
+    }
+    #[cfg(not(lwip_ipv4))]
+    {
+        ip6
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip4_round_trip() {
+        let addr = from_ip4(0x0100a8c0);
+        assert!(!is_v6(&addr));
+        assert_eq!(ip4(&addr), 0x0100a8c0);
+        assert_eq!(ip6(&addr), [0; 4]);
+        assert_eq!(ip_2_ip4(&addr).addr, 0x0100a8c0);
+        assert!(is_any(&from_ip4(0)));
+        assert!(eq(&addr, &from_ip4(0x0100a8c0)));
+        assert!(!eq(&addr, &from_ip4(0x0200a8c0)));
+    }
+
+    #[cfg(lwip_ipv6)]
+    #[test]
+    fn test_ip6_round_trip() {
+        let words = [u32::from_be(0x20010db8), 0, 0, u32::from_be(1)];
+        let addr = from_ip6(words);
+        assert!(is_v6(&addr));
+        assert_eq!(ip6(&addr), words);
+        assert_eq!(ip4(&addr), 0);
+        assert!(!is_any(&addr));
+        assert!(is_any(&from_ip6([0; 4])));
+
+        // Same bits, other family
+        assert!(!eq(&from_ip6([words[0], 0, 0, 0]), &from_ip4(words[0])));
+    }
+}
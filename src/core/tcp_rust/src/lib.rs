@@ -28,11 +28,48 @@ pub mod ffi {
 
     #[repr(C)]
     #[derive(Debug, Copy, Clone, Default)]
-    pub struct ip_addr_t {
+    pub struct ip4_addr_t {
         pub addr: u32,
     }
 
-    pub type netif = u8;
+    #[repr(C)]
+    #[derive(Debug, Copy, Clone, Default)]
+    pub struct ip6_addr_t {
+        pub addr: [u32; 4],
+        pub zone: u8,
+    }
+
+    #[cfg(not(lwip_ipv6))]
+    pub type ip_addr_t = ip4_addr_t;
+
+    /// Dual-stack ip_addr_t, laid out as bindgen emits it
+    #[cfg(lwip_ipv6)]
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub struct ip_addr_t {
+        pub u_addr: ip_addr__bindgen_ty_1,
+        pub type_: u8,
+    }
+
+    #[cfg(lwip_ipv6)]
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    pub union ip_addr__bindgen_ty_1 {
+        pub ip6: ip6_addr_t,
+        pub ip4: ip4_addr_t,
+    }
+
+    #[cfg(lwip_ipv6)]
+    impl Default for ip_addr_t {
+        fn default() -> Self {
+            unsafe { core::mem::zeroed() }
+        }
+    }
+
+    #[repr(C)]
+    pub struct netif {
+        pub num: u8,
+    }
 
     pub use crate::tcp_proto::TcpHdr as tcp_hdr;
 
@@ -64,13 +101,142 @@ pub mod ffi {
     pub use crate::tcp_proto::{TCP_FIN, TCP_SYN, TCP_RST, TCP_PSH, TCP_ACK, TCP_URG};
 
     pub const pbuf_layer_PBUF_TRANSPORT: u32 = 0;
+    pub const pbuf_layer_PBUF_RAW: u32 = 0;
     pub const pbuf_type_PBUF_RAM: u32 = 0;
+    pub const IP_PROTO_TCP: u32 = 6;
+
+    pub type memp_t = u32;
+    pub const memp_t_MEMP_TCP_PCB: memp_t = 0;
+    /// Unbounded, so PCBs other tests leave behind never crowd out a new one
+    pub const MEMP_NUM_TCP_PCB: u32 = u32::MAX;
+
+    /// Heap-backed single pbuf so tests can inspect built segments
+    pub unsafe fn pbuf_alloc(_layer: u32, length: u16, _type: u32) -> *mut pbuf {
+        let payload = Box::into_raw(vec![0u8; length as usize].into_boxed_slice()) as *mut u8;
+        Box::into_raw(Box::new(pbuf {
+            next: core::ptr::null_mut(),
+            payload: payload as *mut c_void,
+            tot_len: length,
+            len: length,
+            type_: 0,
+            flags: 0,
+            ref_: 1,
+        }))
+    }
+
+    /// Interfaces 1 and 2 by netif_get_index; routing picks the first
+    static mut NETIFS: [netif; 2] = [netif { num: 0 }, netif { num: 1 }];
+
+    #[repr(C)]
+    pub struct ip_hdr {
+        pub _v_hl: u8,
+        pub _tos: u8,
+    }
+
+    #[repr(C)]
+    pub struct ip6_addr_p_t {
+        pub addr: [u32; 4],
+    }
+
+    #[repr(C)]
+    pub struct ip6_hdr {
+        pub _v_tc_fl: u32,
+        pub _plen: u16,
+        pub _nexth: u8,
+        pub _hoplim: u8,
+        pub src: ip6_addr_p_t,
+        pub dest: ip6_addr_p_t,
+    }
+
+    #[repr(C)]
+    pub struct ip_globals {
+        pub current_ip4_header: *const ip_hdr,
+        pub current_ip6_header: *const ip6_hdr,
+        pub current_iphdr_src: ip_addr_t,
+        pub current_iphdr_dest: ip_addr_t,
+    }
+
+    /// Header and addresses of the datagram being processed, set by tests
+    pub static mut ip_data: ip_globals = ip_globals {
+        current_ip4_header: core::ptr::null(),
+        current_ip6_header: core::ptr::null(),
+        current_iphdr_src: unsafe { core::mem::zeroed() },
+        current_iphdr_dest: unsafe { core::mem::zeroed() },
+    };
+
+    std::thread_local! {
+        /// Segments handed to ip4_output_if, oldest first
+        pub static IP_OUTPUT: core::cell::RefCell<Vec<Vec<u8>>> = const { core::cell::RefCell::new(Vec::new()) };
+        /// The netif num each IP_OUTPUT segment went out on
+        pub static IP_OUTPUT_NETIF: core::cell::RefCell<Vec<u8>> = const { core::cell::RefCell::new(Vec::new()) };
+        /// The TOS byte each IP_OUTPUT segment went out with
+        pub static IP_OUTPUT_TOS: core::cell::RefCell<Vec<u8>> = const { core::cell::RefCell::new(Vec::new()) };
+        /// Segments handed to ip6_output_if, oldest first
+        pub static IP6_OUTPUT: core::cell::RefCell<Vec<Vec<u8>>> = const { core::cell::RefCell::new(Vec::new()) };
+    }
+
+    /// Routes everything except the unspecified address
+    pub unsafe fn ip4_route(dest: *const ip4_addr_t) -> *mut netif {
+        if (*dest).addr == 0 {
+            return core::ptr::null_mut();
+        }
+        core::ptr::addr_of_mut!(NETIFS[0])
+    }
+
+    pub unsafe fn netif_get_by_index(idx: u8) -> *mut netif {
+        match idx {
+            1 | 2 => core::ptr::addr_of_mut!(NETIFS[idx as usize - 1]),
+            _ => core::ptr::null_mut(),
+        }
+    }
+
+    /// Records the segment instead of sending it; `p` stays with the caller
+    pub unsafe fn ip4_output_if(
+        p: *mut pbuf,
+        _src: *const ip4_addr_t,
+        _dest: *const ip4_addr_t,
+        _ttl: u8,
+        tos: u8,
+        _proto: u8,
+        netif: *mut netif,
+    ) -> i8 {
+        let bytes = core::slice::from_raw_parts((*p).payload as *const u8, (*p).len as usize);
+        IP_OUTPUT.with(|out| out.borrow_mut().push(bytes.to_vec()));
+        IP_OUTPUT_NETIF.with(|out| out.borrow_mut().push((*netif).num));
+        IP_OUTPUT_TOS.with(|out| out.borrow_mut().push(tos));
+        0
+    }
+
+    /// Routes everything except the unspecified address
+    pub unsafe fn ip6_route(_src: *const ip6_addr_t, dest: *const ip6_addr_t) -> *mut netif {
+        if (*dest).addr == [0; 4] {
+            return core::ptr::null_mut();
+        }
+        core::ptr::addr_of_mut!(NETIFS[0])
+    }
 
-    pub unsafe fn pbuf_alloc(_layer: u32, _length: u16, _type: u32) -> *mut pbuf {
-        core::ptr::null_mut()
+    /// Records the segment instead of sending it; `p` stays with the caller
+    pub unsafe fn ip6_output_if(
+        p: *mut pbuf,
+        _src: *const ip6_addr_t,
+        _dest: *const ip6_addr_t,
+        _hl: u8,
+        _tc: u8,
+        _nexth: u8,
+        _netif: *mut netif,
+    ) -> i8 {
+        let bytes = core::slice::from_raw_parts((*p).payload as *const u8, (*p).len as usize);
+        IP6_OUTPUT.with(|out| out.borrow_mut().push(bytes.to_vec()));
+        0
     }
 
-    pub unsafe fn pbuf_free(_p: *mut pbuf) {
+    pub unsafe fn pbuf_free(p: *mut pbuf) {
+        if p.is_null() {
+            return;
+        }
+        let p = Box::from_raw(p);
+        let payload = core::ptr::slice_from_raw_parts_mut(p.payload as *mut u8, p.len as usize);
+        drop(Box::from_raw(payload));
     }
 }
 
@@ -78,37 +244,64 @@ pub mod components;
 pub mod state;
 pub mod tcp_types;
 pub mod tcp_api;
-
-
-pub use state::{TcpState, TcpConnectionState};
+pub mod tcp_out;
+pub mod send_queue;
+pub mod challenge_ack;
+pub mod stats;
+pub mod snapshot;
+pub mod tcp_iss;
+pub mod ip_addr;
+pub mod tcp_conn;
+pub mod pcb_list;
+#[cfg(feature = "md5")]
+pub mod tcp_md5;
+
+
+pub use state::{TcpState, TcpEvent, TcpConnectionState};
 pub use tcp_types::{
-    TcpFlags, TcpSegment,
-    RstValidation, AckValidation, InputAction
+    TcpFlags, TcpSegment, TcpOptions, SegmentDesc, TxSegment, RstReply,
+    RstValidation, AckValidation, DataAction, InputAction, SegmentClass, TcpError, CloseAction
 };
 pub use tcp_api::{
-    tcp_bind, tcp_listen, tcp_connect, tcp_abort, initiate_close
+    tcp_bind, tcp_ephemeral_port, tcp_listen, tcp_listen_with_backlog, tcp_listen_input,
+    tcp_connect, tcp_connect_over_timewait, tcp_write, tcp_recved, tcp_output, tcp_limited_transmit, tcp_abort, initiate_close,
+    tcp_shutdown
 };
-pub use tcp_api::tcp_input;
+pub use tcp_api::{tcp_input, tcp_fasttmr_pcb, tcp_slowtmr_pcb};
+pub use tcp_types::TimerAction;
+pub use tcp_conn::TcpConn;
 
 const ERR_OK: i8 = 0;
 const ERR_MEM: i8 = -1;
+const ERR_BUF: i8 = -2;
+const ERR_RTE: i8 = -4;
 const ERR_VAL: i8 = -6;
+const ERR_USE: i8 = -8;
+const ERR_ISCONN: i8 = -10;
+const ERR_CONN: i8 = -11;
+const ERR_ABRT: i8 = -13;
+const ERR_RST: i8 = -14;
+const ERR_CLSD: i8 = -15;
 const ERR_ARG: i8 = -16;
 
 #[no_mangle]
 pub static mut tcp_ticks: u32 = 0;
 
-#[no_mangle]
-pub static mut tcp_active_pcbs: *mut c_void = ptr::null_mut();
+pub use pcb_list::{tcp_active_pcbs, tcp_bound_pcbs, tcp_listen_pcbs, tcp_tw_pcbs};
 
-#[no_mangle]
-pub static mut tcp_tw_pcbs: *mut c_void = ptr::null_mut();
+/// Counts fast and slow timer runs, never 0 (lwIP's tcp_timer_ctr)
+static mut TCP_TIMER_CTR: u8 = 0;
 
-#[no_mangle]
-pub static mut tcp_bound_pcbs: *mut c_void = ptr::null_mut();
+/// Where the search for the next ephemeral port starts
+static mut TCP_PORT: u16 = tcp_proto::TCP_LOCAL_PORT_RANGE_START;
 
-#[no_mangle]
-pub static mut tcp_listen_pcbs: *mut c_void = ptr::null_mut();
+/// PCBs allocated and not yet freed. Their state lives on the heap, not
+/// in lwIP's MEMP_TCP_PCB pool, but no more than TCP_PCB_LIMIT may be
+/// live at once, as if it did.
+static mut TCP_PCB_COUNT: usize = 0;
+
+/// MEMP_NUM_TCP_PCB; tests lower it to simulate memory pressure
+static mut TCP_PCB_LIMIT: usize = ffi::MEMP_NUM_TCP_PCB as usize;
 
 #[inline]
 unsafe fn pcb_to_state<'a>(pcb: *const ffi::tcp_pcb) -> Option<&'a TcpConnectionState> {
@@ -128,663 +321,4109 @@ unsafe fn pcb_to_state_mut<'a>(pcb: *mut ffi::tcp_pcb) -> Option<&'a mut TcpConn
     }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_init_rust() {
-    tcp_ticks = 0;
-    tcp_active_pcbs = ptr::null_mut();
-    tcp_tw_pcbs = ptr::null_mut();
-    tcp_bound_pcbs = ptr::null_mut();
-    tcp_listen_pcbs = ptr::null_mut();
+/// Is any PCB, bound, listening or connected, using `port` locally?
+unsafe fn tcp_port_in_use(port: u16) -> bool {
+    [tcp_bound_pcbs, tcp_listen_pcbs, tcp_active_pcbs, tcp_tw_pcbs]
+        .into_iter()
+        .flat_map(|list| pcb_list::iter(list))
+        .any(|pcb| (*pcb).conn_mgmt.local_port == port)
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_input_rust(
-    p: *mut ffi::pbuf,
-    inp: *mut ffi::netif,
-) {
-    if p.is_null() {
-        return;
+/// Would binding `pcb` to `ip`:`port` clash with another PCB? (lwIP's
+/// tcp_bind check)
+///
+/// Addresses clash if they are equal or either is the any address, unless
+/// both PCBs set SOF_REUSEADDR. Those may even share a port with a
+/// connection in TIME_WAIT.
+unsafe fn tcp_addr_in_use(pcb: &TcpConnectionState, ip: ffi::ip_addr_t, port: u16) -> bool {
+    let reuse = pcb.conn_mgmt.so_options & tcp_proto::SOF_REUSEADDR != 0;
+    for list in [tcp_bound_pcbs, tcp_listen_pcbs, tcp_active_pcbs, tcp_tw_pcbs] {
+        if reuse && list == tcp_tw_pcbs {
+            continue;
+        }
+        for other in pcb_list::iter(list) {
+            let cm = &(*other).conn_mgmt;
+            let both_reuse = reuse && cm.so_options & tcp_proto::SOF_REUSEADDR != 0;
+            if !ptr::eq(other, pcb)
+                && cm.local_port == port
+                && !both_reuse
+                && (ip_addr::is_any(&cm.local_ip) || ip_addr::is_any(&ip) || ip_addr::eq(&cm.local_ip, &ip))
+            {
+                return true;
+            }
+        }
     }
-    ffi::pbuf_free(p);
+    false
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_new_rust() -> *mut ffi::tcp_pcb {
-    let state = Box::new(TcpConnectionState::new());
-    Box::into_raw(state) as *mut ffi::tcp_pcb
+/// A local port nobody uses, from the ephemeral range (lwIP's tcp_new_port)
+unsafe fn tcp_new_port() -> Option<u16> {
+    let range = tcp_proto::TCP_LOCAL_PORT_RANGE_START..=tcp_proto::TCP_LOCAL_PORT_RANGE_END;
+    tcp_ephemeral_port(&mut *ptr::addr_of_mut!(TCP_PORT), range, |port| tcp_port_in_use(port))
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_new_ip_type_rust(ip_type: u8) -> *mut ffi::tcp_pcb {
-    tcp_new_rust()
-}
+/// Unlink and deallocate a PCB
+///
+/// A child still holding a backlog slot returns it to its listener; a
+/// listener's children forget it.
+unsafe fn tcp_free(pcb: *mut ffi::tcp_pcb) {
+    pcb_list::unlink(pcb.cast());
+    let state = Box::from_raw(pcb as *mut TcpConnectionState);
+    TCP_PCB_COUNT -= 1;
+
+    if !state.listener.is_null() {
+        if state.conn_mgmt.flags & tcp_proto::TF_BACKLOGPEND != 0 {
+            let _ = (*state.listener).conn_mgmt.on_backlog_release();
+        }
+        (*state.listener).accept_queue.retain(|&child| child != pcb as *mut TcpConnectionState);
+    }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_tmr_rust() {
-    tcp_ticks = tcp_ticks.wrapping_add(1);
+    for child in pcb_list::iter(tcp_active_pcbs).chain(pcb_list::iter(tcp_tw_pcbs)) {
+        if (*child).listener == pcb as *mut TcpConnectionState {
+            (*child).listener = ptr::null_mut();
+        }
+    }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_bind_rust(
-    pcb: *mut ffi::tcp_pcb,
-    ipaddr: *const ffi::ip_addr_t,
-    port: u16,
-) -> i8 {
+/// Free a dead connection, then report `err` to its error callback
+///
+/// As in lwIP the PCB is already gone when the callback runs; the callback
+/// is taken first so nothing can fire it twice.
+unsafe fn tcp_free_with_err(pcb: *mut ffi::tcp_pcb, err: i8) {
     let Some(state) = pcb_to_state_mut(pcb) else {
-        return ERR_ARG;
-    };
-
-    let ip = if ipaddr.is_null() {
-        ffi::ip_addr_t { addr: 0 }
-    } else {
-        *ipaddr
+        return;
     };
+    let errf = state.err_callback.take();
+    let arg = state.callback_arg;
 
-    match tcp_bind(state, ip, port) {
-        Ok(_) => ERR_OK,
-        Err(_) => ERR_VAL,
+    tcp_free(pcb);
+    if let Some(errf) = errf {
+        errf(arg, err);
     }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn tcp_connect_rust(
-    pcb: *mut ffi::tcp_pcb,
-    ipaddr: *const ffi::ip_addr_t,
-    port: u16,
-    connected: ffi::tcp_connected_fn,
-) -> i8 {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return ERR_ARG;
-    };
-
-    if ipaddr.is_null() {
-        return ERR_ARG;
-    }
-
-    state.connected_callback = connected.map(|f| {
-        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void, i8) -> i8>(f)
-    });
-
-    match tcp_connect(state, *ipaddr, port) {
-        Ok(_) => ERR_OK,
-        Err(_) => ERR_VAL,
-    }
+pub unsafe extern "C" fn tcp_init_rust() {
+    tcp_ticks = 0;
+    pcb_list::reset();
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_write_rust(
-    pcb: *mut ffi::tcp_pcb,
-    dataptr: *const c_void,
-    len: u16,
-    apiflags: u8,
-) -> i8 {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return ERR_ARG;
-    };
+/// Outcome of matching an incoming segment against the PCB lists
+#[derive(Debug, PartialEq)]
+enum Demux {
+    Pcb(*mut ffi::tcp_pcb), // Active connection or listener
+    SendRst,                // Nobody wants it
+}
 
-    if dataptr.is_null() && len > 0 {
-        return ERR_ARG;
+/// Find the PCB for a segment by its 4-tuple, as seen from our side
+///
+/// Active connections must match all four fields. Only a SYN may fall
+/// back to a listener bound to the local port and address (or any).
+unsafe fn tcp_demux(
+    local_ip: ffi::ip_addr_t,
+    local_port: u16,
+    remote_ip: ffi::ip_addr_t,
+    remote_port: u16,
+    syn: bool,
+) -> Demux {
+    for pcb in pcb_list::iter(tcp_active_pcbs).chain(pcb_list::iter(tcp_tw_pcbs)) {
+        if (*pcb).conn_mgmt.matches(local_ip, local_port, remote_ip, remote_port) {
+            return Demux::Pcb(pcb as *mut ffi::tcp_pcb);
+        }
     }
 
-    ERR_OK
-}
+    if syn {
+        let lpcb = tcp_find_listener(local_ip, local_port);
+        if !lpcb.is_null() {
+            return Demux::Pcb(lpcb as *mut ffi::tcp_pcb);
+        }
+    }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_output_rust(pcb: *mut ffi::tcp_pcb) -> i8 {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return ERR_ARG;
-    };
-    ERR_OK
+    Demux::SendRst
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_close_rust(pcb: *mut ffi::tcp_pcb) -> i8 {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return ERR_ARG;
-    };
-
-    match initiate_close(state) {
-        Ok(send_fin) => {
-            if state.conn_mgmt.state == TcpState::Closed {
-                let _ = Box::from_raw(pcb as *mut TcpConnectionState);
-            }
-            ERR_OK
+/// The listener bound to `local_port` on `local_ip` (or any), if there is one
+///
+/// A listener bound to `local_ip` itself wins over one bound to any address.
+/// Only IPADDR_TYPE_ANY listeners take segments of either family.
+unsafe fn tcp_find_listener(local_ip: ffi::ip_addr_t, local_port: u16) -> *mut TcpConnectionState {
+    let mut any: *mut TcpConnectionState = ptr::null_mut();
+    for lpcb in pcb_list::iter(tcp_listen_pcbs) {
+        let cm = &(*lpcb).conn_mgmt;
+        if cm.local_port != local_port
+            || (cm.ip_type != tcp_proto::IPADDR_TYPE_ANY && cm.is_ip6() != ip_addr::is_v6(&local_ip))
+        {
+            continue;
+        }
+        let (exact, unbound) = if cm.is_ip6() {
+            (cm.local_ip6 == ip_addr::ip6(&local_ip), cm.local_ip6 == [0; 4])
+        } else {
+            (ip_addr::eq(&cm.local_ip, &local_ip), ip_addr::is_any(&cm.local_ip))
+        };
+        if exact {
+            return lpcb;
+        }
+        if unbound && any.is_null() {
+            any = lpcb;
         }
-        Err(_) => ERR_VAL,
     }
+    any
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_abort_rust(pcb: *mut ffi::tcp_pcb) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
-    };
+/// Hand a connection reopened from TIME_WAIT to the listener on its port,
+/// as if that listener had spawned it
+///
+/// False if nobody listens there or the backlog is full.
+unsafe fn tcp_adopt_reopened(state: &mut TcpConnectionState) -> bool {
+    let listener = tcp_find_listener(state.conn_mgmt.local_ip, state.conn_mgmt.local_port);
+    if listener.is_null() || (*listener).conn_mgmt.on_backlog_reserve().is_err() {
+        return false;
+    }
 
-    let _ = tcp_abort(state);
-    let _ = Box::from_raw(pcb as *mut TcpConnectionState);
+    // The old incarnation's application is gone
+    state.recv_callback = None;
+    state.sent_callback = None;
+    state.err_callback = None;
+    state.connected_callback = None;
+    state.poll_callback = None;
+
+    state.listener = listener;
+    state.callback_arg = (*listener).callback_arg;
+    let _ = state.conn_mgmt.on_backlog_pending();
+    true
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_recved_rust(pcb: *mut ffi::tcp_pcb, len: u16) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
-    };
-    state.flow_ctrl.rcv_wnd = state.flow_ctrl.rcv_wnd.saturating_add(len);
+/// Copy the contents of a (possibly chained) pbuf
+unsafe fn pbuf_to_vec(p: *const ffi::pbuf) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity((*p).tot_len as usize);
+    let mut q = p;
+    while !q.is_null() {
+        bytes.extend_from_slice(core::slice::from_raw_parts((*q).payload as *const u8, (*q).len as usize));
+        q = (*q).next;
+    }
+    bytes
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn tcp_arg_rust(pcb: *mut ffi::tcp_pcb, arg: *mut c_void) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
+pub unsafe extern "C" fn tcp_input_rust(
+    p: *mut ffi::pbuf,
+    inp: *mut ffi::netif,
+) {
+    if p.is_null() {
         return;
-    };
-    state.callback_arg = arg;
-}
+    }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_recv_rust(pcb: *mut ffi::tcp_pcb, recv: ffi::tcp_recv_fn) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
-    };
-    state.recv_callback = recv.map(|f| {
-        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_void, i8) -> i8>(f)
-    });
-}
+    // ip4_input/ip6_input have stripped the IP header; its addresses are in ip_data
+    let bytes = pbuf_to_vec(p);
+    ffi::pbuf_free(p);
+    let src = ffi::ip_data.current_iphdr_src;
+    let dst = ffi::ip_data.current_iphdr_dest;
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_sent_rust(pcb: *mut ffi::tcp_pcb, sent: ffi::tcp_sent_fn) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
+    let Ok(mut seg) = TcpSegment::parse(&bytes) else {
         return;
     };
-    state.sent_callback = sent.map(|f| {
-        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void, u16) -> i8>(f)
-    });
+    seg.ce = ip_current_ecn() == tcp_proto::IP_ECN_CE;
+    let hdr = ptr::read_unaligned(bytes.as_ptr() as *const tcp_proto::TcpHdr);
+
+    match tcp_demux(dst, hdr.dest_port(), src, hdr.src_port(), seg.flags.syn) {
+        Demux::Pcb(pcb) => {
+            #[cfg(feature = "md5")]
+            if !tcp_md5_accepts(pcb, &bytes, &src, &dst) {
+                return;
+            }
+            tcp_process(pcb, &seg, src, hdr.src_port());
+        }
+        Demux::SendRst => {
+            tcp_rst_reply(&seg, dst, src, hdr.dest_port(), hdr.src_port());
+        }
+    }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_poll_rust(
+/// Does the segment carry the signature `pcb`'s MD5 key calls for?
+///
+/// A mismatch is dropped silently, without a RST (RFC 2385, section 2.0).
+#[cfg(feature = "md5")]
+unsafe fn tcp_md5_accepts(
     pcb: *mut ffi::tcp_pcb,
-    poll: ffi::tcp_poll_fn,
-    interval: u8,
-) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
+    bytes: &[u8],
+    src: &ffi::ip_addr_t,
+    dst: &ffi::ip_addr_t,
+) -> bool {
+    let Some(state) = pcb_to_state(pcb) else {
+        return false;
     };
-    state.poll_callback = poll.map(|f| {
-        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void) -> i8>(f)
-    });
-    state.poll_interval = interval;
-}
-
-#[no_mangle]
-pub unsafe extern "C" fn tcp_err_rust(pcb: *mut ffi::tcp_pcb, err: ffi::tcp_err_fn) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
+    let pseudo = if state.conn_mgmt.is_ip6() {
+        tcp_md5::pseudo_header_v6(&ip_addr::ip6(src), &ip_addr::ip6(dst), bytes.len())
+    } else {
+        tcp_md5::pseudo_header_v4(src, dst, bytes.len())
     };
-    state.err_callback = err.map(|f| {
-        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, i8)>(f)
-    });
+    tcp_md5::verify(&pseudo, bytes, state.conn_mgmt.md5_key.as_deref())
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_accept_rust(pcb: *mut ffi::tcp_pcb, accept: ffi::tcp_accept_fn) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
+/// Answer a segment nobody may receive with a RST (RFC 793, p. 36)
+///
+/// A RST is dropped, never answered.
+unsafe fn tcp_rst_reply(
+    seg: &TcpSegment,
+    local_ip: ffi::ip_addr_t,
+    remote_ip: ffi::ip_addr_t,
+    local_port: u16,
+    remote_port: u16,
+) {
+    if seg.flags.rst {
         return;
-    };
-    state.accept_callback = accept.map(|f| {
-        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void, i8) -> i8>(f)
-    });
-}
-
-#[no_mangle]
-pub unsafe extern "C" fn tcp_shutdown_rust(pcb: *mut ffi::tcp_pcb, shut_rx: i32, shut_tx: i32) -> i8 {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return ERR_ARG;
-    };
-
-    if shut_tx != 0 {
-        let _ = initiate_close(state);
     }
-    ERR_OK
+    let reply = seg.rst_reply();
+    let addrs = tcp_out::IpPair::from_ip(&local_ip, &remote_ip);
+    let _ = tcp_out::TcpTx::send_rst(&reply, addrs, local_port, remote_port, tcp_proto::TCP_TTL);
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_bind_netif_rust(pcb: *mut ffi::tcp_pcb, _netif: *const ffi::netif) {
-    // netif binding tracked but not currently used
+/// ECN field of the IP header being processed, Not-ECT if there is none
+unsafe fn ip_current_ecn() -> u8 {
+    #[cfg(lwip_ipv6)]
+    {
+        let ip6hdr = ffi::ip_data.current_ip6_header;
+        if !ip6hdr.is_null() {
+            // Version, traffic class and flow label, in network byte order
+            let tc = (u32::from_be((*ip6hdr)._v_tc_fl) >> 20) as u8;
+            return tc & tcp_proto::IP_ECN_MASK;
+        }
+    }
+    #[cfg(lwip_ipv4)]
+    {
+        let iphdr = ffi::ip_data.current_ip4_header;
+        if !iphdr.is_null() {
+            return (*iphdr)._tos & tcp_proto::IP_ECN_MASK;
+        }
+    }
+    0
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_listen_with_backlog_rust(
+/// Run a parsed segment through the state machine and apply the outcome
+///
+/// Returns the PCB, or null if the segment tore the connection down and the
+/// PCB was freed.
+unsafe fn tcp_process(
     pcb: *mut ffi::tcp_pcb,
-    backlog: u8,
+    seg: &TcpSegment,
+    remote_ip: ffi::ip_addr_t,
+    remote_port: u16,
 ) -> *mut ffi::tcp_pcb {
     let Some(state) = pcb_to_state_mut(pcb) else {
         return ptr::null_mut();
     };
 
-    match tcp_listen(state) {
-        Ok(_) => pcb,
-        Err(_) => ptr::null_mut(),
+    if state.conn_mgmt.state == TcpState::Listen {
+        return tcp_listen_process(pcb, seg, remote_ip, remote_port);
     }
-}
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_listen_with_backlog_and_err_rust(
-    pcb: *mut ffi::tcp_pcb,
-    backlog: u8,
-    err: *mut i8,
-) -> *mut ffi::tcp_pcb {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        if !err.is_null() {
-            *err = ERR_ARG;
+    let was_synsent = state.conn_mgmt.state == TcpState::SynSent;
+    let was_synrcvd = state.conn_mgmt.state == TcpState::SynRcvd;
+    let was_timewait = state.conn_mgmt.state == TcpState::TimeWait;
+    let was_lastack = state.conn_mgmt.state == TcpState::LastAck;
+    let prev_snd_wnd = state.flow_ctrl.snd_wnd;
+    let prev_lastack = state.rod.lastack;
+    match tcp_input(state, seg, remote_ip, remote_port) {
+        Ok(InputAction::Abort) => {
+            // Reset by peer: the application must not touch the PCB from
+            // inside the error callback
+            tcp_free_with_err(pcb, ERR_RST);
+            return ptr::null_mut();
+        }
+        Ok(InputAction::Retransmit) => {
+            let _ = tcp_out::TcpTx::retransmit(state);
+        }
+        Ok(InputAction::LimitedTransmit) => {
+            if let Ok(Some(seg)) = tcp_limited_transmit(state) {
+                let _ = tcp_out::TcpTx::send_data(state, &seg);
+            }
+        }
+        // Queued data carries the ACK; it only goes out bare if no data
+        // can be sent
+        Ok(InputAction::SendAck) if !matches!(tcp_flush(state), Ok(true)) => {
+            let _ = tcp_out::TcpTx::send_ack(state);
+        }
+        Ok(InputAction::SendChallengeAck) => {
+            let _ = tcp_out::TcpTx::send_ack(state);
+        }
+        Ok(InputAction::Accept) if state.flow_ctrl.snd_wnd > prev_snd_wnd || state.rod.lastack != prev_lastack => {
+            // The peer opened its window or took data off the wire: queued
+            // data, Nagle's held back short segment included, may go now
+            let _ = tcp_flush(state);
+        }
+        Ok(InputAction::SendRst) => {
+            let cm = &state.conn_mgmt;
+            tcp_rst_reply(seg, cm.local_ip, remote_ip, cm.local_port, remote_port);
+        }
+        Ok(InputAction::SendSynAck) => {
+            // A SYN reopened TIME_WAIT: without a listener to take the new
+            // connection it is refused. Otherwise the SYN crossed ours.
+            if was_timewait && !tcp_adopt_reopened(state) {
+                let cm = &state.conn_mgmt;
+                tcp_rst_reply(seg, cm.local_ip, remote_ip, cm.local_port, remote_port);
+                tcp_free(pcb);
+                return ptr::null_mut();
+            }
+            let _ = tcp_out::TcpTx::send_synack(state);
         }
+        _ => {}
+    }
+
+    if was_synrcvd && state.conn_mgmt.state == TcpState::Listen {
+        // Reset child of a listener: the listener keeps the port, the child
+        // goes away with its backlog slot and nobody hears about it
+        tcp_free(pcb);
+        return ptr::null_mut();
+    }
+
+    if was_lastack && state.conn_mgmt.state == TcpState::Closed {
+        // Our FIN was acknowledged: the application closed this PCB already
+        tcp_free(pcb);
+        return ptr::null_mut();
+    }
+
+    // Handshake complete: a listener's child goes to accept, an active
+    // open (simultaneous ones included) reports connected
+    let opened = state.conn_mgmt.state == TcpState::Established && (was_synsent || was_synrcvd);
+    let passive = state.conn_mgmt.opened_passively;
+    if opened && passive && !tcp_accept_child(pcb, state) {
+        return ptr::null_mut();
+    }
+
+    if opened && !passive && !tcp_report_connected(pcb, state) {
+        return ptr::null_mut();
+    }
+
+    if !was_timewait && state.conn_mgmt.state == TcpState::TimeWait {
+        pcb_list::move_to_timewait(pcb.cast());
+    } else if was_timewait && state.conn_mgmt.state == TcpState::SynRcvd {
+        pcb_list::register_active(pcb.cast());
+    }
+
+    if !tcp_report_sent(pcb, state) {
+        return ptr::null_mut();
+    }
+    if !tcp_deliver(pcb, state) {
+        return ptr::null_mut();
+    }
+    pcb
+}
+
+/// SYN on a listener: spawn, link and answer a child connection
+///
+/// Returns the new child PCB, or null if the SYN was dropped (backlog
+/// full, or not a connection request).
+unsafe fn tcp_listen_process(
+    lpcb: *mut ffi::tcp_pcb,
+    seg: &TcpSegment,
+    remote_ip: ffi::ip_addr_t,
+    remote_port: u16,
+) -> *mut ffi::tcp_pcb {
+    let Some(listener) = pcb_to_state_mut(lpcb) else {
         return ptr::null_mut();
     };
 
-    match tcp_listen(state) {
-        Ok(_) => {
-            if !err.is_null() {
-                *err = ERR_OK;
+    let Ok(child) = tcp_listen_input(listener, seg, remote_ip, remote_port) else {
+        return ptr::null_mut();
+    };
+
+    let child = tcp_alloc(child) as *mut TcpConnectionState;
+    if child.is_null() {
+        let _ = listener.conn_mgmt.on_backlog_release();
+        return ptr::null_mut();
+    }
+    (*child).listener = listener;
+    pcb_list::register_active(child);
+
+    // The child answers from the address the SYN was sent to
+    if (*child).conn_mgmt.is_ip6() {
+        let (src, dst) = (ffi::ip_data.current_iphdr_src, ffi::ip_data.current_iphdr_dest);
+        (*child).conn_mgmt.remote_ip6 = ip_addr::ip6(&src);
+        (*child).conn_mgmt.local_ip6 = ip_addr::ip6(&dst);
+    }
+
+    let _ = tcp_out::TcpTx::send_synack(&*child);
+    child as *mut ffi::tcp_pcb
+}
+
+/// Passive open complete: queue the child on its listener for accept
+///
+/// Returns false if the child is gone: its listener was closed meanwhile,
+/// or the accept callback refused it.
+unsafe fn tcp_accept_child(pcb: *mut ffi::tcp_pcb, state: &mut TcpConnectionState) -> bool {
+    let listener = state.listener;
+    if listener.is_null() {
+        // Nobody can take it
+        tcp_reset_conn(state);
+        tcp_free(pcb);
+        return false;
+    }
+
+    // The child keeps its backlog slot until it is accepted
+    (*listener).accept_queue.push_back(pcb as *mut TcpConnectionState);
+    tcp_accept_queued(listener, pcb as *mut TcpConnectionState)
+}
+
+/// Hand a listener's queued children to its accept callback, oldest first
+///
+/// Children that establish before the application sets the callback wait
+/// on the queue, holding their backlog slots, until it does. As in lwIP a
+/// child the callback does not return ERR_OK for is aborted, unless the
+/// callback aborted it already (ERR_ABRT). The callback may also close or
+/// abort the listener, which takes the children still queued with it.
+///
+/// Returns false if `watch` went away in one of these ways.
+unsafe fn tcp_accept_queued(lpcb: *mut TcpConnectionState, watch: *mut TcpConnectionState) -> bool {
+    let mut alive = true;
+    let mut handed_out = false;
+    while let Some(accept) = (*lpcb).accept_callback {
+        let Some(child) = (*lpcb).accept_queue.pop_front() else {
+            break;
+        };
+        handed_out |= child == watch;
+        tcp_backlog_accepted_rust(child as *mut ffi::tcp_pcb);
+
+        let err = accept((*lpcb).callback_arg, child as *mut c_void, ERR_OK);
+        if err != ERR_OK {
+            if err != ERR_ABRT {
+                tcp_reset_conn(&mut *child);
+                tcp_free(child as *mut ffi::tcp_pcb);
             }
-            pcb
+            alive &= child != watch;
         }
-        Err(_) => {
-            if !err.is_null() {
-                *err = ERR_VAL;
-            }
-            ptr::null_mut()
+
+        if !pcb_list::contains(tcp_listen_pcbs, lpcb) {
+            // The listener is gone, and with it the rest of its queue
+            return alive && (watch.is_null() || handed_out);
         }
     }
+    alive
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_setprio_rust(pcb: *mut ffi::tcp_pcb, prio: u8) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
+/// Our SYN was answered: tell the application that connected
+///
+/// Returns false if the application aborted the PCB from the callback.
+unsafe fn tcp_report_connected(pcb: *mut ffi::tcp_pcb, state: &mut TcpConnectionState) -> bool {
+    let Some(connected) = state.connected_callback else {
+        return true;
     };
-    state.conn_mgmt.prio = prio;
+    connected(state.callback_arg, pcb as *mut c_void, ERR_OK) != ERR_ABRT
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_tcp_get_tcp_addrinfo_rust(
-    pcb: *mut ffi::tcp_pcb,
-    local: i32,
-    addr: *mut ffi::ip_addr_t,
-    port: *mut u16,
-) -> i8 {
-    let Some(state) = pcb_to_state(pcb) else {
-        return ERR_ARG;
+/// Tell the sent callback how many of our bytes the peer acknowledged
+///
+/// Returns false if the application aborted the PCB from the callback.
+unsafe fn tcp_report_sent(pcb: *mut ffi::tcp_pcb, state: &mut TcpConnectionState) -> bool {
+    let mut acked = state.rod.on_sent_reported();
+    let Some(sent) = state.sent_callback else {
+        return true;
     };
+    let arg = state.callback_arg;
 
-    if local != 0 {
-        if !addr.is_null() {
-            *addr = state.conn_mgmt.local_ip;
+    while acked > 0 {
+        let len = acked.min(u16::MAX as u32) as u16;
+        if sent(arg, pcb as *mut c_void, len) == ERR_ABRT {
+            return false;
         }
-        if !port.is_null() {
-            *port = state.conn_mgmt.local_port;
+        acked -= len as u32;
+    }
+    true
+}
+
+/// Pass newly received in-order data, then EOF, to the recv callback
+///
+/// The application owns each pbuf it accepts. One it refuses (any error
+/// but ERR_ABRT) stays with us and is offered again, with everything
+/// after it, on the next segment or fast timer tick, like lwIP's
+/// refused_data. Without a callback the data is dropped. After
+/// tcp_shutdown(shut_rx) not even EOF is reported.
+///
+/// Data the peer pushed ends in a pbuf flagged PBUF_FLAG_PUSH.
+///
+/// Returns false if the application aborted the PCB from the callback.
+unsafe fn tcp_deliver(pcb: *mut ffi::tcp_pcb, state: &mut TcpConnectionState) -> bool {
+    let push = state.rod.rcv_push;
+    let (data, fin) = state.rod.on_deliver();
+    if state.conn_mgmt.flags & tcp_proto::TF_RXCLOSED != 0 {
+        return true;
+    }
+    let Some(recv) = state.recv_callback else {
+        // Nobody to take the data: consume it like tcp_recv_null
+        for chunk in data.chunks(u16::MAX as usize) {
+            let _ = tcp_recved(state, chunk.len() as u16);
         }
-    } else {
-        if !addr.is_null() {
-            *addr = state.conn_mgmt.remote_ip;
+        return true;
+    };
+    let arg = state.callback_arg;
+
+    let mut offset = 0;
+    for chunk in data.chunks(u16::MAX as usize) {
+        let p = ffi::pbuf_alloc(ffi::pbuf_layer_PBUF_RAW, chunk.len() as u16, ffi::pbuf_type_PBUF_RAM);
+        if p.is_null() {
+            state.rod.on_refused(data[offset..].to_vec(), fin, push);
+            return true;
         }
-        if !port.is_null() {
-            *port = state.conn_mgmt.remote_port;
+        ptr::copy_nonoverlapping(chunk.as_ptr(), (*p).payload as *mut u8, chunk.len());
+        if push && offset + chunk.len() == data.len() {
+            (*p).flags |= tcp_proto::PBUF_FLAG_PUSH;
+        }
+        match recv(arg, pcb as *mut c_void, p as *mut c_void, ERR_OK) {
+            ERR_OK => {}
+            ERR_ABRT => return false,
+            _ => {
+                ffi::pbuf_free(p);
+                state.rod.on_refused(data[offset..].to_vec(), fin, push);
+                return true;
+            }
         }
+        offset += chunk.len();
     }
-    ERR_OK
+
+    // lwIP signals EOF with a NULL pbuf
+    if fin {
+        match recv(arg, pcb as *mut c_void, ptr::null_mut(), ERR_OK) {
+            ERR_OK => {}
+            ERR_ABRT => return false,
+            _ => state.rod.on_refused(Vec::new(), true, false),
+        }
+    }
+    true
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_netif_ip_addr_changed_rust(
-    old_addr: *const ffi::ip_addr_t,
-    new_addr: *const ffi::ip_addr_t,
-) {
+/// Hand `state` out as a PCB if it fits within TCP_PCB_LIMIT
+///
+/// As in lwIP's tcp_alloc, room is made when the limit is reached by
+/// aborting the longest idle connection in TIME_WAIT, then LAST_ACK,
+/// then CLOSING, and finally one of lower priority than the new PCB.
+/// NULL if none could go.
+unsafe fn tcp_alloc(state: Box<TcpConnectionState>) -> *mut ffi::tcp_pcb {
+    for victim in [TcpState::TimeWait, TcpState::LastAck, TcpState::Closing] {
+        if TCP_PCB_COUNT < TCP_PCB_LIMIT {
+            break;
+        }
+        tcp_kill_state(victim);
+    }
+    if TCP_PCB_COUNT >= TCP_PCB_LIMIT {
+        tcp_kill_prio(state.conn_mgmt.prio);
+    }
+    if TCP_PCB_COUNT >= TCP_PCB_LIMIT {
+        return ptr::null_mut();
+    }
+
+    TCP_PCB_COUNT += 1;
+    Box::into_raw(state) as *mut ffi::tcp_pcb
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_backlog_delayed_rust(pcb: *mut ffi::tcp_pcb) {
+/// Abort the longest idle active connection in `state`, if any
+unsafe fn tcp_kill_state(state: TcpState) {
+    let mut inactivity = 0;
+    let mut inactive: *mut TcpConnectionState = ptr::null_mut();
+
+    let list = if state == TcpState::TimeWait { tcp_tw_pcbs } else { tcp_active_pcbs };
+    for pcb in pcb_list::iter(list) {
+        let idle = tcp_ticks.wrapping_sub((*pcb).conn_mgmt.tmr);
+        if (*pcb).conn_mgmt.state == state && idle >= inactivity {
+            inactivity = idle;
+            inactive = pcb;
+        }
+    }
+
+    if !inactive.is_null() {
+        tcp_abort_rust(inactive as *mut ffi::tcp_pcb);
+    }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_backlog_accepted_rust(pcb: *mut ffi::tcp_pcb) {
+/// Abort the active connection with the lowest priority below `prio`,
+/// the longest idle one among equals
+unsafe fn tcp_kill_prio(prio: u8) {
+    // Nothing ranks below the lowest priority
+    let mut mprio = prio.min(tcp_proto::TCP_PRIO_MAX);
+    if mprio == 0 {
+        return;
+    }
+    mprio -= 1;
+
+    let mut inactivity = 0;
+    let mut inactive: *mut TcpConnectionState = ptr::null_mut();
+
+    for pcb in pcb_list::iter(tcp_active_pcbs) {
+        let cm = &(*pcb).conn_mgmt;
+        let idle = tcp_ticks.wrapping_sub(cm.tmr);
+        if cm.prio < mprio || (cm.prio == mprio && idle >= inactivity) {
+            inactivity = idle;
+            inactive = pcb;
+            mprio = cm.prio;
+        }
+    }
+
+    if !inactive.is_null() {
+        tcp_abort_rust(inactive as *mut ffi::tcp_pcb);
+    }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn tcp_ext_arg_alloc_id_rust() -> u8 {
-    static mut EXT_ARG_ID: u8 = 0;
-    let id = EXT_ARG_ID;
-    EXT_ARG_ID = EXT_ARG_ID.wrapping_add(1);
-    id
+pub unsafe extern "C" fn tcp_new_rust() -> *mut ffi::tcp_pcb {
+    tcp_alloc(Box::new(TcpConnectionState::new()))
 }
 
+/// tcp_new with a TCP_CC_* congestion control algorithm; NULL if unknown
 #[no_mangle]
-pub unsafe extern "C" fn tcp_ext_arg_set_callbacks_rust(
-    pcb: *mut ffi::tcp_pcb,
-    id: u8,
-    callbacks: *const c_void,
-) {
+pub unsafe extern "C" fn tcp_new_cc_rust(cc: u8) -> *mut ffi::tcp_pcb {
+    match TcpConnectionState::with_congestion_algorithm(cc) {
+        Ok(state) => tcp_alloc(Box::new(state)),
+        Err(_) => ptr::null_mut(),
+    }
 }
 
+/// tcp_new for an address family (IPADDR_TYPE_*); NULL if unknown
 #[no_mangle]
-pub unsafe extern "C" fn tcp_ext_arg_set_rust(
-    pcb: *mut ffi::tcp_pcb,
-    id: u8,
-    arg: *mut c_void,
-) {
+pub unsafe extern "C" fn tcp_new_ip_type_rust(ip_type: u8) -> *mut ffi::tcp_pcb {
+    if !matches!(
+        ip_type,
+        tcp_proto::IPADDR_TYPE_V4 | tcp_proto::IPADDR_TYPE_V6 | tcp_proto::IPADDR_TYPE_ANY
+    ) {
+        return ptr::null_mut();
+    }
+
+    let mut state = Box::new(TcpConnectionState::new());
+    state.conn_mgmt.ip_type = ip_type;
+    tcp_alloc(state)
 }
 
+/// Called every TCP_TMR_INTERVAL: fast timer each time, slow timer every other
 #[no_mangle]
-pub unsafe extern "C" fn tcp_ext_arg_get_rust(
-    pcb: *const ffi::tcp_pcb,
-    id: u8,
-) -> *mut c_void {
-    ptr::null_mut()
+pub unsafe extern "C" fn tcp_tmr_rust() {
+    static mut TCP_TIMER: u8 = 0;
+
+    tcp_fasttmr();
+
+    TCP_TIMER = TCP_TIMER.wrapping_add(1);
+    if TCP_TIMER & 1 == 0 {
+        tcp_slowtmr();
+    }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn tcp_get_state_rust(pcb: *const ffi::tcp_pcb) -> u8 {
-    let Some(state) = pcb_to_state(pcb) else {
-        return 0;
+pub unsafe extern "C" fn tcp_bind_rust(
+    pcb: *mut ffi::tcp_pcb,
+    ipaddr: *const ffi::ip_addr_t,
+    port: u16,
+) -> i8 {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return ERR_ARG;
     };
-    state.conn_mgmt.state as u8
-}
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_get_sndbuf_rust(pcb: *const ffi::tcp_pcb) -> u16 {
-    let Some(state) = pcb_to_state(pcb) else {
-        return 0;
+    // NULL binds to the IPv4 any address, as in lwIP
+    let ip = if ipaddr.is_null() {
+        ip_addr::from_ip4(0)
+    } else {
+        *ipaddr
     };
-    state.rod.snd_buf
-}
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_get_sndqueuelen_rust(pcb: *const ffi::tcp_pcb) -> u16 {
-    let Some(state) = pcb_to_state(pcb) else {
-        return 0;
+    // Port 0: pick one for the caller
+    let port = match port {
+        0 => match tcp_new_port() {
+            Some(port) => port,
+            None => return ERR_BUF,
+        },
+        port if tcp_addr_in_use(state, ip, port) => return TcpError::PortInUse.to_err_t(),
+        port => port,
     };
-    state.rod.snd_queuelen
+
+    match tcp_bind(state, ip, port) {
+        Ok(_) => {
+            if state.conn_mgmt.is_ip6() {
+                state.conn_mgmt.local_ip6 = ip_addr::ip6(&ip);
+            }
+            pcb_list::register_bound(pcb.cast());
+            ERR_OK
+        }
+        Err(e) => e.to_err_t(),
+    }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn tcp_set_flags_rust(pcb: *mut ffi::tcp_pcb, set_flags: u16) {
+pub unsafe extern "C" fn tcp_connect_rust(
+    pcb: *mut ffi::tcp_pcb,
+    ipaddr: *const ffi::ip_addr_t,
+    port: u16,
+    connected: ffi::tcp_connected_fn,
+) -> i8 {
     let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
+        return ERR_ARG;
     };
-    state.conn_mgmt.flags |= set_flags;
-}
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_clear_flags_rust(pcb: *mut ffi::tcp_pcb, clr_flags: u16) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
+    if ipaddr.is_null() {
+        return ERR_ARG;
+    }
+
+    state.connected_callback = connected.map(|f| {
+        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void, i8) -> i8>(f)
+    });
+
+    // Never bound: connect from an ephemeral port
+    if state.conn_mgmt.local_port == 0 && state.conn_mgmt.state == TcpState::Closed {
+        let Some(local_port) = tcp_new_port() else {
+            return ERR_BUF;
+        };
+        if let Err(e) = tcp_bind(state, state.conn_mgmt.local_ip, local_port) {
+            return e.to_err_t();
+        }
+    }
+
+    // The same 4-tuple may only be reused from a connection in TIME_WAIT
+    let cm = &state.conn_mgmt;
+    let same_tuple = |other: &*mut TcpConnectionState| {
+        *other != pcb as *mut TcpConnectionState
+            && (*(*other)).conn_mgmt.matches(cm.local_ip, cm.local_port, *ipaddr, port)
     };
-    state.conn_mgmt.flags &= !clr_flags;
-}
+    if pcb_list::iter(tcp_active_pcbs).any(|other| same_tuple(&other)) {
+        return TcpError::PortInUse.to_err_t();
+    }
+    let tw = pcb_list::iter(tcp_tw_pcbs).find(same_tuple);
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_is_flag_set_rust(pcb: *const ffi::tcp_pcb, flag: u16) -> i32 {
-    let Some(state) = pcb_to_state(pcb) else {
-        return 0;
+    if state.conn_mgmt.is_ip6() {
+        state.conn_mgmt.remote_ip6 = ip_addr::ip6(&*ipaddr);
+    }
+
+    let connected = match tw {
+        Some(tw) => tcp_connect_over_timewait(state, *ipaddr, port, &*tw),
+        None => tcp_connect(state, *ipaddr, port),
     };
-    if (state.conn_mgmt.flags & flag) != 0 { 1 } else { 0 }
+    match connected {
+        Ok(_) => {
+            if let Some(tw) = tw {
+                tcp_free(tw as *mut ffi::tcp_pcb);
+            }
+            pcb_list::register_active(pcb.cast());
+            ERR_OK
+        }
+        Err(e) => e.to_err_t(),
+    }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn tcp_rst(
+pub unsafe extern "C" fn tcp_write_rust(
     pcb: *mut ffi::tcp_pcb,
-    seqno: u32,
-    ackno: u32,
-    local_ip: *const ffi::ip_addr_t,
-    remote_ip: *const ffi::ip_addr_t,
-    local_port: u16,
-    remote_port: u16,
-) {
-}
+    dataptr: *const c_void,
+    len: u16,
+    apiflags: u8,
+) -> i8 {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return ERR_ARG;
+    };
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_next_iss(pcb: *mut ffi::tcp_pcb) -> u32 {
-    static mut ISS: u32 = 6510;
-    ISS = ISS.wrapping_add(64000);
-    ISS
+    if dataptr.is_null() && len > 0 {
+        return ERR_ARG;
+    }
+
+    let data = if len == 0 {
+        &[][..]
+    } else {
+        core::slice::from_raw_parts(dataptr as *const u8, len as usize)
+    };
+
+    match tcp_write(state, data, apiflags) {
+        Ok(()) => ERR_OK,
+        Err(e) => e.to_err_t(),
+    }
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn tcp_fasttmr() {
+pub unsafe extern "C" fn tcp_output_rust(pcb: *mut ffi::tcp_pcb) -> i8 {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return ERR_ARG;
+    };
+
+    match tcp_flush(state) {
+        Ok(_) => ERR_OK,
+        Err(_) => ERR_VAL,
+    }
+}
+
+/// Run tcp_output and hand the planned segments to IP
+///
+/// Returns whether any segment went out.
+unsafe fn tcp_flush(state: &mut TcpConnectionState) -> Result<bool, &'static str> {
+    // Segments are already on the unacked queue, a failed send is
+    // recovered by the retransmission timer
+    let segs = tcp_output(state)?;
+    for seg in &segs {
+        let _ = tcp_out::TcpTx::send_data(state, seg);
+    }
+    Ok(!segs.is_empty())
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn tcp_slowtmr() {
+pub unsafe extern "C" fn tcp_close_rust(pcb: *mut ffi::tcp_pcb) -> i8 {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return ERR_ARG;
+    };
+
+    if state.conn_mgmt.state == TcpState::Listen {
+        tcp_reset_children(pcb);
+    }
+
+    match initiate_close(state) {
+        Ok(action) => {
+            tcp_close_finish(pcb, state, action);
+            ERR_OK
+        }
+        Err(e) => e.to_err_t(),
+    }
+}
+
+/// Reset and free the half-open and not yet accepted children of a
+/// closing listener
+///
+/// Nobody is left to accept them. Children already accepted belong to the
+/// application and are left alone.
+unsafe fn tcp_reset_children(lpcb: *mut ffi::tcp_pcb) {
+    let queued = core::mem::take(&mut (*(lpcb as *mut TcpConnectionState)).accept_queue);
+    let children: Vec<_> = pcb_list::iter(tcp_active_pcbs)
+        .filter(|&child| (*child).listener == lpcb as *mut TcpConnectionState && (*child).conn_mgmt.state == TcpState::SynRcvd)
+        .chain(queued)
+        .map(|child| child as *mut ffi::tcp_pcb)
+        .collect();
+
+    for child in children {
+        tcp_reset_conn(&mut *(child as *mut TcpConnectionState));
+        tcp_free(child);
+    }
+}
+
+/// Abort a connection, telling the peer with a RST+ACK
+unsafe fn tcp_reset_conn(state: &mut TcpConnectionState) {
+    let reply = RstReply { seqno: state.rod.snd_nxt, ackno: state.rod.rcv_nxt, ack: true };
+    let cm = &state.conn_mgmt;
+    let _ = tcp_out::TcpTx::send_rst(&reply, tcp_out::IpPair::of(cm), cm.local_port, cm.remote_port, cm.ttl);
+    let _ = tcp_abort(state);
+}
+
+/// After a close: free a PCB that went straight to CLOSED, or send what
+/// the windows allow of its queued data and FIN
+unsafe fn tcp_close_finish(pcb: *mut ffi::tcp_pcb, state: &mut TcpConnectionState, action: CloseAction) {
+    if state.conn_mgmt.state == TcpState::Closed {
+        tcp_free(pcb);
+    } else if action != CloseAction::None {
+        // The PCB stays until the FIN is acknowledged
+        let _ = tcp_flush(state);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_abort_rust(pcb: *mut ffi::tcp_pcb) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+
+    if state.conn_mgmt.state == TcpState::Listen {
+        tcp_reset_children(pcb);
+    }
+
+    // tcp_abort clears the sequence numbers the RST carries
+    let reply = RstReply { seqno: state.rod.snd_nxt, ackno: state.rod.rcv_nxt, ack: true };
+    if let Ok(true) = tcp_abort(state) {
+        let cm = &state.conn_mgmt;
+        let _ = tcp_out::TcpTx::send_rst(&reply, tcp_out::IpPair::of(cm), cm.local_port, cm.remote_port, cm.ttl);
+    }
+    tcp_free_with_err(pcb, ERR_ABRT);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_recved_rust(pcb: *mut ffi::tcp_pcb, len: u16) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    if let Ok(true) = tcp_recved(state, len) {
+        let _ = tcp_out::TcpTx::send_ack(state);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_arg_rust(pcb: *mut ffi::tcp_pcb, arg: *mut c_void) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.callback_arg = arg;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_recv_rust(pcb: *mut ffi::tcp_pcb, recv: ffi::tcp_recv_fn) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.recv_callback = recv.map(|f| {
+        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_void, i8) -> i8>(f)
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_sent_rust(pcb: *mut ffi::tcp_pcb, sent: ffi::tcp_sent_fn) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.sent_callback = sent.map(|f| {
+        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void, u16) -> i8>(f)
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_poll_rust(
+    pcb: *mut ffi::tcp_pcb,
+    poll: ffi::tcp_poll_fn,
+    interval: u8,
+) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.poll_callback = poll.map(|f| {
+        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void) -> i8>(f)
+    });
+    let _ = state.conn_mgmt.set_poll_interval(interval);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_err_rust(pcb: *mut ffi::tcp_pcb, err: ffi::tcp_err_fn) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.err_callback = err.map(|f| {
+        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, i8)>(f)
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_accept_rust(pcb: *mut ffi::tcp_pcb, accept: ffi::tcp_accept_fn) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.accept_callback = accept.map(|f| {
+        core::mem::transmute::<_, unsafe extern "C" fn(*mut c_void, *mut c_void, i8) -> i8>(f)
+    });
+    // The callback may free the listener: nothing may touch it afterwards
+    tcp_accept_queued(pcb as *mut TcpConnectionState, ptr::null_mut());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_shutdown_rust(pcb: *mut ffi::tcp_pcb, shut_rx: i32, shut_tx: i32) -> i8 {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return ERR_ARG;
+    };
+
+    match tcp_shutdown(state, shut_rx != 0, shut_tx != 0) {
+        Ok(action) => {
+            tcp_close_finish(pcb, state, action);
+            ERR_OK
+        }
+        Err(e) => e.to_err_t(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_bind_netif_rust(pcb: *mut ffi::tcp_pcb, netif: *const ffi::netif) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    // lwIP's netif_get_index: num + 1, leaving 0 for "none"
+    let netif_idx = if netif.is_null() { tcp_proto::NETIF_NO_INDEX } else { (*netif).num + 1 };
+    state.conn_mgmt.on_bind_netif(netif_idx);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_listen_with_backlog_rust(
+    pcb: *mut ffi::tcp_pcb,
+    backlog: u8,
+) -> *mut ffi::tcp_pcb {
+    tcp_listen_with_backlog_and_err_rust(pcb, backlog, ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_listen_with_backlog_and_err_rust(
+    pcb: *mut ffi::tcp_pcb,
+    backlog: u8,
+    err: *mut i8,
+) -> *mut ffi::tcp_pcb {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        if !err.is_null() {
+            *err = ERR_ARG;
+        }
+        return ptr::null_mut();
+    };
+
+    match tcp_listen_with_backlog(state, backlog) {
+        Ok(_) => {
+            pcb_list::register_listen(pcb.cast());
+            if !err.is_null() {
+                *err = ERR_OK;
+            }
+            pcb
+        }
+        Err(e) => {
+            if !err.is_null() {
+                *err = e.to_err_t();
+            }
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_setprio_rust(pcb: *mut ffi::tcp_pcb, prio: u8) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.conn_mgmt.prio = prio;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_tcp_get_tcp_addrinfo_rust(
+    pcb: *mut ffi::tcp_pcb,
+    local: i32,
+    addr: *mut ffi::ip_addr_t,
+    port: *mut u16,
+) -> i8 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return ERR_ARG;
+    };
+
+    if local != 0 {
+        if !addr.is_null() {
+            *addr = state.conn_mgmt.local_ip;
+        }
+        if !port.is_null() {
+            *port = state.conn_mgmt.local_port;
+        }
+    } else {
+        if !addr.is_null() {
+            *addr = state.conn_mgmt.remote_ip;
+        }
+        if !port.is_null() {
+            *port = state.conn_mgmt.remote_port;
+        }
+    }
+    ERR_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_netif_ip_addr_changed_rust(
+    old_addr: *const ffi::ip_addr_t,
+    new_addr: *const ffi::ip_addr_t,
+) {
+}
+
+/// Hold the listener's backlog slot for `pcb` until tcp_backlog_accepted
+#[no_mangle]
+pub unsafe extern "C" fn tcp_backlog_delayed_rust(pcb: *mut ffi::tcp_pcb) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    if state.listener.is_null() || state.conn_mgmt.flags & tcp_proto::TF_BACKLOGPEND != 0 {
+        return;
+    }
+    if (*state.listener).conn_mgmt.on_backlog_delayed().is_ok() {
+        let _ = state.conn_mgmt.on_backlog_pending();
+    }
+}
+
+/// Give back the backlog slot tcp_backlog_delayed held for `pcb`
+#[no_mangle]
+pub unsafe extern "C" fn tcp_backlog_accepted_rust(pcb: *mut ffi::tcp_pcb) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    if state.listener.is_null() || state.conn_mgmt.flags & tcp_proto::TF_BACKLOGPEND == 0 {
+        return;
+    }
+    let _ = (*state.listener).conn_mgmt.on_backlog_release();
+    let _ = state.conn_mgmt.on_backlog_accepted();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_ext_arg_alloc_id_rust() -> u8 {
+    static mut EXT_ARG_ID: u8 = 0;
+    let id = EXT_ARG_ID;
+    EXT_ARG_ID = EXT_ARG_ID.wrapping_add(1);
+    id
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_ext_arg_set_callbacks_rust(
+    pcb: *mut ffi::tcp_pcb,
+    id: u8,
+    callbacks: *const c_void,
+) {
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_ext_arg_set_rust(
+    pcb: *mut ffi::tcp_pcb,
+    id: u8,
+    arg: *mut c_void,
+) {
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_free_ooseq(pcb: *mut ffi::tcp_pcb) {
-}
+#[no_mangle]
+pub unsafe extern "C" fn tcp_ext_arg_get_rust(
+    pcb: *const ffi::tcp_pcb,
+    id: u8,
+) -> *mut c_void {
+    ptr::null_mut()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_get_state_rust(pcb: *const ffi::tcp_pcb) -> u8 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return 0;
+    };
+    state.conn_mgmt.state as u8
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_get_stats_rust(pcb: *const ffi::tcp_pcb, stats: *mut stats::TcpStats) -> i8 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return ERR_ARG;
+    };
+    if stats.is_null() {
+        return ERR_ARG;
+    }
+    *stats = state.stats.get();
+    ERR_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_debug_snapshot_rust(
+    pcb: *const ffi::tcp_pcb,
+    info: *mut snapshot::TcpDebugInfo,
+) -> i8 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return ERR_ARG;
+    };
+    if info.is_null() {
+        return ERR_ARG;
+    }
+    *info = snapshot::TcpDebugInfo::from_state(state);
+    ERR_OK
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_get_sndbuf_rust(pcb: *const ffi::tcp_pcb) -> u16 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return 0;
+    };
+    state.rod.snd_buf
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_get_sndqueuelen_rust(pcb: *const ffi::tcp_pcb) -> u16 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return 0;
+    };
+    state.rod.snd_queuelen
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_set_flags_rust(pcb: *mut ffi::tcp_pcb, set_flags: u16) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.conn_mgmt.flags |= set_flags;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_clear_flags_rust(pcb: *mut ffi::tcp_pcb, clr_flags: u16) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.conn_mgmt.flags &= !clr_flags;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_is_flag_set_rust(pcb: *const ffi::tcp_pcb, flag: u16) -> i32 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return 0;
+    };
+    if (state.conn_mgmt.flags & flag) != 0 { 1 } else { 0 }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_rst(
+    pcb: *mut ffi::tcp_pcb,
+    seqno: u32,
+    ackno: u32,
+    local_ip: *const ffi::ip_addr_t,
+    remote_ip: *const ffi::ip_addr_t,
+    local_port: u16,
+    remote_port: u16,
+) {
+    if local_ip.is_null() || remote_ip.is_null() {
+        return;
+    }
+
+    // lwIP's tcp_rst always acknowledges `ackno`
+    let reply = RstReply { seqno, ackno, ack: true };
+    let ttl = pcb_to_state(pcb).map_or(tcp_proto::TCP_TTL, |state| state.conn_mgmt.ttl);
+    let addrs = tcp_out::IpPair::from_ip(&*local_ip, &*remote_ip);
+    let _ = tcp_out::TcpTx::send_rst(&reply, addrs, local_port, remote_port, ttl);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_next_iss(pcb: *mut ffi::tcp_pcb) -> u32 {
+    match pcb_to_state(pcb) {
+        Some(state) => tcp_iss::generate_iss(&state.conn_mgmt),
+        None => tcp_iss::generate_iss(&components::ConnectionManagementState::new()),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_fasttmr() {
+    tcp_timer_walk(ptr::addr_of!(tcp_active_pcbs), |pcb| {
+        let state = &mut *pcb;
+
+        // Offer refused data again; the application may abort meanwhile
+        if (!state.rod.rcv_ready.is_empty() || state.rod.rcv_fin) && !tcp_deliver(pcb as *mut ffi::tcp_pcb, state) {
+            return;
+        }
+
+        if let Ok(TimerAction::SendAck) = tcp_fasttmr_pcb(state) {
+            let _ = tcp_out::TcpTx::send_ack(state);
+        }
+    });
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_slowtmr() {
+    tcp_ticks = tcp_ticks.wrapping_add(1);
+
+    // The TIME_WAIT head is only read once the active pass is done
+    tcp_timer_walk(ptr::addr_of!(tcp_active_pcbs), |pcb| tcp_slowtmr_process(pcb as *mut ffi::tcp_pcb));
+    tcp_timer_walk(ptr::addr_of!(tcp_tw_pcbs), |pcb| tcp_slowtmr_process(pcb as *mut ffi::tcp_pcb));
+}
+
+/// Run one timer's work on every PCB of `*list` once
+///
+/// The work may run callbacks that close, abort or allocate PCBs, freeing
+/// the next one the walk would visit. Whenever the lists changed, the walk
+/// starts over from the head, skipping PCBs this run already handled.
+unsafe fn tcp_timer_walk(list: *const pcb_list::PcbList, mut work: impl FnMut(*mut TcpConnectionState)) {
+    TCP_TIMER_CTR = TCP_TIMER_CTR.wrapping_add(1).max(1);
+    let ctr = TCP_TIMER_CTR;
+
+    'restart: loop {
+        for pcb in pcb_list::iter(*list) {
+            if (*pcb).last_timer == ctr {
+                continue;
+            }
+            (*pcb).last_timer = ctr;
+
+            let generation = pcb_list::generation();
+            work(pcb);
+            if pcb_list::generation() != generation {
+                continue 'restart;
+            }
+        }
+        return;
+    }
+}
+
+/// Slow timer work on one connection: its timers, then the poll callback
+unsafe fn tcp_slowtmr_process(pcb: *mut ffi::tcp_pcb) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+
+    match tcp_slowtmr_pcb(state) {
+        Ok(TimerAction::Retransmit) => {
+            let _ = tcp_out::TcpTx::retransmit(state);
+        }
+        Ok(TimerAction::Probe) => {
+            let _ = tcp_out::TcpTx::send_probe(state);
+        }
+        Ok(TimerAction::KeepAlive) => {
+            let _ = tcp_out::TcpTx::send_keepalive(state);
+        }
+        Ok(TimerAction::Abort) => {
+            tcp_free_with_err(pcb, ERR_ABRT);
+            return;
+        }
+        Ok(TimerAction::Expired) => {
+            // The application let go of it before TIME_WAIT; nobody to tell
+            tcp_free(pcb);
+            return;
+        }
+        _ => {}
+    }
+
+    if !state.conn_mgmt.on_poll_tmr() {
+        return;
+    }
+    let Some(poll) = state.poll_callback else {
+        return;
+    };
+    // ERR_ABRT means the application aborted the PCB from the callback;
+    // otherwise send whatever it queued
+    if poll(state.callback_arg, pcb as *mut c_void) == ERR_OK {
+        let _ = tcp_flush(state);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_free_ooseq(pcb: *mut ffi::tcp_pcb) {
+    if let Some(state) = pcb_to_state_mut(pcb) {
+        let _ = state.rod.on_free_ooseq();
+    }
+}
+
+/// Set (`on`) or clear a SOF_* socket option bit
+#[no_mangle]
+pub unsafe extern "C" fn tcp_set_option_rust(pcb: *mut ffi::tcp_pcb, opt: u8, on: bool) -> i8 {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return ERR_ARG;
+    };
+    match state.conn_mgmt.set_so_option(opt, on) {
+        Ok(()) => ERR_OK,
+        Err(_) => ERR_VAL,
+    }
+}
+
+/// Cap the receive window advertised to the peer at `bytes`, rate-limiting
+/// the connection below its buffer size; 0 lifts the cap
+#[no_mangle]
+pub unsafe extern "C" fn tcp_set_max_rcv_wnd_rust(pcb: *mut ffi::tcp_pcb, bytes: u32) -> i8 {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return ERR_ARG;
+    };
+    match state.flow_ctrl.set_max_rcv_wnd(bytes) {
+        Ok(()) => ERR_OK,
+        Err(_) => ERR_VAL,
+    }
+}
+
+/// Set the TCP-MD5 key (RFC 2385) signing and checking every segment;
+/// a null `key` or zero `len` removes it
+#[cfg(feature = "md5")]
+#[no_mangle]
+pub unsafe extern "C" fn tcp_set_md5_key_rust(pcb: *mut ffi::tcp_pcb, key: *const u8, len: u8) -> i8 {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return ERR_ARG;
+    };
+    let key = if key.is_null() { &[][..] } else { core::slice::from_raw_parts(key, len as usize) };
+    match state.conn_mgmt.set_md5_key(key) {
+        Ok(()) => ERR_OK,
+        Err(_) => ERR_ARG,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_get_keep_idle_rust(pcb: *const ffi::tcp_pcb) -> u32 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return 7200000;
+    };
+    state.conn_mgmt.keep_idle
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_set_keep_idle_rust(pcb: *mut ffi::tcp_pcb, idle: u32) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.conn_mgmt.keep_idle = idle;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_get_keep_intvl_rust(pcb: *const ffi::tcp_pcb) -> u32 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return 75000;
+    };
+    state.conn_mgmt.keep_intvl
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_set_keep_intvl_rust(pcb: *mut ffi::tcp_pcb, intvl: u32) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.conn_mgmt.keep_intvl = intvl;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_get_keep_cnt_rust(pcb: *const ffi::tcp_pcb) -> u32 {
+    let Some(state) = pcb_to_state(pcb) else {
+        return 9;
+    };
+    state.conn_mgmt.keep_cnt
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tcp_set_keep_cnt_rust(pcb: *mut ffi::tcp_pcb, cnt: u32) {
+    let Some(state) = pcb_to_state_mut(pcb) else {
+        return;
+    };
+    state.conn_mgmt.keep_cnt = cnt;
+}
+
+#[cfg(test)]
+mod ffi_tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_new_allocates_state() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            assert!(!pcb.is_null());
+
+            let state = pcb_to_state(pcb).unwrap();
+            assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_new_ip_type_records_family() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_ip_type_rust(tcp_proto::IPADDR_TYPE_V6);
+            assert!(!pcb.is_null());
+            assert!(pcb_to_state(pcb).unwrap().conn_mgmt.is_ip6());
+            tcp_abort_rust(pcb);
+
+            let pcb = tcp_new_ip_type_rust(tcp_proto::IPADDR_TYPE_V4);
+            assert!(!pcb_to_state(pcb).unwrap().conn_mgmt.is_ip6());
+            tcp_abort_rust(pcb);
+
+            assert!(tcp_new_ip_type_rust(0xFF).is_null());
+        }
+    }
+
+    /// 2001:db8::`host`
+    #[cfg(lwip_ipv6)]
+    fn ip6_addr(host: u32) -> ffi::ip_addr_t {
+        ip_addr::from_ip6([u32::from_be(0x20010db8), 0, 0, host.to_be()])
+    }
+
+    #[cfg(lwip_ipv6)]
+    #[test]
+    fn test_ip6_bind_and_connect_record_addresses() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let local = ip6_addr(1);
+            let remote = ip6_addr(2);
+            let pcb = tcp_new_ip_type_rust(tcp_proto::IPADDR_TYPE_V6);
+            assert_eq!(tcp_bind_rust(pcb, &local, 9137), ERR_OK);
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.local_ip6, ip_addr::ip6(&local));
+
+            ffi::IP6_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert_eq!(tcp_connect_rust(pcb, &remote, 80, None), ERR_OK);
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.remote_ip6, ip_addr::ip6(&remote));
+
+            // Routed to the remote address over IPv6
+            assert_eq!(tcp_out::TcpTx::send_syn(pcb_to_state(pcb).unwrap()), Ok(()));
+            let syn = ffi::IP6_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            assert_ne!(syn[13] & tcp_proto::TCP_SYN, 0);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[cfg(lwip_ipv6)]
+    #[test]
+    fn test_ip6_child_takes_addresses_from_syn() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_ip_type_rust(tcp_proto::IPADDR_TYPE_V6);
+            assert_eq!(tcp_bind_rust(pcb, ptr::null(), 9138), ERR_OK);
+            let lpcb = tcp_listen_with_backlog_rust(pcb, 1);
+
+            let (local, remote) = (ip6_addr(1), ip6_addr(2));
+            ffi::ip_data.current_iphdr_src = remote;
+            ffi::ip_data.current_iphdr_dest = local;
+            ffi::IP6_OUTPUT.with(|out| out.borrow_mut().clear());
+
+            let child = tcp_process(lpcb, &syn_from(5000), remote, 40000);
+
+            let cm = &pcb_to_state(child).unwrap().conn_mgmt;
+            assert_eq!(cm.remote_ip6, ip_addr::ip6(&remote));
+            assert_eq!(cm.local_ip6, ip_addr::ip6(&local));
+            assert!(ffi::IP6_OUTPUT.with(|out| out.borrow_mut().pop()).is_some());
+
+            tcp_abort_rust(child);
+            tcp_abort_rust(lpcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_new_cc_selects_algorithm() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_cc_rust(tcp_proto::TCP_CC_CUBIC);
+            assert!(!pcb.is_null());
+            tcp_abort_rust(pcb);
+
+            assert!(tcp_new_cc_rust(0xFF).is_null());
+        }
+    }
+
+    #[test]
+    fn test_tcp_bind_sets_address() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            assert!(!pcb.is_null());
+
+            let addr = ip_addr::from_ip4(0x0100007f); // 127.0.0.1
+            let result = tcp_bind_rust(pcb, &addr, 8080);
+            assert_eq!(result, ERR_OK);
+
+            let state = pcb_to_state(pcb).unwrap();
+            assert_eq!(state.conn_mgmt.local_port, 8080);
+            assert_eq!(ip_addr::ip4(&state.conn_mgmt.local_ip), 0x0100007f);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_listen_transitions_state() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+
+            let addr = ip_addr::from_ip4(0);
+            tcp_bind_rust(pcb, &addr, 8080);
+
+            let listen_pcb = tcp_listen_with_backlog_rust(pcb, 5);
+            assert!(!listen_pcb.is_null());
+
+            assert_eq!(tcp_get_state_rust(listen_pcb), TcpState::Listen as u8);
+
+            tcp_abort_rust(listen_pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_connect_transitions_to_syn_sent() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+
+            let local_addr = ip_addr::from_ip4(0);
+            tcp_bind_rust(pcb, &local_addr, 0);
+
+            let remote_addr = ip_addr::from_ip4(0x0100007f);
+            let result = tcp_connect_rust(pcb, &remote_addr, 80, None);
+            assert_eq!(result, ERR_OK);
+
+            assert_eq!(tcp_get_state_rust(pcb), TcpState::SynSent as u8);
+
+            let state = pcb_to_state(pcb).unwrap();
+            assert_eq!(state.conn_mgmt.remote_port, 80);
+            assert!(state.rod.iss > 0);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_set_option_toggles_bits() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+
+            assert_eq!(tcp_set_option_rust(pcb, tcp_proto::SOF_KEEPALIVE, true), ERR_OK);
+            assert_eq!(tcp_set_option_rust(pcb, tcp_proto::SOF_REUSEADDR, true), ERR_OK);
+            let options = pcb_to_state(pcb).unwrap().conn_mgmt.so_options;
+            assert_eq!(options, tcp_proto::SOF_KEEPALIVE | tcp_proto::SOF_REUSEADDR);
+
+            assert_eq!(tcp_set_option_rust(pcb, tcp_proto::SOF_KEEPALIVE, false), ERR_OK);
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.so_options, tcp_proto::SOF_REUSEADDR);
+
+            // Unknown bits are refused and change nothing
+            assert_eq!(tcp_set_option_rust(pcb, 0x01, true), ERR_VAL);
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.so_options, tcp_proto::SOF_REUSEADDR);
+            assert_eq!(tcp_set_option_rust(ptr::null_mut(), tcp_proto::SOF_KEEPALIVE, true), ERR_ARG);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_getters_return_correct_values() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+
+            tcp_set_keep_idle_rust(pcb, 60000);
+            assert_eq!(tcp_get_keep_idle_rust(pcb), 60000);
+
+            tcp_set_keep_intvl_rust(pcb, 10000);
+            assert_eq!(tcp_get_keep_intvl_rust(pcb), 10000);
+
+            tcp_set_keep_cnt_rust(pcb, 5);
+            assert_eq!(tcp_get_keep_cnt_rust(pcb), 5);
+
+            tcp_setprio_rust(pcb, 100);
+            let state = pcb_to_state(pcb).unwrap();
+            assert_eq!(state.conn_mgmt.prio, 100);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_flags_operations() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+
+            tcp_set_flags_rust(pcb, 0x01);
+            assert_eq!(tcp_is_flag_set_rust(pcb, 0x01), 1);
+            assert_eq!(tcp_is_flag_set_rust(pcb, 0x02), 0);
+
+            tcp_set_flags_rust(pcb, 0x02);
+            assert_eq!(tcp_is_flag_set_rust(pcb, 0x01), 1);
+            assert_eq!(tcp_is_flag_set_rust(pcb, 0x02), 1);
+
+            tcp_clear_flags_rust(pcb, 0x01);
+            assert_eq!(tcp_is_flag_set_rust(pcb, 0x01), 0);
+            assert_eq!(tcp_is_flag_set_rust(pcb, 0x02), 1);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_callback_arg() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+
+            let mut data: u32 = 42;
+            let data_ptr = &mut data as *mut u32 as *mut c_void;
+
+            tcp_arg_rust(pcb, data_ptr);
+
+            let state = pcb_to_state(pcb).unwrap();
+            assert_eq!(state.callback_arg, data_ptr);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_addrinfo() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+
+            let local_addr = ip_addr::from_ip4(0x0100007f);
+            tcp_bind_rust(pcb, &local_addr, 8080);
+
+            let remote_addr = ip_addr::from_ip4(0x0200007f);
+            tcp_connect_rust(pcb, &remote_addr, 80, None);
+
+            let mut addr = ip_addr::from_ip4(0);
+            let mut port: u16 = 0;
+
+            tcp_tcp_get_tcp_addrinfo_rust(pcb, 1, &mut addr, &mut port);
+            assert_eq!(ip_addr::ip4(&addr), 0x0100007f);
+            assert_eq!(port, 8080);
+
+            tcp_tcp_get_tcp_addrinfo_rust(pcb, 0, &mut addr, &mut port);
+            assert_eq!(ip_addr::ip4(&addr), 0x0200007f);
+            assert_eq!(port, 80);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_close_deallocates() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+
+            let result = tcp_close_rust(pcb);
+            assert_eq!(result, ERR_OK);
+        }
+    }
+
+    #[test]
+    fn test_null_pcb_handling() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            assert_eq!(tcp_bind_rust(ptr::null_mut(), ptr::null(), 80), ERR_ARG);
+            assert_eq!(tcp_connect_rust(ptr::null_mut(), ptr::null(), 80, None), ERR_ARG);
+            assert_eq!(tcp_close_rust(ptr::null_mut()), ERR_ARG);
+            assert_eq!(tcp_get_state_rust(ptr::null()), 0);
+            assert_eq!(tcp_get_sndbuf_rust(ptr::null()), 0);
+        }
+    }
+
+    unsafe extern "C" fn record_err(arg: *mut c_void, err: i8) {
+        *(arg as *mut i8) = err;
+    }
+
+    #[test]
+    fn test_rst_in_fast_recovery_fires_err_callback() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut last_err: i8 = ERR_OK;
+            tcp_arg_rust(pcb, &mut last_err as *mut i8 as *mut c_void);
+            tcp_err_rust(pcb, Some(record_err));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.conn_mgmt.flags |= tcp_proto::TF_INFR;
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1101;
+            state.rod.lastack = 1001;
+            state.rod.unacked.push(components::UnackedSegment::new(1001, tcp_proto::TCP_ACK, vec![0; 100]));
+            state.rod.rtime = 6;
+            state.flow_ctrl.rcv_wnd = 4096;
+
+            let rst = TcpSegment {
+                seqno: 2001,
+                ackno: 0,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_RST),
+                wnd: 0,
+                tcphdr_len: 20,
+                payload_len: 0,
+                ..Default::default()
+            };
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert!(tcp_process(pcb, &rst, remote_ip, 0x100).is_null());
+            assert_eq!(last_err, ERR_RST);
+        }
+    }
+
+    #[test]
+    fn test_abort_fires_err_callback() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut last_err: i8 = ERR_OK;
+            tcp_arg_rust(pcb, &mut last_err as *mut i8 as *mut c_void);
+            tcp_err_rust(pcb, Some(record_err));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+
+            tcp_abort_rust(pcb);
+            assert_eq!(last_err, ERR_ABRT);
+        }
+    }
+
+    #[test]
+    fn test_output_sends_what_the_window_allows() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9126, (0x0200a8c0, 80));
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.rod.snd_lbb = 1001;
+            state.rod.rcv_nxt = 2001;
+            state.conn_mgmt.eff_mss = 300;
+            state.cong_ctrl.cwnd = 0xFFFF;
+            state.flow_ctrl.snd_wnd = 600;
+            state.flow_ctrl.snd_wnd_max = 600;
+
+            // Three segments' worth queued, a window for two
+            let data = [7u8; 900];
+            assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 900, 0), ERR_OK);
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            let lens: Vec<usize> = sent.iter().map(|seg| seg.len() - tcp_proto::TCP_HLEN).collect();
+            assert_eq!(lens, [300, 300]);
+            let state = pcb_to_state_mut(pcb).unwrap();
+            assert_eq!(state.rod.unacked.len(), 2);
+            assert!(state.rod.rtime > 0);
+
+            // The ACK of the first moves the window over the last
+            let ack = TcpSegment {
+                seqno: 2001,
+                ackno: 1301,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 600,
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+            assert_eq!(tcp_process(pcb, &ack, ip_addr::from_ip4(0x0200a8c0), 80), pcb);
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            let lens: Vec<usize> = sent.iter().map(|seg| seg.len() - tcp_proto::TCP_HLEN).collect();
+            assert_eq!(lens, [300]);
+            assert!(pcb_to_state(pcb).unwrap().rod.unsent.is_empty());
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_bind_netif_stores_its_index() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            tcp_bind_netif_rust(pcb, ffi::netif_get_by_index(2));
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.netif_idx, 2);
+
+            tcp_bind_netif_rust(pcb, ptr::null());
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.netif_idx, tcp_proto::NETIF_NO_INDEX);
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_abort_unlinks_connection_with_queued_data() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9125, (0x0200a8c0, 80));
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1000;
+            state.rod.snd_lbb = 1001;
+            assert_eq!(tcp_write_rust(pcb, [7u8; 100].as_ptr() as *const c_void, 100, 0), ERR_OK);
+            assert!(is_linked(tcp_active_pcbs, pcb));
+
+            tcp_abort_rust(pcb);
+            assert!(!is_linked(tcp_active_pcbs, pcb));
+        }
+    }
+
+    #[test]
+    fn test_abort_resets_the_peer() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9143, (0x0200a8c0, 80));
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.snd_nxt = 1001;
+            state.rod.rcv_nxt = 2001;
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+
+            tcp_abort_rust(pcb);
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0][13], tcp_proto::TCP_RST | tcp_proto::TCP_ACK);
+            assert_eq!(u32::from_be_bytes(sent[0][4..8].try_into().unwrap()), 1001);
+            assert_eq!(u32::from_be_bytes(sent[0][8..12].try_into().unwrap()), 2001);
+            assert_eq!(u16::from_be_bytes([sent[0][0], sent[0][1]]), 9143);
+        }
+    }
+
+    #[test]
+    fn test_abort_of_listener_sends_nothing() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9144, 1);
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+
+            tcp_abort_rust(lpcb);
+            assert!(ffi::IP_OUTPUT.with(|out| out.borrow().is_empty()));
+        }
+    }
+
+    #[test]
+    fn test_unanswered_persist_probes_fire_err_callback() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut last_err: i8 = ERR_OK;
+            tcp_arg_rust(pcb, &mut last_err as *mut i8 as *mut c_void);
+            tcp_err_rust(pcb, Some(record_err));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.flow_ctrl.snd_wnd = 0;
+            state.flow_ctrl.on_zero_window().unwrap();
+
+            // Still persisting after the last allowed probe
+            state.flow_ctrl.persist_probe = tcp_proto::TCP_MAXRTX;
+            tcp_slowtmr_process(pcb);
+            assert_eq!(last_err, ERR_ABRT);
+        }
+    }
+
+    #[derive(Default)]
+    struct Reported {
+        err: i8,
+        calls: usize,
+    }
+
+    unsafe extern "C" fn count_err(arg: *mut c_void, err: i8) {
+        let reported = &mut *(arg as *mut Reported);
+        reported.err = err;
+        reported.calls += 1;
+    }
+
+    unsafe extern "C" fn count_connected(arg: *mut c_void, _pcb: *mut ffi::tcp_pcb, err: i8) -> i8 {
+        count_err(arg, err);
+        ERR_OK
+    }
+
+    #[test]
+    fn test_synack_fires_connected_callback_once() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut reported = Reported { err: ERR_VAL, calls: 0 };
+            tcp_arg_rust(pcb, &mut reported as *mut Reported as *mut c_void);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert_eq!(tcp_connect_rust(pcb, &remote_ip, 9119, Some(count_connected)), ERR_OK);
+            assert_eq!(reported.calls, 0);
+
+            let iss = pcb_to_state(pcb).unwrap().rod.iss;
+            let synack = TcpSegment {
+                seqno: 5000,
+                ackno: iss.wrapping_add(1),
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_SYN | tcp_proto::TCP_ACK),
+                wnd: 8192,
+                tcphdr_len: 20,
+                payload_len: 0,
+                ..Default::default()
+            };
+            assert_eq!(tcp_process(pcb, &synack, remote_ip, 9119), pcb);
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.state, TcpState::Established);
+            assert_eq!((reported.err, reported.calls), (ERR_OK, 1));
+
+            // Later segments on the open connection do not report it again
+            let ack = TcpSegment {
+                seqno: 5001,
+                ackno: iss.wrapping_add(1),
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 8192,
+                tcphdr_len: 20,
+                payload_len: 0,
+                ..Default::default()
+            };
+            assert_eq!(tcp_process(pcb, &ack, remote_ip, 9119), pcb);
+            assert_eq!(reported.calls, 1);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_retransmission_exhaustion_fires_err_callback_once() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut reported = Reported::default();
+            tcp_arg_rust(pcb, &mut reported as *mut Reported as *mut c_void);
+            tcp_err_rust(pcb, Some(count_err));
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert_eq!(tcp_connect_rust(pcb, &remote_ip, 9119, None), ERR_OK);
+
+            // The SYN has been retransmitted as often as allowed
+            let state = pcb_to_state_mut(pcb).unwrap();
+            let iss = state.rod.iss;
+            state.rod.unacked.push(components::UnackedSegment::new(iss, tcp_proto::TCP_SYN, Vec::new()));
+            state.rod.nrtx = tcp_proto::TCP_SYNMAXRTX;
+            state.rod.rtime = 1;
+
+            tcp_slowtmr_process(pcb);
+            assert_eq!((reported.err, reported.calls), (ERR_ABRT, 1));
+        }
+    }
+
+    unsafe extern "C" fn record_accept(arg: *mut c_void, newpcb: *mut ffi::tcp_pcb, _err: i8) -> i8 {
+        (*(arg as *mut Vec<*mut ffi::tcp_pcb>)).push(newpcb);
+        ERR_OK
+    }
+
+    /// Children offered to accept_in_turn, and what it answers each, in order
+    struct AcceptReplies {
+        offered: Vec<*mut ffi::tcp_pcb>,
+        replies: Vec<i8>,
+    }
+
+    /// Accept callback answering from AcceptReplies; aborts the child
+    /// itself before answering ERR_ABRT
+    unsafe extern "C" fn accept_in_turn(arg: *mut c_void, newpcb: *mut ffi::tcp_pcb, _err: i8) -> i8 {
+        let script = &mut *(arg as *mut AcceptReplies);
+        script.offered.push(newpcb);
+        let reply = script.replies.remove(0);
+        if reply == ERR_ABRT {
+            tcp_abort_rust(newpcb);
+        }
+        reply
+    }
+
+    unsafe fn listener(port: u16, backlog: u8) -> *mut ffi::tcp_pcb {
+        let pcb = tcp_new_rust();
+        tcp_bind_rust(pcb, &ip_addr::from_ip4(0x0100a8c0), port);
+        tcp_listen_with_backlog_rust(pcb, backlog)
+    }
+
+    fn syn_from(seqno: u32) -> TcpSegment {
+        TcpSegment {
+            seqno,
+            ackno: 0,
+            flags: TcpFlags::from_tcphdr(tcp_proto::TCP_SYN),
+            wnd: 8192,
+            tcphdr_len: 20,
+            payload_len: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Complete the handshake on a child spawned by `syn_from(seqno)`
+    unsafe fn ack_synack(child: *mut ffi::tcp_pcb, seqno: u32, remote_port: u16) -> *mut ffi::tcp_pcb {
+        let iss = pcb_to_state(child).unwrap().rod.iss;
+        let ack = TcpSegment {
+            seqno: seqno.wrapping_add(1),
+            ackno: iss.wrapping_add(1),
+            flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+            wnd: 8192,
+            tcphdr_len: 20,
+            payload_len: 0,
+            ..Default::default()
+        };
+        tcp_process(child, &ack, ip_addr::from_ip4(0x0200a8c0), remote_port)
+    }
+
+    #[test]
+    fn test_handshake_on_listener_fires_accept() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(8080, 5);
+            let mut accepted: Vec<*mut ffi::tcp_pcb> = Vec::new();
+            tcp_arg_rust(lpcb, &mut accepted as *mut Vec<*mut ffi::tcp_pcb> as *mut c_void);
+            tcp_accept_rust(lpcb, Some(record_accept));
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let child = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            assert!(!child.is_null());
+            assert_ne!(child, lpcb);
+
+            // Listener keeps listening, the child carries the connection
+            assert_eq!(tcp_get_state_rust(lpcb), TcpState::Listen as u8);
+            let state = pcb_to_state(child).unwrap();
+            assert_eq!(state.conn_mgmt.state, TcpState::SynRcvd);
+            assert_eq!(state.conn_mgmt.local_port, 8080);
+            assert_eq!(state.conn_mgmt.remote_port, 40000);
+            assert_eq!(state.callback_arg, pcb_to_state(lpcb).unwrap().callback_arg);
+            assert!(accepted.is_empty());
+
+            assert_eq!(ack_synack(child, 5000, 40000), child);
+            assert_eq!(tcp_get_state_rust(child), TcpState::Established as u8);
+            assert_eq!(accepted, vec![child]);
+            assert_eq!(pcb_to_state(lpcb).unwrap().conn_mgmt.accepts_pending, 0);
+
+            tcp_abort_rust(child);
+            tcp_abort_rust(lpcb);
+        }
+    }
+
+    #[test]
+    fn test_listener_backlog_drops_excess_syns() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(8081, 1);
+            let mut accepted: Vec<*mut ffi::tcp_pcb> = Vec::new();
+            tcp_arg_rust(lpcb, &mut accepted as *mut Vec<*mut ffi::tcp_pcb> as *mut c_void);
+            tcp_accept_rust(lpcb, Some(record_accept));
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+
+            let first = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            assert!(!first.is_null());
+
+            // One half-open connection already fills the backlog
+            assert!(tcp_process(lpcb, &syn_from(7000), remote_ip, 40001).is_null());
+
+            // Once it is accepted the slot is free again
+            ack_synack(first, 5000, 40000);
+            let second = tcp_process(lpcb, &syn_from(7000), remote_ip, 40001);
+            assert!(!second.is_null());
+
+            // Freeing a half-open child also returns its slot
+            tcp_abort_rust(second);
+            assert_eq!(pcb_to_state(lpcb).unwrap().conn_mgmt.accepts_pending, 0);
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(lpcb);
+        }
+    }
+
+    unsafe fn is_linked(list: pcb_list::PcbList, pcb: *mut ffi::tcp_pcb) -> bool {
+        pcb_list::contains(list, pcb.cast())
+    }
+
+    #[test]
+    fn test_close_listener_resets_half_open_children() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9113, 5);
+            let mut accepted: Vec<*mut ffi::tcp_pcb> = Vec::new();
+            tcp_arg_rust(lpcb, &mut accepted as *mut Vec<*mut ffi::tcp_pcb> as *mut c_void);
+            tcp_accept_rust(lpcb, Some(record_accept));
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let established = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            ack_synack(established, 5000, 40000);
+            let half_open = tcp_process(lpcb, &syn_from(7000), remote_ip, 40001);
+            assert!(is_linked(tcp_listen_pcbs, lpcb));
+
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert_eq!(tcp_close_rust(lpcb), ERR_OK);
+
+            assert!(!is_linked(tcp_listen_pcbs, lpcb));
+            assert!(!is_linked(tcp_active_pcbs, half_open));
+
+            // The half-open child's peer is told with a RST+ACK
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            let rst = TcpSegment::parse(&sent[0]).unwrap();
+            assert!(rst.flags.rst && rst.flags.ack);
+            assert_eq!(u16::from_be_bytes([sent[0][2], sent[0][3]]), 40001);
+            assert_eq!(rst.ackno, 7001);
+
+            // The accepted one belongs to the application and carries on
+            let state = pcb_to_state(established).unwrap();
+            assert_eq!(state.conn_mgmt.state, TcpState::Established);
+            assert!(state.listener.is_null());
+
+            tcp_abort_rust(established);
+        }
+    }
+
+    #[test]
+    fn test_accept_callback_result_decides_child_fate() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9129, 5);
+            let mut script = AcceptReplies { offered: Vec::new(), replies: vec![ERR_OK, ERR_MEM, ERR_ABRT] };
+            tcp_arg_rust(lpcb, &mut script as *mut AcceptReplies as *mut c_void);
+            tcp_accept_rust(lpcb, Some(accept_in_turn));
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+
+            // Accepted: the child carries on with the listener's arg
+            let kept = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            assert_eq!(pcb_to_state(kept).unwrap().callback_arg, &mut script as *mut AcceptReplies as *mut c_void);
+            assert_eq!(ack_synack(kept, 5000, 40000), kept);
+            assert_eq!(script.offered, vec![kept]);
+
+            // Refused: we abort it and tell the peer
+            let refused = tcp_process(lpcb, &syn_from(7000), remote_ip, 40001);
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert!(ack_synack(refused, 7000, 40001).is_null());
+            assert_eq!(script.offered, vec![kept, refused]);
+            assert!(!is_linked(tcp_active_pcbs, refused));
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            assert!(TcpSegment::parse(&sent[0]).unwrap().flags.rst);
+
+            // Aborted by the callback itself: gone, and not freed twice
+            let aborted = tcp_process(lpcb, &syn_from(9000), remote_ip, 40002);
+            assert!(ack_synack(aborted, 9000, 40002).is_null());
+            assert_eq!(script.offered, vec![kept, refused, aborted]);
+            assert!(!is_linked(tcp_active_pcbs, aborted));
+
+            assert!(is_linked(tcp_active_pcbs, kept));
+            assert_eq!(pcb_to_state(lpcb).unwrap().conn_mgmt.accepts_pending, 0);
+
+            tcp_abort_rust(kept);
+            tcp_abort_rust(lpcb);
+        }
+    }
+
+    #[test]
+    fn test_backlog_delayed_holds_slot_until_accepted() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9130, 1);
+            let mut accepted: Vec<*mut ffi::tcp_pcb> = Vec::new();
+            tcp_arg_rust(lpcb, &mut accepted as *mut Vec<*mut ffi::tcp_pcb> as *mut c_void);
+            tcp_accept_rust(lpcb, Some(record_accept));
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let first = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            ack_synack(first, 5000, 40000);
+            assert_eq!(accepted, vec![first]);
+
+            // The application is not ready for it yet: the slot stays taken
+            tcp_backlog_delayed_rust(first);
+            tcp_backlog_delayed_rust(first);
+            assert_eq!(pcb_to_state(lpcb).unwrap().conn_mgmt.accepts_pending, 1);
+            assert!(tcp_process(lpcb, &syn_from(7000), remote_ip, 40001).is_null());
+
+            tcp_backlog_accepted_rust(first);
+            tcp_backlog_accepted_rust(first);
+            assert_eq!(pcb_to_state(lpcb).unwrap().conn_mgmt.accepts_pending, 0);
+            let second = tcp_process(lpcb, &syn_from(7000), remote_ip, 40001);
+            assert!(!second.is_null());
+
+            // A delayed child that goes away also gives its slot back
+            ack_synack(second, 7000, 40001);
+            tcp_backlog_delayed_rust(second);
+            tcp_abort_rust(second);
+            assert_eq!(pcb_to_state(lpcb).unwrap().conn_mgmt.accepts_pending, 0);
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(lpcb);
+        }
+    }
+
+    #[test]
+    fn test_accept_queue_drains_when_callback_set() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9127, 2);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let first = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            let second = tcp_process(lpcb, &syn_from(7000), remote_ip, 40001);
+            ack_synack(first, 5000, 40000);
+            ack_synack(second, 7000, 40001);
+
+            // Established with nobody to take them: queued, still holding
+            // the backlog, so a full queue refuses further SYNs
+            let state = pcb_to_state(lpcb).unwrap();
+            assert_eq!(state.accept_queue.len(), 2);
+            assert_eq!(state.conn_mgmt.accepts_pending, 2);
+            assert!(tcp_process(lpcb, &syn_from(9000), remote_ip, 40002).is_null());
+
+            let mut accepted: Vec<*mut ffi::tcp_pcb> = Vec::new();
+            tcp_arg_rust(lpcb, &mut accepted as *mut Vec<*mut ffi::tcp_pcb> as *mut c_void);
+            tcp_accept_rust(lpcb, Some(record_accept));
+            assert_eq!(accepted, vec![first, second]);
+            let state = pcb_to_state(lpcb).unwrap();
+            assert!(state.accept_queue.is_empty());
+            assert_eq!(state.conn_mgmt.accepts_pending, 0);
+            assert!(!tcp_process(lpcb, &syn_from(9000), remote_ip, 40002).is_null());
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(second);
+            tcp_abort_rust(lpcb);
+        }
+    }
+
+    /// Accept callback that closes the listener (CloseOnAccept) the first
+    /// time it runs
+    struct CloseOnAccept {
+        lpcb: *mut ffi::tcp_pcb,
+        accepted: Vec<*mut ffi::tcp_pcb>,
+    }
+
+    unsafe extern "C" fn close_listener_on_accept(arg: *mut c_void, newpcb: *mut ffi::tcp_pcb, _err: i8) -> i8 {
+        let script = &mut *(arg as *mut CloseOnAccept);
+        script.accepted.push(newpcb);
+        if !script.lpcb.is_null() {
+            assert_eq!(tcp_close_rust(script.lpcb), ERR_OK);
+            script.lpcb = ptr::null_mut();
+        }
+        ERR_OK
+    }
+
+    #[test]
+    fn test_accept_callback_may_close_listener() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9134, 5);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let first = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            let second = tcp_process(lpcb, &syn_from(7000), remote_ip, 40001);
+            ack_synack(first, 5000, 40000);
+            ack_synack(second, 7000, 40001);
+
+            // The first child is accepted, the listener closed under the
+            // loop handing out the queue: the second goes with it
+            let mut script = CloseOnAccept { lpcb, accepted: Vec::new() };
+            tcp_arg_rust(lpcb, &mut script as *mut CloseOnAccept as *mut c_void);
+            tcp_accept_rust(lpcb, Some(close_listener_on_accept));
+            assert_eq!(script.accepted, vec![first]);
+            assert!(!is_linked(tcp_listen_pcbs, lpcb));
+            assert!(is_linked(tcp_active_pcbs, first));
+            assert!(!is_linked(tcp_active_pcbs, second));
+
+            // A half-open child goes with the listener too
+            let lpcb = listener(9135, 5);
+            let third = tcp_process(lpcb, &syn_from(5000), remote_ip, 40002);
+            let fourth = tcp_process(lpcb, &syn_from(7000), remote_ip, 40003);
+            ack_synack(third, 5000, 40002);
+            script = CloseOnAccept { lpcb, accepted: Vec::new() };
+            tcp_arg_rust(lpcb, &mut script as *mut CloseOnAccept as *mut c_void);
+            tcp_accept_rust(lpcb, Some(close_listener_on_accept));
+            assert_eq!(script.accepted, vec![third]);
+            assert!(!is_linked(tcp_active_pcbs, fourth));
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(third);
+        }
+    }
+
+    #[test]
+    fn test_close_listener_resets_queued_children() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9128, 5);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let gone = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            let queued = tcp_process(lpcb, &syn_from(7000), remote_ip, 40001);
+            ack_synack(gone, 5000, 40000);
+            ack_synack(queued, 7000, 40001);
+
+            // A queued child reset by its peer leaves the queue
+            let rst = TcpSegment {
+                seqno: 5001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_RST),
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+            assert!(tcp_process(gone, &rst, remote_ip, 40000).is_null());
+            let state = pcb_to_state(lpcb).unwrap();
+            assert_eq!(state.accept_queue.iter().copied().collect::<Vec<_>>(), [queued.cast()]);
+
+            // The rest are never accepted once the listener closes
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert_eq!(tcp_close_rust(lpcb), ERR_OK);
+            assert!(!is_linked(tcp_active_pcbs, queued));
+
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            let reset = TcpSegment::parse(&sent[0]).unwrap();
+            assert!(reset.flags.rst && reset.flags.ack);
+            assert_eq!(u16::from_be_bytes([sent[0][2], sent[0][3]]), 40001);
+        }
+    }
+
+    #[test]
+    fn test_rst_frees_half_open_child() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(8082, 1);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+
+            let child = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            assert!(!child.is_null());
+
+            let rst = TcpSegment {
+                seqno: 5001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_RST),
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+            assert!(tcp_process(child, &rst, remote_ip, 40000).is_null());
+
+            // The listener is untouched and has its slot back
+            let listener = pcb_to_state(lpcb).unwrap();
+            assert_eq!(listener.conn_mgmt.state, TcpState::Listen);
+            assert_eq!(listener.conn_mgmt.accepts_pending, 0);
+
+            tcp_abort_rust(lpcb);
+        }
+    }
+
+    /// Total bytes received and EOF count, filled in by record_recv
+    #[derive(Default)]
+    struct Received {
+        bytes: usize,
+        calls: usize,
+        eof: usize,
+        pushed: Vec<bool>, // PBUF_FLAG_PUSH of each pbuf, in order
+    }
+
+    unsafe extern "C" fn record_recv(arg: *mut c_void, _pcb: *mut ffi::tcp_pcb, p: *mut ffi::pbuf, _err: i8) -> i8 {
+        let rx = &mut *(arg as *mut Received);
+        if p.is_null() {
+            rx.eof += 1;
+        } else {
+            rx.bytes += (*p).tot_len as usize;
+            rx.calls += 1;
+            rx.pushed.push((*p).flags & tcp_proto::PBUF_FLAG_PUSH != 0);
+            ffi::pbuf_free(p);
+        }
+        ERR_OK
+    }
+
+    #[test]
+    fn test_small_recveds_hold_back_window_update() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut rx = Received::default();
+            tcp_arg_rust(pcb, &mut rx as *mut Received as *mut c_void);
+            tcp_recv_rust(pcb, Some(record_recv));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.conn_mgmt.remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.flow_ctrl.rcv_wnd = tcp_proto::TCP_WND as u32;
+            state.flow_ctrl.rcv_ann_wnd = tcp_proto::TCP_WND as u32;
+            state.flow_ctrl.rcv_ann_right_edge = 2001 + tcp_proto::TCP_WND as u32;
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let data = TcpSegment {
+                seqno: 2001,
+                ackno: 1001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 4096,
+                tcphdr_len: 20,
+                payload_len: 1000,
+                payload: vec![7; 1000],
+                ..Default::default()
+            };
+            tcp_process(pcb, &data, remote_ip, 0x100);
+            assert_eq!(rx.bytes, 1000);
+
+            // The application reads 100 bytes at a time: no update until
+            // min(MSS, TCP_WND / 2) of window has been freed
+            let threshold = (tcp_proto::TCP_MSS as u32).min(tcp_proto::TCP_WND as u32 / 2);
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            let mut freed = 0;
+            while ffi::IP_OUTPUT.with(|out| out.borrow().is_empty()) {
+                assert!(freed < threshold);
+                tcp_recved_rust(pcb, 100);
+                freed += 100;
+            }
+            assert!(freed >= threshold);
+
+            let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            let update = TcpSegment::parse(&sent).unwrap();
+            assert_eq!(update.wnd as u32, tcp_proto::TCP_WND as u32 - 1000 + freed);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_recv_callback_gets_data_and_eof() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut rx = Received::default();
+            tcp_arg_rust(pcb, &mut rx as *mut Received as *mut c_void);
+            tcp_recv_rust(pcb, Some(record_recv));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.flow_ctrl.rcv_wnd = 4096;
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let data = TcpSegment {
+                seqno: 2001,
+                ackno: 1001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 4096,
+                tcphdr_len: 20,
+                payload_len: 100,
+                payload: vec![7; 100],
+                ..Default::default()
+            };
+            assert_eq!(tcp_process(pcb, &data, remote_ip, 0x100), pcb);
+            assert_eq!((rx.bytes, rx.calls, rx.eof), (100, 1, 0));
+
+            let fin = TcpSegment {
+                seqno: 2101,
+                ackno: 1001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_FIN),
+                wnd: 4096,
+                tcphdr_len: 20,
+                payload_len: 0,
+                ..Default::default()
+            };
+            assert_eq!(tcp_process(pcb, &fin, remote_ip, 0x100), pcb);
+            assert_eq!((rx.bytes, rx.calls, rx.eof), (100, 1, 1));
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    /// Refuses the first `refuse` pbufs, then records like record_recv
+    #[derive(Default)]
+    struct Refusing {
+        refuse: usize,
+        rx: Received,
+    }
+
+    unsafe extern "C" fn refuse_recv(arg: *mut c_void, pcb: *mut ffi::tcp_pcb, p: *mut ffi::pbuf, err: i8) -> i8 {
+        let refusing = &mut *(arg as *mut Refusing);
+        if !p.is_null() && refusing.refuse > 0 {
+            refusing.refuse -= 1;
+            return ERR_MEM;
+        }
+        record_recv(&mut refusing.rx as *mut Received as *mut c_void, pcb, p, err)
+    }
+
+    #[test]
+    fn test_refused_data_is_offered_again() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut refusing = Refusing { refuse: 1, ..Default::default() };
+            tcp_arg_rust(pcb, &mut refusing as *mut Refusing as *mut c_void);
+            tcp_recv_rust(pcb, Some(refuse_recv));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.flow_ctrl.rcv_wnd = 4096;
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let data = TcpSegment {
+                seqno: 2001,
+                ackno: 1001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 4096,
+                tcphdr_len: 20,
+                payload_len: 100,
+                payload: vec![7; 100],
+                ..Default::default()
+            };
+            assert_eq!(tcp_process(pcb, &data, remote_ip, 0x100), pcb);
+            assert_eq!((refusing.rx.bytes, refusing.rx.calls), (0, 0));
+            assert_eq!(pcb_to_state_mut(pcb).unwrap().rod.rcv_ready.len(), 100);
+
+            // The refused bytes come ahead of the FIN that follows them
+            let fin = TcpSegment {
+                seqno: 2101,
+                ackno: 1001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_FIN),
+                wnd: 4096,
+                tcphdr_len: 20,
+                payload_len: 0,
+                ..Default::default()
+            };
+            assert_eq!(tcp_process(pcb, &fin, remote_ip, 0x100), pcb);
+            assert_eq!((refusing.rx.bytes, refusing.rx.calls, refusing.rx.eof), (100, 1, 1));
+            assert!(pcb_to_state_mut(pcb).unwrap().rod.rcv_ready.is_empty());
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_push_flag_marks_the_pushed_delivery() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut rx = Received::default();
+            tcp_arg_rust(pcb, &mut rx as *mut Received as *mut c_void);
+            tcp_recv_rust(pcb, Some(record_recv));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.flow_ctrl.rcv_wnd = 4096;
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            for (seqno, flags) in [
+                (2001, tcp_proto::TCP_ACK),
+                (2101, tcp_proto::TCP_ACK | tcp_proto::TCP_PSH),
+            ] {
+                let seg = TcpSegment {
+                    seqno,
+                    ackno: 1001,
+                    flags: TcpFlags::from_tcphdr(flags),
+                    wnd: 4096,
+                    tcphdr_len: 20,
+                    payload_len: 100,
+                    payload: vec![7; 100],
+                    ..Default::default()
+                };
+                assert_eq!(tcp_process(pcb, &seg, remote_ip, 0x100), pcb);
+            }
+            assert_eq!(rx.pushed, [false, true]);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_shut_rx_stops_recv_callback() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut rx = Received::default();
+            tcp_arg_rust(pcb, &mut rx as *mut Received as *mut c_void);
+            tcp_recv_rust(pcb, Some(record_recv));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.flow_ctrl.rcv_wnd = 4096;
+            assert_eq!(tcp_shutdown_rust(pcb, 1, 0), ERR_OK);
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let data = TcpSegment {
+                seqno: 2001,
+                ackno: 1001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_PSH),
+                wnd: 4096,
+                tcphdr_len: 20,
+                payload_len: 100,
+                payload: vec![7; 100],
+                ..Default::default()
+            };
+            assert_eq!(tcp_process(pcb, &data, remote_ip, 0x100), pcb);
+            assert_eq!((rx.bytes, rx.calls), (0, 0));
+
+            // Acknowledged all the same, and sending is unaffected
+            let state = pcb_to_state_mut(pcb).unwrap();
+            assert_eq!(state.rod.rcv_nxt, 2101);
+            assert_eq!(tcp_write_rust(pcb, b"x".as_ptr() as *const c_void, 1, 0), ERR_OK);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_shut_tx_unconnected_is_err_conn() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            assert_eq!(tcp_shutdown_rust(pcb, 0, 1), ERR_CONN);
+            assert_eq!(pcb_to_state_mut(pcb).unwrap().conn_mgmt.state, TcpState::Closed);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_recv_without_callback_drops_data() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.flow_ctrl.rcv_wnd = 4096;
+
+            let data = TcpSegment {
+                seqno: 2001,
+                ackno: 1001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 4096,
+                tcphdr_len: 20,
+                payload_len: 10,
+                payload: vec![7; 10],
+                ..Default::default()
+            };
+            tcp_process(pcb, &data, ip_addr::from_ip4(0x0200a8c0), 0x100);
+
+            let state = pcb_to_state(pcb).unwrap();
+            assert_eq!(state.rod.rcv_nxt, 2011);
+            assert!(state.rod.rcv_ready.is_empty());
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    unsafe extern "C" fn record_sent(arg: *mut c_void, _pcb: *mut ffi::tcp_pcb, len: u16) -> i8 {
+        *(arg as *mut u32) += len as u32;
+        ERR_OK
+    }
+
+    #[test]
+    fn test_sent_callback_reports_acked_bytes() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let mut acked: u32 = 0;
+            tcp_arg_rust(pcb, &mut acked as *mut u32 as *mut c_void);
+            tcp_sent_rust(pcb, Some(record_sent));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.conn_mgmt.remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.rod.snd_lbb = 1001;
+            state.flow_ctrl.snd_wnd = 4096;
+            state.flow_ctrl.rcv_wnd = 4096;
+            state.cong_ctrl.cwnd = 4096;
+            tcp_set_flags_rust(pcb, tcp_proto::TF_NODELAY);
+
+            let data = [0u8; 1000];
+            assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 1000, 0), ERR_OK);
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+            assert_eq!(pcb_to_state(pcb).unwrap().rod.unacked.len(), 2);
+
+            // ACK covers the first 536-byte segment only
+            let ack = TcpSegment {
+                seqno: 2001,
+                ackno: 1537,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 4096,
+                tcphdr_len: 20,
+                payload_len: 0,
+                ..Default::default()
+            };
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert_eq!(tcp_process(pcb, &ack, remote_ip, 0x100), pcb);
+            assert_eq!(acked, 536);
+
+            // A duplicate reports nothing new
+            tcp_process(pcb, &ack, remote_ip, 0x100);
+            assert_eq!(acked, 536);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    /// Sent callback that counts its calls, then aborts the PCB
+    unsafe extern "C" fn abort_on_sent(arg: *mut c_void, pcb: *mut ffi::tcp_pcb, _len: u16) -> i8 {
+        *(arg as *mut u32) += 1;
+        tcp_abort_rust(pcb);
+        ERR_ABRT
+    }
+
+    #[test]
+    fn test_sent_callback_may_abort() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9133, (0x0200a8c0, 80));
+            let mut calls: u32 = 0;
+            tcp_arg_rust(pcb, &mut calls as *mut u32 as *mut c_void);
+            tcp_sent_rust(pcb, Some(abort_on_sent));
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.rod.snd_lbb = 1001;
+            state.flow_ctrl.snd_wnd = 4096;
+            state.cong_ctrl.cwnd = 4096;
+            let data = [0u8; 100];
+            assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 100, 0), ERR_OK);
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+
+            // The PCB is gone once the callback returns: nothing touches it
+            let ack = TcpSegment {
+                seqno: 2001,
+                ackno: 1101,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 4096,
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+            assert!(tcp_process(pcb, &ack, ip_addr::from_ip4(0x0200a8c0), 80).is_null());
+            assert_eq!(calls, 1);
+            assert!(!is_linked(tcp_active_pcbs, pcb));
+        }
+    }
+
+    unsafe extern "C" fn count_poll(arg: *mut c_void, _pcb: *mut ffi::tcp_pcb) -> i8 {
+        *(arg as *mut u32) += 1;
+        ERR_OK
+    }
+
+    /// Poll callback that counts its calls and aborts the PCBs listed
+    struct PollVictims {
+        polls: u32,
+        victims: Vec<*mut ffi::tcp_pcb>,
+    }
+
+    unsafe extern "C" fn poll_and_abort(arg: *mut c_void, _pcb: *mut ffi::tcp_pcb) -> i8 {
+        let script = &mut *(arg as *mut PollVictims);
+        script.polls += 1;
+        for victim in script.victims.drain(..) {
+            tcp_abort_rust(victim);
+        }
+        ERR_OK
+    }
+
+    #[test]
+    fn test_slowtmr_survives_callbacks_freeing_pcbs() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            // Only this test's PCBs on the lists while the timer walks them
+            let saved = (tcp_active_pcbs, tcp_tw_pcbs);
+            tcp_active_pcbs = ptr::null_mut();
+            tcp_tw_pcbs = ptr::null_mut();
+
+            let time_wait = connected(9136, (0x0200a8c0, 40000));
+            enter_time_wait(time_wait);
+            let oldest = connected(9136, (0x0200a8c0, 40001));
+            let next = connected(9136, (0x0200a8c0, 40002));
+            let first = connected(9136, (0x0200a8c0, 40003));
+
+            let (mut oldest_polls, mut next_polls) = (0u32, 0u32);
+            tcp_arg_rust(oldest, &mut oldest_polls as *mut u32 as *mut c_void);
+            tcp_poll_rust(oldest, Some(count_poll), 1);
+            tcp_arg_rust(next, &mut next_polls as *mut u32 as *mut c_void);
+            tcp_poll_rust(next, Some(count_poll), 1);
+
+            // The first PCB walked frees the one after it and the TIME_WAIT one
+            let mut script = PollVictims { polls: 0, victims: vec![next, time_wait] };
+            tcp_arg_rust(first, &mut script as *mut PollVictims as *mut c_void);
+            tcp_poll_rust(first, Some(poll_and_abort), 1);
+
+            tcp_slowtmr();
+            assert_eq!((script.polls, next_polls, oldest_polls), (1, 0, 1));
+            assert!(tcp_tw_pcbs.is_null());
+
+            // Once per tick each, after the walk started over
+            tcp_slowtmr();
+            assert_eq!((script.polls, next_polls, oldest_polls), (2, 0, 2));
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(oldest);
+            (tcp_active_pcbs, tcp_tw_pcbs) = saved;
+        }
+    }
+
+    #[test]
+    fn test_poll_callback_fires_every_interval() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            pcb_to_state_mut(pcb).unwrap().conn_mgmt.state = TcpState::Established;
+            let mut polls = 0u32;
+            tcp_arg_rust(pcb, &mut polls as *mut u32 as *mut c_void);
+            tcp_poll_rust(pcb, Some(count_poll), 3);
+
+            let mut fired = Vec::new();
+            for _ in 0..7 {
+                tcp_slowtmr_process(pcb);
+                fired.push(polls);
+            }
+            assert_eq!(fired, vec![0, 0, 1, 1, 1, 2, 2]);
+
+            // A zero interval turns polling off
+            tcp_poll_rust(pcb, Some(count_poll), 0);
+            for _ in 0..10 {
+                tcp_slowtmr_process(pcb);
+            }
+            assert_eq!(polls, 2);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_poll_interval_two_fires_on_second_tick() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9118, (0x0200a8c0, 40000));
+            let mut polls = 0u32;
+            tcp_arg_rust(pcb, &mut polls as *mut u32 as *mut c_void);
+            tcp_poll_rust(pcb, Some(count_poll), 2);
+
+            tcp_slowtmr_process(pcb);
+            assert_eq!(polls, 0);
+            tcp_slowtmr_process(pcb);
+            assert_eq!(polls, 1);
+
+            // TIME_WAIT is not polled
+            enter_time_wait(pcb);
+            for _ in 0..4 {
+                tcp_slowtmr_process(pcb);
+            }
+            assert_eq!(polls, 1);
+
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_write_queues_data() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.rod.snd_lbb = 1001;
+
+            let chunks: [&[u8]; 3] = [b"hello", b" ", b"world"];
+            for (i, chunk) in chunks.iter().enumerate() {
+                let more = if i < 2 { tcp_proto::TCP_WRITE_FLAG_MORE } else { 0 };
+                let err = tcp_write_rust(pcb, chunk.as_ptr() as *const c_void, chunk.len() as u16, more);
+                assert_eq!(err, ERR_OK);
+            }
+
+            // Small writes with more to follow share one segment
+            let state = pcb_to_state(pcb).unwrap();
+            assert_eq!(tcp_get_sndqueuelen_rust(pcb), 1);
+            assert_eq!(tcp_get_sndbuf_rust(pcb), tcp_proto::TCP_SND_BUF - 11);
+            assert_eq!(state.rod.snd_lbb, 1012);
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_get_keep_idle_rust(pcb: *const ffi::tcp_pcb) -> u32 {
-    let Some(state) = pcb_to_state(pcb) else {
-        return 7200000;
-    };
-    state.conn_mgmt.keep_idle
-}
+            let seqnos: Vec<u32> = state.rod.unsent.iter().map(|seg| seg.seqno).collect();
+            assert_eq!(seqnos, vec![1001]);
+            assert_eq!(state.rod.unsent.front().unwrap().data, b"hello world".to_vec());
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_set_keep_idle_rust(pcb: *mut ffi::tcp_pcb, idle: u32) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
-    };
-    state.conn_mgmt.keep_idle = idle;
-}
+            tcp_abort_rust(pcb);
+        }
+    }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_get_keep_intvl_rust(pcb: *const ffi::tcp_pcb) -> u32 {
-    let Some(state) = pcb_to_state(pcb) else {
-        return 75000;
-    };
-    state.conn_mgmt.keep_intvl
-}
+    #[test]
+    fn test_small_writes_go_out_as_one_segment() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9132, (0x0200a8c0, 40000));
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.rod.snd_lbb = 1001;
+            state.rod.rcv_nxt = 2001;
+            state.cong_ctrl.cwnd = 0xFFFF;
+            state.flow_ctrl.snd_wnd = 8192;
+            state.flow_ctrl.snd_wnd_max = 8192;
+
+            for i in 0..10u8 {
+                let data = [i; 10];
+                let more = if i < 9 { tcp_proto::TCP_WRITE_FLAG_MORE } else { 0 };
+                assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 10, more), ERR_OK);
+            }
+            assert_eq!(tcp_get_sndqueuelen_rust(pcb), 1);
+
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            let seg = TcpSegment::parse(&sent[0]).unwrap();
+            assert_eq!(seg.seqno, 1001);
+            assert!(seg.flags.psh);
+            assert_eq!(sent[0].len() - tcp_proto::TCP_HLEN, 100);
+            assert_eq!(&sent[0][sent[0].len() - 10..], [9; 10]);
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_set_keep_intvl_rust(pcb: *mut ffi::tcp_pcb, intvl: u32) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
-    };
-    state.conn_mgmt.keep_intvl = intvl;
-}
+            tcp_abort_rust(pcb);
+        }
+    }
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_get_keep_cnt_rust(pcb: *const ffi::tcp_pcb) -> u32 {
-    let Some(state) = pcb_to_state(pcb) else {
-        return 9;
-    };
-    state.conn_mgmt.keep_cnt
-}
+    #[test]
+    fn test_pushed_writes_go_out_as_own_segments() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9141, (0x0200a8c0, 40000));
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.rod.snd_lbb = 1001;
+            state.rod.rcv_nxt = 2001;
+            state.cong_ctrl.cwnd = 0xFFFF;
+            state.flow_ctrl.snd_wnd = 8192;
+            state.flow_ctrl.snd_wnd_max = 8192;
+            tcp_set_flags_rust(pcb, tcp_proto::TF_NODELAY);
+
+            for i in 0..3u8 {
+                let data = [i; 10];
+                assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 10, 0), ERR_OK);
+            }
+            assert_eq!(tcp_get_sndqueuelen_rust(pcb), 3);
 
-#[no_mangle]
-pub unsafe extern "C" fn tcp_set_keep_cnt_rust(pcb: *mut ffi::tcp_pcb, cnt: u32) {
-    let Some(state) = pcb_to_state_mut(pcb) else {
-        return;
-    };
-    state.conn_mgmt.keep_cnt = cnt;
-}
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            let segs: Vec<TcpSegment> = sent.iter().map(|bytes| TcpSegment::parse(bytes).unwrap()).collect();
+            let seqnos: Vec<u32> = segs.iter().map(|seg| seg.seqno).collect();
+            assert_eq!(seqnos, vec![1001, 1011, 1021]);
+            assert!(segs.iter().all(|seg| seg.flags.psh && seg.payload_len == 10));
 
-#[cfg(test)]
-mod ffi_tests {
-    use super::*;
+            tcp_abort_rust(pcb);
+        }
+    }
 
     #[test]
-    fn test_tcp_new_allocates_state() {
+    fn test_sndbuf_and_queuelen_follow_writes_and_acks() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9111, 5);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let child = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            ack_synack(child, 5000, 40000);
+            let state = pcb_to_state_mut(child).unwrap();
+            let iss = state.rod.iss;
+            state.cong_ctrl.cwnd = 4096;
+            tcp_set_flags_rust(child, tcp_proto::TF_NODELAY);
+
+            let ack = |acked: u32| TcpSegment {
+                seqno: 5001,
+                ackno: iss.wrapping_add(1 + acked),
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 8192,
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+
+            let data = [0u8; 600];
+            let more = tcp_proto::TCP_WRITE_FLAG_MORE;
+            assert_eq!(tcp_write_rust(child, data.as_ptr() as *const c_void, 600, more), ERR_OK);
+            assert_eq!(tcp_write_rust(child, data.as_ptr() as *const c_void, 100, 0), ERR_OK);
+            assert_eq!(tcp_get_sndbuf_rust(child), tcp_proto::TCP_SND_BUF - 700);
+            // One MSS, then the rest of the first write topped up by the second
+            assert_eq!(tcp_get_sndqueuelen_rust(child), 2);
+
+            assert_eq!(tcp_output_rust(child), ERR_OK);
+            assert_eq!(pcb_to_state(child).unwrap().rod.unacked.len(), 2);
+            assert_eq!(tcp_get_sndqueuelen_rust(child), 2);
+
+            // Acked segments refund both
+            tcp_process(child, &ack(536), remote_ip, 40000);
+            assert_eq!(tcp_get_sndbuf_rust(child), tcp_proto::TCP_SND_BUF - 164);
+            assert_eq!(tcp_get_sndqueuelen_rust(child), 1);
+            tcp_process(child, &ack(600), remote_ip, 40000);
+            assert_eq!(tcp_get_sndbuf_rust(child), tcp_proto::TCP_SND_BUF - 100);
+            assert_eq!(tcp_get_sndqueuelen_rust(child), 1);
+            // A partial ACK refunds its bytes but frees no segment
+            tcp_process(child, &ack(650), remote_ip, 40000);
+            assert_eq!(tcp_get_sndbuf_rust(child), tcp_proto::TCP_SND_BUF - 50);
+            assert_eq!(tcp_get_sndqueuelen_rust(child), 1);
+            tcp_process(child, &ack(700), remote_ip, 40000);
+            assert_eq!(tcp_get_sndbuf_rust(child), tcp_proto::TCP_SND_BUF);
+            assert_eq!(tcp_get_sndqueuelen_rust(child), 0);
+
+            tcp_abort_rust(child);
+            tcp_abort_rust(lpcb);
+        }
+    }
+
+    #[test]
+    fn test_tcp_write_rejects_oversized_and_closed() {
+        let _lists = pcb_list::test_lock();
         unsafe {
             let pcb = tcp_new_rust();
-            assert!(!pcb.is_null());
+            let data = vec![0u8; tcp_proto::TCP_SND_BUF as usize + 1];
 
-            let state = pcb_to_state(pcb).unwrap();
-            assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+            // Not connected yet
+            assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 10, 0), ERR_CONN);
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+
+            // More than the send buffer holds: nothing is queued
+            let err = tcp_write_rust(pcb, data.as_ptr() as *const c_void, data.len() as u16, 0);
+            assert_eq!(err, ERR_MEM);
+            assert_eq!(tcp_get_sndqueuelen_rust(pcb), 0);
+            assert_eq!(tcp_get_sndbuf_rust(pcb), tcp_proto::TCP_SND_BUF);
+
+            // Nor anything after shutting down the send direction
+            assert_eq!(tcp_shutdown_rust(pcb, 0, 1), ERR_OK);
+            assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 10, 0), ERR_CLSD);
 
             tcp_abort_rust(pcb);
         }
     }
 
+    /// Active PCB bound to 192.168.0.1:`local_port`, connected to `remote`
+    unsafe fn connected(local_port: u16, remote: (u32, u16)) -> *mut ffi::tcp_pcb {
+        let pcb = tcp_new_rust();
+        let state = pcb_to_state_mut(pcb).unwrap();
+        state.conn_mgmt.state = TcpState::Established;
+        state.conn_mgmt.local_ip = ip_addr::from_ip4(0x0100a8c0);
+        state.conn_mgmt.local_port = local_port;
+        state.conn_mgmt.remote_ip = ip_addr::from_ip4(remote.0);
+        state.conn_mgmt.remote_port = remote.1;
+        pcb_list::register_active(pcb.cast());
+        pcb
+    }
+
+    /// Park a `connected` PCB in TIME_WAIT, as tcp_process would
+    unsafe fn enter_time_wait(pcb: *mut ffi::tcp_pcb) {
+        let state = pcb_to_state_mut(pcb).unwrap();
+        state.conn_mgmt.state = TcpState::TimeWait;
+        state.conn_mgmt.time_wait_ticks = tcp_proto::TCP_TIME_WAIT_TICKS;
+        pcb_list::move_to_timewait(pcb.cast());
+    }
+
     #[test]
-    fn test_tcp_bind_sets_address() {
+    fn test_connect_reuses_tuple_in_time_wait() {
+        let _lists = pcb_list::test_lock();
         unsafe {
-            let pcb = tcp_new_rust();
-            assert!(!pcb.is_null());
+            let local_ip = ip_addr::from_ip4(0x0100a8c0);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let old = connected(9131, (ip_addr::ip4(&remote_ip), 40000));
+            // The old incarnation sent enough to run ahead of the ISS clock
+            let old_snd_nxt = tcp_iss::generate_iss(&pcb_to_state(old).unwrap().conn_mgmt).wrapping_add(0x1000_0000);
+            pcb_to_state_mut(old).unwrap().rod.snd_nxt = old_snd_nxt;
+            enter_time_wait(old);
 
-            let addr = ffi::ip_addr_t { addr: 0x0100007f }; // 127.0.0.1
-            let result = tcp_bind_rust(pcb, &addr, 8080);
-            assert_eq!(result, ERR_OK);
+            let pcb = tcp_new_rust();
+            tcp_set_option_rust(pcb, tcp_proto::SOF_REUSEADDR, true);
+            assert_eq!(tcp_bind_rust(pcb, &local_ip, 9131), ERR_OK);
+            assert_eq!(tcp_connect_rust(pcb, &remote_ip, 40000, None), ERR_OK);
 
+            // The TIME_WAIT PCB made way, the new ISS follows its sequence space
+            assert!(!is_linked(tcp_tw_pcbs, old));
+            assert!(is_linked(tcp_active_pcbs, pcb));
             let state = pcb_to_state(pcb).unwrap();
-            assert_eq!(state.conn_mgmt.local_port, 8080);
-            assert_eq!(state.conn_mgmt.local_ip.addr, 0x0100007f);
+            assert_eq!(state.conn_mgmt.state, TcpState::SynSent);
+            assert_eq!(state.rod.iss, old_snd_nxt.wrapping_add(tcp_proto::TCP_ISS_INCR));
 
+            // A live connection on the tuple is never taken over
+            let clash = tcp_new_rust();
+            tcp_set_option_rust(clash, tcp_proto::SOF_REUSEADDR, true);
+            assert_eq!(tcp_bind_rust(clash, &local_ip, 9131), ERR_OK);
+            assert_eq!(tcp_connect_rust(clash, &remote_ip, 40000, None), ERR_USE);
+            assert_eq!(pcb_to_state(clash).unwrap().conn_mgmt.state, TcpState::Closed);
+
+            tcp_abort_rust(clash);
             tcp_abort_rust(pcb);
         }
     }
 
     #[test]
-    fn test_tcp_listen_transitions_state() {
+    fn test_demux_matches_full_tuple() {
+        let _lists = pcb_list::test_lock();
         unsafe {
-            let pcb = tcp_new_rust();
+            let local_ip = ip_addr::from_ip4(0x0100a8c0);
+            let first = connected(9100, (0x0200a8c0, 40000));
+            let second = connected(9100, (0x0300a8c0, 40000));
+
+            let demux = |remote: u32, port: u16| tcp_demux(local_ip, 9100, ip_addr::from_ip4(remote), port, false);
+            assert_eq!(demux(0x0200a8c0, 40000), Demux::Pcb(first));
+            assert_eq!(demux(0x0300a8c0, 40000), Demux::Pcb(second));
+            assert_eq!(demux(0x0200a8c0, 40001), Demux::SendRst);
+            assert_eq!(demux(0x0400a8c0, 40000), Demux::SendRst);
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(second);
+        }
+    }
 
-            let addr = ffi::ip_addr_t { addr: 0 };
-            tcp_bind_rust(pcb, &addr, 8080);
+    #[test]
+    fn test_bind_port_zero_picks_distinct_ephemeral_ports() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let first = tcp_new_rust();
+            let second = tcp_new_rust();
+            assert_eq!(tcp_bind_rust(first, ptr::null(), 0), ERR_OK);
+            assert_eq!(tcp_bind_rust(second, ptr::null(), 0), ERR_OK);
+
+            let ports = [first, second].map(|pcb| pcb_to_state(pcb).unwrap().conn_mgmt.local_port);
+            assert_ne!(ports[0], ports[1]);
+            for port in ports {
+                assert!((tcp_proto::TCP_LOCAL_PORT_RANGE_START..=tcp_proto::TCP_LOCAL_PORT_RANGE_END).contains(&port));
+                assert!(tcp_port_in_use(port));
+            }
 
-            let listen_pcb = tcp_listen_with_backlog_rust(pcb, 5);
-            assert!(!listen_pcb.is_null());
+            tcp_abort_rust(first);
+            tcp_abort_rust(second);
+        }
+    }
 
-            assert_eq!(tcp_get_state_rust(listen_pcb), TcpState::Listen as u8);
+    #[test]
+    fn test_errors_reach_c_callers_as_err_t() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let local_ip = ip_addr::from_ip4(0x0100a8c0);
+            let first = tcp_new_rust();
+            assert_eq!(tcp_bind_rust(first, &local_ip, 9121), ERR_OK);
+
+            // The same port, on the same or any address
+            let second = tcp_new_rust();
+            assert_eq!(tcp_bind_rust(second, &local_ip, 9121), ERR_USE);
+            assert_eq!(tcp_bind_rust(second, ptr::null(), 9121), ERR_USE);
+            assert_eq!(tcp_bind_rust(second, &ip_addr::from_ip4(0x0500a8c0), 9121), ERR_OK);
+
+            // Nothing to send on before connecting
+            assert_eq!(tcp_write_rust(first, b"x".as_ptr() as *const c_void, 1, 0), ERR_CONN);
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert_eq!(tcp_connect_rust(first, &remote_ip, 80, None), ERR_OK);
+            assert_eq!(tcp_connect_rust(first, &remote_ip, 80, None), ERR_ISCONN);
+            assert_eq!(tcp_bind_rust(first, &local_ip, 9122), ERR_ISCONN);
+
+            // More than the send buffer holds
+            let state = pcb_to_state_mut(first).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            let room = state.rod.snd_buf as usize;
+            let data = vec![0u8; room + 1];
+            assert_eq!(tcp_write_rust(first, data.as_ptr() as *const c_void, data.len() as u16, 0), ERR_MEM);
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(second);
+        }
+    }
 
-            tcp_abort_rust(listen_pcb);
+    #[test]
+    fn test_specific_listener_wins_over_any() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            // Sharing the port takes SOF_REUSEADDR on both
+            let any = tcp_new_rust();
+            tcp_set_option_rust(any, tcp_proto::SOF_REUSEADDR, true);
+            assert_eq!(tcp_bind_rust(any, ptr::null(), 9120), ERR_OK);
+            let any = tcp_listen_with_backlog_rust(any, 5);
+            let specific = tcp_new_rust();
+            tcp_set_option_rust(specific, tcp_proto::SOF_REUSEADDR, true);
+            assert_eq!(tcp_bind_rust(specific, &ip_addr::from_ip4(0x0100a8c0), 9120), ERR_OK);
+            let specific = tcp_listen_with_backlog_rust(specific, 5);
+
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let demux = |local: u32| tcp_demux(ip_addr::from_ip4(local), 9120, remote_ip, 40000, true);
+            assert_eq!(demux(0x0100a8c0), Demux::Pcb(specific));
+            assert_eq!(demux(0x0500a8c0), Demux::Pcb(any));
+
+            tcp_close_rust(specific);
+            tcp_close_rust(any);
         }
     }
 
     #[test]
-    fn test_tcp_connect_transitions_to_syn_sent() {
+    fn test_demux_separates_connections_on_one_port() {
+        let _lists = pcb_list::test_lock();
         unsafe {
-            let pcb = tcp_new_rust();
+            let local_ip = ip_addr::from_ip4(0x0100a8c0);
+            let first = connected(9140, (0x0200a8c0, 40000));
+            let other_port = connected(9140, (0x0200a8c0, 40001));
+            let other_ip = connected(9140, (0x0300a8c0, 40000));
+
+            let demux = |remote: u32, port: u16| tcp_demux(local_ip, 9140, ip_addr::from_ip4(remote), port, false);
+            assert_eq!(demux(0x0200a8c0, 40000), Demux::Pcb(first));
+            assert_eq!(demux(0x0200a8c0, 40001), Demux::Pcb(other_port));
+            assert_eq!(demux(0x0300a8c0, 40000), Demux::Pcb(other_ip));
+            // Known address, known port, but not together
+            assert_eq!(demux(0x0300a8c0, 40001), Demux::SendRst);
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(other_port);
+            tcp_abort_rust(other_ip);
+        }
+    }
 
-            let local_addr = ffi::ip_addr_t { addr: 0 };
-            tcp_bind_rust(pcb, &local_addr, 0);
+    #[cfg(lwip_ipv6)]
+    #[test]
+    fn test_demux_separates_ip6_peers_on_one_port() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let local = ip6_addr(1);
+            let pcb = tcp_new_ip_type_rust(tcp_proto::IPADDR_TYPE_V6);
+            assert_eq!(tcp_bind_rust(pcb, &local, 9145), ERR_OK);
+            let lpcb = tcp_listen_with_backlog_rust(pcb, 5);
+            let v4 = listener(9145, 5);
+
+            // Two peers, same port, differing only in their IPv6 address
+            let spawn = |host: u32| {
+                ffi::ip_data.current_iphdr_src = ip6_addr(host);
+                ffi::ip_data.current_iphdr_dest = local;
+                tcp_process(lpcb, &syn_from(5000), ip6_addr(host), 40000)
+            };
+            let first = spawn(3);
+            let second = spawn(4);
+            assert!(!first.is_null() && !second.is_null() && first != second);
+
+            let demux = |host: u32, syn: bool| tcp_demux(local, 9145, ip6_addr(host), 40000, syn);
+            assert_eq!(demux(3, false), Demux::Pcb(first));
+            assert_eq!(demux(4, false), Demux::Pcb(second));
+            assert_eq!(demux(5, false), Demux::SendRst);
+            // A new peer's SYN goes to the IPv6 listener, not the IPv4 one
+            assert_eq!(demux(5, true), Demux::Pcb(lpcb));
+            let v4_syn = tcp_demux(ip_addr::from_ip4(0x0100a8c0), 9145, ip_addr::from_ip4(0x0200a8c0), 40000, true);
+            assert_eq!(v4_syn, Demux::Pcb(v4));
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(second);
+            tcp_abort_rust(lpcb);
+            tcp_abort_rust(v4);
+        }
+    }
 
-            let remote_addr = ffi::ip_addr_t { addr: 0x0100007f };
-            let result = tcp_connect_rust(pcb, &remote_addr, 80, None);
-            assert_eq!(result, ERR_OK);
+    #[test]
+    fn test_demux_falls_back_to_listener_for_syn() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let local_ip = ip_addr::from_ip4(0x0100a8c0);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let lpcb = listener(9101, 5);
+            let active = connected(9101, (0x0200a8c0, 40000));
+
+            // The established connection wins over the listener
+            assert_eq!(tcp_demux(local_ip, 9101, remote_ip, 40000, true), Demux::Pcb(active));
+            assert_eq!(tcp_demux(local_ip, 9101, remote_ip, 40001, true), Demux::Pcb(lpcb));
+            // Only a SYN reaches a listener
+            assert_eq!(tcp_demux(local_ip, 9101, remote_ip, 40001, false), Demux::SendRst);
+            // Bound to another address
+            let other_ip = ip_addr::from_ip4(0x0500a8c0);
+            assert_eq!(tcp_demux(other_ip, 9101, remote_ip, 40001, true), Demux::SendRst);
+
+            tcp_abort_rust(active);
+            tcp_abort_rust(lpcb);
+            assert_eq!(tcp_demux(local_ip, 9101, remote_ip, 40001, true), Demux::SendRst);
+        }
+    }
 
-            assert_eq!(tcp_get_state_rust(pcb), TcpState::SynSent as u8);
+    #[test]
+    fn test_tcp_input_routes_segment_to_connection() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let first = connected(9102, (0x0200a8c0, 40000));
+            let second = connected(9102, (0x0300a8c0, 40000));
+            for pcb in [first, second] {
+                let state = pcb_to_state_mut(pcb).unwrap();
+                state.rod.rcv_nxt = 2001;
+                state.rod.snd_nxt = 1001;
+                state.rod.lastack = 1001;
+                state.flow_ctrl.rcv_wnd = 4096;
+            }
+            let mut rx = Received::default();
+            tcp_arg_rust(second, &mut rx as *mut Received as *mut c_void);
+            tcp_recv_rust(second, Some(record_recv));
+
+            // Wire image of a 10-byte segment from 192.168.0.3:40000
+            let mut hdr = tcp_proto::TcpHdr {
+                src: 40000u16.to_be(),
+                dest: 9102u16.to_be(),
+                seqno: 2001u32.to_be(),
+                ackno: 1001u32.to_be(),
+                _hdrlen_rsvd_flags: 0,
+                wnd: 4096u16.to_be(),
+                chksum: 0,
+                urgp: 0,
+            };
+            hdr.set_hdrlen_flags(5, tcp_proto::TCP_ACK);
+            let mut bytes = core::slice::from_raw_parts(&hdr as *const tcp_proto::TcpHdr as *const u8, tcp_proto::TCP_HLEN).to_vec();
+            bytes.extend_from_slice(&[1; 10]);
+
+            let p = ffi::pbuf_alloc(ffi::pbuf_layer_PBUF_RAW, bytes.len() as u16, ffi::pbuf_type_PBUF_RAM);
+            core::slice::from_raw_parts_mut((*p).payload as *mut u8, bytes.len()).copy_from_slice(&bytes);
+            ffi::ip_data.current_iphdr_src = ip_addr::from_ip4(0x0300a8c0);
+            ffi::ip_data.current_iphdr_dest = ip_addr::from_ip4(0x0100a8c0);
+            tcp_input_rust(p, ptr::null_mut());
+
+            assert_eq!((rx.bytes, rx.calls), (10, 1));
+            assert_eq!(pcb_to_state(second).unwrap().rod.rcv_nxt, 2011);
+            assert_eq!(pcb_to_state(first).unwrap().rod.rcv_nxt, 2001);
+
+            tcp_abort_rust(first);
+            tcp_abort_rust(second);
+        }
+    }
 
-            let state = pcb_to_state(pcb).unwrap();
-            assert_eq!(state.conn_mgmt.remote_port, 80);
-            assert!(state.rod.iss > 0);
+    #[test]
+    fn test_stats_count_handshake_and_data() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9105, 5);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let child = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            ack_synack(child, 5000, 40000);
+            let iss = pcb_to_state(child).unwrap().rod.iss;
+
+            let seg = |seqno: u32, ackno: u32, wnd: u16, payload: &[u8]| TcpSegment {
+                seqno,
+                ackno,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd,
+                tcphdr_len: 20,
+                payload_len: payload.len() as u16,
+                payload: payload.to_vec(),
+                ..Default::default()
+            };
+
+            tcp_process(child, &seg(5001, iss.wrapping_add(1), 8192, &[1; 10]), remote_ip, 40000);
+
+            let data = [0u8; 100];
+            assert_eq!(tcp_write_rust(child, data.as_ptr() as *const c_void, 100, 0), ERR_OK);
+            assert_eq!(tcp_output_rust(child), ERR_OK);
+
+            // A duplicate ACK, then everything acknowledged into a closed window
+            tcp_process(child, &seg(5011, iss.wrapping_add(1), 8192, &[]), remote_ip, 40000);
+            tcp_process(child, &seg(5011, iss.wrapping_add(101), 0, &[]), remote_ip, 40000);
+
+            // 10 bytes beyond a gap
+            tcp_process(child, &seg(5021, iss.wrapping_add(101), 0, &[2; 10]), remote_ip, 40000);
+
+            let mut stats = stats::TcpStats::default();
+            assert_eq!(tcp_get_stats_rust(child, &mut stats), ERR_OK);
+            // SYN, ACK of the SYN+ACK, two ACKs, two data segments
+            assert_eq!(stats.segs_received, 6);
+            assert_eq!(stats.bytes_received, 20);
+            // SYN+ACK, data segment, duplicate ACK for the gap
+            assert_eq!(stats.segs_sent, 3);
+            assert_eq!(stats.bytes_sent, 100);
+            assert_eq!(stats.ooseq_segs, 1);
+            assert_eq!(stats.dup_acks, 1);
+            assert_eq!(stats.zero_window, 1);
+            assert_eq!(stats.retransmits, 0);
+
+            assert_eq!(tcp_get_stats_rust(child, ptr::null_mut()), ERR_ARG);
+            assert_eq!(tcp_get_stats_rust(ptr::null(), &mut stats), ERR_ARG);
+
+            tcp_abort_rust(child);
+            tcp_abort_rust(lpcb);
+        }
+    }
 
-            tcp_abort_rust(pcb);
+    #[test]
+    fn test_immediate_ack_rides_on_queued_data() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9110, 5);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let child = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            ack_synack(child, 5000, 40000);
+            let state = pcb_to_state_mut(child).unwrap();
+            let iss = state.rod.iss;
+            state.cong_ctrl.cwnd = 4096;
+
+            let seg = |seqno: u32, payload: &[u8]| TcpSegment {
+                seqno,
+                ackno: iss.wrapping_add(1),
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 8192,
+                tcphdr_len: 20,
+                payload_len: payload.len() as u16,
+                payload: payload.to_vec(),
+                ..Default::default()
+            };
+
+            // Data queued but not yet output, and an ACK already delayed
+            let data = [7u8; 50];
+            assert_eq!(tcp_write_rust(child, data.as_ptr() as *const c_void, 50, 0), ERR_OK);
+            tcp_process(child, &seg(5001, &[1; 10]), remote_ip, 40000);
+
+            // The second segment calls for an ACK: it goes out with the data
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            tcp_process(child, &seg(5011, &[2; 10]), remote_ip, 40000);
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].len(), tcp_proto::TCP_HLEN + 50);
+            assert_eq!(u32::from_be_bytes(sent[0][8..12].try_into().unwrap()), 5021);
+            assert_ne!(sent[0][13] & tcp_proto::TCP_ACK, 0);
+
+            tcp_abort_rust(child);
+            tcp_abort_rust(lpcb);
         }
     }
 
     #[test]
-    fn test_tcp_getters_return_correct_values() {
+    fn test_reopened_window_releases_queued_data() {
+        let _lists = pcb_list::test_lock();
         unsafe {
-            let pcb = tcp_new_rust();
+            let lpcb = listener(9112, 5);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let child = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            ack_synack(child, 5000, 40000);
+            let state = pcb_to_state_mut(child).unwrap();
+            let iss = state.rod.iss;
+            state.cong_ctrl.cwnd = 4096;
+
+            let update = |wnd: u16| TcpSegment {
+                seqno: 5001,
+                ackno: iss.wrapping_add(1),
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd,
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+
+            // Window closed: the write stays queued
+            tcp_process(child, &update(0), remote_ip, 40000);
+            let data = [7u8; 50];
+            assert_eq!(tcp_write_rust(child, data.as_ptr() as *const c_void, 50, 0), ERR_OK);
+            assert_eq!(tcp_output_rust(child), ERR_OK);
+
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            tcp_process(child, &update(1000), remote_ip, 40000);
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].len(), tcp_proto::TCP_HLEN + 50);
+
+            tcp_abort_rust(child);
+            tcp_abort_rust(lpcb);
+        }
+    }
 
-            tcp_set_keep_idle_rust(pcb, 60000);
-            assert_eq!(tcp_get_keep_idle_rust(pcb), 60000);
+    #[test]
+    fn test_persist_timer_sends_one_byte_probe() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9115, 5);
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            let child = tcp_process(lpcb, &syn_from(5000), remote_ip, 40000);
+            ack_synack(child, 5000, 40000);
+            let iss = pcb_to_state(child).unwrap().rod.iss;
+
+            let zero_wnd = TcpSegment {
+                seqno: 5001,
+                ackno: iss.wrapping_add(1),
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                wnd: 0,
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+            tcp_process(child, &zero_wnd, remote_ip, 40000);
+            let data = [7u8; 50];
+            assert_eq!(tcp_write_rust(child, data.as_ptr() as *const c_void, 50, 0), ERR_OK);
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert_eq!(tcp_output_rust(child), ERR_OK);
+            assert!(ffi::IP_OUTPUT.with(|out| out.borrow().is_empty()));
+
+            for _ in 0..tcp_proto::TCP_PERSIST_BACKOFF[0] {
+                tcp_slowtmr_process(child);
+            }
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            let probe = TcpSegment::parse(&sent[0]).unwrap();
+            assert_eq!(probe.seqno, iss.wrapping_add(1));
+            assert_eq!(probe.payload_len, 1);
+            assert_eq!(sent[0][tcp_proto::TCP_HLEN], 7);
+
+            tcp_abort_rust(child);
+            tcp_abort_rust(lpcb);
+        }
+    }
 
-            tcp_set_keep_intvl_rust(pcb, 10000);
-            assert_eq!(tcp_get_keep_intvl_rust(pcb), 10000);
+    #[test]
+    fn test_keepalive_probes_idle_connection_then_aborts() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9123, (0x0200a8c0, 40000));
+            let mut reported = Reported::default();
+            tcp_arg_rust(pcb, &mut reported as *mut Reported as *mut c_void);
+            tcp_err_rust(pcb, Some(count_err));
+            assert_eq!(tcp_set_option_rust(pcb, tcp_proto::SOF_KEEPALIVE, true), ERR_OK);
+
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.rod.rcv_nxt = 2001;
+            state.conn_mgmt.keep_idle = 2 * tcp_proto::TCP_SLOW_INTERVAL;
+            state.conn_mgmt.keep_intvl = tcp_proto::TCP_SLOW_INTERVAL;
+            state.conn_mgmt.keep_cnt = 2;
+
+            // Idle for `ticks` slow timer ticks as of now
+            let idle_for = |ticks: u32| pcb_to_state_mut(pcb).unwrap().conn_mgmt.tmr = tcp_ticks.wrapping_sub(ticks);
+
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            for ticks in [3, 4] {
+                idle_for(ticks);
+                tcp_slowtmr_process(pcb);
+                let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+                assert_eq!(sent.len(), 1);
+                let probe = TcpSegment::parse(&sent[0]).unwrap();
+                assert_eq!((probe.seqno, probe.ackno, probe.payload_len), (1000, 2001, 0));
+                assert!(probe.flags.ack);
+            }
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.keep_cnt_sent, 2);
+            assert_eq!(reported.calls, 0);
 
-            tcp_set_keep_cnt_rust(pcb, 5);
-            assert_eq!(tcp_get_keep_cnt_rust(pcb), 5);
+            // keep_cnt probes went unanswered
+            idle_for(5);
+            tcp_slowtmr_process(pcb);
+            assert_eq!((reported.err, reported.calls), (ERR_ABRT, 1));
+        }
+    }
 
-            tcp_setprio_rust(pcb, 100);
-            let state = pcb_to_state(pcb).unwrap();
-            assert_eq!(state.conn_mgmt.prio, 100);
+    #[test]
+    fn test_debug_snapshot_reads_back_state() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = tcp_new_rust();
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::Established;
+            state.conn_mgmt.remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.rod.snd_lbb = 1001;
+            state.rod.rto = 1500;
+            state.flow_ctrl.snd_wnd = 4096;
+            state.flow_ctrl.rcv_wnd = 3000;
+            state.cong_ctrl.cwnd = 536;
+            state.cong_ctrl.ssthresh = 8000;
+
+            // One segment goes out under cwnd, the rest stays queued
+            let data = [0u8; 1000];
+            assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 600, 0), ERR_OK);
+            assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 400, 0), ERR_OK);
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+
+            let mut info = snapshot::TcpDebugInfo::default();
+            assert_eq!(tcp_debug_snapshot_rust(pcb, &mut info), ERR_OK);
+            assert_eq!(info.state, TcpState::Established as u32);
+            assert_eq!((info.snd_nxt, info.rcv_nxt, info.lastack), (1537, 2001, 1001));
+            assert_eq!((info.snd_wnd, info.rcv_wnd), (4096, 3000));
+            assert_eq!((info.cwnd, info.ssthresh), (536, 8000));
+            assert_eq!(info.rto, 1500);
+            assert_eq!(info.unacked_len, 1);
+            assert_eq!(info.unsent_len as usize, pcb_to_state(pcb).unwrap().rod.unsent.len());
+            assert!(info.unsent_len > 0);
+            assert_eq!(info.ooseq_len, 0);
+
+            assert_eq!(tcp_debug_snapshot_rust(pcb, ptr::null_mut()), ERR_ARG);
+            assert_eq!(tcp_debug_snapshot_rust(ptr::null(), &mut info), ERR_ARG);
 
             tcp_abort_rust(pcb);
         }
     }
 
+    /// Run a bare segment from 192.168.0.9:`src_port` to 192.168.0.1:`dest_port`
+    /// through tcp_input_rust
+    unsafe fn input_bare(src_port: u16, dest_port: u16, seqno: u32, ackno: u32, flags: u8) {
+        let mut hdr = tcp_proto::TcpHdr {
+            src: src_port.to_be(),
+            dest: dest_port.to_be(),
+            seqno: seqno.to_be(),
+            ackno: ackno.to_be(),
+            _hdrlen_rsvd_flags: 0,
+            wnd: 4096u16.to_be(),
+            chksum: 0,
+            urgp: 0,
+        };
+        hdr.set_hdrlen_flags(5, flags);
+        let bytes = core::slice::from_raw_parts(&hdr as *const tcp_proto::TcpHdr as *const u8, tcp_proto::TCP_HLEN);
+
+        let p = ffi::pbuf_alloc(ffi::pbuf_layer_PBUF_RAW, bytes.len() as u16, ffi::pbuf_type_PBUF_RAM);
+        core::slice::from_raw_parts_mut((*p).payload as *mut u8, bytes.len()).copy_from_slice(bytes);
+        ffi::ip_data.current_iphdr_src = ip_addr::from_ip4(0x0900a8c0);
+        ffi::ip_data.current_iphdr_dest = ip_addr::from_ip4(0x0100a8c0);
+        tcp_input_rust(p, ptr::null_mut());
+    }
+
+    #[test]
+    fn test_unmatched_ack_answered_with_rst() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            input_bare(40000, 9103, 5000, 777, tcp_proto::TCP_ACK);
+
+            let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            let rst = TcpSegment::parse(&sent).unwrap();
+            assert!(rst.flags.rst && !rst.flags.ack);
+            assert_eq!(rst.seqno, 777);
+            assert_eq!(u16::from_be_bytes([sent[0], sent[1]]), 9103);
+            assert_eq!(u16::from_be_bytes([sent[2], sent[3]]), 40000);
+
+            // A SYN to a port nobody listens on gets RST+ACK
+            input_bare(40000, 9103, 5000, 0, tcp_proto::TCP_SYN);
+            let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            let rst = TcpSegment::parse(&sent).unwrap();
+            assert!(rst.flags.rst && rst.flags.ack);
+            assert_eq!((rst.seqno, rst.ackno), (0, 5001));
+        }
+    }
+
     #[test]
-    fn test_tcp_flags_operations() {
+    fn test_closed_pcb_answers_ack_with_rst() {
+        let _lists = pcb_list::test_lock();
         unsafe {
             let pcb = tcp_new_rust();
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.local_ip = ip_addr::from_ip4(0x0100a8c0);
+            state.conn_mgmt.local_port = 9124;
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+
+            let bogus_ack = TcpSegment {
+                seqno: 5000,
+                ackno: 777,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            assert_eq!(tcp_process(pcb, &bogus_ack, remote_ip, 40000), pcb);
+
+            let sent = ffi::IP_OUTPUT.with(|out| core::mem::take(&mut *out.borrow_mut()));
+            assert_eq!(sent.len(), 1);
+            let rst = TcpSegment::parse(&sent[0]).unwrap();
+            assert!(rst.flags.rst && !rst.flags.ack);
+            assert_eq!(rst.seqno, 777);
+            assert_eq!(u16::from_be_bytes([sent[0][0], sent[0][1]]), 9124);
+            assert_eq!(u16::from_be_bytes([sent[0][2], sent[0][3]]), 40000);
 
-            tcp_set_flags_rust(pcb, 0x01);
-            assert_eq!(tcp_is_flag_set_rust(pcb, 0x01), 1);
-            assert_eq!(tcp_is_flag_set_rust(pcb, 0x02), 0);
+            tcp_abort_rust(pcb);
+        }
+    }
 
-            tcp_set_flags_rust(pcb, 0x02);
-            assert_eq!(tcp_is_flag_set_rust(pcb, 0x01), 1);
-            assert_eq!(tcp_is_flag_set_rust(pcb, 0x02), 1);
+    #[test]
+    fn test_unmatched_rst_dropped() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            input_bare(40000, 9104, 5000, 0, tcp_proto::TCP_RST);
+            input_bare(40000, 9104, 5000, 777, tcp_proto::TCP_RST | tcp_proto::TCP_ACK);
 
-            tcp_clear_flags_rust(pcb, 0x01);
-            assert_eq!(tcp_is_flag_set_rust(pcb, 0x01), 0);
-            assert_eq!(tcp_is_flag_set_rust(pcb, 0x02), 1);
+            assert!(ffi::IP_OUTPUT.with(|out| out.borrow().is_empty()));
+        }
+    }
 
-            tcp_abort_rust(pcb);
+    #[test]
+    fn test_syn_reopening_time_wait_goes_to_listener() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let lpcb = listener(9108, 5);
+            let mut accepted: Vec<*mut ffi::tcp_pcb> = Vec::new();
+            tcp_arg_rust(lpcb, &mut accepted as *mut Vec<*mut ffi::tcp_pcb> as *mut c_void);
+            tcp_accept_rust(lpcb, Some(record_accept));
+
+            let tw = connected(9108, (0x0200a8c0, 40000));
+            enter_time_wait(tw);
+            let state = pcb_to_state_mut(tw).unwrap();
+            state.rod.rcv_nxt = 2002;
+            state.rod.snd_nxt = 1002;
+
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert_eq!(tcp_process(tw, &syn_from(90000), remote_ip, 40000), tw);
+
+            let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            let synack = TcpSegment::parse(&sent).unwrap();
+            assert!(synack.flags.syn && synack.flags.ack);
+            assert_eq!(synack.ackno, 90001);
+            assert_eq!(synack.seqno, 1002 + tcp_proto::TCP_ISS_INCR);
+            assert_eq!(pcb_to_state(lpcb).unwrap().conn_mgmt.accepts_pending, 1);
+
+            // The listener accepts the new incarnation like any child
+            ack_synack(tw, 90000, 40000);
+            assert_eq!(accepted, vec![tw]);
+            assert_eq!(pcb_to_state(lpcb).unwrap().conn_mgmt.accepts_pending, 0);
+            assert!(is_linked(tcp_active_pcbs, tw));
+            assert!(!is_linked(tcp_tw_pcbs, tw));
+
+            tcp_abort_rust(tw);
+            tcp_abort_rust(lpcb);
         }
     }
 
     #[test]
-    fn test_tcp_callback_arg() {
+    fn test_syn_reopening_time_wait_without_listener_is_refused() {
+        let _lists = pcb_list::test_lock();
         unsafe {
-            let pcb = tcp_new_rust();
+            let tw = connected(9109, (0x0200a8c0, 40000));
+            enter_time_wait(tw);
+            let state = pcb_to_state_mut(tw).unwrap();
+            state.rod.rcv_nxt = 2002;
+
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert!(tcp_process(tw, &syn_from(90000), remote_ip, 40000).is_null());
+
+            let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            let rst = TcpSegment::parse(&sent).unwrap();
+            assert!(rst.flags.rst && rst.flags.ack);
+            assert_eq!(rst.ackno, 90001);
+            let local_ip = ip_addr::from_ip4(0x0100a8c0);
+            assert_eq!(tcp_demux(local_ip, 9109, remote_ip, 40000, false), Demux::SendRst);
+        }
+    }
 
-            let mut data: u32 = 42;
-            let data_ptr = &mut data as *mut u32 as *mut c_void;
+    #[test]
+    fn test_time_wait_pcb_freed_after_2msl() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9114, (0x0200a8c0, 40000));
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.state = TcpState::FinWait2;
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+
+            let fin = TcpSegment {
+                seqno: 2001,
+                ackno: 1001,
+                flags: TcpFlags::from_tcphdr(tcp_proto::TCP_FIN | tcp_proto::TCP_ACK),
+                wnd: 8192,
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+            let remote_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert_eq!(tcp_process(pcb, &fin, remote_ip, 40000), pcb);
+            assert_eq!(pcb_to_state(pcb).unwrap().conn_mgmt.state, TcpState::TimeWait);
+            assert!(is_linked(tcp_tw_pcbs, pcb));
+            assert!(!is_linked(tcp_active_pcbs, pcb));
+
+            for _ in 1..tcp_proto::TCP_TIME_WAIT_TICKS {
+                tcp_slowtmr_process(pcb);
+            }
+            assert!(is_linked(tcp_tw_pcbs, pcb));
 
-            tcp_arg_rust(pcb, data_ptr);
+            tcp_slowtmr_process(pcb);
+            assert!(!is_linked(tcp_tw_pcbs, pcb));
+            let local_ip = ip_addr::from_ip4(0x0100a8c0);
+            assert_eq!(tcp_demux(local_ip, 9114, remote_ip, 40000, false), Demux::SendRst);
+        }
+    }
 
-            let state = pcb_to_state(pcb).unwrap();
-            assert_eq!(state.callback_arg, data_ptr);
+    /// Established connection at priority `prio` reporting its error into `err`
+    unsafe fn connected_at(remote: (u32, u16), prio: u8, err: &mut i8) -> *mut ffi::tcp_pcb {
+        let pcb = connected(9107, remote);
+        assert!(!pcb.is_null());
+        tcp_setprio_rust(pcb, prio);
+        tcp_arg_rust(pcb, err as *mut i8 as *mut c_void);
+        tcp_err_rust(pcb, Some(record_err));
+        pcb
+    }
 
-            tcp_abort_rust(pcb);
+    #[test]
+    fn test_full_pool_evicts_lowest_priority() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let (mut low_err, mut mid_err, mut high_err) = (ERR_OK, ERR_OK, ERR_OK);
+            connected_at((0x0200a8c0, 40000), 10, &mut low_err);
+            connected_at((0x0300a8c0, 40000), 40, &mut mid_err);
+            let high = connected_at((0x0400a8c0, 40000), 100, &mut high_err);
+            let live = TCP_PCB_COUNT;
+            TCP_PCB_LIMIT = live;
+            let local_ip = ip_addr::from_ip4(0x0100a8c0);
+
+            // A normal-priority PCB takes the place of the lowest one
+            let first = tcp_new_rust();
+            assert!(!first.is_null());
+            assert_eq!(low_err, ERR_ABRT);
+            assert_eq!(mid_err, ERR_OK);
+            let low_ip = ip_addr::from_ip4(0x0200a8c0);
+            assert_eq!(tcp_demux(local_ip, 9107, low_ip, 40000, false), Demux::SendRst);
+
+            // Then the next lowest below TCP_PRIO_NORMAL
+            let second = tcp_new_rust();
+            assert!(!second.is_null());
+            assert_eq!(mid_err, ERR_ABRT);
+
+            // Nothing left ranks below: the allocation fails
+            assert!(tcp_new_rust().is_null());
+            assert_eq!(high_err, ERR_OK);
+            assert!(matches!(
+                tcp_demux(local_ip, 9107, ip_addr::from_ip4(0x0400a8c0), 40000, false),
+                Demux::Pcb(pcb) if pcb == high
+            ));
+
+            for pcb in [high, first, second] {
+                tcp_abort_rust(pcb);
+            }
+            assert_eq!(TCP_PCB_COUNT, live - 3);
+            TCP_PCB_LIMIT = ffi::MEMP_NUM_TCP_PCB as usize;
         }
     }
 
     #[test]
-    fn test_tcp_addrinfo() {
+    fn test_full_pool_evicts_time_wait_first() {
+        let _lists = pcb_list::test_lock();
         unsafe {
+            let (mut low_err, mut tw_err) = (ERR_OK, ERR_OK);
+            let low = connected_at((0x0500a8c0, 40000), tcp_proto::TCP_PRIO_MIN, &mut low_err);
+            let tw = connected_at((0x0600a8c0, 40000), tcp_proto::TCP_PRIO_MAX, &mut tw_err);
+            enter_time_wait(tw);
+            TCP_PCB_LIMIT = TCP_PCB_COUNT;
+
             let pcb = tcp_new_rust();
+            assert!(!pcb.is_null());
+            assert_eq!(tw_err, ERR_ABRT);
+            assert_eq!(low_err, ERR_OK);
 
-            let local_addr = ffi::ip_addr_t { addr: 0x0100007f };
-            tcp_bind_rust(pcb, &local_addr, 8080);
+            tcp_abort_rust(low);
+            tcp_abort_rust(pcb);
+            TCP_PCB_LIMIT = ffi::MEMP_NUM_TCP_PCB as usize;
+        }
+    }
 
-            let remote_addr = ffi::ip_addr_t { addr: 0x0200007f };
-            tcp_connect_rust(pcb, &remote_addr, 80, None);
+    /// Run a segment from 192.168.0.3 to 192.168.0.1 through tcp_input_rust
+    #[cfg(feature = "md5")]
+    unsafe fn input_from_peer(bytes: &[u8]) {
+        input_between(bytes, ip_addr::from_ip4(0x0300a8c0), ip_addr::from_ip4(0x0100a8c0));
+    }
 
-            let mut addr = ffi::ip_addr_t { addr: 0 };
-            let mut port: u16 = 0;
+    /// Run a segment from 2001:db8::3 to 2001:db8::1 through tcp_input_rust
+    #[cfg(lwip_ipv6)]
+    unsafe fn input6_from_peer(bytes: &[u8]) {
+        input_between(bytes, ip6_addr(3), ip6_addr(1));
+    }
 
-            tcp_tcp_get_tcp_addrinfo_rust(pcb, 1, &mut addr, &mut port);
-            assert_eq!(addr.addr, 0x0100007f);
-            assert_eq!(port, 8080);
+    #[cfg(any(feature = "md5", lwip_ipv6))]
+    unsafe fn input_between(bytes: &[u8], src: ffi::ip_addr_t, dst: ffi::ip_addr_t) {
+        let p = ffi::pbuf_alloc(ffi::pbuf_layer_PBUF_RAW, bytes.len() as u16, ffi::pbuf_type_PBUF_RAM);
+        core::slice::from_raw_parts_mut((*p).payload as *mut u8, bytes.len()).copy_from_slice(bytes);
+        ffi::ip_data.current_iphdr_src = src;
+        ffi::ip_data.current_iphdr_dest = dst;
+        tcp_input_rust(p, ptr::null_mut());
+    }
 
-            tcp_tcp_get_tcp_addrinfo_rust(pcb, 0, &mut addr, &mut port);
-            assert_eq!(addr.addr, 0x0200007f);
-            assert_eq!(port, 80);
+    /// IPv6 counterpart of `connected`: 2001:db8::1:`local_port` to
+    /// 2001:db8::3:`remote_port`
+    #[cfg(lwip_ipv6)]
+    unsafe fn connected6(local_port: u16, remote_port: u16) -> *mut ffi::tcp_pcb {
+        let pcb = connected(local_port, (0, remote_port));
+        let cm = &mut pcb_to_state_mut(pcb).unwrap().conn_mgmt;
+        cm.ip_type = tcp_proto::IPADDR_TYPE_V6;
+        cm.local_ip = ip6_addr(1);
+        cm.remote_ip = ip6_addr(3);
+        cm.local_ip6 = ip_addr::ip6(&cm.local_ip);
+        cm.remote_ip6 = ip_addr::ip6(&cm.remote_ip);
+        pcb
+    }
+
+    /// The peer end of a `connected6` PCB, for building its segments
+    #[cfg(lwip_ipv6)]
+    fn peer6(local_port: u16) -> TcpConnectionState {
+        let mut peer = TcpConnectionState::new();
+        peer.conn_mgmt.ip_type = tcp_proto::IPADDR_TYPE_V6;
+        peer.conn_mgmt.local_ip6 = ip_addr::ip6(&ip6_addr(3));
+        peer.conn_mgmt.remote_ip6 = ip_addr::ip6(&ip6_addr(1));
+        peer.conn_mgmt.local_port = 40000;
+        peer.conn_mgmt.remote_port = local_port;
+        peer
+    }
+
+    #[cfg(feature = "md5")]
+    #[test]
+    fn test_md5_signed_segments_round_trip() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            let pcb = connected(9106, (0x0300a8c0, 40000));
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.rcv_nxt = 2001;
+            state.rod.snd_nxt = 1001;
+            state.rod.lastack = 1001;
+            state.rod.snd_lbb = 1001;
+            state.flow_ctrl.rcv_wnd = 4096;
+            state.flow_ctrl.snd_wnd = 4096;
+            state.cong_ctrl.cwnd = 4096;
+            let mut rx = Received::default();
+            tcp_arg_rust(pcb, &mut rx as *mut Received as *mut c_void);
+            tcp_recv_rust(pcb, Some(record_recv));
+
+            let key = b"bgp-secret";
+            assert_eq!(tcp_set_md5_key_rust(pcb, key.as_ptr(), key.len() as u8), ERR_OK);
+
+            // Outgoing data is signed over the pseudo-header, header, payload and key
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            let data = [7u8; 32];
+            assert_eq!(tcp_write_rust(pcb, data.as_ptr() as *const c_void, 32, 0), ERR_OK);
+            assert_eq!(tcp_output_rust(pcb), ERR_OK);
+            let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            let at = tcp_md5::find_digest(&sent).unwrap();
+            let pseudo = tcp_md5::pseudo_header_v4(
+                &ip_addr::from_ip4(0x0100a8c0),
+                &ip_addr::from_ip4(0x0300a8c0),
+                sent.len(),
+            );
+            assert_eq!(sent[at..at + 16], tcp_md5::digest(&pseudo, &sent, key));
+            assert_eq!(&sent[sent.len() - 32..], &data[..]);
+
+            // The peer signs with the same key: accepted
+            let mut peer = TcpConnectionState::new();
+            peer.conn_mgmt.local_ip = ip_addr::from_ip4(0x0300a8c0);
+            peer.conn_mgmt.remote_ip = ip_addr::from_ip4(0x0100a8c0);
+            peer.conn_mgmt.local_port = 40000;
+            peer.conn_mgmt.remote_port = 9106;
+            peer.conn_mgmt.set_md5_key(key).unwrap();
+            let signed = tcp_out::TcpTx::build_segment(&peer, 2001, 1033, tcp_proto::TCP_ACK, &[], &[1; 10]).unwrap();
+            input_from_peer(&signed);
+            assert_eq!((rx.bytes, rx.calls), (10, 1));
+
+            // Tampered payload, wrong key, or no signature at all: dropped
+            let mut tampered = tcp_out::TcpTx::build_segment(&peer, 2011, 1033, tcp_proto::TCP_ACK, &[], &[2; 10]).unwrap();
+            let last = tampered.len() - 1;
+            tampered[last] ^= 0xFF;
+            input_from_peer(&tampered);
+
+            peer.conn_mgmt.set_md5_key(b"wrong").unwrap();
+            input_from_peer(&tcp_out::TcpTx::build_segment(&peer, 2011, 1033, tcp_proto::TCP_ACK, &[], &[3; 10]).unwrap());
+
+            peer.conn_mgmt.set_md5_key(&[]).unwrap();
+            input_from_peer(&tcp_out::TcpTx::build_segment(&peer, 2011, 1033, tcp_proto::TCP_ACK, &[], &[4; 10]).unwrap());
+
+            assert_eq!((rx.bytes, rx.calls), (10, 1));
+            assert_eq!(pcb_to_state(pcb).unwrap().rod.rcv_nxt, 2011);
+
+            // Key too long, or no PCB
+            assert_eq!(tcp_set_md5_key_rust(pcb, [0u8; 81].as_ptr(), 81), ERR_ARG);
+            assert_eq!(tcp_set_md5_key_rust(ptr::null_mut(), key.as_ptr(), 4), ERR_ARG);
 
             tcp_abort_rust(pcb);
         }
     }
 
+    #[cfg(all(feature = "md5", lwip_ipv6))]
     #[test]
-    fn test_tcp_close_deallocates() {
+    fn test_md5_checked_over_ip6_pseudo_header() {
+        let _lists = pcb_list::test_lock();
         unsafe {
-            let pcb = tcp_new_rust();
+            let pcb = connected6(9139, 40000);
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.rod.rcv_nxt = 2001;
+            state.flow_ctrl.rcv_wnd = 4096;
+            let mut rx = Received::default();
+            tcp_arg_rust(pcb, &mut rx as *mut Received as *mut c_void);
+            tcp_recv_rust(pcb, Some(record_recv));
+
+            let key = b"bgp-secret";
+            assert_eq!(tcp_set_md5_key_rust(pcb, key.as_ptr(), key.len() as u8), ERR_OK);
+
+            // Signed over the IPv6 pseudo-header: accepted
+            let mut peer = peer6(9139);
+            peer.conn_mgmt.set_md5_key(key).unwrap();
+            input6_from_peer(&tcp_out::TcpTx::build_segment(&peer, 2001, 0, tcp_proto::TCP_ACK, &[], &[1; 10]).unwrap());
+            assert_eq!((rx.bytes, rx.calls), (10, 1));
+
+            // Signed over an IPv4 pseudo-header: dropped
+            peer.conn_mgmt.ip_type = tcp_proto::IPADDR_TYPE_V4;
+            peer.conn_mgmt.local_ip = ip_addr::from_ip4(0x0300a8c0);
+            peer.conn_mgmt.remote_ip = ip_addr::from_ip4(0x0100a8c0);
+            input6_from_peer(&tcp_out::TcpTx::build_segment(&peer, 2011, 0, tcp_proto::TCP_ACK, &[], &[2; 10]).unwrap());
+            assert_eq!((rx.bytes, rx.calls), (10, 1));
 
-            let result = tcp_close_rust(pcb);
-            assert_eq!(result, ERR_OK);
+            tcp_abort_rust(pcb);
         }
     }
 
+    #[cfg(lwip_ipv6)]
     #[test]
-    fn test_null_pcb_handling() {
+    fn test_ce_read_from_ip6_traffic_class() {
+        let _lists = pcb_list::test_lock();
         unsafe {
-            assert_eq!(tcp_bind_rust(ptr::null_mut(), ptr::null(), 80), ERR_ARG);
-            assert_eq!(tcp_connect_rust(ptr::null_mut(), ptr::null(), 80, None), ERR_ARG);
-            assert_eq!(tcp_close_rust(ptr::null_mut()), ERR_ARG);
-            assert_eq!(tcp_get_state_rust(ptr::null()), 0);
-            assert_eq!(tcp_get_sndbuf_rust(ptr::null()), 0);
+            let pcb = connected6(9142, 40000);
+            let state = pcb_to_state_mut(pcb).unwrap();
+            state.conn_mgmt.ecn_enabled = true;
+            state.rod.rcv_nxt = 2001;
+            state.flow_ctrl.rcv_wnd = 4096;
+
+            // Version 6, traffic class CE, no flow label
+            let ip6hdr = ffi::ip6_hdr {
+                _v_tc_fl: (6u32 << 28 | (tcp_proto::IP_ECN_CE as u32) << 20).to_be(),
+                _plen: 0,
+                _nexth: ffi::IP_PROTO_TCP as u8,
+                _hoplim: 64,
+                src: ffi::ip6_addr_p_t { addr: ip_addr::ip6(&ip6_addr(3)) },
+                dest: ffi::ip6_addr_p_t { addr: ip_addr::ip6(&ip6_addr(1)) },
+            };
+            ffi::ip_data.current_ip6_header = &ip6hdr;
+
+            let peer = peer6(9142);
+            input6_from_peer(&tcp_out::TcpTx::build_segment(&peer, 2001, 0, tcp_proto::TCP_ACK, &[], &[1; 10]).unwrap());
+
+            let state = pcb_to_state(pcb).unwrap();
+            assert_eq!(state.rod.rcv_nxt, 2011);
+            assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_ECN_ECHO, 0);
+
+            ffi::ip_data.current_ip6_header = ptr::null();
+            tcp_abort_rust(pcb);
+        }
+    }
+
+    #[cfg(lwip_ipv6)]
+    #[test]
+    fn test_ip6_rst_goes_out_over_ip6() {
+        let _lists = pcb_list::test_lock();
+        unsafe {
+            ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+            ffi::IP6_OUTPUT.with(|out| out.borrow_mut().clear());
+
+            // Aborting a connection resets the peer over its own family
+            let pcb = connected6(9146, 40000);
+            tcp_abort_rust(pcb);
+            let rst = ffi::IP6_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            assert_eq!(rst[13], tcp_proto::TCP_RST | tcp_proto::TCP_ACK);
+            assert_eq!(u16::from_be_bytes([rst[0], rst[1]]), 9146);
+
+            // So is a segment for a port nobody uses
+            let peer = peer6(9147);
+            input6_from_peer(&tcp_out::TcpTx::build_segment(&peer, 2001, 0, tcp_proto::TCP_SYN, &[], &[]).unwrap());
+            let rst = ffi::IP6_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+            assert_ne!(rst[13] & tcp_proto::TCP_RST, 0);
+            assert_eq!(u16::from_be_bytes([rst[0], rst[1]]), 9147);
+
+            assert!(ffi::IP_OUTPUT.with(|out| out.borrow().is_empty()));
         }
     }
 }
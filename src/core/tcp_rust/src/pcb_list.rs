@@ -0,0 +1,260 @@
+//! PCB Lists
+//!
+//! As in lwIP, every PCB is on at most one of four singly linked lists,
+//! threaded through `TcpConnectionState::next`:
+//!
+//! - tcp_bound_pcbs: bound to a local port, neither listening nor connecting
+//! - tcp_listen_pcbs: listening
+//! - tcp_active_pcbs: connecting, connected or closing
+//! - tcp_tw_pcbs: waiting out TIME_WAIT
+//!
+//! The heads keep lwIP's symbol names, but the nodes are
+//! `TcpConnectionState`, not `struct tcp_pcb`: C code (the SNMP MIB
+//! included) must not walk them. The register functions move a PCB off
+//! whatever list it was on.
+//!
+//! Every change to a list bumps a generation count, so a walk that runs
+//! application callbacks can tell when the PCBs it holds on to may be gone
+//! (lwIP's tcp_active_pcbs_changed).
+
+use core::ptr;
+
+use crate::state::TcpConnectionState;
+
+/// Head of a PCB list, null when empty
+pub type PcbList = *mut TcpConnectionState;
+
+#[no_mangle]
+pub static mut tcp_active_pcbs: PcbList = ptr::null_mut();
+
+#[no_mangle]
+pub static mut tcp_tw_pcbs: PcbList = ptr::null_mut();
+
+#[no_mangle]
+pub static mut tcp_bound_pcbs: PcbList = ptr::null_mut();
+
+#[no_mangle]
+pub static mut tcp_listen_pcbs: PcbList = ptr::null_mut();
+
+/// Bumped whenever a PCB is pushed onto or removed from any list
+static mut GENERATION: u32 = 0;
+
+/// Current list generation; differs from an earlier value once any list
+/// changed in between
+pub(crate) unsafe fn generation() -> u32 {
+    GENERATION
+}
+
+/// Walks a list from its head
+///
+/// The following PCB is read before one is yielded, so the caller may
+/// unlink or free the PCB it was given (but not the one after it).
+pub(crate) struct Iter {
+    next: *mut TcpConnectionState,
+}
+
+impl Iterator for Iter {
+    type Item = *mut TcpConnectionState;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next.is_null() {
+            return None;
+        }
+        let pcb = self.next;
+        // iter()'s caller keeps every PCB on the list valid
+        self.next = unsafe { (*pcb).next };
+        Some(pcb)
+    }
+}
+
+/// Iterate over `list`, head first
+pub(crate) unsafe fn iter(list: PcbList) -> Iter {
+    Iter { next: list }
+}
+
+/// Push `pcb`, which must be on no list, onto the head of `list`
+pub(crate) unsafe fn push(list: *mut PcbList, pcb: *mut TcpConnectionState) {
+    (*pcb).next = *list;
+    *list = pcb;
+    GENERATION = GENERATION.wrapping_add(1);
+}
+
+/// Remove `pcb` from `list`; false if it was not on it
+pub(crate) unsafe fn remove(list: *mut PcbList, pcb: *mut TcpConnectionState) -> bool {
+    let mut link = list;
+    while !(*link).is_null() {
+        if *link == pcb {
+            *link = (*pcb).next;
+            (*pcb).next = ptr::null_mut();
+            GENERATION = GENERATION.wrapping_add(1);
+            return true;
+        }
+        link = ptr::addr_of_mut!((**link).next);
+    }
+    false
+}
+
+/// Is `pcb` on `list`?
+pub(crate) unsafe fn contains(list: PcbList, pcb: *mut TcpConnectionState) -> bool {
+    iter(list).any(|it| it == pcb)
+}
+
+/// Take `pcb` off whichever list it is on
+pub(crate) unsafe fn unlink(pcb: *mut TcpConnectionState) {
+    for list in [
+        ptr::addr_of_mut!(tcp_bound_pcbs),
+        ptr::addr_of_mut!(tcp_listen_pcbs),
+        ptr::addr_of_mut!(tcp_active_pcbs),
+        ptr::addr_of_mut!(tcp_tw_pcbs),
+    ] {
+        if remove(list, pcb) {
+            return;
+        }
+    }
+}
+
+/// Bound PCB (tcp_bind), moved to the head when bound again
+pub(crate) unsafe fn register_bound(pcb: *mut TcpConnectionState) {
+    unlink(pcb);
+    push(ptr::addr_of_mut!(tcp_bound_pcbs), pcb);
+}
+
+/// Listening PCB (tcp_listen)
+pub(crate) unsafe fn register_listen(pcb: *mut TcpConnectionState) {
+    unlink(pcb);
+    push(ptr::addr_of_mut!(tcp_listen_pcbs), pcb);
+}
+
+/// Connecting, accepted or reopened PCB
+pub(crate) unsafe fn register_active(pcb: *mut TcpConnectionState) {
+    unlink(pcb);
+    push(ptr::addr_of_mut!(tcp_active_pcbs), pcb);
+}
+
+/// Connection that entered TIME_WAIT: only the slow timer and stray
+/// segments need it from now on
+pub(crate) unsafe fn move_to_timewait(pcb: *mut TcpConnectionState) {
+    unlink(pcb);
+    push(ptr::addr_of_mut!(tcp_tw_pcbs), pcb);
+}
+
+/// Forget every PCB (tcp_init)
+pub(crate) unsafe fn reset() {
+    tcp_active_pcbs = ptr::null_mut();
+    tcp_tw_pcbs = ptr::null_mut();
+    tcp_bound_pcbs = ptr::null_mut();
+    tcp_listen_pcbs = ptr::null_mut();
+}
+
+/// Serialise unit tests that use the lists: cargo runs tests on parallel
+/// threads, but the stack, like lwIP, runs on one
+#[cfg(test)]
+pub(crate) fn test_lock() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    // A failed test must not fail every one after it
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcbs(n: usize) -> Vec<TcpConnectionState> {
+        (0..n).map(|_| TcpConnectionState::new()).collect()
+    }
+
+    #[test]
+    fn test_push_iterates_newest_first() {
+        let _lists = test_lock();
+        let mut states = pcbs(3);
+        let p: Vec<_> = states.iter_mut().map(|s| s as *mut _).collect();
+        let mut list: PcbList = ptr::null_mut();
+
+        unsafe {
+            for &pcb in &p {
+                push(&mut list, pcb);
+            }
+            assert_eq!(iter(list).collect::<Vec<_>>(), [p[2], p[1], p[0]]);
+        }
+    }
+
+    #[test]
+    fn test_remove_relinks_neighbours() {
+        let _lists = test_lock();
+        let mut states = pcbs(4);
+        let p: Vec<_> = states.iter_mut().map(|s| s as *mut _).collect();
+        let mut list: PcbList = ptr::null_mut();
+
+        unsafe {
+            for &pcb in &p {
+                push(&mut list, pcb);
+            }
+
+            // Middle, head, then tail
+            assert!(remove(&mut list, p[2]));
+            assert_eq!(iter(list).collect::<Vec<_>>(), [p[3], p[1], p[0]]);
+            assert!(remove(&mut list, p[3]));
+            assert_eq!(iter(list).collect::<Vec<_>>(), [p[1], p[0]]);
+            assert!(remove(&mut list, p[0]));
+            assert_eq!(iter(list).collect::<Vec<_>>(), [p[1]]);
+
+            // Removed PCBs are detached and not found again
+            assert!((*p[2]).next.is_null());
+            assert!(!remove(&mut list, p[2]));
+            assert!(!contains(list, p[0]));
+            assert!(contains(list, p[1]));
+        }
+    }
+
+    #[test]
+    fn test_iter_survives_unlinking_current() {
+        let _lists = test_lock();
+        let mut states = pcbs(3);
+        let p: Vec<_> = states.iter_mut().map(|s| s as *mut _).collect();
+        let mut list: PcbList = ptr::null_mut();
+
+        unsafe {
+            for &pcb in &p {
+                push(&mut list, pcb);
+            }
+
+            let mut seen = Vec::new();
+            for pcb in iter(list) {
+                seen.push(pcb);
+                remove(&mut list, pcb);
+            }
+            assert_eq!(seen, [p[2], p[1], p[0]]);
+            assert!(list.is_null());
+        }
+    }
+
+    #[test]
+    fn test_register_moves_between_lists() {
+        let _lists = test_lock();
+        // Only heap PCBs may go on the global lists
+        let pcb = Box::into_raw(Box::new(TcpConnectionState::new()));
+
+        unsafe {
+            register_bound(pcb);
+            assert!(contains(tcp_bound_pcbs, pcb));
+
+            register_active(pcb);
+            assert!(!contains(tcp_bound_pcbs, pcb));
+            assert!(contains(tcp_active_pcbs, pcb));
+
+            move_to_timewait(pcb);
+            assert!(!contains(tcp_active_pcbs, pcb));
+            assert!(contains(tcp_tw_pcbs, pcb));
+
+            unlink(pcb);
+            assert!(!contains(tcp_tw_pcbs, pcb));
+
+            register_listen(pcb);
+            assert!(contains(tcp_listen_pcbs, pcb));
+            unlink(pcb);
+            assert!(!contains(tcp_listen_pcbs, pcb));
+
+            drop(Box::from_raw(pcb));
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! TCP Send Queue
+//!
+//! Application data accepted by tcp_write() that has not been transmitted
+//! yet (lwIP's pcb->unsent). Owned by the ROD component, which accounts
+//! for send buffer space and assigns sequence numbers.
+
+use std::collections::VecDeque;
+
+/// A chunk of application data waiting to be sent
+pub struct UnsentSegment {
+    pub seqno: u32,    // Sequence number of the first byte
+    pub data: Vec<u8>,
+    pub push: bool,    // Ends a write made without TCP_WRITE_FLAG_MORE
+}
+
+/// Queue of unsent data, in sequence order
+#[derive(Default)]
+pub struct SendQueue {
+    segs: VecDeque<UnsentSegment>,
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        Self { segs: VecDeque::new() }
+    }
+
+    /// Append a copy of `data` starting at sequence number `seqno`
+    pub fn push(&mut self, seqno: u32, data: &[u8]) {
+        self.segs.push_back(UnsentSegment {
+            seqno,
+            data: data.to_vec(),
+            push: false,
+        });
+    }
+
+    /// Append `data` starting at `seqno` in chunks of at most `max` bytes
+    ///
+    /// As in lwIP's tcp_write, the last chunk is topped up first if the
+    /// data follows on from it, so small writes share a segment - unless
+    /// that chunk was pushed. `push` marks the end of this write the same
+    /// way. Returns how many chunks were added.
+    pub fn append(&mut self, seqno: u32, data: &[u8], max: usize, push: bool) -> usize {
+        let mut rest = data;
+        if let Some(tail) = self.segs.back_mut() {
+            let room = max.saturating_sub(tail.data.len());
+            if tail.seqno.wrapping_add(tail.data.len() as u32) == seqno && room > 0 && !tail.push {
+                let (head, more) = rest.split_at(room.min(rest.len()));
+                tail.data.extend_from_slice(head);
+                rest = more;
+            }
+        }
+
+        let mut seqno = seqno.wrapping_add((data.len() - rest.len()) as u32);
+        let before = self.segs.len();
+        for chunk in rest.chunks(max.max(1)) {
+            self.push(seqno, chunk);
+            seqno = seqno.wrapping_add(chunk.len() as u32);
+        }
+        if let Some(tail) = self.segs.back_mut() {
+            tail.push = push;
+        }
+        self.segs.len() - before
+    }
+
+    /// Oldest unsent chunk
+    pub fn front(&self) -> Option<&UnsentSegment> {
+        self.segs.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<UnsentSegment> {
+        self.segs.pop_front()
+    }
+
+    /// Remove up to `len` bytes from the front chunk
+    ///
+    /// A longer chunk is split; its remainder stays queued with the
+    /// sequence number advanced accordingly, and keeps the push mark.
+    pub fn split_front(&mut self, len: usize) -> Option<UnsentSegment> {
+        let front = self.segs.front_mut()?;
+        if len >= front.data.len() {
+            return self.segs.pop_front();
+        }
+
+        let rest = front.data.split_off(len);
+        let head = core::mem::replace(&mut front.data, rest);
+        let seqno = front.seqno;
+        front.seqno = seqno.wrapping_add(len as u32);
+
+        Some(UnsentSegment { seqno, data: head, push: false })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &UnsentSegment> {
+        self.segs.iter()
+    }
+
+    /// Number of queued chunks
+    pub fn len(&self) -> usize {
+        self.segs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segs.is_empty()
+    }
+
+    /// Total number of queued bytes
+    pub fn bytes(&self) -> usize {
+        self.segs.iter().map(|seg| seg.data.len()).sum()
+    }
+
+    /// Drop every queued chunk
+    pub fn clear(&mut self) {
+        self.segs.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SendQueue;
+
+    #[test]
+    fn test_push_keeps_order_and_copies_data() {
+        let mut queue = SendQueue::new();
+        let mut buf = [1u8, 2, 3];
+        queue.push(100, &buf);
+        buf[0] = 9;
+        queue.push(103, &[4, 5]);
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.bytes(), 5);
+
+        let first = queue.pop_front().unwrap();
+        assert_eq!(first.seqno, 100);
+        assert_eq!(first.data, vec![1, 2, 3]);
+        assert_eq!(queue.front().unwrap().seqno, 103);
+    }
+
+    #[test]
+    fn test_append_tops_up_tail_then_cuts_chunks() {
+        let mut queue = SendQueue::new();
+        assert_eq!(queue.append(100, &[1; 6], 8, false), 1);
+        assert_eq!(queue.append(106, &[2; 12], 8, false), 2);
+
+        let chunks: Vec<_> = queue.iter().map(|seg| (seg.seqno, seg.data.len())).collect();
+        assert_eq!(chunks, [(100, 8), (108, 8), (116, 2)]);
+        assert_eq!(queue.front().unwrap().data, [1, 1, 1, 1, 1, 1, 2, 2]);
+
+        // Data that does not follow on from the tail starts a chunk of its own
+        assert_eq!(queue.append(200, &[3], 8, false), 1);
+        assert_eq!(queue.len(), 4);
+    }
+
+    #[test]
+    fn test_append_starts_new_chunk_after_push() {
+        let mut queue = SendQueue::new();
+        assert_eq!(queue.append(100, &[1; 2], 8, true), 1);
+        assert_eq!(queue.append(102, &[2; 2], 8, false), 1);
+        assert_eq!(queue.append(104, &[3; 2], 8, true), 0);
+
+        let chunks: Vec<_> = queue.iter().map(|seg| (seg.seqno, seg.data.len(), seg.push)).collect();
+        assert_eq!(chunks, [(100, 2, true), (102, 4, true)]);
+    }
+
+    #[test]
+    fn test_split_front() {
+        let mut queue = SendQueue::new();
+        queue.push(100, &[1, 2, 3, 4, 5]);
+
+        let head = queue.split_front(2).unwrap();
+        assert_eq!((head.seqno, head.data), (100, vec![1, 2]));
+        assert_eq!(queue.front().unwrap().seqno, 102);
+        assert_eq!(queue.bytes(), 3);
+
+        let rest = queue.split_front(10).unwrap();
+        assert_eq!((rest.seqno, rest.data), (102, vec![3, 4, 5]));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,44 @@
+//! Connection State Snapshot
+//!
+//! Flattens the state components into one C struct, so test harnesses on
+//! the C side can check internal invariants without knowing the layout
+//! of the Rust state.
+
+use crate::state::TcpConnectionState;
+
+/// Copy of the fields most invariants are written against
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpDebugInfo {
+    pub state: u32,       // TcpState as its lwIP enum value
+    pub snd_nxt: u32,
+    pub rcv_nxt: u32,
+    pub lastack: u32,
+    pub snd_wnd: u32,
+    pub rcv_wnd: u32,
+    pub cwnd: u32,
+    pub ssthresh: u32,
+    pub rto: u32,         // ms
+    pub unsent_len: u16,  // Chunks written, not yet sent
+    pub unacked_len: u16, // Segments sent, not yet acknowledged
+    pub ooseq_len: u16,   // Segments held beyond a gap
+}
+
+impl TcpDebugInfo {
+    pub fn from_state(state: &TcpConnectionState) -> Self {
+        Self {
+            state: state.conn_mgmt.state as u32,
+            snd_nxt: state.rod.snd_nxt,
+            rcv_nxt: state.rod.rcv_nxt,
+            lastack: state.rod.lastack,
+            snd_wnd: state.flow_ctrl.snd_wnd,
+            rcv_wnd: state.flow_ctrl.rcv_wnd,
+            cwnd: state.cong_ctrl.cwnd,
+            ssthresh: state.cong_ctrl.ssthresh,
+            rto: state.rod.rto,
+            unsent_len: state.rod.unsent.len().min(u16::MAX as usize) as u16,
+            unacked_len: state.rod.unacked.len().min(u16::MAX as usize) as u16,
+            ooseq_len: state.rod.ooseq.len().min(u16::MAX as usize) as u16,
+        }
+    }
+}
@@ -11,6 +11,11 @@ pub use crate::components::{
     CongestionControlState,
     DemuxState,
 };
+use std::collections::VecDeque;
+
+use crate::components::{CongestionControl, CubicCongestionControl, RenoCongestionControl};
+use crate::stats::TcpStats;
+use crate::tcp_proto;
 
 /// TCP State Machine States
 #[repr(u32)]
@@ -29,7 +34,103 @@ pub enum TcpState {
     TimeWait = 10,
 }
 
+/// Events that drive the connection state machine
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TcpEvent {
+    Bind,            // tcp_bind
+    PassiveOpen,     // tcp_listen, or a child taking over its listener
+    ActiveOpen,      // tcp_connect
+    Close,           // tcp_close
+    Abort,           // tcp_abort or a fatal timeout
+    RcvSyn,          // SYN from a peer
+    RcvSynAck,       // SYN+ACK answering our SYN
+    RcvAckOfSyn,     // ACK covering our SYN
+    RcvFin,          // FIN from the peer
+    RcvAckOfFin,     // ACK covering our FIN
+    RcvRst,          // Acceptable RST
+    TimeWaitTimeout, // 2MSL elapsed
+}
+
+impl TcpEvent {
+    pub const ALL: [TcpEvent; 12] = [
+        TcpEvent::Bind,
+        TcpEvent::PassiveOpen,
+        TcpEvent::ActiveOpen,
+        TcpEvent::Close,
+        TcpEvent::Abort,
+        TcpEvent::RcvSyn,
+        TcpEvent::RcvSynAck,
+        TcpEvent::RcvAckOfSyn,
+        TcpEvent::RcvFin,
+        TcpEvent::RcvAckOfFin,
+        TcpEvent::RcvRst,
+        TcpEvent::TimeWaitTimeout,
+    ];
+}
+
 impl TcpState {
+    pub const ALL: [TcpState; 11] = [
+        TcpState::Closed,
+        TcpState::Listen,
+        TcpState::SynSent,
+        TcpState::SynRcvd,
+        TcpState::Established,
+        TcpState::FinWait1,
+        TcpState::FinWait2,
+        TcpState::CloseWait,
+        TcpState::Closing,
+        TcpState::LastAck,
+        TcpState::TimeWait,
+    ];
+
+    /// Transition table: the state `event` leads to, None if illegal
+    ///
+    /// Follows the RFC 793 diagram (p. 23), simultaneous open included,
+    /// with lwIP's departure that closing before ESTABLISHED drops the
+    /// connection without a FIN. A new SYN may reopen TIME_WAIT
+    /// (RFC 1122, 4.2.2.13). A reset passive open going back to
+    /// LISTEN depends on how the connection was opened and is handled by
+    /// connection management.
+    pub fn next_state(self, event: TcpEvent) -> Option<TcpState> {
+        match (self, event) {
+            (_, TcpEvent::Abort) | (_, TcpEvent::RcvRst) => Some(TcpState::Closed),
+
+            (TcpState::Closed, TcpEvent::Bind) => Some(TcpState::Closed),
+            (TcpState::Closed, TcpEvent::PassiveOpen) => Some(TcpState::Listen),
+            (TcpState::Closed, TcpEvent::ActiveOpen) => Some(TcpState::SynSent),
+
+            (TcpState::Listen, TcpEvent::RcvSyn) => Some(TcpState::SynRcvd),
+            (TcpState::TimeWait, TcpEvent::RcvSyn) => Some(TcpState::SynRcvd),
+            (TcpState::SynSent, TcpEvent::RcvSyn) => Some(TcpState::SynRcvd),
+            (TcpState::SynSent, TcpEvent::RcvSynAck) => Some(TcpState::Established),
+            (TcpState::SynRcvd, TcpEvent::RcvAckOfSyn) => Some(TcpState::Established),
+
+            (TcpState::Listen, TcpEvent::Close)
+            | (TcpState::SynSent, TcpEvent::Close)
+            | (TcpState::SynRcvd, TcpEvent::Close) => Some(TcpState::Closed),
+            (TcpState::Established, TcpEvent::Close) => Some(TcpState::FinWait1),
+            (TcpState::CloseWait, TcpEvent::Close) => Some(TcpState::LastAck),
+
+            (TcpState::Established, TcpEvent::RcvFin) => Some(TcpState::CloseWait),
+            (TcpState::FinWait1, TcpEvent::RcvFin) => Some(TcpState::Closing),
+            (TcpState::FinWait2, TcpEvent::RcvFin) => Some(TcpState::TimeWait),
+            (TcpState::TimeWait, TcpEvent::RcvFin) => Some(TcpState::TimeWait),
+
+            (TcpState::FinWait1, TcpEvent::RcvAckOfFin) => Some(TcpState::FinWait2),
+            (TcpState::Closing, TcpEvent::RcvAckOfFin) => Some(TcpState::TimeWait),
+            (TcpState::LastAck, TcpEvent::RcvAckOfFin) => Some(TcpState::Closed),
+
+            (TcpState::TimeWait, TcpEvent::TimeWaitTimeout) => Some(TcpState::Closed),
+
+            _ => None,
+        }
+    }
+
+    /// Does the transition table allow `event` in state `from`?
+    pub fn can_transition(from: TcpState, event: TcpEvent) -> bool {
+        from.next_state(event).is_some()
+    }
+
     pub fn from_u32(val: u32) -> Option<Self> {
         match val {
             0 => Some(TcpState::Closed),
@@ -50,6 +151,14 @@ impl TcpState {
     pub fn is_closing(&self) -> bool {
         *self >= TcpState::FinWait1
     }
+
+    /// States in which the application may queue data (tcp_write_checks)
+    pub fn can_send(&self) -> bool {
+        matches!(
+            self,
+            TcpState::SynSent | TcpState::SynRcvd | TcpState::Established | TcpState::CloseWait
+        )
+    }
 }
 
 /// Complete TCP Connection State
@@ -63,6 +172,12 @@ pub struct TcpConnectionState {
     pub cong_ctrl: CongestionControlState,
     pub demux: DemuxState,
 
+    /* PCB list linkage (tcp_active_pcbs) */
+    pub next: *mut TcpConnectionState,
+    pub listener: *mut TcpConnectionState, // Listener that spawned us, until closed
+    pub accept_queue: VecDeque<*mut TcpConnectionState>, // LISTEN: established children not yet accepted
+    pub last_timer: u8, // Timer tick that last processed this PCB
+
     pub callback_arg: *mut core::ffi::c_void,
     pub recv_callback: Option<unsafe extern "C" fn(*mut core::ffi::c_void, *mut core::ffi::c_void, *mut core::ffi::c_void, i8) -> i8>,
     pub sent_callback: Option<unsafe extern "C" fn(*mut core::ffi::c_void, *mut core::ffi::c_void, u16) -> i8>,
@@ -70,7 +185,9 @@ pub struct TcpConnectionState {
     pub connected_callback: Option<unsafe extern "C" fn(*mut core::ffi::c_void, *mut core::ffi::c_void, i8) -> i8>,
     pub poll_callback: Option<unsafe extern "C" fn(*mut core::ffi::c_void, *mut core::ffi::c_void) -> i8>,
     pub accept_callback: Option<unsafe extern "C" fn(*mut core::ffi::c_void, *mut core::ffi::c_void, i8) -> i8>,
-    pub poll_interval: u8,
+
+    // A Cell so the read-only TX path can count too
+    pub stats: core::cell::Cell<TcpStats>,
 }
 
 impl TcpConnectionState {
@@ -81,6 +198,10 @@ impl TcpConnectionState {
             flow_ctrl: FlowControlState::new(),
             cong_ctrl: CongestionControlState::new(),
             demux: DemuxState::new(),
+            next: core::ptr::null_mut(),
+            listener: core::ptr::null_mut(),
+            accept_queue: VecDeque::new(),
+            last_timer: 0,
             callback_arg: core::ptr::null_mut(),
             recv_callback: None,
             sent_callback: None,
@@ -88,7 +209,31 @@ impl TcpConnectionState {
             connected_callback: None,
             poll_callback: None,
             accept_callback: None,
-            poll_interval: 0,
+            stats: core::cell::Cell::new(TcpStats::default()),
         }
     }
+
+    /// Update the statistics counters
+    pub fn count(&self, f: impl FnOnce(&mut TcpStats)) {
+        let mut stats = self.stats.get();
+        f(&mut stats);
+        self.stats.set(stats);
+    }
+
+    /// New connection running `algorithm` instead of Reno
+    pub fn with_congestion_control(algorithm: Box<dyn CongestionControl>) -> Self {
+        let mut state = Self::new();
+        state.cong_ctrl = CongestionControlState::with_algorithm(algorithm);
+        state
+    }
+
+    /// New connection running the TCP_CC_* algorithm `cc`
+    pub fn with_congestion_algorithm(cc: u8) -> Result<Self, &'static str> {
+        let algorithm: Box<dyn CongestionControl> = match cc {
+            tcp_proto::TCP_CC_RENO => Box::new(RenoCongestionControl),
+            tcp_proto::TCP_CC_CUBIC => Box::new(CubicCongestionControl::new()),
+            _ => return Err("Unknown congestion control algorithm"),
+        };
+        Ok(Self::with_congestion_control(algorithm))
+    }
 }
@@ -0,0 +1,18 @@
+//! Per-connection Statistics
+//!
+//! Counters for debugging a single connection. Nothing in the stack reads
+//! them back, they only observe the RX and TX paths.
+
+/// Segment and byte counters of one connection, laid out for C
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TcpStats {
+    pub segs_sent: u32,      // Segments handed to IP, retransmissions included
+    pub bytes_sent: u32,     // Payload bytes in those segments
+    pub segs_received: u32,  // Segments run through the state machine
+    pub bytes_received: u32, // Payload bytes in those segments
+    pub retransmits: u32,    // Fast retransmits and RTO resends
+    pub dup_acks: u32,       // Duplicate ACKs received
+    pub ooseq_segs: u32,     // Segments queued beyond a gap
+    pub zero_window: u32,    // Times the peer closed its window
+}
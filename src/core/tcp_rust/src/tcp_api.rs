@@ -5,26 +5,108 @@
 
 use crate::state::{TcpConnectionState, TcpState};
 use crate::ffi;
+use crate::tcp_proto;
+use crate::tcp_types::{CloseAction, TcpError};
+use crate::tcp_iss::generate_iss;
 
 /// Bind to a local IP and port
 ///
 /// Transition: CLOSED -> CLOSED (with IP and port assigned)
-/// Returns: Ok(port) on success
+/// Returns: Ok(port) on success, AlreadyConnected once the PCB is in use
 pub fn tcp_bind(
     state: &mut TcpConnectionState,
     local_ip: ffi::ip_addr_t,
     local_port: u16,
-) -> Result<u16, &'static str> {
+) -> Result<u16, TcpError> {
+    if state.conn_mgmt.state != TcpState::Closed {
+        return Err(TcpError::AlreadyConnected);
+    }
+    if local_port == 0 {
+        // Only the caller knows which ports are taken: it resolves port 0
+        // with tcp_ephemeral_port first
+        return Err(TcpError::InvalidArgument);
+    }
+
     // Delegate to connection management component
-    state.conn_mgmt.on_bind(local_ip, local_port)
+    Ok(state.conn_mgmt.on_bind(local_ip, local_port)?)
+}
+
+/// Pick a free local port from `range`, starting at the cursor `next`
+///
+/// The cursor moves past the chosen port, so consecutive calls hand out
+/// different ports even before the first one is in use. None when every
+/// port in the range is taken.
+pub fn tcp_ephemeral_port(
+    next: &mut u16,
+    range: core::ops::RangeInclusive<u16>,
+    in_use: impl Fn(u16) -> bool,
+) -> Option<u16> {
+    let (start, end) = (*range.start(), *range.end());
+    let wrap = |port: u16| if port < start || port > end { start } else { port };
+
+    let mut port = wrap(*next);
+    for _ in start..=end {
+        let candidate = port;
+        port = if port == end { start } else { wrap(port + 1) };
+        if !in_use(candidate) {
+            *next = port;
+            return Some(candidate);
+        }
+    }
+    None
 }
 
 /// Start listening for connections
 ///
 /// Transition: CLOSED -> LISTEN
-pub fn tcp_listen(state: &mut TcpConnectionState) -> Result<(), &'static str> {
+pub fn tcp_listen(state: &mut TcpConnectionState) -> Result<(), TcpError> {
+    tcp_listen_with_backlog(state, tcp_proto::TCP_DEFAULT_LISTEN_BACKLOG)
+}
+
+/// Start listening, admitting at most `backlog` half-open connections
+pub fn tcp_listen_with_backlog(state: &mut TcpConnectionState, backlog: u8) -> Result<(), TcpError> {
+    if state.conn_mgmt.state != TcpState::Closed {
+        return Err(TcpError::BadState);
+    }
+    if state.conn_mgmt.local_port == 0 {
+        return Err(TcpError::NotBound);
+    }
+
     // Delegate to connection management component
-    state.conn_mgmt.on_listen()
+    Ok(state.conn_mgmt.on_listen(backlog)?)
+}
+
+/// Spawn a child connection for a SYN that arrived on a listener
+///
+/// Fails when the listener's backlog is full, so the SYN is dropped. The
+/// child inherits the listener's endpoint, options and callback argument
+/// and is left in SYN_RCVD; the caller links it, records its listener and
+/// sends the SYN+ACK.
+pub fn tcp_listen_input(
+    listener: &mut TcpConnectionState,
+    seg: &crate::tcp_types::TcpSegment,
+    remote_ip: ffi::ip_addr_t,
+    remote_port: u16,
+) -> Result<Box<TcpConnectionState>, &'static str> {
+    use crate::tcp_types::InputAction;
+
+    if seg.class() != crate::tcp_types::SegmentClass::Syn {
+        return Err("Not a connection request");
+    }
+
+    listener.conn_mgmt.on_backlog_reserve()?;
+
+    let mut child = Box::new(TcpConnectionState::new());
+    child.conn_mgmt.on_spawn_from_listener(&listener.conn_mgmt)?;
+    child.callback_arg = listener.callback_arg;
+
+    match tcp_input(&mut child, seg, remote_ip, remote_port) {
+        Ok(InputAction::SendSynAck) => Ok(child),
+        _ => {
+            listener.conn_mgmt.on_backlog_release()?;
+            Err("SYN rejected")
+        }
+    }
 }
 
 /// Initiate active connection
@@ -35,29 +117,205 @@ pub fn tcp_connect(
     state: &mut TcpConnectionState,
     remote_ip: ffi::ip_addr_t,
     remote_port: u16,
-) -> Result<(), &'static str> {
+) -> Result<(), TcpError> {
     // Validate state first (before calling any component methods)
     if state.conn_mgmt.state != TcpState::Closed {
-        return Err("Can only connect from CLOSED state");
+        return Err(TcpError::AlreadyConnected);
     }
 
     // Each component handles its own initialization
-    // Order: data components first, then state transition last
-    state.rod.on_connect()?;
+    // Order: data components first, then state transition last; the ISS
+    // hashes the 4-tuple, so ROD follows once the remote end is known
     state.flow_ctrl.on_connect()?;
     state.cong_ctrl.on_connect(&state.conn_mgmt)?;
     state.conn_mgmt.on_connect(remote_ip, remote_port)?;
+    state.rod.on_connect(generate_iss(&state.conn_mgmt))?;
 
     Ok(())
 }
 
+/// Active open on the 4-tuple of `tw`, our connection in TIME_WAIT
+///
+/// The new connection takes over the tuple with its ISS past the old
+/// sequence space; the caller frees `tw` once this succeeds.
+pub fn tcp_connect_over_timewait(
+    state: &mut TcpConnectionState,
+    remote_ip: ffi::ip_addr_t,
+    remote_port: u16,
+    tw: &TcpConnectionState,
+) -> Result<(), TcpError> {
+    if tw.conn_mgmt.state != TcpState::TimeWait {
+        return Err(TcpError::PortInUse);
+    }
+
+    tcp_connect(state, remote_ip, remote_port)?;
+    state.rod.on_connect_over_timewait(tw.rod.snd_nxt)?;
+
+    Ok(())
+}
+
+/// Queue application data for transmission
+///
+/// Data is copied into the send queue; nothing is sent until tcp_output.
+/// `apiflags` are lwIP's TCP_WRITE_FLAG_*: with TCP_WRITE_FLAG_MORE the
+/// data is not pushed and the next write may share its segment.
+/// More than the send buffer has room for is NoMemory, as in lwIP. Once
+/// our FIN is queued (tcp_close, tcp_shutdown with shut_tx) it is Closed.
+pub fn tcp_write(state: &mut TcpConnectionState, data: &[u8], apiflags: u8) -> Result<(), TcpError> {
+    if state.conn_mgmt.flags & tcp_proto::TF_FIN != 0 {
+        return Err(TcpError::Closed);
+    }
+    if !state.conn_mgmt.state.can_send() {
+        return Err(TcpError::NotConnected);
+    }
+    if data.len() > state.rod.snd_buf as usize {
+        return Err(TcpError::NoMemory);
+    }
+
+    Ok(state.rod.on_write(data, state.conn_mgmt.eff_mss, apiflags)?)
+}
+
+/// Application has taken `len` received bytes: reopen the receive window
+///
+/// Returns true when the advertised window moved far enough to be worth
+/// an immediate window update.
+pub fn tcp_recved(state: &mut TcpConnectionState, len: u16) -> Result<bool, &'static str> {
+    Ok(state.flow_ctrl.recv_window_update(len).is_some())
+}
+
+/// Send as much queued data as the windows allow
+///
+/// Segmentation is planned by TcpTx::segmentize; the planned segments move
+/// to the unacked queue and are returned for the caller to hand to TcpTx,
+/// in order. After a close this keeps draining the queue, then sends the
+/// FIN.
+pub fn tcp_output(
+    state: &mut TcpConnectionState,
+) -> Result<Vec<crate::tcp_types::TxSegment>, &'static str> {
+    if !matches!(
+        state.conn_mgmt.state,
+        TcpState::Established | TcpState::CloseWait | TcpState::FinWait1 | TcpState::Closing | TcpState::LastAck
+    ) {
+        return Ok(Vec::new());
+    }
+
+    let now = unsafe { crate::tcp_ticks };
+    let plan = crate::tcp_out::TcpTx::segmentize(state, usize::MAX);
+
+    // Window closed or too small to use, with nothing in flight to bring
+    // an update: only probes can reopen it
+    if plan.is_empty() && state.rod.unacked.is_empty() && !state.rod.unsent.is_empty() {
+        state.flow_ctrl.on_zero_window()?;
+    }
+
+    let mut segs = plan
+        .iter()
+        .map(|desc| state.rod.on_output(desc, now))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // RFC 3168 §6.1.2: the reduction is announced on the next new data
+    if state.conn_mgmt.flags & tcp_proto::TF_ECN_CWR != 0 {
+        if let Some(seg) = segs.iter_mut().find(|seg| !seg.data.is_empty()) {
+            seg.flags |= tcp_proto::TCP_CWR;
+            state.conn_mgmt.on_cwr_sent()?;
+        }
+    }
+
+    // Every segment carries an ACK of rcv_nxt: a pending one rides along
+    if !segs.is_empty() {
+        state.conn_mgmt.on_ack_sent()?;
+    }
+
+    Ok(segs)
+}
+
+/// Limited transmit (RFC 3042): send one new segment on a duplicate ACK
+///
+/// Called on InputAction::LimitedTransmit. The segment may take the flight
+/// up to cwnd plus one MSS per duplicate ACK, but never past the peer's
+/// window. Returns the segment for the caller to hand to TcpTx, if one fits.
+pub fn tcp_limited_transmit(
+    state: &mut TcpConnectionState,
+) -> Result<Option<crate::tcp_types::TxSegment>, &'static str> {
+    let Some(desc) = crate::tcp_out::TcpTx::limited_transmit(state) else {
+        return Ok(None);
+    };
+
+    let now = unsafe { crate::tcp_ticks };
+    let seg = state.rod.on_output(&desc, now)?;
+    state.conn_mgmt.on_ack_sent()?;
+    Ok(Some(seg))
+}
+
 /// Initiate graceful close
 ///
-/// Handles closing from various states
-/// Returns: Ok(true) if FIN should be sent, Ok(false) if already closing/closed
-pub fn initiate_close(state: &mut TcpConnectionState) -> Result<bool, &'static str> {
-    // Delegate to connection management component
-    state.conn_mgmt.on_close()
+/// Handles closing from various states. A FIN is queued behind any unsent
+/// data; tcp_output sends it once that data is out, which a closed window
+/// may hold up. As in lwIP the state changes right away. The connection
+/// lives on until the FIN is acknowledged.
+/// Returns: SendFin or FinQueued (behind data), None if already closing/closed
+pub fn initiate_close(state: &mut TcpConnectionState) -> Result<CloseAction, TcpError> {
+    match state.conn_mgmt.state {
+        TcpState::Established => {
+            state.rod.on_close_in_established()?;
+            state.flow_ctrl.on_close_in_established()?;
+            state.cong_ctrl.on_close_in_established()?;
+        }
+        TcpState::CloseWait => {
+            state.rod.on_close_in_closewait()?;
+            state.flow_ctrl.on_close_in_closewait()?;
+            state.cong_ctrl.on_close_in_closewait()?;
+        }
+        _ => {}
+    }
+
+    if !state.conn_mgmt.on_close()? {
+        return Ok(CloseAction::None);
+    }
+    if state.rod.unsent.is_empty() {
+        Ok(CloseAction::SendFin)
+    } else {
+        Ok(CloseAction::FinQueued)
+    }
+}
+
+/// Shut down one or both directions of a connection
+///
+/// shut_rx stops delivery to the application: data already received and
+/// everything arriving later is ACKed and discarded. shut_tx is a half
+/// close, the FIN goes out behind the queued data. Both together is a full
+/// close. A half close needs a connection that can still send its FIN.
+/// Returns: what initiate_close did for shut_tx, else None
+pub fn tcp_shutdown(state: &mut TcpConnectionState, shut_rx: bool, shut_tx: bool) -> Result<CloseAction, TcpError> {
+    if state.conn_mgmt.state == TcpState::Listen {
+        return Err(TcpError::NotConnected);
+    }
+    let can_send_fin = matches!(
+        state.conn_mgmt.state,
+        TcpState::SynRcvd | TcpState::Established | TcpState::CloseWait
+    );
+    if shut_tx && !shut_rx && !can_send_fin {
+        return Err(TcpError::NotConnected);
+    }
+
+    if shut_rx {
+        state.conn_mgmt.on_shutdown_rx()?;
+        tcp_discard_received(state)?;
+    }
+
+    if shut_tx {
+        return initiate_close(state);
+    }
+    Ok(CloseAction::None)
+}
+
+/// Drop received data nobody will read, reopening the window it used
+fn tcp_discard_received(state: &mut TcpConnectionState) -> Result<(), &'static str> {
+    let (data, _) = state.rod.on_deliver();
+    for chunk in data.chunks(u16::MAX as usize) {
+        state.flow_ctrl.recv_window_update(chunk.len() as u16);
+    }
+    Ok(())
 }
 
 /// Abort connection (send RST)
@@ -65,8 +323,9 @@ pub fn initiate_close(state: &mut TcpConnectionState) -> Result<bool, &'static s
 /// Transition: ANY -> CLOSED
 /// Returns: Ok(true) if RST should be sent, Ok(false) otherwise
 pub fn tcp_abort(state: &mut TcpConnectionState) -> Result<bool, &'static str> {
+    // As in lwIP's tcp_abandon, a connection in TIME_WAIT goes quietly
     let should_send_rst = match state.conn_mgmt.state {
-        TcpState::Closed | TcpState::Listen => false,
+        TcpState::Closed | TcpState::Listen | TcpState::TimeWait => false,
         _ => true,
     };
 
@@ -79,26 +338,136 @@ pub fn tcp_abort(state: &mut TcpConnectionState) -> Result<bool, &'static str> {
     Ok(should_send_rst)
 }
 
+/// Run the slow timer (every TCP_SLOW_INTERVAL) for one connection
+///
+/// Returns Retransmit when the oldest unacked segment must be resent, Probe
+/// or KeepAlive when the peer must be prodded, or Abort once the
+/// retransmission, probe or keep-alive limit is reached. On Abort every
+/// component has already been reset; the caller reports ERR_ABRT and frees
+/// the PCB.
+pub fn tcp_slowtmr_pcb(
+    state: &mut TcpConnectionState,
+) -> Result<crate::tcp_types::TimerAction, &'static str> {
+    use crate::tcp_types::TimerAction;
+
+    let now = unsafe { crate::tcp_ticks };
+
+    // Nothing is left to send or probe in TIME_WAIT; only 2MSL runs
+    if state.conn_mgmt.state == TcpState::TimeWait {
+        if !state.conn_mgmt.on_time_wait_tmr() {
+            return Ok(TimerAction::None);
+        }
+        state.conn_mgmt.on_timewait_timeout()?;
+        return Ok(TimerAction::Expired);
+    }
+
+    let action = tcp_rexmit_tmr(state, now)?;
+    if action != TimerAction::None {
+        return Ok(action);
+    }
+
+    let action = state.conn_mgmt.on_keepalive_tmr(now);
+    if action == TimerAction::Abort {
+        tcp_abort(state)?;
+    }
+    Ok(action)
+}
+
+/// Retransmission and persist timers at tick `now`
+fn tcp_rexmit_tmr(
+    state: &mut TcpConnectionState,
+    now: u32,
+) -> Result<crate::tcp_types::TimerAction, &'static str> {
+    use crate::tcp_types::TimerAction;
+
+    // Persisting replaces the retransmission timer until the window opens
+    if state.flow_ctrl.persist_backoff > 0 {
+        if state.flow_ctrl.persist_probe >= tcp_proto::TCP_MAXRTX {
+            tcp_abort(state)?;
+            return Ok(TimerAction::Abort);
+        }
+
+        if !state.flow_ctrl.on_persist_tick() {
+            return Ok(TimerAction::None);
+        }
+        state.rod.on_zero_window_probe(now)?;
+        return Ok(TimerAction::Probe);
+    }
+
+    if !state.rod.on_slowtmr() {
+        return Ok(TimerAction::None);
+    }
+
+    let max_rtx = match state.conn_mgmt.state {
+        TcpState::SynSent | TcpState::SynRcvd => tcp_proto::TCP_SYNMAXRTX,
+        _ => tcp_proto::TCP_MAXRTX,
+    };
+
+    if state.rod.nrtx >= max_rtx {
+        tcp_abort(state)?;
+        return Ok(TimerAction::Abort);
+    }
+
+    // Congestion response first, it needs the flight size before the resend
+    let flight = state.rod.snd_nxt.wrapping_sub(state.rod.lastack);
+    state.cong_ctrl.on_timeout_in_established(&state.conn_mgmt, flight)?;
+    state.rod.on_rto_timeout(now)?;
+    state.conn_mgmt.on_rto_timeout()?;
+
+    Ok(TimerAction::Retransmit)
+}
+
+/// Run the fast timer on one connection
+///
+/// Called every TCP_FAST_INTERVAL. Flushes a delayed ACK.
+pub fn tcp_fasttmr_pcb(
+    state: &mut TcpConnectionState,
+) -> Result<crate::tcp_types::TimerAction, &'static str> {
+    use crate::tcp_types::TimerAction;
+
+    if state.conn_mgmt.flags & tcp_proto::TF_ACK_DELAY != 0 {
+        state.conn_mgmt.on_ack_sent()?;
+        return Ok(TimerAction::SendAck);
+    }
+
+    Ok(TimerAction::None)
+}
+
 /// Process an incoming TCP segment represented as a parsed `TcpSegment`.
 ///
-/// This is a test-friendly dispatcher that mirrors the old `ControlPath::tcp_input` behavior.
+/// This is the test-friendly dispatcher; the FFI path goes through `tcp_process`.
 pub fn tcp_input(
     state: &mut TcpConnectionState,
     seg: &crate::tcp_types::TcpSegment,
     remote_ip: ffi::ip_addr_t,
     remote_port: u16,
 ) -> Result<crate::tcp_types::InputAction, &'static str> {
-    use crate::tcp_types::{InputAction};
+    use crate::tcp_types::{InputAction, SegmentClass};
+
+    let now = unsafe { crate::tcp_ticks };
+    let class = seg.class();
+    state.conn_mgmt.on_segment_received(now)?;
+    state.count(|s| {
+        s.segs_received = s.segs_received.wrapping_add(1);
+        s.bytes_received = s.bytes_received.wrapping_add(seg.payload_len as u32);
+    });
 
     // Handle RST first (in any state)
-    if seg.flags.rst {
+    if class == SegmentClass::Rst {
         match state.rod.validate_rst(seg, state.flow_ctrl.rcv_wnd) {
             crate::tcp_types::RstValidation::Valid => {
-                // Close connection
+                // Tear down every component, whatever recovery phase we were in
+                state.rod.on_rst()?;
+                state.flow_ctrl.on_rst()?;
+                state.cong_ctrl.on_rst()?;
                 state.conn_mgmt.on_rst()?;
+                if state.conn_mgmt.state == TcpState::Listen {
+                    // Passive open reset: nothing to report, keep listening
+                    return Ok(InputAction::Drop);
+                }
                 return Ok(InputAction::Abort);
             }
-            crate::tcp_types::RstValidation::Challenge => return Ok(InputAction::SendChallengeAck),
+            crate::tcp_types::RstValidation::Challenge => return Ok(challenge_ack(state)),
             crate::tcp_types::RstValidation::Invalid => return Ok(InputAction::Drop),
         }
     }
@@ -106,22 +475,19 @@ pub fn tcp_input(
     // Dispatch based on current state
     match state.conn_mgmt.state {
         TcpState::Closed => {
-            // RFC 793: All segments are rejected in CLOSED state
-            // Send RST if not already RST
-            if !seg.flags.rst {
-                Ok(InputAction::SendRst)
-            } else {
-                Ok(InputAction::Drop)
-            }
+            // RFC 793: All segments are rejected in CLOSED state; a RST
+            // never gets here
+            Ok(InputAction::SendRst)
         }
         TcpState::Listen => {
             // Only accept SYN in LISTEN state
-            if seg.flags.syn && !seg.flags.ack {
-                // Process the SYN using component methods
-                state.rod.on_syn_in_listen(seg)?;
+            if class == SegmentClass::Syn {
+                // Process the SYN using component methods; ROD goes last
+                // as the ISS hashes the 4-tuple
                 state.flow_ctrl.on_syn_in_listen(seg, &state.conn_mgmt)?;
                 state.cong_ctrl.on_syn_in_listen(&state.conn_mgmt)?;
-                state.conn_mgmt.on_syn_in_listen(remote_ip, remote_port)?;
+                state.conn_mgmt.on_syn_in_listen(remote_ip, remote_port, seg)?;
+                state.rod.on_syn_in_listen(seg, generate_iss(&state.conn_mgmt))?;
                 Ok(InputAction::SendSynAck)
             } else {
                 Ok(InputAction::SendRst)
@@ -129,28 +495,38 @@ pub fn tcp_input(
         }
         TcpState::SynSent => {
             // Expecting SYN+ACK
-            if seg.flags.syn && seg.flags.ack {
+            if class == SegmentClass::SynAck {
                 // Let components process SYN+ACK
                 state.rod.on_synack_in_synsent(seg)?;
-                state.flow_ctrl.on_synack_in_synsent(seg)?;
+                state.flow_ctrl.on_synack_in_synsent(seg, &state.conn_mgmt)?;
                 state.cong_ctrl.on_synack_in_synsent(&state.conn_mgmt)?;
-                state.conn_mgmt.on_synack_in_synsent()?;
-                Ok(InputAction::Accept)
-            } else if seg.flags.syn {
-                // Simultaneous open (SYN without ACK)
-                Ok(InputAction::Accept)
+                state.conn_mgmt.on_synack_in_synsent(seg)?;
+                // Complete the handshake with an immediate ACK
+                Ok(InputAction::SendAck)
+            } else if class == SegmentClass::Syn {
+                // Simultaneous open: the peer's SYN crossed ours; answer
+                // with SYN+ACK and wait in SYN_RCVD for it to ACK our SYN
+                state.rod.on_syn_in_synsent(seg)?;
+                state.flow_ctrl.on_syn_in_synsent(seg, &state.conn_mgmt)?;
+                state.cong_ctrl.on_syn_in_synsent(&state.conn_mgmt)?;
+                state.conn_mgmt.on_syn_in_synsent(seg)?;
+                Ok(InputAction::SendSynAck)
             } else {
                 Ok(InputAction::Drop)
             }
         }
         TcpState::SynRcvd => {
-            // Validate sequence number
-            if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
-                return Ok(InputAction::Drop);
+            // In a simultaneous open the peer's SYN+ACK repeats the SYN we
+            // already took, so it lies before rcv_nxt but still ACKs ours
+            let crossing_synack = class == SegmentClass::SynAck && seg.seqno == state.rod.irs;
+
+            // RFC 793: an unacceptable segment is answered with an ACK
+            if !crossing_synack && !tcp_acceptable(state, seg)? {
+                return Ok(InputAction::SendAck);
             }
 
             // Expecting ACK of our SYN
-            if seg.flags.ack {
+            if class.has_ack() {
                 // Let components handle ACK in SYN_RCVD
                 state.rod.on_ack_in_synrcvd(seg)?;
                 state.flow_ctrl.on_ack_in_synrcvd(seg)?;
@@ -162,96 +538,179 @@ pub fn tcp_input(
             }
         }
         TcpState::Established => {
-            // Validate sequence number
-            if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
-                return Ok(InputAction::Drop);
+            // RFC 793: an unacceptable segment is answered with an ACK
+            if !tcp_acceptable(state, seg)? {
+                return Ok(InputAction::SendAck);
             }
 
             // Validate ACK if present
-            if seg.flags.ack {
-                match state.rod.validate_ack(seg) {
-                    crate::tcp_types::AckValidation::Valid | crate::tcp_types::AckValidation::Duplicate => {
-                        // Process normally via components (ACK handling)
-                        // For now, no-op at API level
-                    }
-                    crate::tcp_types::AckValidation::Future => {
-                        // RFC 5961: ACK of unsent data - send challenge ACK
-                        return Ok(InputAction::SendChallengeAck);
-                    }
-                    crate::tcp_types::AckValidation::Old | crate::tcp_types::AckValidation::Invalid => {
-                        return Ok(InputAction::Drop);
-                    }
+            if class.has_ack() {
+                if let Some(action) = tcp_receive_ack(state, seg, now)? {
+                    return Ok(action);
                 }
             }
 
+            state.conn_mgmt.on_ecn_marks(seg)?;
+
+            tcp_receive_data(state, seg)?;
+
             // Check for FIN
-            if seg.flags.fin {
+            if state.rod.fin_in_order(seg) {
                 // Process FIN and transition to CLOSE_WAIT
                 state.rod.on_fin_in_established(seg)?;
                 state.flow_ctrl.on_fin_in_established(seg)?;
                 state.cong_ctrl.on_fin_in_established(seg)?;
                 state.conn_mgmt.on_fin_in_established()?;
+                state.conn_mgmt.on_ack_sent()?;
                 Ok(InputAction::SendAck)
             } else {
-                Ok(InputAction::Accept)
+                ack_if_due(state)
             }
         }
         TcpState::FinWait1 => {
-            if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
+            if !tcp_acceptable(state, seg)? {
+                return Ok(InputAction::SendAck);
+            }
+
+            if !class.has_ack() && !class.has_fin() {
                 return Ok(InputAction::Drop);
             }
 
-            if seg.flags.ack || seg.flags.fin {
-                Ok(InputAction::Accept)
+            if class.has_ack() {
+                if let Some(action) = tcp_receive_ack(state, seg, now)? {
+                    return Ok(action);
+                }
+                if state.rod.on_ack_in_finwait1(seg).is_ok() {
+                    state.flow_ctrl.on_ack_in_finwait1(seg)?;
+                    state.cong_ctrl.on_ack_in_finwait1(seg)?;
+                    state.conn_mgmt.on_ack_in_finwait1()?;
+                }
+            }
+
+            // Only our direction is closed: keep taking the peer's data
+            tcp_receive_data(state, seg)?;
+
+            if !state.rod.fin_in_order(seg) {
+                return ack_if_due(state);
+            }
+
+            if state.conn_mgmt.state == TcpState::FinWait2 {
+                // FIN and the ACK of ours in one segment
+                state.rod.on_fin_in_finwait2(seg)?;
+                state.flow_ctrl.on_fin_in_finwait2(seg)?;
+                state.cong_ctrl.on_fin_in_finwait2(seg)?;
+                state.conn_mgmt.on_fin_in_finwait2()?;
             } else {
-                Ok(InputAction::Drop)
+                // Crossing FINs: simultaneous close
+                state.rod.on_fin_in_finwait1(seg)?;
+                state.flow_ctrl.on_fin_in_finwait1(seg)?;
+                state.cong_ctrl.on_fin_in_finwait1(seg)?;
+                state.conn_mgmt.on_fin_in_finwait1()?;
             }
+            state.conn_mgmt.on_ack_sent()?;
+            Ok(InputAction::SendAck)
         }
         TcpState::FinWait2 => {
-            if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
-                return Ok(InputAction::Drop);
+            if !tcp_acceptable(state, seg)? {
+                return Ok(InputAction::SendAck);
             }
 
-            if seg.flags.fin {
-                Ok(InputAction::Accept)
+            if class.has_ack() {
+                if let Some(action) = tcp_receive_ack(state, seg, now)? {
+                    return Ok(action);
+                }
+            }
+
+            tcp_receive_data(state, seg)?;
+
+            if state.rod.fin_in_order(seg) {
+                state.rod.on_fin_in_finwait2(seg)?;
+                state.flow_ctrl.on_fin_in_finwait2(seg)?;
+                state.cong_ctrl.on_fin_in_finwait2(seg)?;
+                state.conn_mgmt.on_fin_in_finwait2()?;
+                state.conn_mgmt.on_ack_sent()?;
+                Ok(InputAction::SendAck)
             } else {
-                Ok(InputAction::Accept)
+                ack_if_due(state)
             }
         }
         TcpState::CloseWait => {
-            if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
-                return Ok(InputAction::Drop);
+            if !tcp_acceptable(state, seg)? {
+                return Ok(InputAction::SendAck);
+            }
+
+            // Our direction is still open: ACKs free sent data as usual
+            if class.has_ack() {
+                if let Some(action) = tcp_receive_ack(state, seg, now)? {
+                    return Ok(action);
+                }
             }
             Ok(InputAction::Accept)
         }
         TcpState::Closing => {
-            if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
+            if !tcp_acceptable(state, seg)? {
+                return Ok(InputAction::SendAck);
+            }
+
+            if !class.has_ack() {
                 return Ok(InputAction::Drop);
             }
 
-            if seg.flags.ack {
-                Ok(InputAction::Accept)
-            } else {
-                Ok(InputAction::Drop)
+            if let Some(action) = tcp_receive_ack(state, seg, now)? {
+                return Ok(action);
+            }
+            if state.rod.on_ack_in_closing(seg).is_ok() {
+                state.flow_ctrl.on_ack_in_closing(seg)?;
+                state.cong_ctrl.on_ack_in_closing(seg)?;
+                state.conn_mgmt.on_ack_in_closing()?;
             }
+            Ok(InputAction::Accept)
         }
         TcpState::LastAck => {
-            if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
+            if !tcp_acceptable(state, seg)? {
+                return Ok(InputAction::SendAck);
+            }
+
+            if !class.has_ack() {
                 return Ok(InputAction::Drop);
             }
 
-            if seg.flags.ack {
-                Ok(InputAction::Accept)
-            } else {
-                Ok(InputAction::Drop)
+            if let Some(action) = tcp_receive_ack(state, seg, now)? {
+                return Ok(action);
+            }
+            if state.rod.on_ack_in_lastack(seg).is_ok() {
+                state.flow_ctrl.on_ack_in_lastack(seg)?;
+                state.cong_ctrl.on_ack_in_lastack(seg)?;
+                state.conn_mgmt.on_ack_in_lastack()?;
             }
+            Ok(InputAction::Accept)
         }
         TcpState::TimeWait => {
-            if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
-                return Ok(InputAction::Drop);
+            // A new SYN may reopen the connection (RFC 1122, 4.2.2.13);
+            // one within the old sequence space is an error
+            if class == SegmentClass::Syn {
+                if state.rod.on_syn_in_timewait(seg).is_err() {
+                    return Ok(InputAction::SendRst);
+                }
+                state.flow_ctrl.on_syn_in_timewait(seg, &state.conn_mgmt)?;
+                state.cong_ctrl.on_syn_in_timewait(&state.conn_mgmt)?;
+                state.conn_mgmt.on_syn_in_timewait(seg)?;
+                return Ok(InputAction::SendSynAck);
+            }
+
+            // A retransmitted FIN means our last ACK was lost: repeat it
+            if class.has_fin() && state.rod.on_fin_in_timewait(seg).is_ok() {
+                state.flow_ctrl.on_fin_in_timewait(seg)?;
+                state.cong_ctrl.on_fin_in_timewait(seg)?;
+                state.conn_mgmt.on_fin_in_timewait()?;
+                return Ok(InputAction::SendAck);
             }
 
-            if seg.flags.fin {
+            if !tcp_acceptable(state, seg)? {
+                return Ok(InputAction::SendAck);
+            }
+
+            if class.has_fin() {
                 Ok(InputAction::SendAck)
             } else {
                 Ok(InputAction::Accept)
@@ -259,3 +718,162 @@ pub fn tcp_input(
         }
     }
 }
+
+/// RFC 793 acceptability test, plus PAWS (RFC 7323) with timestamps on
+///
+/// An acceptable segment's TSval is recorded for echoing.
+fn tcp_acceptable(
+    state: &mut TcpConnectionState,
+    seg: &crate::tcp_types::TcpSegment,
+) -> Result<bool, &'static str> {
+    if !state.rod.validate_sequence_number(seg, state.flow_ctrl.rcv_wnd) {
+        return Ok(false);
+    }
+
+    if state.conn_mgmt.flags & tcp_proto::TF_TIMESTAMP != 0 {
+        if state.rod.paws_reject(seg) {
+            return Ok(false);
+        }
+        state.rod.on_timestamp(seg)?;
+    }
+
+    Ok(true)
+}
+
+/// Deliver the payload of a synchronized-state segment
+///
+/// In-order bytes go to the application, out-of-order ones wait in the
+/// reassembly queue; either way the receive window shrinks and an ACK is
+/// scheduled.
+fn tcp_receive_data(
+    state: &mut TcpConnectionState,
+    seg: &crate::tcp_types::TcpSegment,
+) -> Result<(), &'static str> {
+    if seg.payload_len == 0 {
+        return Ok(());
+    }
+
+    let prev_rcv_nxt = state.rod.rcv_nxt;
+    let action = state.rod.on_data_in_established(seg, state.flow_ctrl.rcv_wnd)?;
+    if action == crate::tcp_types::DataAction::OutOfOrder {
+        state.count(|s| s.ooseq_segs = s.ooseq_segs.wrapping_add(1));
+    }
+    let accepted = state.rod.rcv_nxt.wrapping_sub(prev_rcv_nxt);
+    state.flow_ctrl.on_data_in_established(accepted, state.rod.rcv_nxt, &state.conn_mgmt)?;
+    state.conn_mgmt.on_data_in_established(seg, &action)?;
+
+    if state.conn_mgmt.flags & tcp_proto::TF_RXCLOSED != 0 {
+        tcp_discard_received(state)?;
+    }
+    Ok(())
+}
+
+/// ACK right away if received data asked for it, else leave it to tcp_fasttmr
+fn ack_if_due(state: &mut TcpConnectionState) -> Result<crate::tcp_types::InputAction, &'static str> {
+    if state.conn_mgmt.flags & tcp_proto::TF_ACK_NOW != 0 {
+        state.conn_mgmt.on_ack_sent()?;
+        Ok(crate::tcp_types::InputAction::SendAck)
+    } else {
+        Ok(crate::tcp_types::InputAction::Accept)
+    }
+}
+
+/// Process the ACK field of a synchronized-state segment
+///
+/// Frees acknowledged data, updates the send window and runs congestion
+/// control. Returns the action to take right away, if any: a fast
+/// retransmit, a challenge ACK or dropping the segment.
+fn tcp_receive_ack(
+    state: &mut TcpConnectionState,
+    seg: &crate::tcp_types::TcpSegment,
+    now: u32,
+) -> Result<Option<crate::tcp_types::InputAction>, &'static str> {
+    use crate::tcp_types::{AckValidation, InputAction};
+
+    match state.rod.validate_ack(seg) {
+        AckValidation::Valid | AckValidation::Duplicate => {
+            let prev_lastack = state.rod.lastack;
+            let prev_dupacks = state.rod.dupacks;
+            let prev_snd_wnd = state.flow_ctrl.snd_wnd;
+            let window_update = state.flow_ctrl.is_window_update(seg);
+            if state.conn_mgmt.flags & tcp_proto::TF_TIMESTAMP != 0 && seg.ackno != prev_lastack {
+                if let Some((_, tsecr)) = seg.options.ts.filter(|&(_, tsecr)| tsecr != 0) {
+                    state.rod.on_ts_echo(tsecr, now)?;
+                }
+            }
+            state.rod.on_ack_in_established(seg, now)?;
+            if window_update && state.rod.dupacks > prev_dupacks {
+                state.rod.on_window_update_ack()?;
+            }
+            if state.conn_mgmt.flags & tcp_proto::TF_SACK != 0 {
+                state.rod.on_sack(seg)?;
+            }
+            let acked = state.rod.lastack.wrapping_sub(prev_lastack);
+            state.flow_ctrl.on_ack_in_established(seg, acked.min(u16::MAX as u32) as u16)?;
+            if state.rod.dupacks > prev_dupacks {
+                state.count(|s| s.dup_acks = s.dup_acks.wrapping_add(1));
+            }
+            if prev_snd_wnd != 0 && state.flow_ctrl.snd_wnd == 0 {
+                state.count(|s| s.zero_window = s.zero_window.wrapping_add(1));
+            }
+
+            if state.rod.lastack != prev_lastack {
+                if state.conn_mgmt.flags & tcp_proto::TF_INFR != 0 {
+                    // New data acknowledged: leave fast recovery
+                    state.cong_ctrl.on_recovery_exit()?;
+                    state.conn_mgmt.on_recovery_exit()?;
+                } else if state.conn_mgmt.ecn_enabled && seg.flags.ece && state.rod.ecn_response_due(seg.ackno) {
+                    // Congestion experienced on the path: reduce, no retransmit
+                    state.rod.on_ecn_echo()?;
+                    state.cong_ctrl.on_ecn_echo(&state.conn_mgmt)?;
+                    state.conn_mgmt.on_ecn_echo()?;
+                } else {
+                    let srtt = (state.rod.sa >> 3).max(0) as u32;
+                    state.cong_ctrl.on_ack_in_established(seg, &state.conn_mgmt, acked, now, srtt)?;
+                }
+            } else if state.rod.dupacks >= 3 {
+                let flight = state.rod.snd_nxt.wrapping_sub(state.rod.lastack);
+                state.cong_ctrl.on_dupack_in_established(&state.conn_mgmt, state.rod.dupacks, flight)?;
+
+                if state.rod.dupacks == 3 {
+                    // Third duplicate: resend the segment at lastack now
+                    state.rod.on_fast_retransmit(now)?;
+                    state.conn_mgmt.on_fast_retransmit()?;
+                    return Ok(Some(InputAction::Retransmit));
+                }
+            } else if state.rod.dupacks > prev_dupacks && !state.rod.unsent.is_empty() {
+                // A segment has left the network: keep the ACK clock going
+                return Ok(Some(InputAction::LimitedTransmit));
+            }
+            Ok(None)
+        }
+        AckValidation::Future => {
+            // RFC 5961: ACK of unsent data - send challenge ACK
+            Ok(Some(challenge_ack(state)))
+        }
+        AckValidation::Old if state.rod.lastack.wrapping_sub(seg.ackno) > state.flow_ctrl.snd_wnd_max => {
+            // RFC 5961, 5.2: older than any window the peer could still
+            // be acknowledging - ACK and drop, like an ACK of unsent data
+            Ok(Some(challenge_ack(state)))
+        }
+        // RFC 793, p. 72: a duplicate of an old ACK is ignored, the rest of
+        // the segment is still processed
+        AckValidation::Old => Ok(None),
+        AckValidation::Invalid => Ok(Some(InputAction::Drop)),
+    }
+}
+
+/// Challenge the peer, unless this connection did so within the last
+/// window or the global challenge ACK budget is spent
+///
+/// A challenge the global budget refuses does not count against the
+/// connection's own window.
+fn challenge_ack(state: &mut TcpConnectionState) -> crate::tcp_types::InputAction {
+    let now = unsafe { crate::tcp_ticks };
+    if state.rod.challenge_ack_due(now) && crate::challenge_ack::challenge_ack_allowed() {
+        state.rod.on_challenge_ack(now);
+        crate::tcp_types::InputAction::SendChallengeAck
+    } else {
+        crate::tcp_types::InputAction::Drop
+    }
+}
@@ -0,0 +1,194 @@
+//! Safe Rust Handle over a PCB
+//!
+//! `TcpConn` owns one PCB and drives it through the same `*_rust` entry
+//! points the C side calls, so Rust users get the lwIP raw API without
+//! touching raw pointers. Errors are the lwIP err_t those functions return.
+//!
+//! A `TcpConn` is neither `Send` nor `Sync`: like every lwIP PCB it must
+//! stay on the thread that runs tcp_input and the TCP timers.
+//!
+//! The stack frees a PCB by itself when the connection dies (RST, give-up
+//! timers, TIME_WAIT expiry). The handle hears of it through the err
+//! callback it installs, and from then on every call fails with ERR_CLSD.
+
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::ptr::{self, NonNull};
+
+use crate::ffi;
+use crate::state::TcpState;
+
+pub struct TcpConn {
+    pcb: NonNull<ffi::tcp_pcb>,
+    alive: Box<Cell<bool>>, // Cleared by conn_err once the stack freed the PCB
+}
+
+/// Err callback of every handle: the PCB is gone
+unsafe extern "C" fn conn_err(arg: *mut c_void, _err: i8) {
+    (*(arg as *const Cell<bool>)).set(false);
+}
+
+impl TcpConn {
+    /// A fresh PCB in CLOSED; None if the PCB pool is exhausted
+    pub fn new() -> Option<Self> {
+        let pcb = NonNull::new(unsafe { crate::tcp_new_rust() })?;
+        let alive = Box::new(Cell::new(true));
+        unsafe {
+            crate::tcp_arg_rust(pcb.as_ptr(), &*alive as *const Cell<bool> as *mut c_void);
+            crate::tcp_err_rust(pcb.as_ptr(), Some(conn_err));
+        }
+        Some(Self { pcb, alive })
+    }
+
+    /// The underlying PCB for registering C callbacks, or null once the
+    /// stack has freed it
+    ///
+    /// It stays owned by this handle: it must not be closed or aborted,
+    /// nor its arg or err callback replaced.
+    pub fn as_ptr(&self) -> *mut ffi::tcp_pcb {
+        self.live().unwrap_or(ptr::null_mut())
+    }
+
+    /// CLOSED once the stack has freed the PCB
+    pub fn state(&self) -> TcpState {
+        match self.live() {
+            Ok(pcb) => unsafe { (*(pcb as *const crate::TcpConnectionState)).conn_mgmt.state },
+            Err(_) => TcpState::Closed,
+        }
+    }
+
+    /// Bind to `port` on `ip`, or on any address if None
+    pub fn bind(&mut self, ip: Option<ffi::ip_addr_t>, port: u16) -> Result<(), i8> {
+        let pcb = self.live()?;
+        let ipaddr = ip.as_ref().map_or(ptr::null(), |ip| ip as *const ffi::ip_addr_t);
+        check(unsafe { crate::tcp_bind_rust(pcb, ipaddr, port) })
+    }
+
+    /// Start listening, with at most `backlog` connections pending accept
+    pub fn listen(&mut self, backlog: u8) -> Result<(), i8> {
+        let pcb = self.live()?;
+        let mut err = crate::ERR_OK;
+        unsafe { crate::tcp_listen_with_backlog_and_err_rust(pcb, backlog, &mut err) };
+        check(err)
+    }
+
+    pub fn connect(&mut self, ip: ffi::ip_addr_t, port: u16) -> Result<(), i8> {
+        let pcb = self.live()?;
+        check(unsafe { crate::tcp_connect_rust(pcb, &ip, port, None) })
+    }
+
+    /// Queue `data` for sending; at most u16::MAX bytes per call
+    pub fn write(&mut self, data: &[u8]) -> Result<(), i8> {
+        let pcb = self.live()?;
+        let len = u16::try_from(data.len()).map_err(|_| crate::ERR_ARG)?;
+        check(unsafe { crate::tcp_write_rust(pcb, data.as_ptr() as *const c_void, len, 0) })
+    }
+
+    /// Close gracefully, handing the PCB to the stack to finish the close
+    ///
+    /// If the close fails the connection is aborted instead.
+    pub fn close(self) -> Result<(), i8> {
+        let pcb = self.live()?;
+        // The PCB may be freed right away or live on without us: detach first
+        unsafe {
+            crate::tcp_err_rust(pcb, None);
+            crate::tcp_arg_rust(pcb, ptr::null_mut());
+        }
+        if let Err(err) = check(unsafe { crate::tcp_close_rust(pcb) }) {
+            unsafe {
+                crate::tcp_arg_rust(pcb, &*self.alive as *const Cell<bool> as *mut c_void);
+                crate::tcp_err_rust(pcb, Some(conn_err));
+            }
+            return Err(err);
+        }
+        self.alive.set(false);
+        Ok(())
+    }
+
+    /// The PCB, unless the stack has freed it
+    fn live(&self) -> Result<*mut ffi::tcp_pcb, i8> {
+        match self.alive.get() {
+            true => Ok(self.pcb.as_ptr()),
+            false => Err(crate::ERR_CLSD),
+        }
+    }
+}
+
+impl Drop for TcpConn {
+    /// Abort: the peer gets a RST and the err callback ERR_ABRT
+    fn drop(&mut self) {
+        if let Ok(pcb) = self.live() {
+            unsafe { crate::tcp_abort_rust(pcb) };
+        }
+    }
+}
+
+fn check(err: i8) -> Result<(), i8> {
+    match err {
+        crate::ERR_OK => Ok(()),
+        err => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TcpConn;
+    use crate::ip_addr;
+    use crate::state::TcpState;
+
+    #[test]
+    fn test_bind_listen_abort() {
+        let _lists = crate::pcb_list::test_lock();
+        let mut conn = TcpConn::new().unwrap();
+        conn.bind(None, 9116).unwrap();
+        conn.listen(4).unwrap();
+        assert_eq!(conn.state(), TcpState::Listen);
+
+        // Already listening
+        assert!(conn.listen(4).is_err());
+        drop(conn);
+    }
+
+    #[test]
+    fn test_write_needs_a_connection() {
+        let _lists = crate::pcb_list::test_lock();
+        let mut conn = TcpConn::new().unwrap();
+        assert_eq!(conn.write(b"hello"), Err(crate::ERR_CONN));
+        assert_eq!(conn.write(&[0; 70000]), Err(crate::ERR_ARG));
+    }
+
+    #[test]
+    fn test_handle_outlives_pcb_freed_by_stack() {
+        let _lists = crate::pcb_list::test_lock();
+        unsafe {
+            let mut conn = TcpConn::new().unwrap();
+            conn.connect(ip_addr::from_ip4(0x0200a8c0), 80).unwrap();
+            assert_eq!(conn.state(), TcpState::SynSent);
+            let pcb = conn.as_ptr();
+            let snd_nxt = (*(pcb as *const crate::TcpConnectionState)).rod.snd_nxt;
+
+            // The peer refuses: the stack frees the PCB
+            let rst = crate::TcpSegment {
+                ackno: snd_nxt,
+                flags: crate::TcpFlags::from_tcphdr(crate::tcp_proto::TCP_RST | crate::tcp_proto::TCP_ACK),
+                tcphdr_len: 20,
+                ..Default::default()
+            };
+            assert!(crate::tcp_process(pcb, &rst, ip_addr::from_ip4(0x0200a8c0), 80).is_null());
+
+            // The handle knows, and dropping it touches nothing
+            assert!(conn.as_ptr().is_null());
+            assert_eq!(conn.state(), TcpState::Closed);
+            assert_eq!(conn.write(b"hello"), Err(crate::ERR_CLSD));
+            assert_eq!(conn.close(), Err(crate::ERR_CLSD));
+        }
+    }
+
+    #[test]
+    fn test_close_unconnected_frees_pcb() {
+        let _lists = crate::pcb_list::test_lock();
+        let mut conn = TcpConn::new().unwrap();
+        conn.bind(None, 9117).unwrap();
+        assert_eq!(conn.close(), Ok(()));
+    }
+}
@@ -0,0 +1,173 @@
+//! Initial Sequence Numbers (RFC 6528)
+//!
+//! ISS = M + F(localip, localport, remoteip, remoteport, secretkey), where
+//! M is a clock ticking every 4 microseconds and F is SipHash-2-4 keyed
+//! with a secret drawn once per boot. Every 4-tuple starts at its own
+//! offset into the sequence space, so one connection's ISS tells an
+//! off-path attacker nothing about another's.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::OnceLock;
+
+use crate::components::ConnectionManagementState;
+use crate::ip_addr;
+use crate::tcp_proto;
+
+/// SipHash-2-4 (Aumasson, Bernstein)
+pub struct SipHasher24 {
+    v: [u64; 4],
+    tail: u64,   // Bytes not yet making up a whole word, little-endian
+    ntail: usize,
+    len: usize,
+}
+
+impl SipHasher24 {
+    pub fn new(k0: u64, k1: u64) -> Self {
+        Self {
+            v: [
+                k0 ^ 0x736f6d6570736575,
+                k1 ^ 0x646f72616e646f6d,
+                k0 ^ 0x6c7967656e657261,
+                k1 ^ 0x7465646279746573,
+            ],
+            tail: 0,
+            ntail: 0,
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.len += data.len();
+        for &b in data {
+            self.tail |= (b as u64) << (8 * self.ntail);
+            self.ntail += 1;
+            if self.ntail == 8 {
+                self.compress(self.tail);
+                self.tail = 0;
+                self.ntail = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> u64 {
+        let last = self.tail | ((self.len as u64 & 0xff) << 56);
+        self.compress(last);
+
+        self.v[2] ^= 0xff;
+        for _ in 0..4 {
+            self.round();
+        }
+        self.v[0] ^ self.v[1] ^ self.v[2] ^ self.v[3]
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v[3] ^= m;
+        self.round();
+        self.round();
+        self.v[0] ^= m;
+    }
+
+    fn round(&mut self) {
+        let [v0, v1, v2, v3] = &mut self.v;
+        *v0 = v0.wrapping_add(*v1);
+        *v1 = v1.rotate_left(13) ^ *v0;
+        *v0 = v0.rotate_left(32);
+        *v2 = v2.wrapping_add(*v3);
+        *v3 = v3.rotate_left(16) ^ *v2;
+        *v0 = v0.wrapping_add(*v3);
+        *v3 = v3.rotate_left(21) ^ *v0;
+        *v2 = v2.wrapping_add(*v1);
+        *v1 = v1.rotate_left(17) ^ *v2;
+        *v2 = v2.rotate_left(32);
+    }
+}
+
+/// Per-boot secret, drawn from the OS on first use
+fn secret() -> (u64, u64) {
+    static SECRET: OnceLock<(u64, u64)> = OnceLock::new();
+    *SECRET.get_or_init(|| {
+        // std seeds every RandomState from the OS's random source
+        let draw = || RandomState::new().build_hasher().finish();
+        (draw(), draw())
+    })
+}
+
+/// M: time since boot in 4 microsecond units, wrapping
+pub fn iss_clock(ticks: u32) -> u32 {
+    ticks.wrapping_mul(tcp_proto::TCP_SLOW_INTERVAL * 250)
+}
+
+/// F: this connection's fixed offset into the sequence space
+pub fn iss_offset(conn_mgmt: &ConnectionManagementState) -> u32 {
+    let (k0, k1) = secret();
+    let mut hasher = SipHasher24::new(k0, k1);
+
+    if conn_mgmt.is_ip6() {
+        for word in conn_mgmt.local_ip6.iter().chain(conn_mgmt.remote_ip6.iter()) {
+            hasher.update(&word.to_ne_bytes());
+        }
+    } else {
+        hasher.update(&ip_addr::ip4(&conn_mgmt.local_ip).to_ne_bytes());
+        hasher.update(&ip_addr::ip4(&conn_mgmt.remote_ip).to_ne_bytes());
+    }
+    hasher.update(&conn_mgmt.local_port.to_be_bytes());
+    hasher.update(&conn_mgmt.remote_port.to_be_bytes());
+
+    hasher.finish() as u32
+}
+
+/// ISS for the connection `conn_mgmt` addresses, as of now
+pub fn generate_iss(conn_mgmt: &ConnectionManagementState) -> u32 {
+    let now = unsafe { crate::tcp_ticks };
+    iss_clock(now).wrapping_add(iss_offset(conn_mgmt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_siphash_reference_vector() {
+        // Appendix A of the SipHash paper: key 00..0f, message 00..0e
+        let k0 = u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+        let k1 = u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]);
+        let mut hasher = SipHasher24::new(k0, k1);
+        hasher.update(&(0..15).collect::<Vec<u8>>());
+        assert_eq!(hasher.finish(), 0xa129ca6149be45e5);
+    }
+
+    fn endpoints(remote_port: u16) -> ConnectionManagementState {
+        let mut cm = ConnectionManagementState::new();
+        cm.local_ip = ip_addr::from_ip4(0x0100a8c0);
+        cm.remote_ip = ip_addr::from_ip4(0x0200a8c0);
+        cm.local_port = 80;
+        cm.remote_port = remote_port;
+        cm
+    }
+
+    #[test]
+    fn test_offset_depends_on_4_tuple() {
+        assert_eq!(iss_offset(&endpoints(40000)), iss_offset(&endpoints(40000)));
+        assert_ne!(iss_offset(&endpoints(40000)), iss_offset(&endpoints(40001)));
+    }
+
+    #[test]
+    fn test_clock_advances_every_4_microseconds() {
+        // One slow tick is 500ms
+        assert_eq!(iss_clock(1).wrapping_sub(iss_clock(0)), 125_000);
+        assert_eq!(iss_clock(10).wrapping_sub(iss_clock(9)), 125_000);
+    }
+
+    #[test]
+    fn test_split_updates_match_one_shot() {
+        let data: Vec<u8> = (0..40).collect();
+        let mut one_shot = SipHasher24::new(1, 2);
+        one_shot.update(&data);
+        let mut split = SipHasher24::new(1, 2);
+        for chunk in data.chunks(3) {
+            split.update(chunk);
+        }
+        assert_eq!(split.finish(), one_shot.finish());
+    }
+}
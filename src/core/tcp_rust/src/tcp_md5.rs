@@ -0,0 +1,271 @@
+//! TCP MD5 Signature Option (RFC 2385)
+//!
+//! Each segment carries an MD5 digest over the pseudo-header, the fixed TCP
+//! header with a zero checksum, the payload and a secret shared by both
+//! ends. Segments whose digest does not match are dropped unseen.
+
+use crate::ffi;
+use crate::ip_addr;
+use crate::tcp_proto;
+
+/// MD5 message digest (RFC 1321)
+pub struct Md5 {
+    state: [u32; 4],
+    block: [u8; 64],
+    block_len: usize,
+    total_len: u64,
+}
+
+/// Per-round shift amounts
+const S: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22,
+    5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20,
+    4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23,
+    6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+/// floor(abs(sin(i + 1)) * 2^32)
+const K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+impl Md5 {
+    pub fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            block: [0; 64],
+            block_len: 0,
+            total_len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len = self.total_len.wrapping_add(data.len() as u64);
+
+        while !data.is_empty() {
+            let take = core::cmp::min(64 - self.block_len, data.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&data[..take]);
+            self.block_len += take;
+            data = &data[take..];
+
+            if self.block_len == 64 {
+                let block = self.block;
+                self.compress(&block);
+                self.block_len = 0;
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; 16] {
+        let bit_len = self.total_len.wrapping_mul(8);
+
+        // 0x80, zeros up to 56 mod 64, then the message length in bits
+        self.update(&[0x80]);
+        while self.block_len != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_len.to_le_bytes());
+
+        let mut digest = [0; 16];
+        for (out, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+            out.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8; 64]) {
+        let mut m = [0u32; 16];
+        for (word, bytes) in m.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(K[i]).wrapping_add(m[g]).rotate_left(S[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+
+        self.state[0] = self.state[0].wrapping_add(a);
+        self.state[1] = self.state[1].wrapping_add(b);
+        self.state[2] = self.state[2].wrapping_add(c);
+        self.state[3] = self.state[3].wrapping_add(d);
+    }
+}
+
+impl Default for Md5 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// IPv4 pseudo-header of a `len` byte segment from `src` to `dst`
+pub fn pseudo_header_v4(src: &ffi::ip_addr_t, dst: &ffi::ip_addr_t, len: usize) -> Vec<u8> {
+    // Addresses are held in network byte order
+    let mut pseudo = Vec::with_capacity(12);
+    pseudo.extend_from_slice(&ip_addr::ip4(src).to_ne_bytes());
+    pseudo.extend_from_slice(&ip_addr::ip4(dst).to_ne_bytes());
+    pseudo.extend_from_slice(&[0, ffi::IP_PROTO_TCP as u8]);
+    pseudo.extend_from_slice(&(len as u16).to_be_bytes());
+    pseudo
+}
+
+/// IPv6 pseudo-header of a `len` byte segment from `src` to `dst`
+pub fn pseudo_header_v6(src: &[u32; 4], dst: &[u32; 4], len: usize) -> Vec<u8> {
+    let mut pseudo = Vec::with_capacity(40);
+    for word in src.iter().chain(dst.iter()) {
+        pseudo.extend_from_slice(&word.to_ne_bytes());
+    }
+    pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, ffi::IP_PROTO_TCP as u8]);
+    pseudo
+}
+
+/// Digest of `segment` (header, options and payload) under `key`
+///
+/// Options are left out and the checksum counts as zero, so the digest
+/// can be computed before either is final.
+pub fn digest(pseudo: &[u8], segment: &[u8], key: &[u8]) -> [u8; 16] {
+    let hdrlen = segment.get(12).map_or(0, |b| (b >> 4) as usize * 4);
+    let hdrlen = hdrlen.clamp(tcp_proto::TCP_HLEN, segment.len());
+
+    let mut header = [0; tcp_proto::TCP_HLEN];
+    header.copy_from_slice(&segment[..tcp_proto::TCP_HLEN]);
+    header[16] = 0;
+    header[17] = 0;
+
+    let mut md5 = Md5::new();
+    md5.update(pseudo);
+    md5.update(&header);
+    md5.update(&segment[hdrlen..]);
+    md5.update(key);
+    md5.finish()
+}
+
+/// The MD5 signature option (kind=19, len=18), led by two NOPs
+pub fn option(digest: &[u8; 16]) -> [u8; tcp_proto::TCP_OPT_LEN_MD5_PADDED] {
+    let mut opt = [0; tcp_proto::TCP_OPT_LEN_MD5_PADDED];
+    opt[..4].copy_from_slice(&[
+        tcp_proto::TCP_OPT_NOP,
+        tcp_proto::TCP_OPT_NOP,
+        tcp_proto::TCP_OPT_MD5,
+        tcp_proto::TCP_OPT_LEN_MD5 as u8,
+    ]);
+    opt[4..].copy_from_slice(digest);
+    opt
+}
+
+/// Offset of the digest carried in `segment`'s options, if any
+pub fn find_digest(segment: &[u8]) -> Option<usize> {
+    let hdrlen = (*segment.get(12)? >> 4) as usize * 4;
+    let opts = segment.get(tcp_proto::TCP_HLEN..hdrlen)?;
+
+    let mut i = 0;
+    while i < opts.len() {
+        match opts[i] {
+            tcp_proto::TCP_OPT_EOL => return None,
+            tcp_proto::TCP_OPT_NOP => {
+                i += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        let len = *opts.get(i + 1)? as usize;
+        if len < 2 || i + len > opts.len() {
+            return None;
+        }
+        if opts[i] == tcp_proto::TCP_OPT_MD5 && len == tcp_proto::TCP_OPT_LEN_MD5 {
+            return Some(tcp_proto::TCP_HLEN + i + 2);
+        }
+        i += len;
+    }
+    None
+}
+
+/// Does `segment` carry what `key` calls for?
+///
+/// With a key the segment must be signed with it; without one it must
+/// not be signed at all.
+pub fn verify(pseudo: &[u8], segment: &[u8], key: Option<&[u8]>) -> bool {
+    match (key, find_digest(segment)) {
+        (None, None) => true,
+        (Some(key), Some(at)) => segment[at..at + 16] == digest(pseudo, segment, key),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn md5(data: &[u8]) -> [u8; 16] {
+        let mut md5 = Md5::new();
+        md5.update(data);
+        md5.finish()
+    }
+
+    #[test]
+    fn test_md5_rfc1321_vectors() {
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(md5(b"message digest")), "f96b697d7cb7938d525a2f31aaf161d0");
+        assert_eq!(
+            hex(md5(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+
+    #[test]
+    fn test_md5_split_updates_match_one_shot() {
+        let data: Vec<u8> = (0..200u8).collect();
+        let mut split = Md5::new();
+        for chunk in data.chunks(7) {
+            split.update(chunk);
+        }
+        assert_eq!(split.finish(), md5(&data));
+    }
+
+    #[test]
+    fn test_digest_ignores_options_and_checksum() {
+        let pseudo = [10, 0, 0, 1, 10, 0, 0, 2, 0, 6, 0, 28];
+        let mut segment = vec![0u8; 28];
+        segment[12] = 6 << 4; // 24 byte header
+        segment[20..24].copy_from_slice(&[1, 1, 1, 1]);
+        segment[24..].copy_from_slice(b"data");
+        let key = b"secret";
+
+        let d = digest(&pseudo, &segment, key);
+
+        let mut expected = Md5::new();
+        expected.update(&pseudo);
+        expected.update(&segment[..20]);
+        expected.update(b"data");
+        expected.update(key);
+        assert_eq!(d, expected.finish());
+
+        segment[16] = 0xAB; // checksum
+        segment[20] = 0;    // options
+        assert_eq!(digest(&pseudo, &segment, key), d);
+        assert_ne!(digest(&pseudo, &segment, b"other"), d);
+    }
+}
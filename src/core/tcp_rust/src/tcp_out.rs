@@ -0,0 +1,1220 @@
+//! TCP Output Path
+//!
+//! Builds outgoing TCP segments and hands them to the IP layer.
+//! The TX path only reads connection state - any resulting state change
+//! is applied by the caller through the owning component's methods.
+
+use crate::components::ConnectionManagementState;
+use crate::ffi;
+use crate::ip_addr;
+use crate::state::TcpConnectionState;
+use crate::tcp_proto::{self, TcpHdr};
+use crate::tcp_types::{RstReply, SegmentDesc, TxSegment};
+#[cfg(feature = "md5")]
+use crate::tcp_md5;
+
+/// Local and remote address of a segment, in the family it goes out over
+#[derive(Clone, Copy)]
+pub enum IpPair {
+    V4(ffi::ip_addr_t, ffi::ip_addr_t),
+    V6([u32; 4], [u32; 4]), // Network byte order words
+}
+
+impl IpPair {
+    /// The connection's own endpoints
+    pub fn of(cm: &ConnectionManagementState) -> Self {
+        if cm.is_ip6() {
+            IpPair::V6(cm.local_ip6, cm.remote_ip6)
+        } else {
+            IpPair::V4(cm.local_ip, cm.remote_ip)
+        }
+    }
+
+    /// Endpoints as lwIP hands them over, IPv6 if `local` is
+    pub fn from_ip(local: &ffi::ip_addr_t, remote: &ffi::ip_addr_t) -> Self {
+        if ip_addr::is_v6(local) {
+            IpPair::V6(ip_addr::ip6(local), ip_addr::ip6(remote))
+        } else {
+            IpPair::V4(*local, *remote)
+        }
+    }
+}
+
+/// TCP Transmit Path
+pub struct TcpTx;
+
+impl TcpTx {
+    // ------------------------------------------------------------------------
+    // Handshake Segments
+    // ------------------------------------------------------------------------
+
+    /// Send a SYN (active open) carrying our MSS, SACK-permitted and
+    /// window scale options, with ECE and CWR set to ask for ECN
+    pub fn send_syn(state: &TcpConnectionState) -> Result<(), &'static str> {
+        Self::output(state, &Self::syn_segment(state)?)
+    }
+
+    /// The segment send_syn puts on the wire
+    pub fn syn_segment(state: &TcpConnectionState) -> Result<Vec<u8>, &'static str> {
+        let opts = Self::syn_options(state);
+        let flags = tcp_proto::TCP_SYN | tcp_proto::TCP_ECN_FLAGS;
+        Self::segment(state, state.rod.iss, 0, flags, &opts, &[])
+    }
+
+    /// Send a SYN+ACK (passive open) carrying our MSS option, and
+    /// SACK-permitted and window scale if the peer offered them. ECE
+    /// accepts the peer's ECN offer.
+    pub fn send_synack(state: &TcpConnectionState) -> Result<(), &'static str> {
+        Self::output(state, &Self::synack_segment(state)?)
+    }
+
+    /// The segment send_synack puts on the wire
+    pub fn synack_segment(state: &TcpConnectionState) -> Result<Vec<u8>, &'static str> {
+        let opts = Self::syn_options(state);
+        let mut flags = tcp_proto::TCP_SYN | tcp_proto::TCP_ACK;
+        if state.conn_mgmt.ecn_enabled {
+            flags |= tcp_proto::TCP_ECE;
+        }
+        Self::segment(state, state.rod.iss, state.rod.rcv_nxt, flags, &opts, &[])
+    }
+
+    // ------------------------------------------------------------------------
+    // Data Segments
+    // ------------------------------------------------------------------------
+
+    /// Plan segments for the unsent queue, at most `max_bytes` in total
+    ///
+    /// Each segment carries at most one effective MSS and never crosses a
+    /// pushed write boundary. Planning stops where the in-flight data would
+    /// exceed min(cwnd, snd_wnd); the segment ending a pushed write and the
+    /// last planned segment carry PSH.
+    ///
+    /// Sender SWS avoidance (RFC 1122, 4.2.3.4): a segment cut short by the
+    /// window is only sent if it is at least half the largest window the
+    /// peer has offered, or a pending FIN needs the data flushed.
+    ///
+    /// Nagle (RFC 896): a short segment at the end of the queue waits while
+    /// data is in flight, so small writes go out together on the next ACK.
+    /// TF_NODELAY turns this off.
+    ///
+    /// A pending FIN rides on the segment that drains the queue, or goes
+    /// out on its own if the queue is already empty.
+    pub fn segmentize(state: &TcpConnectionState, max_bytes: usize) -> Vec<SegmentDesc> {
+        let mut plan = Vec::new();
+
+        let wnd = core::cmp::min(state.cong_ctrl.window(), state.flow_ctrl.effective_snd_wnd()) as usize;
+        let in_flight = state.rod.snd_nxt.wrapping_sub(state.rod.lastack) as usize;
+        let mut budget = wnd.saturating_sub(in_flight).min(max_bytes);
+        let mss = state.conn_mgmt.eff_mss as usize;
+        let mut drained = true;
+        let mut chunks = state.rod.unsent.iter().peekable();
+
+        'queue: while let Some(chunk) = chunks.next() {
+            let mut offset = 0;
+            while offset < chunk.data.len() {
+                let rest = chunk.data.len() - offset;
+                let len = rest.min(mss).min(budget);
+                if len == 0 || (len < rest.min(mss) && !Self::sws_allows(state, len)) {
+                    drained = false;
+                    break 'queue;
+                }
+                let tail = len == rest && chunks.peek().is_none();
+                let unacked = in_flight > 0 || !plan.is_empty();
+                if len < mss && tail && unacked && !Self::nagle_allows(state) {
+                    drained = false;
+                    break 'queue;
+                }
+
+                let push = chunk.push && len == rest;
+                plan.push(SegmentDesc {
+                    seqno: chunk.seqno.wrapping_add(offset as u32),
+                    len,
+                    flags: if push { tcp_proto::TCP_ACK | tcp_proto::TCP_PSH } else { tcp_proto::TCP_ACK },
+                });
+                offset += len;
+                budget -= len;
+            }
+        }
+
+        if let Some(last) = plan.last_mut() {
+            last.flags |= tcp_proto::TCP_PSH;
+        }
+
+        if state.rod.fin_pending && drained {
+            match plan.last_mut() {
+                Some(last) => last.flags |= tcp_proto::TCP_FIN,
+                None => plan.push(SegmentDesc {
+                    seqno: state.rod.snd_lbb.wrapping_sub(1),
+                    len: 0,
+                    flags: tcp_proto::TCP_ACK | tcp_proto::TCP_FIN,
+                }),
+            }
+        }
+        plan
+    }
+
+    /// The new segment a duplicate ACK releases (RFC 3042), if any
+    ///
+    /// Each of the first two duplicates lets the flight exceed cwnd by one
+    /// more MSS; the peer's window still applies.
+    pub fn limited_transmit(state: &TcpConnectionState) -> Option<SegmentDesc> {
+        let chunk = state.rod.unsent.front()?;
+        let mss = state.conn_mgmt.eff_mss as usize;
+        let extra = state.rod.dupacks.min(2) as usize * mss;
+
+        let in_flight = state.rod.snd_nxt.wrapping_sub(state.rod.lastack) as usize;
+        let cwnd_room = (state.cong_ctrl.window() as usize + extra).saturating_sub(in_flight);
+        let wnd_room = (state.flow_ctrl.effective_snd_wnd() as usize).saturating_sub(in_flight);
+
+        let len = chunk.data.len().min(mss).min(cwnd_room).min(wnd_room);
+        if len == 0 {
+            return None;
+        }
+        Some(SegmentDesc { seqno: chunk.seqno, len, flags: tcp_proto::TCP_ACK | tcp_proto::TCP_PSH })
+    }
+
+    /// May a window-limited segment of `len` bytes go out?
+    fn sws_allows(state: &TcpConnectionState, len: usize) -> bool {
+        len >= (state.flow_ctrl.snd_wnd_max / 2) as usize
+            || state.conn_mgmt.flags & tcp_proto::TF_FIN != 0
+    }
+
+    /// May a short segment go out with data in flight?
+    ///
+    /// Yes with TF_NODELAY, in fast recovery, or once a FIN is queued
+    /// behind it: nothing more is coming to fill it.
+    fn nagle_allows(state: &TcpConnectionState) -> bool {
+        state.conn_mgmt.flags & (tcp_proto::TF_NODELAY | tcp_proto::TF_INFR | tcp_proto::TF_FIN) != 0
+    }
+
+    /// Send an empty ACK for everything received so far
+    ///
+    /// With SACK on, out-of-order data we hold is described in SACK blocks.
+    pub fn send_ack(state: &TcpConnectionState) -> Result<(), &'static str> {
+        Self::output(state, &Self::ack_segment(state)?)
+    }
+
+    /// The segment send_ack puts on the wire
+    pub fn ack_segment(state: &TcpConnectionState) -> Result<Vec<u8>, &'static str> {
+        let sack = Self::sack_option(state);
+        Self::segment(state, state.rod.snd_nxt, state.rod.rcv_nxt, tcp_proto::TCP_ACK, &sack, &[])
+    }
+
+    /// Send a data segment chosen by tcp_output
+    pub fn send_data(state: &TcpConnectionState, seg: &TxSegment) -> Result<(), &'static str> {
+        Self::output(state, &Self::data_segment(state, seg)?)
+    }
+
+    /// The segment send_data puts on the wire
+    pub fn data_segment(state: &TcpConnectionState, seg: &TxSegment) -> Result<Vec<u8>, &'static str> {
+        Self::segment(state, seg.seqno, state.rod.rcv_nxt, seg.flags, &[], &seg.data)
+    }
+
+    // ------------------------------------------------------------------------
+    // Retransmission
+    // ------------------------------------------------------------------------
+
+    /// Resend the oldest unacked segment
+    pub fn retransmit(state: &TcpConnectionState) -> Result<(), &'static str> {
+        let Some(bytes) = Self::rexmit_segment(state)? else {
+            return Ok(());
+        };
+        Self::send_to_ip(state, Self::to_pbuf(&bytes)?, true)?;
+        state.count(|s| s.retransmits = s.retransmits.wrapping_add(1));
+        Ok(())
+    }
+
+    /// The segment retransmit puts on the wire, if anything is unacked
+    pub fn rexmit_segment(state: &TcpConnectionState) -> Result<Option<Vec<u8>>, &'static str> {
+        let Some(seg) = state.rod.retransmit_candidate() else {
+            return Ok(None);
+        };
+
+        // A retransmitted SYN must carry the same options as the original
+        let syn_opts = Self::syn_options(state);
+        let opts: &[u8] = if seg.flags & tcp_proto::TCP_SYN != 0 { &syn_opts } else { &[] };
+
+        let ackno = if seg.flags & tcp_proto::TCP_ACK != 0 { state.rod.rcv_nxt } else { 0 };
+        Self::segment(state, seg.seqno, ackno, seg.flags, opts, &seg.data).map(Some)
+    }
+
+    /// Send the one-byte zero window probe queued by the persist timer
+    pub fn send_probe(state: &TcpConnectionState) -> Result<(), &'static str> {
+        match Self::probe_segment(state)? {
+            Some(bytes) => Self::output(state, &bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// The segment send_probe puts on the wire, if anything is unacked
+    pub fn probe_segment(state: &TcpConnectionState) -> Result<Option<Vec<u8>>, &'static str> {
+        let Some(seg) = state.rod.unacked.front() else {
+            return Ok(None);
+        };
+
+        let len = seg.data.len().min(1);
+        Self::segment(state, seg.seqno, state.rod.rcv_nxt, tcp_proto::TCP_ACK, &[], &seg.data[..len]).map(Some)
+    }
+
+    /// Send a keep-alive: an empty ACK one below snd_nxt, which the peer
+    /// must answer
+    pub fn send_keepalive(state: &TcpConnectionState) -> Result<(), &'static str> {
+        let seqno = state.rod.snd_nxt.wrapping_sub(1);
+        Self::output(state, &Self::segment(state, seqno, state.rod.rcv_nxt, tcp_proto::TCP_ACK, &[], &[])?)
+    }
+
+    // ------------------------------------------------------------------------
+    // Reset
+    // ------------------------------------------------------------------------
+
+    /// Send a RST to an endpoint we may hold no connection for
+    ///
+    /// Only the addressing and `ttl` are needed; the segment carries no
+    /// options and advertises no window. It goes out over the family of
+    /// `addrs`.
+    pub fn send_rst(
+        reply: &RstReply,
+        addrs: IpPair,
+        local_port: u16,
+        remote_port: u16,
+        ttl: u8,
+    ) -> Result<(), &'static str> {
+        let p = Self::to_pbuf(&Self::rst_segment(reply, addrs, local_port, remote_port))?;
+        let err = unsafe {
+            let err = match addrs {
+                IpPair::V4(local, remote) => {
+                    Self::ip4_output_to(&local, &remote, ttl, 0, tcp_proto::NETIF_NO_INDEX, p)
+                }
+                IpPair::V6(local, remote) => {
+                    Self::ip6_output_to(&local, &remote, ttl, 0, tcp_proto::NETIF_NO_INDEX, p)
+                }
+            };
+            ffi::pbuf_free(p);
+            err
+        };
+        Self::ip_result(err)
+    }
+
+    /// The segment send_rst puts on the wire
+    pub fn rst_segment(reply: &RstReply, addrs: IpPair, local_port: u16, remote_port: u16) -> Vec<u8> {
+        let flags = if reply.ack { tcp_proto::TCP_RST | tcp_proto::TCP_ACK } else { tcp_proto::TCP_RST };
+        let mut hdr = TcpHdr {
+            src: local_port.to_be(),
+            dest: remote_port.to_be(),
+            seqno: reply.seqno.to_be(),
+            ackno: reply.ackno.to_be(),
+            _hdrlen_rsvd_flags: 0,
+            wnd: 0,
+            chksum: 0,
+            urgp: 0,
+        };
+        hdr.set_hdrlen_flags((tcp_proto::TCP_HLEN / 4) as u16, flags);
+
+        let mut bytes = Self::hdr_bytes(&hdr).to_vec();
+        let chksum = Self::checksum_between(&addrs, &bytes);
+        bytes[16..18].copy_from_slice(&chksum.to_be_bytes());
+        bytes
+    }
+
+    // ------------------------------------------------------------------------
+    // Segment Construction
+    // ------------------------------------------------------------------------
+
+    /// Build a segment into a freshly allocated pbuf
+    ///
+    /// `options` is copied verbatim after the fixed header and padded with
+    /// EOL bytes up to a 4-byte boundary, followed by `payload`; the data
+    /// offset covers both. `payload` must already fit the negotiated MSS,
+    /// segmentize splits larger writes. The caller owns the returned pbuf.
+    pub fn send_segment(
+        state: &TcpConnectionState,
+        seqno: u32,
+        ackno: u32,
+        flags: u8,
+        options: &[u8],
+        payload: &[u8],
+    ) -> Result<*mut ffi::pbuf, &'static str> {
+        Self::to_pbuf(&Self::segment(state, seqno, ackno, flags, options, payload)?)
+    }
+
+    /// Serialize a segment whose payload fits the negotiated MSS
+    fn segment(
+        state: &TcpConnectionState,
+        seqno: u32,
+        ackno: u32,
+        flags: u8,
+        options: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, &'static str> {
+        if payload.len() > state.conn_mgmt.eff_mss as usize {
+            return Err("Payload exceeds the MSS");
+        }
+        Self::build_segment(state, seqno, ackno, flags, options, payload)
+    }
+
+    /// Copy serialized segment bytes into a freshly allocated pbuf
+    fn to_pbuf(bytes: &[u8]) -> Result<*mut ffi::pbuf, &'static str> {
+        unsafe {
+            let p = ffi::pbuf_alloc(
+                ffi::pbuf_layer_PBUF_TRANSPORT,
+                bytes.len() as u16,
+                ffi::pbuf_type_PBUF_RAM,
+            );
+            if p.is_null() {
+                return Err("Out of memory allocating segment");
+            }
+            if ((*p).len as usize) < bytes.len() {
+                ffi::pbuf_free(p);
+                return Err("Segment does not fit its pbuf");
+            }
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), (*p).payload as *mut u8, bytes.len());
+            Ok(p)
+        }
+    }
+
+    /// Window to put in the header: never scaled on a SYN (RFC 7323, 2.2)
+    fn window_field(state: &TcpConnectionState, flags: u8) -> u16 {
+        if flags & tcp_proto::TCP_SYN != 0 {
+            state.flow_ctrl.rcv_ann_wnd.min(u16::MAX as u32) as u16
+        } else {
+            state.flow_ctrl.advertised_window()
+        }
+    }
+
+    /// Serialize header + options + payload into wire format, with the checksum filled in
+    ///
+    /// Once timestamps are on, every segment but a RST also carries the
+    /// timestamp option after `options`. With an MD5 key set, the
+    /// signature option goes last. While congestion experienced is being
+    /// echoed, every ACK but the SYN-ACK carries ECE.
+    pub fn build_segment(
+        state: &TcpConnectionState,
+        seqno: u32,
+        ackno: u32,
+        flags: u8,
+        options: &[u8],
+        payload: &[u8],
+    ) -> Result<Vec<u8>, &'static str> {
+        let with_ts;
+        let options = if state.conn_mgmt.flags & tcp_proto::TF_TIMESTAMP != 0 && flags & tcp_proto::TCP_RST == 0 {
+            let now = unsafe { crate::tcp_ticks };
+            with_ts = [options, &Self::ts_option(now, state.rod.ts_recent)].concat();
+            &with_ts[..]
+        } else {
+            options
+        };
+
+        #[cfg(feature = "md5")]
+        let with_md5;
+        #[cfg(feature = "md5")]
+        let options = if state.conn_mgmt.md5_key.is_some() {
+            // Signed below, once the rest of the segment is in place
+            with_md5 = [options, &tcp_md5::option(&[0; 16])].concat();
+            &with_md5[..]
+        } else {
+            options
+        };
+
+        if options.len() > tcp_proto::TCP_MAX_OPTION_BYTES {
+            return Err("TCP options too long");
+        }
+
+        let echo_ce = state.conn_mgmt.flags & tcp_proto::TF_ECN_ECHO != 0
+            && flags & tcp_proto::TCP_ACK != 0
+            && flags & (tcp_proto::TCP_SYN | tcp_proto::TCP_RST) == 0;
+        let flags = if echo_ce { flags | tcp_proto::TCP_ECE } else { flags };
+
+        // Options are padded to a multiple of 32 bits
+        let optlen = (options.len() + 3) & !3;
+        let hdrlen = tcp_proto::TCP_HLEN + optlen;
+
+        let mut hdr = TcpHdr {
+            src: state.conn_mgmt.local_port.to_be(),
+            dest: state.conn_mgmt.remote_port.to_be(),
+            seqno: seqno.to_be(),
+            ackno: ackno.to_be(),
+            _hdrlen_rsvd_flags: 0,
+            wnd: Self::window_field(state, flags).to_be(),
+            chksum: 0,
+            urgp: 0,
+        };
+        hdr.set_hdrlen_flags((hdrlen / 4) as u16, flags);
+
+        if hdrlen + payload.len() > u16::MAX as usize {
+            return Err("Segment too large");
+        }
+
+        let mut bytes = Vec::with_capacity(hdrlen + payload.len());
+        bytes.extend_from_slice(Self::hdr_bytes(&hdr));
+        bytes.extend_from_slice(options);
+        bytes.resize(hdrlen, tcp_proto::TCP_OPT_EOL);
+        bytes.extend_from_slice(payload);
+
+        #[cfg(feature = "md5")]
+        if let Some(key) = &state.conn_mgmt.md5_key {
+            let pseudo = Self::md5_pseudo_header(state, bytes.len());
+            let digest = tcp_md5::digest(&pseudo, &bytes, key);
+            if let Some(at) = tcp_md5::find_digest(&bytes) {
+                bytes[at..at + 16].copy_from_slice(&digest);
+            }
+        }
+
+        let chksum = Self::checksum(state, &bytes);
+        bytes[16..18].copy_from_slice(&chksum.to_be_bytes());
+
+        Ok(bytes)
+    }
+
+    /// Build the MSS option (kind=2, len=4)
+    pub fn mss_option(mss: u16) -> [u8; tcp_proto::TCP_OPT_LEN_MSS] {
+        let mss = mss.to_be_bytes();
+        [tcp_proto::TCP_OPT_MSS, tcp_proto::TCP_OPT_LEN_MSS as u8, mss[0], mss[1]]
+    }
+
+    /// Options for a SYN or SYN+ACK: MSS, then SACK-permitted while TF_SACK
+    /// is set (timestamps are added by build_segment)
+    pub fn syn_options(state: &TcpConnectionState) -> Vec<u8> {
+        let mut opts = Self::mss_option(state.conn_mgmt.mss).to_vec();
+        if state.conn_mgmt.flags & tcp_proto::TF_SACK != 0 {
+            opts.extend_from_slice(&[
+                tcp_proto::TCP_OPT_NOP,
+                tcp_proto::TCP_OPT_NOP,
+                tcp_proto::TCP_OPT_SACK_PERM,
+                tcp_proto::TCP_OPT_LEN_SACK_PERM as u8,
+            ]);
+        }
+        let cm = &state.conn_mgmt;
+        if cm.flags & tcp_proto::TF_WND_SCALE != 0 && cm.window_scale_fits() {
+            opts.extend_from_slice(&[
+                tcp_proto::TCP_OPT_NOP,
+                tcp_proto::TCP_OPT_WS,
+                tcp_proto::TCP_OPT_LEN_WS as u8,
+                tcp_proto::TCP_RCV_SCALE,
+            ]);
+        }
+        opts
+    }
+
+    /// Build the SACK option (kind=5) from the ooseq queue, led by two NOPs
+    ///
+    /// At most 3 blocks, fewer if the timestamp and MD5 options leave no
+    /// room within the 40 option bytes. Empty if there is nothing to report
+    /// or no block fits.
+    pub fn sack_option(state: &TcpConnectionState) -> Vec<u8> {
+        if state.conn_mgmt.flags & tcp_proto::TF_SACK == 0 || state.rod.ooseq.is_empty() {
+            return Vec::new();
+        }
+
+        let mut room = tcp_proto::TCP_MAX_OPTION_BYTES - 4;
+        if state.conn_mgmt.flags & tcp_proto::TF_TIMESTAMP != 0 {
+            room -= tcp_proto::TCP_OPT_LEN_TS_PADDED;
+        }
+        #[cfg(feature = "md5")]
+        if state.conn_mgmt.md5_key.is_some() {
+            room -= tcp_proto::TCP_OPT_LEN_MD5_PADDED;
+        }
+        let blocks = state.rod.ooseq.sack_blocks((room / 8).min(3));
+        if blocks.is_empty() {
+            return Vec::new();
+        }
+
+        let mut opt = vec![
+            tcp_proto::TCP_OPT_NOP,
+            tcp_proto::TCP_OPT_NOP,
+            tcp_proto::TCP_OPT_SACK,
+            (2 + 8 * blocks.len()) as u8,
+        ];
+        for (left, right) in blocks {
+            opt.extend_from_slice(&left.to_be_bytes());
+            opt.extend_from_slice(&right.to_be_bytes());
+        }
+        opt
+    }
+
+    /// Build the timestamp option (kind=8, len=10), led by two NOPs for
+    /// alignment (RFC 7323, appendix A)
+    pub fn ts_option(tsval: u32, tsecr: u32) -> [u8; tcp_proto::TCP_OPT_LEN_TS_PADDED] {
+        let mut opt = [0; tcp_proto::TCP_OPT_LEN_TS_PADDED];
+        opt[..4].copy_from_slice(&[
+            tcp_proto::TCP_OPT_NOP,
+            tcp_proto::TCP_OPT_NOP,
+            tcp_proto::TCP_OPT_TS,
+            tcp_proto::TCP_OPT_LEN_TS as u8,
+        ]);
+        opt[4..8].copy_from_slice(&tsval.to_be_bytes());
+        opt[8..].copy_from_slice(&tsecr.to_be_bytes());
+        opt
+    }
+
+    // ------------------------------------------------------------------------
+    // IP Layer Interface
+    // ------------------------------------------------------------------------
+
+    /// Put serialized segment bytes in a pbuf and hand it to the IP layer
+    fn output(state: &TcpConnectionState, bytes: &[u8]) -> Result<(), &'static str> {
+        Self::send_to_ip(state, Self::to_pbuf(bytes)?, false)
+    }
+
+    /// Hand a finished segment to the IP layer
+    ///
+    /// Takes ownership of `p` - it is freed on every path. `rexmit` marks
+    /// a retransmission, see ip_tos.
+    fn send_to_ip(state: &TcpConnectionState, p: *mut ffi::pbuf, rexmit: bool) -> Result<(), &'static str> {
+        let (err, payload_len) = unsafe {
+            let payload_len = Self::payload_len(p);
+            let err = Self::ip_output(state, p, rexmit);
+            ffi::pbuf_free(p);
+            (err, payload_len)
+        };
+
+        Self::ip_result(err)?;
+        state.count(|s| {
+            s.segs_sent = s.segs_sent.wrapping_add(1);
+            s.bytes_sent = s.bytes_sent.wrapping_add(payload_len);
+        });
+        Ok(())
+    }
+
+    /// The IP layer's err_t as a result
+    fn ip_result(err: i8) -> Result<(), &'static str> {
+        match err {
+            crate::ERR_OK => Ok(()),
+            crate::ERR_RTE => Err("No route to host"),
+            _ => Err("IP output failed"),
+        }
+    }
+
+    /// Route and output `p` over the connection's address family,
+    /// returning lwIP's err_t
+    unsafe fn ip_output(state: &TcpConnectionState, p: *mut ffi::pbuf, rexmit: bool) -> i8 {
+        if state.conn_mgmt.is_ip6() {
+            Self::ip6_output(state, p, rexmit)
+        } else {
+            Self::ip4_output(state, p, rexmit)
+        }
+    }
+
+    /// Route and output `p` over IPv4
+    unsafe fn ip4_output(state: &TcpConnectionState, p: *mut ffi::pbuf, rexmit: bool) -> i8 {
+        let cm = &state.conn_mgmt;
+        let tos = Self::ip_tos(state, p, rexmit);
+        Self::ip4_output_to(&cm.local_ip, &cm.remote_ip, cm.ttl, tos, cm.netif_idx, p)
+    }
+
+    /// Route and output `p` from `src` to `dst` over IPv4, through
+    /// interface `netif_idx` if one is bound
+    ///
+    /// ip4_output_if does not take ownership of `p`.
+    #[cfg(lwip_ipv4)]
+    unsafe fn ip4_output_to(
+        src: &ffi::ip_addr_t,
+        dst: &ffi::ip_addr_t,
+        ttl: u8,
+        tos: u8,
+        netif_idx: u8,
+        p: *mut ffi::pbuf,
+    ) -> i8 {
+        let src = ip_addr::ip_2_ip4(src);
+        let dst = ip_addr::ip_2_ip4(dst);
+
+        let netif = Self::route(netif_idx, || ffi::ip4_route(dst));
+        if netif.is_null() {
+            return crate::ERR_RTE;
+        }
+
+        ffi::ip4_output_if(p, src, dst, ttl, tos, ffi::IP_PROTO_TCP as u8, netif)
+    }
+
+    /// No IPv4 layer compiled in: nothing can be routed
+    #[cfg(not(lwip_ipv4))]
+    unsafe fn ip4_output_to(
+        _src: &ffi::ip_addr_t,
+        _dst: &ffi::ip_addr_t,
+        _ttl: u8,
+        _tos: u8,
+        _netif_idx: u8,
+        _p: *mut ffi::pbuf,
+    ) -> i8 {
+        crate::ERR_RTE
+    }
+
+    /// Route and output `p` over IPv6
+    unsafe fn ip6_output(state: &TcpConnectionState, p: *mut ffi::pbuf, rexmit: bool) -> i8 {
+        let cm = &state.conn_mgmt;
+        let tos = Self::ip_tos(state, p, rexmit);
+        Self::ip6_output_to(&cm.local_ip6, &cm.remote_ip6, cm.ttl, tos, cm.netif_idx, p)
+    }
+
+    /// Route and output `p` from `src` to `dst` over IPv6, through
+    /// interface `netif_idx` if one is bound; hop limit and traffic class
+    /// come from `ttl` and `tos`
+    ///
+    /// ip6_output_if does not take ownership of `p`. The test shim always
+    /// provides it, so the v6 path is built for tests too.
+    #[cfg(any(lwip_ipv6, test))]
+    unsafe fn ip6_output_to(
+        src: &[u32; 4],
+        dst: &[u32; 4],
+        ttl: u8,
+        tos: u8,
+        netif_idx: u8,
+        p: *mut ffi::pbuf,
+    ) -> i8 {
+        let src = ffi::ip6_addr_t { addr: *src, ..Default::default() };
+        let dst = ffi::ip6_addr_t { addr: *dst, ..Default::default() };
+
+        let netif = Self::route(netif_idx, || ffi::ip6_route(&src, &dst));
+        if netif.is_null() {
+            return crate::ERR_RTE;
+        }
+
+        ffi::ip6_output_if(p, &src, &dst, ttl, tos, ffi::IP_PROTO_TCP as u8, netif)
+    }
+
+    /// No IPv6 layer compiled in: nothing can be routed
+    #[cfg(not(any(lwip_ipv6, test)))]
+    unsafe fn ip6_output_to(
+        _src: &[u32; 4],
+        _dst: &[u32; 4],
+        _ttl: u8,
+        _tos: u8,
+        _netif_idx: u8,
+        _p: *mut ffi::pbuf,
+    ) -> i8 {
+        crate::ERR_RTE
+    }
+
+    /// Interface for a segment: the bound one `netif_idx` if any (lwIP's
+    /// tcp_route), else whatever `ip_route` picks. Null when there is
+    /// none, including a bound interface since removed.
+    unsafe fn route(netif_idx: u8, ip_route: impl FnOnce() -> *mut ffi::netif) -> *mut ffi::netif {
+        if netif_idx != tcp_proto::NETIF_NO_INDEX {
+            ffi::netif_get_by_index(netif_idx)
+        } else {
+            ip_route()
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    // Helpers
+    // ------------------------------------------------------------------------
+
+    /// TOS byte for `p`: segments carrying data are ECN-capable once ECN
+    /// is negotiated. Pure ACKs (RFC 3168 §6.1.4) and retransmissions
+    /// (§6.1.5) stay Not-ECT.
+    unsafe fn ip_tos(state: &TcpConnectionState, p: *mut ffi::pbuf, rexmit: bool) -> u8 {
+        if state.conn_mgmt.ecn_enabled && !rexmit && Self::payload_len(p) > 0 {
+            (state.conn_mgmt.tos & !tcp_proto::IP_ECN_MASK) | tcp_proto::IP_ECN_ECT0
+        } else {
+            state.conn_mgmt.tos
+        }
+    }
+
+    /// Data bytes following the TCP header in segment `p`
+    unsafe fn payload_len(p: *mut ffi::pbuf) -> u32 {
+        let bytes = core::slice::from_raw_parts((*p).payload as *const u8, (*p).len as usize);
+        let hdrlen = bytes.get(12).map_or(0, |b| (b >> 4) as usize * 4);
+        bytes.len().saturating_sub(hdrlen) as u32
+    }
+
+    /// Pseudo-header the MD5 signature covers, for a `len` byte segment
+    #[cfg(feature = "md5")]
+    fn md5_pseudo_header(state: &TcpConnectionState, len: usize) -> Vec<u8> {
+        let cm = &state.conn_mgmt;
+        if cm.is_ip6() {
+            tcp_md5::pseudo_header_v6(&cm.local_ip6, &cm.remote_ip6, len)
+        } else {
+            tcp_md5::pseudo_header_v4(&cm.local_ip, &cm.remote_ip, len)
+        }
+    }
+
+    fn hdr_bytes(hdr: &TcpHdr) -> &[u8] {
+        // TcpHdr is repr(C, packed) and already in network byte order
+        unsafe {
+            core::slice::from_raw_parts(hdr as *const TcpHdr as *const u8, tcp_proto::TCP_HLEN)
+        }
+    }
+
+    /// Internet checksum over the pseudo-header for the connection's
+    /// address family (RFC 793 for IPv4, RFC 8200 §8.1 for IPv6) and `segment`
+    fn checksum(state: &TcpConnectionState, segment: &[u8]) -> u16 {
+        Self::checksum_between(&IpPair::of(&state.conn_mgmt), segment)
+    }
+
+    /// Checksum of `segment` between `addrs`, over their family's pseudo-header
+    fn checksum_between(addrs: &IpPair, segment: &[u8]) -> u16 {
+        match addrs {
+            IpPair::V4(src, dst) => Self::checksum_ip4(src, dst, segment),
+            IpPair::V6(src, dst) => Self::checksum_ip6(src, dst, segment),
+        }
+    }
+
+    /// Checksum of `segment` from `src` to `dst` over IPv4
+    fn checksum_ip4(src: &ffi::ip_addr_t, dst: &ffi::ip_addr_t, segment: &[u8]) -> u16 {
+        // Addresses are held in network byte order
+        let mut acc = 0;
+        Self::sum(&mut acc, &ip_addr::ip4(src).to_ne_bytes());
+        Self::sum(&mut acc, &ip_addr::ip4(dst).to_ne_bytes());
+        Self::sum(&mut acc, &[0, ffi::IP_PROTO_TCP as u8]);
+        Self::sum(&mut acc, &(segment.len() as u16).to_be_bytes());
+        Self::sum(&mut acc, segment);
+        Self::fold(acc)
+    }
+
+    /// Checksum of `segment` from `src` to `dst` over IPv6
+    fn checksum_ip6(src: &[u32; 4], dst: &[u32; 4], segment: &[u8]) -> u16 {
+        let mut acc = 0;
+        for word in src.iter().chain(dst.iter()) {
+            Self::sum(&mut acc, &word.to_ne_bytes());
+        }
+        Self::sum(&mut acc, &(segment.len() as u32).to_be_bytes());
+        Self::sum(&mut acc, &[0, 0, 0, ffi::IP_PROTO_TCP as u8]);
+        Self::sum(&mut acc, segment);
+        Self::fold(acc)
+    }
+
+    /// Add `bytes` to a one's complement sum as 16-bit big-endian words
+    fn sum(acc: &mut u32, bytes: &[u8]) {
+        for chunk in bytes.chunks(2) {
+            let hi = chunk[0] as u32;
+            let lo = if chunk.len() > 1 { chunk[1] as u32 } else { 0 };
+            *acc += (hi << 8) | lo;
+        }
+    }
+
+    /// Fold the carries of a one's complement sum and complement it
+    fn fold(mut acc: u32) -> u16 {
+        while acc >> 16 != 0 {
+            acc = (acc & 0xFFFF) + (acc >> 16);
+        }
+        !(acc as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::TcpState;
+
+    fn syn_sent_state() -> TcpConnectionState {
+        let mut state = TcpConnectionState::new();
+        state.conn_mgmt.local_ip = ip_addr::from_ip4(0x0100a8c0);
+        state.conn_mgmt.remote_ip = ip_addr::from_ip4(0x0200a8c0);
+        state.conn_mgmt.local_port = 0x101;
+        state.conn_mgmt.remote_port = 0x100;
+        state.conn_mgmt.state = TcpState::SynSent;
+        state.conn_mgmt.mss = 1460;
+        state.rod.iss = 6510;
+        state.flow_ctrl.rcv_ann_wnd = 4096;
+        state
+    }
+
+    unsafe fn pbuf_bytes<'a>(p: *mut ffi::pbuf) -> &'a [u8] {
+        core::slice::from_raw_parts((*p).payload as *const u8, (*p).len as usize)
+    }
+
+    #[test]
+    fn test_syn_carries_mss_option() {
+        let state = syn_sent_state();
+        let opts = TcpTx::mss_option(state.conn_mgmt.mss);
+        let p = TcpTx::send_segment(&state, state.rod.iss, 0, tcp_proto::TCP_SYN, &opts, &[]).unwrap();
+
+        unsafe {
+            assert_eq!((*p).tot_len, 24);
+            let bytes = pbuf_bytes(p);
+
+            // Data offset is 6 words
+            assert_eq!(bytes[12] >> 4, 6);
+            assert_eq!(bytes[13], tcp_proto::TCP_SYN);
+
+            // MSS option: kind=2, len=4, value=1460
+            assert_eq!(&bytes[20..24], &[2, 4, 0x05, 0xB4]);
+
+            ffi::pbuf_free(p);
+        }
+    }
+
+    #[test]
+    fn test_data_offset_covers_options_before_payload() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.state = TcpState::Established;
+        let nops = [tcp_proto::TCP_OPT_NOP; 3];
+        let payload: Vec<u8> = (0..50).collect();
+        let p = TcpTx::send_segment(&state, 6511, 1, tcp_proto::TCP_ACK, &nops, &payload).unwrap();
+
+        unsafe {
+            assert_eq!((*p).tot_len, 20 + 4 + 50);
+            let bytes = pbuf_bytes(p);
+
+            // Three NOPs padded with an EOL make one option word
+            assert_eq!(bytes[12] >> 4, 6);
+            assert_eq!(&bytes[20..24], &[1, 1, 1, 0]);
+            assert_eq!(&bytes[24..], &payload[..]);
+
+            ffi::pbuf_free(p);
+        }
+    }
+
+    #[test]
+    fn test_payload_beyond_mss_is_refused() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.eff_mss = 100;
+
+        let p = TcpTx::send_segment(&state, 6511, 1, tcp_proto::TCP_ACK, &[], &[0; 100]).unwrap();
+        unsafe { ffi::pbuf_free(p) };
+        assert!(TcpTx::send_segment(&state, 6511, 1, tcp_proto::TCP_ACK, &[], &[0; 101]).is_err());
+    }
+
+    #[test]
+    fn test_syn_offers_sack_permitted() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.flags |= tcp_proto::TF_SACK;
+        let bytes = TcpTx::build_segment(&state, state.rod.iss, 0, tcp_proto::TCP_SYN, &TcpTx::syn_options(&state), &[]).unwrap();
+
+        let seg = crate::tcp_types::TcpSegment::parse(&bytes).unwrap();
+        assert_eq!(seg.options.mss, Some(1460));
+        assert!(seg.options.sack_perm);
+    }
+
+    #[test]
+    fn test_advertised_window_scaled_except_on_syn() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.flags |= tcp_proto::TF_WND_SCALE;
+        state.flow_ctrl.rcv_scale = 7;
+        state.flow_ctrl.rcv_ann_wnd = 1 << 20;
+
+        let syn = TcpTx::build_segment(&state, state.rod.iss, 0, tcp_proto::TCP_SYN, &TcpTx::syn_options(&state), &[]).unwrap();
+        let syn = crate::tcp_types::TcpSegment::parse(&syn).unwrap();
+        assert_eq!(syn.options.ws, Some(tcp_proto::TCP_RCV_SCALE));
+        assert_eq!(syn.wnd, u16::MAX);
+
+        let ack = TcpTx::build_segment(&state, state.rod.iss + 1, 1, tcp_proto::TCP_ACK, &[], &[]).unwrap();
+        let ack = crate::tcp_types::TcpSegment::parse(&ack).unwrap();
+        assert_eq!(ack.wnd, 8192);
+    }
+
+    #[test]
+    fn test_dup_ack_carries_sack_blocks() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.state = TcpState::Established;
+        state.conn_mgmt.flags |= tcp_proto::TF_SACK | tcp_proto::TF_TIMESTAMP;
+        state.rod.rcv_nxt = 1001;
+
+        // Two holes: [1101, 1201) arrived first, then [1401, 1501)
+        state.rod.ooseq.insert(1101, &[0; 100], false);
+        state.rod.ooseq.insert(1401, &[0; 100], false);
+        ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+
+        assert_eq!(TcpTx::send_ack(&state), Ok(()));
+
+        let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        let seg = crate::tcp_types::TcpSegment::parse(&sent).unwrap();
+        assert_eq!(seg.ackno, 1001);
+        assert_eq!(seg.options.sack_blocks(), &[(1401, 1501), (1101, 1201)]);
+        assert!(seg.options.ts.is_some());
+        assert!(sent.len() <= tcp_proto::TCP_HLEN + tcp_proto::TCP_MAX_OPTION_BYTES);
+    }
+
+    #[test]
+    fn test_sack_option_limited_to_three_blocks() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.flags |= tcp_proto::TF_SACK | tcp_proto::TF_TIMESTAMP;
+        state.rod.rcv_nxt = 1001;
+        for start in [1101, 1301, 1501, 1701] {
+            state.rod.ooseq.insert(start, &[0; 100], false);
+        }
+
+        let opt = TcpTx::sack_option(&state);
+        assert_eq!(opt.len(), 4 + 3 * 8);
+
+        // Newest first; the oldest range no longer fits
+        let blocks = crate::tcp_types::TcpOptions::parse(&opt);
+        assert_eq!(blocks.sack_blocks(), &[(1701, 1801), (1501, 1601), (1301, 1401)]);
+    }
+
+    #[cfg(feature = "md5")]
+    #[test]
+    fn test_sack_option_omitted_when_no_block_fits() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.flags |= tcp_proto::TF_SACK | tcp_proto::TF_TIMESTAMP;
+        state.conn_mgmt.set_md5_key(b"secret").unwrap();
+        state.rod.rcv_nxt = 1001;
+        state.rod.ooseq.insert(1101, &[0; 100], false);
+
+        // Timestamps and the signature leave 4 bytes, too few for a block
+        assert!(TcpTx::sack_option(&state).is_empty());
+    }
+
+    #[test]
+    fn test_options_padded_to_word_boundary() {
+        let state = syn_sent_state();
+        let opts = [tcp_proto::TCP_OPT_NOP; 3];
+        let bytes = TcpTx::build_segment(&state, 1, 0, tcp_proto::TCP_ACK, &opts, &[]).unwrap();
+
+        assert_eq!(bytes.len(), 24);
+        assert_eq!(bytes[12] >> 4, 6);
+        assert_eq!(&bytes[20..24], &[1, 1, 1, tcp_proto::TCP_OPT_EOL]);
+    }
+
+    #[test]
+    fn test_timestamp_option_round_trips() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.flags |= tcp_proto::TF_TIMESTAMP;
+        state.rod.ts_recent = 77;
+        let opts = TcpTx::mss_option(state.conn_mgmt.mss);
+        let bytes = TcpTx::build_segment(&state, state.rod.iss, 0, tcp_proto::TCP_SYN, &opts, &[]).unwrap();
+
+        // MSS, then NOP NOP and the 10-byte timestamp option
+        assert_eq!(bytes[12] >> 4, 9);
+        assert_eq!(&bytes[24..28], &[1, 1, 8, 10]);
+        let seg = crate::tcp_types::TcpSegment::parse(&bytes).unwrap();
+        assert_eq!(seg.options.mss, Some(1460));
+        assert_eq!(seg.options.ts.map(|(_, tsecr)| tsecr), Some(77));
+
+        // A RST never carries it
+        let bytes = TcpTx::build_segment(&state, 1, 0, tcp_proto::TCP_RST, &[], &[]).unwrap();
+        assert_eq!(bytes.len(), tcp_proto::TCP_HLEN);
+    }
+
+    #[test]
+    fn test_checksum_covers_options() {
+        let state = syn_sent_state();
+        let opts = TcpTx::mss_option(state.conn_mgmt.mss);
+        let bytes = TcpTx::build_segment(&state, state.rod.iss, 0, tcp_proto::TCP_SYN, &opts, &[]).unwrap();
+
+        // Re-summing the segment including its checksum must yield zero
+        let mut zeroed = bytes.clone();
+        zeroed[16] = 0;
+        zeroed[17] = 0;
+        let expected = TcpTx::checksum(&state, &zeroed);
+        assert_eq!(u16::from_be_bytes([bytes[16], bytes[17]]), expected);
+        assert_eq!(TcpTx::checksum(&state, &bytes), 0);
+    }
+
+    #[test]
+    fn test_send_to_ip_outputs_segment() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.state = TcpState::Established;
+        state.rod.snd_nxt = 6511;
+        state.rod.rcv_nxt = 1001;
+        ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+
+        assert_eq!(TcpTx::send_ack(&state), Ok(()));
+
+        let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        assert_eq!(sent.len(), 20);
+        assert_eq!(sent[13], tcp_proto::TCP_ACK);
+        assert_eq!(u32::from_be_bytes([sent[8], sent[9], sent[10], sent[11]]), 1001);
+    }
+
+    #[test]
+    fn test_send_to_ip_without_route() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.remote_ip = ip_addr::from_ip4(0);
+        let p = TcpTx::send_segment(&state, 1, 0, tcp_proto::TCP_ACK, &[], &[]).unwrap();
+
+        assert_eq!(unsafe { TcpTx::ip_output(&state, p, false) }, crate::ERR_RTE);
+        unsafe { ffi::pbuf_free(p) };
+
+        let p = TcpTx::send_segment(&state, 1, 0, tcp_proto::TCP_ACK, &[], &[]).unwrap();
+        assert_eq!(TcpTx::send_to_ip(&state, p, false), Err("No route to host"));
+    }
+
+    #[test]
+    #[cfg(lwip_ipv4)]
+    fn test_bound_netif_overrides_routing() {
+        let mut state = syn_sent_state();
+        ffi::IP_OUTPUT_NETIF.with(|out| out.borrow_mut().clear());
+
+        // Routing picks netif 0; bound to index 2 it must be netif 1
+        state.conn_mgmt.on_bind_netif(2);
+        assert_eq!(TcpTx::send_ack(&state), Ok(()));
+        state.conn_mgmt.on_bind_netif(tcp_proto::NETIF_NO_INDEX);
+        assert_eq!(TcpTx::send_ack(&state), Ok(()));
+        assert_eq!(ffi::IP_OUTPUT_NETIF.with(|out| out.borrow_mut().split_off(0)), [1, 0]);
+
+        // An interface that is gone is no route, whatever ip4_route says
+        state.conn_mgmt.on_bind_netif(7);
+        assert_eq!(TcpTx::send_ack(&state), Err("No route to host"));
+    }
+
+    fn ip6_state() -> TcpConnectionState {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.state = TcpState::Established;
+        state.conn_mgmt.ip_type = tcp_proto::IPADDR_TYPE_V6;
+        state.conn_mgmt.local_ip = ip_addr::from_ip4(0);
+        state.conn_mgmt.remote_ip = ip_addr::from_ip4(0);
+        // 2001:db8::1 -> 2001:db8::2
+        state.conn_mgmt.local_ip6 = [u32::from_be(0x20010db8), 0, 0, u32::from_be(1)];
+        state.conn_mgmt.remote_ip6 = [u32::from_be(0x20010db8), 0, 0, u32::from_be(2)];
+        state
+    }
+
+    #[test]
+    fn test_ip6_pcb_outputs_through_ip6() {
+        let state = ip6_state();
+        ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+        ffi::IP6_OUTPUT.with(|out| out.borrow_mut().clear());
+
+        assert_eq!(TcpTx::send_ack(&state), Ok(()));
+
+        assert!(ffi::IP_OUTPUT.with(|out| out.borrow().is_empty()));
+        let sent = ffi::IP6_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        assert_eq!(sent[13], tcp_proto::TCP_ACK);
+    }
+
+    #[test]
+    fn test_ip6_checksum_uses_ip6_pseudo_header() {
+        let state = ip6_state();
+        let bytes = TcpTx::build_segment(&state, 1, 2, tcp_proto::TCP_ACK, &[], b"data").unwrap();
+        assert_eq!(TcpTx::checksum(&state, &bytes), 0);
+
+        // The same segment does not verify against the IPv4 pseudo-header
+        let mut v4 = ip6_state();
+        v4.conn_mgmt.ip_type = tcp_proto::IPADDR_TYPE_V4;
+        assert_ne!(TcpTx::checksum(&v4, &bytes), 0);
+    }
+
+    #[test]
+    fn test_rst_reply_to_segment_without_ack() {
+        use crate::tcp_types::TcpSegment;
+        // SYN with 3 bytes of data from 192.168.0.2:256 to port 257
+        let peer = syn_sent_state();
+        let bytes = TcpTx::build_segment(&peer, 5000, 0, tcp_proto::TCP_SYN, &[], b"abc").unwrap();
+        let seg = TcpSegment::parse(&bytes).unwrap();
+
+        let reply = seg.rst_reply();
+        assert_eq!(reply, RstReply { seqno: 0, ackno: 5004, ack: true });
+
+        let local = ip_addr::from_ip4(0x0200a8c0);
+        let remote = ip_addr::from_ip4(0x0100a8c0);
+        ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+        assert_eq!(TcpTx::send_rst(&reply, IpPair::V4(local, remote), 0x100, 0x101, 64), Ok(()));
+
+        let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        let rst = TcpSegment::parse(&sent).unwrap();
+        assert_eq!(sent[13], tcp_proto::TCP_RST | tcp_proto::TCP_ACK);
+        assert_eq!((rst.seqno, rst.ackno, rst.wnd), (0, 5004, 0));
+        assert_eq!(rst.tcphdr_len as usize, tcp_proto::TCP_HLEN);
+        assert_eq!(rst.payload_len, 0);
+        assert_eq!(u16::from_be_bytes([sent[0], sent[1]]), 0x100);
+        assert_eq!(u16::from_be_bytes([sent[2], sent[3]]), 0x101);
+
+        // Checksummed over the reply's own addresses
+        let mut answering = syn_sent_state();
+        answering.conn_mgmt.local_ip = local;
+        answering.conn_mgmt.remote_ip = remote;
+        assert_eq!(TcpTx::checksum(&answering, &sent), 0);
+    }
+
+    #[test]
+    fn test_rst_reply_to_ack_uses_its_ackno() {
+        use crate::tcp_types::TcpSegment;
+        let peer = syn_sent_state();
+        let bytes = TcpTx::build_segment(&peer, 5000, 777, tcp_proto::TCP_ACK, &[], b"abc").unwrap();
+        let reply = TcpSegment::parse(&bytes).unwrap().rst_reply();
+        assert_eq!(reply, RstReply { seqno: 777, ackno: 0, ack: false });
+
+        ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+        let local = ip_addr::from_ip4(0x0200a8c0);
+        let remote = ip_addr::from_ip4(0x0100a8c0);
+        TcpTx::send_rst(&reply, IpPair::V4(local, remote), 0x100, 0x101, 64).unwrap();
+
+        let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        let rst = TcpSegment::parse(&sent).unwrap();
+        assert_eq!(sent[13], tcp_proto::TCP_RST);
+        assert_eq!((rst.seqno, rst.ackno), (777, 0));
+    }
+
+    #[test]
+    fn test_rst_goes_out_over_ip6() {
+        let state = ip6_state();
+        let reply = RstReply { seqno: 777, ackno: 5004, ack: true };
+        ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+        ffi::IP6_OUTPUT.with(|out| out.borrow_mut().clear());
+        let addrs = IpPair::of(&state.conn_mgmt);
+        assert_eq!(TcpTx::send_rst(&reply, addrs, 0x100, 0x101, 64), Ok(()));
+
+        assert!(ffi::IP_OUTPUT.with(|out| out.borrow().is_empty()));
+        let sent = ffi::IP6_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        assert_eq!(sent[13], tcp_proto::TCP_RST | tcp_proto::TCP_ACK);
+        // Checksummed over the IPv6 pseudo-header
+        assert_eq!(TcpTx::checksum(&state, &sent), 0);
+    }
+
+    #[test]
+    fn test_ip6_without_route() {
+        let mut state = ip6_state();
+        state.conn_mgmt.remote_ip6 = [0; 4];
+        let p = TcpTx::send_segment(&state, 1, 0, tcp_proto::TCP_ACK, &[], &[]).unwrap();
+        assert_eq!(TcpTx::send_to_ip(&state, p, false), Err("No route to host"));
+    }
+
+    #[test]
+    fn test_probe_sends_one_byte() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.state = TcpState::Established;
+        state.rod.rcv_nxt = 1001;
+        state.rod.unacked.push(crate::components::UnackedSegment::new(6511, tcp_proto::TCP_ACK, vec![0x42]));
+        ffi::IP_OUTPUT.with(|out| out.borrow_mut().clear());
+
+        assert_eq!(TcpTx::send_probe(&state), Ok(()));
+
+        let sent = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        assert_eq!(sent.len(), 21);
+        assert_eq!(u32::from_be_bytes([sent[4], sent[5], sent[6], sent[7]]), 6511);
+        assert_eq!(sent[20], 0x42);
+    }
+
+    #[test]
+    fn test_retransmitted_data_is_not_ect() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.state = TcpState::Established;
+        state.conn_mgmt.ecn_enabled = true;
+        state.rod.rcv_nxt = 1001;
+        let seg = TxSegment { seqno: 6511, flags: tcp_proto::TCP_ACK, data: vec![0x42; 10] };
+        state.rod.unacked.push(crate::components::UnackedSegment::new(seg.seqno, seg.flags, seg.data.clone()));
+        ffi::IP_OUTPUT_TOS.with(|out| out.borrow_mut().clear());
+
+        TcpTx::send_data(&state, &seg).unwrap();
+        TcpTx::retransmit(&state).unwrap();
+        TcpTx::send_ack(&state).unwrap();
+
+        let tos = ffi::IP_OUTPUT_TOS.with(|out| core::mem::take(&mut *out.borrow_mut()));
+        assert_eq!(tos, [tcp_proto::IP_ECN_ECT0, 0, 0]);
+    }
+
+    #[test]
+    fn test_handshake_segments_carry_ecn_setup() {
+        let mut state = syn_sent_state();
+        TcpTx::send_syn(&state).unwrap();
+        let syn = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        assert_eq!(syn[13], tcp_proto::TCP_SYN | tcp_proto::TCP_ECE | tcp_proto::TCP_CWR);
+
+        state.conn_mgmt.state = TcpState::SynRcvd;
+        state.conn_mgmt.ecn_enabled = true;
+        TcpTx::send_synack(&state).unwrap();
+        let synack = ffi::IP_OUTPUT.with(|out| out.borrow_mut().pop()).unwrap();
+        assert_eq!(synack[13], tcp_proto::TCP_SYN | tcp_proto::TCP_ACK | tcp_proto::TCP_ECE);
+    }
+
+    #[test]
+    fn test_synack_acks_peer_syn() {
+        let mut state = syn_sent_state();
+        state.conn_mgmt.state = TcpState::SynRcvd;
+        state.rod.rcv_nxt = 1001;
+        let opts = TcpTx::mss_option(state.conn_mgmt.mss);
+        let bytes = TcpTx::build_segment(
+            &state,
+            state.rod.iss,
+            state.rod.rcv_nxt,
+            tcp_proto::TCP_SYN | tcp_proto::TCP_ACK,
+            &opts,
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]), 1001);
+        assert_eq!(&bytes[20..22], &[tcp_proto::TCP_OPT_MSS, 4]);
+    }
+}
@@ -5,6 +5,17 @@
 /// TCP header length (excluding options)
 pub const TCP_HLEN: usize = 20;
 
+/// IPv4 header length (excluding options), used for MSS calculations
+pub const IP_HLEN: usize = 20;
+
+/// IPv6 fixed header length
+pub const IP6_HLEN: usize = 40;
+
+/// Address families (lwIP's enum lwip_ip_addr_type)
+pub const IPADDR_TYPE_V4: u8 = 0;
+pub const IPADDR_TYPE_V6: u8 = 6;
+pub const IPADDR_TYPE_ANY: u8 = 46;
+
 /// TCP header flags
 pub const TCP_FIN: u8 = 0x01;
 pub const TCP_SYN: u8 = 0x02;
@@ -15,10 +26,139 @@ pub const TCP_URG: u8 = 0x20;
 pub const TCP_ECE: u8 = 0x40;
 pub const TCP_CWR: u8 = 0x80;
 pub const TCP_FLAGS: u8 = 0x3F;
+pub const TCP_ECN_FLAGS: u8 = TCP_ECE | TCP_CWR;
+
+/// ECN field of the IP TOS / traffic class byte (RFC 3168)
+pub const IP_ECN_MASK: u8 = 0x03;
+pub const IP_ECN_ECT0: u8 = 0x02;     // ECN-capable transport
+pub const IP_ECN_CE: u8 = 0x03;       // Congestion experienced
 
 /// Maximum TCP option bytes
 pub const TCP_MAX_OPTION_BYTES: usize = 40;
 
+/// Default buffer sizes (lwIP opt.h defaults)
+pub const TCP_MSS: u16 = 536;
+pub const TCP_SND_BUF: u16 = 2 * TCP_MSS;
+pub const TCP_WND: u16 = 4096;          // Receive buffer we advertise
+
+/// Window scaling (RFC 7323, 2)
+pub const TCP_RCV_SCALE: u8 = 0;     // Shift we ask the peer to apply to our windows
+pub const TCP_MAX_WND_SCALE: u8 = 14; // Largest shift either side may use
+
+/// ISS step between connection incarnations (BSD's TCP_ISSINCR)
+pub const TCP_ISS_INCR: u32 = 64000;
+
+/// netif_idx of a PCB not bound to an interface (lwIP's NETIF_NO_INDEX)
+pub const NETIF_NO_INDEX: u8 = 0;
+
+/// Time to live of outgoing segments (lwIP's TCP_TTL)
+pub const TCP_TTL: u8 = 255;
+
+/// PCB priorities (lwIP's TCP_PRIO_*); under memory pressure a new PCB
+/// may evict a connection of lower priority
+pub const TCP_PRIO_MIN: u8 = 1;
+pub const TCP_PRIO_NORMAL: u8 = 64;
+pub const TCP_PRIO_MAX: u8 = 127;
+
+/// Listen backlog when none is given (lwIP's TCP_DEFAULT_LISTEN_BACKLOG)
+pub const TCP_DEFAULT_LISTEN_BACKLOG: u8 = 0xff;
+
+/// Local ports handed out for tcp_bind(port 0) and unbound connects
+/// (lwIP's TCP_LOCAL_PORT_RANGE_START/END, the IANA dynamic range)
+pub const TCP_LOCAL_PORT_RANGE_START: u16 = 0xc000;
+pub const TCP_LOCAL_PORT_RANGE_END: u16 = 0xffff;
+
+/// tcp_write apiflags (lwIP's TCP_WRITE_FLAG_*)
+pub const TCP_WRITE_FLAG_COPY: u8 = 0x01; // Data is always copied here
+pub const TCP_WRITE_FLAG_MORE: u8 = 0x02; // More data follows: no PSH yet
+
+/// Upper bound on out-of-order data held for reassembly
+pub const TCP_OOSEQ_MAX_BYTES: usize = 4 * TCP_MSS as usize;
+
+/// RFC 5961 challenge ACKs allowed per window, shared by all PCBs
+pub const TCP_CHALLENGE_ACK_LIMIT: u32 = 10;
+pub const TCP_CHALLENGE_ACK_WINDOW: u32 = 2; // Slow timer ticks (1 s)
+
+/// Socket option bits for so_options (lwIP's SOF_*)
+pub const SOF_REUSEADDR: u8 = 0x04;   // Allow local address reuse
+pub const SOF_KEEPALIVE: u8 = 0x08;   // Keep connection alive
+pub const SOF_BROADCAST: u8 = 0x20;   // Permit sending of broadcast msgs
+pub const SOF_INHERITED: u8 = SOF_REUSEADDR | SOF_KEEPALIVE; // Passed from listener to children
+
+/// Congestion control algorithms selectable at PCB creation
+pub const TCP_CC_RENO: u8 = 0;
+pub const TCP_CC_CUBIC: u8 = 1;
+
+/// Appropriate Byte Counting (RFC 3465): slow start grows cwnd by at most
+/// this many MSS per ACK
+pub const TCP_ABC_LIMIT: u16 = 2;
+
+/// Timer intervals in milliseconds
+pub const TCP_TMR_INTERVAL: u32 = 250;  // tcp_tmr() period
+pub const TCP_FAST_INTERVAL: u32 = TCP_TMR_INTERVAL;
+pub const TCP_SLOW_INTERVAL: u32 = 2 * TCP_TMR_INTERVAL;
+
+/// Retransmission limits
+pub const TCP_MAXRTX: u8 = 12;          // Data segment retransmissions before abort
+pub const TCP_SYNMAXRTX: u8 = 6;        // SYN retransmissions before abort
+pub const TCP_RTO_INITIAL: u32 = 1000;  // RTO before the first RTT sample (ms, RFC 6298)
+pub const TCP_RTO_MIN: u32 = 200;       // Lower bound for the computed RTO (ms)
+pub const TCP_RTO_MAX: u32 = 60000;     // Upper bound for the backed-off RTO (ms)
+
+/// Maximum segment lifetime (ms); TIME_WAIT lasts 2*MSL
+pub const TCP_MSL: u32 = 60000;
+pub const TCP_TIME_WAIT_TICKS: u32 = 2 * TCP_MSL / TCP_SLOW_INTERVAL;
+
+/// Slow ticks between zero window probes, one entry per backoff step
+/// (lwIP's tcp_persist_backoff); the last entry caps the backoff
+pub const TCP_PERSIST_BACKOFF: [u8; 7] = [3, 6, 12, 24, 48, 96, 120];
+
+/// PCB flags (tcpflags_t), values match lwIP's tcp.h
+pub const TF_ACK_DELAY: u16 = 0x01;   // Delayed ACK
+pub const TF_ACK_NOW: u16 = 0x02;     // Immediate ACK
+pub const TF_INFR: u16 = 0x04;        // In fast recovery
+pub const TF_CLOSEPEND: u16 = 0x08;   // tcp_close failed to enqueue the FIN
+pub const TF_RXCLOSED: u16 = 0x10;    // rx closed by tcp_shutdown
+pub const TF_FIN: u16 = 0x20;         // Connection was closed locally (FIN enqueued)
+pub const TF_NODELAY: u16 = 0x40;     // Disable Nagle algorithm
+pub const TF_NAGLEMEMERR: u16 = 0x80; // Nagle enabled, memerr, try to output
+pub const TF_WND_SCALE: u16 = 0x0100; // Window Scale option enabled
+pub const TF_BACKLOGPEND: u16 = 0x0200; // Connection increased the backlog on its listener
+pub const TF_TIMESTAMP: u16 = 0x0400; // Timestamp option enabled
+pub const TF_RTO: u16 = 0x0800;       // RTO timer fired, in-flight data being retransmitted
+pub const TF_SACK: u16 = 0x1000;      // Selective ACKs enabled
+pub const TF_ECN_ECHO: u16 = 0x2000;  // CE seen: set ECE on ACKs until the peer sends CWR
+pub const TF_ECN_CWR: u16 = 0x4000;   // Window reduced for ECE: set CWR on the next data segment
+
+/// pbuf flags, values match lwIP's pbuf.h
+pub const PBUF_FLAG_PUSH: u8 = 0x01; // Last pbuf of data the sender pushed
+
+/// TCP option kinds
+pub const TCP_OPT_EOL: u8 = 0;
+pub const TCP_OPT_NOP: u8 = 1;
+pub const TCP_OPT_MSS: u8 = 2;
+pub const TCP_OPT_WS: u8 = 3;
+pub const TCP_OPT_SACK_PERM: u8 = 4;
+pub const TCP_OPT_SACK: u8 = 5;
+pub const TCP_OPT_TS: u8 = 8;
+pub const TCP_OPT_MD5: u8 = 19;
+
+/// TCP option lengths
+pub const TCP_OPT_LEN_MSS: usize = 4;
+pub const TCP_OPT_LEN_WS: usize = 3;
+pub const TCP_OPT_LEN_WS_PADDED: usize = 4; // NOP, window scale
+pub const TCP_OPT_LEN_TS: usize = 10;
+pub const TCP_OPT_LEN_TS_PADDED: usize = 12; // NOP, NOP, timestamps
+pub const TCP_OPT_LEN_SACK_PERM: usize = 2;
+pub const TCP_OPT_LEN_MD5: usize = 18;
+pub const TCP_OPT_LEN_MD5_PADDED: usize = 20; // NOP, NOP, signature
+
+/// Longest TCP-MD5 key accepted (as in Linux's TCP_MD5SIG_MAXKEYLEN)
+pub const TCP_MD5_MAX_KEY_LEN: usize = 80;
+
+/// SACK blocks that fit in the option space (RFC 2018: 4, or 3 next to timestamps)
+pub const TCP_SACK_MAX_BLOCKS: usize = 4;
+
 /// TCP Header Structure
 ///
 /// Fields are in network byte order (big-endian).
@@ -77,6 +217,12 @@ impl TcpHdr {
         (u16::from_be(self._hdrlen_rsvd_flags) & TCP_FLAGS as u16) as u8
     }
 
+    /// Get the ECE and CWR bits, which TCP_FLAGS masks off
+    #[inline]
+    pub fn ecn_flags(&self) -> u8 {
+        (u16::from_be(self._hdrlen_rsvd_flags) & TCP_ECN_FLAGS as u16) as u8
+    }
+
     /// Set header length (in 32-bit words)
     ///
     /// Equivalent to C macro: TCPH_HDRLEN_SET(phdr, len)
@@ -5,7 +5,7 @@
 use crate::tcp_proto;
 
 /// TCP Flags from the header
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct TcpFlags {
     pub fin: bool,
     pub syn: bool,
@@ -13,6 +13,8 @@ pub struct TcpFlags {
     pub psh: bool,
     pub ack: bool,
     pub urg: bool,
+    pub ece: bool,
+    pub cwr: bool,
 }
 
 impl TcpFlags {
@@ -24,11 +26,89 @@ impl TcpFlags {
             psh: (flags & tcp_proto::TCP_PSH) != 0,
             ack: (flags & tcp_proto::TCP_ACK) != 0,
             urg: (flags & tcp_proto::TCP_URG) != 0,
+            ece: (flags & tcp_proto::TCP_ECE) != 0,
+            cwr: (flags & tcp_proto::TCP_CWR) != 0,
         }
     }
 }
 
+/// TCP options carried in a segment header
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TcpOptions {
+    pub mss: Option<u16>,
+    pub ts: Option<(u32, u32)>, // (TSval, TSecr)
+    pub ws: Option<u8>,         // Window scale shift, only valid on a SYN
+    pub sack_perm: bool,        // SACK-permitted, only valid on a SYN
+    pub sack: [(u32, u32); tcp_proto::TCP_SACK_MAX_BLOCKS], // (left, right) edges
+    pub sack_len: u8,           // Blocks used in `sack`
+}
+
+impl TcpOptions {
+    /// Parse the options area of a TCP header (the bytes after the fixed 20)
+    ///
+    /// Unknown options are skipped using their length byte. A malformed
+    /// length ends parsing, keeping whatever was read so far.
+    pub fn parse(opts: &[u8]) -> Self {
+        let mut parsed = Self::default();
+        let mut i = 0;
+
+        while i < opts.len() {
+            match opts[i] {
+                tcp_proto::TCP_OPT_EOL => break,
+                tcp_proto::TCP_OPT_NOP => {
+                    i += 1;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let Some(&len) = opts.get(i + 1) else { break };
+            let len = len as usize;
+            if len < 2 || i + len > opts.len() {
+                break;
+            }
+            let data = &opts[i + 2..i + len];
+
+            match (opts[i], len) {
+                (tcp_proto::TCP_OPT_MSS, tcp_proto::TCP_OPT_LEN_MSS) => {
+                    parsed.mss = Some(u16::from_be_bytes([data[0], data[1]]));
+                }
+                (tcp_proto::TCP_OPT_WS, tcp_proto::TCP_OPT_LEN_WS) => {
+                    // RFC 7323, 2.3: larger shifts are taken as 14
+                    parsed.ws = Some(data[0].min(tcp_proto::TCP_MAX_WND_SCALE));
+                }
+                (tcp_proto::TCP_OPT_TS, tcp_proto::TCP_OPT_LEN_TS) => {
+                    let tsval = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                    let tsecr = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+                    parsed.ts = Some((tsval, tsecr));
+                }
+                (tcp_proto::TCP_OPT_SACK_PERM, tcp_proto::TCP_OPT_LEN_SACK_PERM) => {
+                    parsed.sack_perm = true;
+                }
+                (tcp_proto::TCP_OPT_SACK, _) if data.len().is_multiple_of(8) => {
+                    for block in data.chunks_exact(8).take(tcp_proto::TCP_SACK_MAX_BLOCKS) {
+                        let left = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+                        let right = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+                        parsed.sack[parsed.sack_len as usize] = (left, right);
+                        parsed.sack_len += 1;
+                    }
+                }
+                _ => {}
+            }
+            i += len;
+        }
+
+        parsed
+    }
+
+    /// SACK blocks carried by the segment, in option order
+    pub fn sack_blocks(&self) -> &[(u32, u32)] {
+        &self.sack[..self.sack_len as usize]
+    }
+}
+
 /// Parsed TCP segment information
+#[derive(Default)]
 pub struct TcpSegment {
     pub seqno: u32,
     pub ackno: u32,
@@ -36,6 +116,174 @@ pub struct TcpSegment {
     pub wnd: u16,
     pub tcphdr_len: u16,
     pub payload_len: u16,
+    pub options: TcpOptions,
+    pub payload: Vec<u8>,  // Segment data, if the caller keeps it
+    pub ce: bool,          // IP header carried Congestion Experienced
+}
+
+impl TcpSegment {
+    /// Parse a raw TCP segment (header, options and payload)
+    ///
+    /// The checksum is not verified here.
+    pub fn parse(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.len() < tcp_proto::TCP_HLEN {
+            return Err("Segment shorter than a TCP header");
+        }
+
+        let hdr = unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const tcp_proto::TcpHdr) };
+        let hdrlen = hdr.hdrlen_bytes() as usize;
+        if hdrlen < tcp_proto::TCP_HLEN || hdrlen > bytes.len() {
+            return Err("Invalid TCP header length");
+        }
+
+        let payload = &bytes[hdrlen..];
+        Ok(Self {
+            seqno: hdr.sequence_number(),
+            ackno: hdr.ack_number(),
+            flags: TcpFlags::from_tcphdr(hdr.flags() | hdr.ecn_flags()),
+            wnd: hdr.window(),
+            tcphdr_len: hdrlen as u16,
+            payload_len: payload.len() as u16,
+            options: TcpOptions::parse(&bytes[tcp_proto::TCP_HLEN..hdrlen]),
+            payload: payload.to_vec(),
+            ce: false,
+        })
+    }
+
+    /// The RST answering this segment when nobody may receive it (RFC 793, p. 36)
+    ///
+    /// An ACK is answered from its ackno; anything else gets RST+ACK for
+    /// all it occupies. A RST itself must never be answered, callers drop it.
+    pub fn rst_reply(&self) -> RstReply {
+        if self.flags.ack {
+            RstReply { seqno: self.ackno, ackno: 0, ack: false }
+        } else {
+            let seg_len = self.payload_len as u32 + self.flags.syn as u32 + self.flags.fin as u32;
+            RstReply { seqno: 0, ackno: self.seqno.wrapping_add(seg_len), ack: true }
+        }
+    }
+
+    /// What the segment asks of the state machine, from its flags and payload
+    ///
+    /// RST outranks SYN, SYN outranks FIN, and FIN outranks a plain ACK;
+    /// the flags a class leaves out are ignored by every state.
+    pub fn class(&self) -> SegmentClass {
+        let f = &self.flags;
+        match (f.rst, f.syn, f.fin, f.ack) {
+            (true, ..) => SegmentClass::Rst,
+            (false, true, _, false) => SegmentClass::Syn,
+            (false, true, _, true) => SegmentClass::SynAck,
+            (false, false, true, false) => SegmentClass::Fin,
+            (false, false, true, true) => SegmentClass::FinAck,
+            (false, false, false, true) if self.payload_len > 0 => SegmentClass::DataAck,
+            (false, false, false, true) => SegmentClass::PureAck,
+            (false, false, false, false) => SegmentClass::NoAck,
+        }
+    }
+}
+
+/// Segment classified for the input dispatcher, see TcpSegment::class
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentClass {
+    Rst,     // Reset, whatever else is set
+    Syn,     // Connection request
+    SynAck,  // Answer to our SYN
+    Fin,     // FIN without an ACK
+    FinAck,  // FIN acknowledging our data, maybe carrying its own
+    DataAck, // Data with an ACK
+    PureAck, // ACK alone: window update, duplicate or ACK of our data
+    NoAck,   // None of the control flags, with or without data
+}
+
+impl SegmentClass {
+    /// Carries an ACK worth processing
+    pub fn has_ack(self) -> bool {
+        matches!(self, Self::SynAck | Self::FinAck | Self::DataAck | Self::PureAck)
+    }
+
+    pub fn has_fin(self) -> bool {
+        matches!(self, Self::Fin | Self::FinAck)
+    }
+}
+
+/// Sequence numbers of a RST sent without a connection, see TcpTx::send_rst
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RstReply {
+    pub seqno: u32,
+    pub ackno: u32,
+    pub ack: bool, // RST+ACK acknowledging `ackno`
+}
+
+/// Planned data segment: a slice of the send queue, see TcpTx::segmentize
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentDesc {
+    pub seqno: u32,
+    pub len: usize,
+    pub flags: u8,
+}
+
+/// A data segment chosen for transmission by tcp_output
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxSegment {
+    pub seqno: u32,
+    pub flags: u8,
+    pub data: Vec<u8>,
+}
+
+/// Error returned by the TCP API
+///
+/// Each variant stands for one lwIP err_t, see `to_err_t`. Failures inside
+/// a state component still carry their message in `Component`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpError {
+    BadState,                // Not allowed in the current state
+    AlreadyConnected,        // Connection already open or opening
+    NotConnected,            // Needs a synchronized connection
+    Closed,                  // Sending was shut down locally
+    NotBound,                // Needs a local port first
+    InvalidArgument,         // Bad parameter from the caller
+    NoMemory,                // Out of buffers or queue space
+    PortInUse,               // Local address and port already taken
+    Component(&'static str), // A state component refused the event
+}
+
+impl TcpError {
+    /// The lwIP err_t reported to C callers
+    pub fn to_err_t(self) -> i8 {
+        match self {
+            TcpError::BadState => crate::ERR_VAL,
+            TcpError::AlreadyConnected => crate::ERR_ISCONN,
+            TcpError::NotConnected => crate::ERR_CONN,
+            TcpError::Closed => crate::ERR_CLSD,
+            TcpError::NotBound => crate::ERR_VAL,
+            TcpError::InvalidArgument => crate::ERR_ARG,
+            TcpError::NoMemory => crate::ERR_MEM,
+            TcpError::PortInUse => crate::ERR_USE,
+            TcpError::Component(_) => crate::ERR_VAL,
+        }
+    }
+}
+
+impl From<&'static str> for TcpError {
+    fn from(msg: &'static str) -> Self {
+        TcpError::Component(msg)
+    }
+}
+
+impl core::fmt::Display for TcpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TcpError::BadState => f.write_str("operation not allowed in this state"),
+            TcpError::AlreadyConnected => f.write_str("already connected"),
+            TcpError::NotConnected => f.write_str("not connected"),
+            TcpError::Closed => f.write_str("connection closed for sending"),
+            TcpError::NotBound => f.write_str("not bound to a local port"),
+            TcpError::InvalidArgument => f.write_str("invalid argument"),
+            TcpError::NoMemory => f.write_str("out of memory"),
+            TcpError::PortInUse => f.write_str("address in use"),
+            TcpError::Component(msg) => f.write_str(msg),
+        }
+    }
 }
 
 /// RST validation result (RFC 5961)
@@ -56,6 +304,15 @@ pub enum AckValidation {
     Old,     // ACK for already acknowledged data
 }
 
+/// Where received data landed in the sequence space
+#[derive(Debug, PartialEq)]
+pub enum DataAction {
+    InOrder,     // Advanced rcv_nxt
+    OutOfOrder,  // Queued beyond a gap - send a duplicate ACK
+    Duplicate,   // Already received - re-ACK so the peer stops resending
+    Refused,     // No room in our window - re-ACK with the window we have
+}
+
 /// Action to take after processing input
 #[derive(Debug, PartialEq)]
 pub enum InputAction {
@@ -65,5 +322,110 @@ pub enum InputAction {
     SendSynAck,  // For handshake
     SendChallengeAck,
     SendRst,
+    Retransmit,  // Fast retransmit of the oldest unacked segment
+    LimitedTransmit, // First or second dup ACK: one new segment may go out (RFC 3042)
     Abort,  // For aborting connection
 }
+
+/// What a close left for tcp_output to send
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseAction {
+    None,        // Already closing or closed, or only the receive side shut
+    SendFin,     // Nothing queued: the FIN can go right away
+    FinQueued,   // The FIN waits behind unsent data
+}
+
+/// Action to take after running the slow timer on a connection
+#[derive(Debug, PartialEq)]
+pub enum TimerAction {
+    None,
+    SendAck,     // Delayed ACK is due
+    Retransmit,  // RTO expired - resend the oldest unacked segment
+    Probe,       // Peer window is zero - send a one-byte probe
+    KeepAlive,   // Connection idle - check the peer is still there
+    Abort,       // Too many retransmissions - connection is dead
+    Expired,     // 2MSL elapsed in TIME_WAIT - connection is closed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(flags: u8, payload_len: u16) -> SegmentClass {
+        TcpSegment { flags: TcpFlags::from_tcphdr(flags), payload_len, ..Default::default() }.class()
+    }
+
+    #[test]
+    fn test_segment_classes() {
+        use tcp_proto::{TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN};
+
+        assert_eq!(classify(TCP_RST, 0), SegmentClass::Rst);
+        assert_eq!(classify(TCP_RST | TCP_ACK, 0), SegmentClass::Rst);
+        assert_eq!(classify(TCP_RST | TCP_SYN | TCP_FIN, 0), SegmentClass::Rst);
+        assert_eq!(classify(TCP_SYN, 0), SegmentClass::Syn);
+        assert_eq!(classify(TCP_SYN | TCP_FIN, 0), SegmentClass::Syn);
+        assert_eq!(classify(TCP_SYN | TCP_ACK, 0), SegmentClass::SynAck);
+        assert_eq!(classify(TCP_FIN, 0), SegmentClass::Fin);
+        assert_eq!(classify(TCP_FIN | TCP_ACK, 10), SegmentClass::FinAck);
+        assert_eq!(classify(TCP_ACK | TCP_PSH, 10), SegmentClass::DataAck);
+        assert_eq!(classify(TCP_ACK, 0), SegmentClass::PureAck);
+        assert_eq!(classify(TCP_PSH, 10), SegmentClass::NoAck);
+        assert_eq!(classify(0, 0), SegmentClass::NoAck);
+    }
+
+    #[test]
+    fn test_rst_reply_numbers() {
+        use tcp_proto::{TCP_ACK, TCP_FIN, TCP_SYN};
+        let seg = |flags: u8, payload_len: u16| TcpSegment {
+            seqno: 5000,
+            ackno: 777,
+            flags: TcpFlags::from_tcphdr(flags),
+            payload_len,
+            ..Default::default()
+        };
+
+        // An ACK is answered from its ackno, RST alone
+        assert_eq!(seg(TCP_ACK, 100).rst_reply(), RstReply { seqno: 777, ackno: 0, ack: false });
+        // Otherwise RST+ACK for everything the segment occupies
+        assert_eq!(seg(TCP_SYN, 0).rst_reply(), RstReply { seqno: 0, ackno: 5001, ack: true });
+        assert_eq!(seg(TCP_SYN | TCP_FIN, 100).rst_reply(), RstReply { seqno: 0, ackno: 5102, ack: true });
+    }
+
+    #[test]
+    fn test_parse_two_sack_blocks() {
+        // NOP, NOP, SACK with blocks [1000, 1500) and [2000, 2600)
+        let opts = [
+            1, 1, 5, 18,
+            0, 0, 0x03, 0xE8, 0, 0, 0x05, 0xDC,
+            0, 0, 0x07, 0xD0, 0, 0, 0x0A, 0x28,
+        ];
+        let parsed = TcpOptions::parse(&opts);
+
+        assert_eq!(parsed.sack_blocks(), &[(1000, 1500), (2000, 2600)]);
+        assert!(!parsed.sack_perm);
+    }
+
+    #[test]
+    fn test_parse_sack_permitted_next_to_mss() {
+        let opts = [2, 4, 0x05, 0xB4, 1, 1, 4, 2];
+        let parsed = TcpOptions::parse(&opts);
+
+        assert_eq!(parsed.mss, Some(1460));
+        assert!(parsed.sack_perm);
+        assert!(parsed.sack_blocks().is_empty());
+    }
+
+    #[test]
+    fn test_parse_window_scale_capped_at_14() {
+        assert_eq!(TcpOptions::parse(&[1, 3, 3, 7]).ws, Some(7));
+        assert_eq!(TcpOptions::parse(&[1, 3, 3, 20]).ws, Some(14));
+        assert_eq!(TcpOptions::parse(&[2, 4, 0x05, 0xB4]).ws, None);
+    }
+
+    #[test]
+    fn test_parse_malformed_sack_ignored() {
+        // Length not a whole number of blocks
+        let opts = [5, 6, 0, 0, 0, 1];
+        assert!(TcpOptions::parse(&opts).sack_blocks().is_empty());
+    }
+}
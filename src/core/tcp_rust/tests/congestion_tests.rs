@@ -0,0 +1,556 @@
+//! TCP Congestion Control Tests
+//!
+//! Drive ACK sequences through tcp_input on an established connection, or run
+//! the slow timer, and check the congestion response: window growth,
+//! fast retransmit, fast recovery and retransmission timeouts.
+
+mod test_helpers;
+
+use test_helpers::*;
+use lwip_tcp_rust::{
+    tcp_input, tcp_limited_transmit, tcp_output, tcp_slowtmr_pcb, tcp_write, InputAction, TcpFlags, TcpSegment,
+    TimerAction,
+};
+use lwip_tcp_rust::components::{
+    CongestionControl, CongestionWindow, CubicCongestionControl, RenoCongestionControl, UnackedSegment,
+};
+use lwip_tcp_rust::state::{TcpConnectionState, TcpState};
+use lwip_tcp_rust::tcp_out::TcpTx;
+use lwip_tcp_rust::tcp_proto;
+use lwip_tcp_rust::ffi;
+
+const MSS: u32 = 536;
+
+/// Established connection with `segments` full-sized segments in flight
+fn established_in_flight(segments: u32) -> TcpConnectionState {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+
+    for _ in 0..segments {
+        let seg = UnackedSegment::new(state.rod.snd_nxt, tcp_proto::TCP_ACK, vec![0; MSS as usize]);
+        state.rod.on_segment_sent(seg, 1).unwrap();
+    }
+    state
+}
+
+fn ack_seg(state: &TcpConnectionState, ackno: u32) -> TcpSegment {
+    TcpSegment {
+        seqno: state.rod.rcv_nxt,
+        ackno,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    }
+}
+
+fn input(state: &mut TcpConnectionState, seg: &TcpSegment) -> InputAction {
+    tcp_input(state, seg, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT).unwrap()
+}
+
+// ============================================================================
+// Fast Retransmit / Fast Recovery
+// ============================================================================
+
+#[test]
+fn test_three_dupacks_trigger_fast_retransmit() {
+    let mut state = established_in_flight(4);
+    let lastack = state.rod.lastack;
+    let dup = ack_seg(&state, lastack);
+
+    assert_eq!(input(&mut state, &dup), InputAction::Accept);
+    assert_eq!(input(&mut state, &dup), InputAction::Accept);
+    assert_eq!(state.rod.dupacks, 2);
+
+    // Third duplicate resends the segment at lastack
+    assert_eq!(input(&mut state, &dup), InputAction::Retransmit);
+    assert_eq!(state.rod.dupacks, 3);
+    assert_eq!(state.rod.unacked.front().unwrap().seqno, lastack);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_INFR, 0);
+
+    // ssthresh = FlightSize/2, cwnd = ssthresh + 3*MSS
+    assert_eq!(state.cong_ctrl.ssthresh, 2 * MSS);
+    assert_eq!(state.cong_ctrl.cwnd, 5 * MSS);
+}
+
+#[test]
+fn test_further_dupacks_inflate_cwnd() {
+    let mut state = established_in_flight(4);
+    let dup = ack_seg(&state, state.rod.lastack);
+
+    for _ in 0..3 {
+        input(&mut state, &dup);
+    }
+    let cwnd = state.cong_ctrl.cwnd;
+
+    // Only the third duplicate retransmits
+    assert_eq!(input(&mut state, &dup), InputAction::Accept);
+    assert_eq!(state.cong_ctrl.cwnd, cwnd + MSS);
+}
+
+#[test]
+fn test_new_ack_clears_dupacks_and_exits_recovery() {
+    let mut state = established_in_flight(4);
+    let dup = ack_seg(&state, state.rod.lastack);
+
+    for _ in 0..3 {
+        input(&mut state, &dup);
+    }
+
+    let new_ack = ack_seg(&state, state.rod.lastack.wrapping_add(MSS));
+    assert_eq!(input(&mut state, &new_ack), InputAction::Accept);
+
+    assert_eq!(state.rod.dupacks, 0);
+    assert_eq!(state.rod.unacked.len(), 3);
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_INFR, 0);
+    assert_eq!(state.cong_ctrl.cwnd, state.cong_ctrl.ssthresh);
+}
+
+#[test]
+fn test_first_two_dupacks_each_release_one_new_segment() {
+    let mut state = established_in_flight(4);
+    state.cong_ctrl.cwnd = 4 * MSS;
+    state.flow_ctrl.snd_wnd = 8192;
+    state.rod.snd_buf = 8192;
+    state.rod.snd_lbb = state.rod.snd_nxt;
+    tcp_write(&mut state, &[0; 3 * MSS as usize], 0).unwrap();
+    let dup = ack_seg(&state, state.rod.lastack);
+
+    // cwnd is full, so nothing goes out by the usual route
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+
+    for n in 1..=2u32 {
+        assert_eq!(input(&mut state, &dup), InputAction::LimitedTransmit);
+        let seg = tcp_limited_transmit(&mut state).unwrap().unwrap();
+        assert_eq!(seg.seqno, 1001 + (4 + n - 1) * MSS);
+        assert_eq!(seg.data.len(), MSS as usize);
+        // Only one per duplicate
+        assert!(tcp_limited_transmit(&mut state).unwrap().is_none());
+    }
+    // Limited transmit leaves cwnd alone
+    assert_eq!(state.cong_ctrl.cwnd, 4 * MSS);
+
+    // The third is a loss signal as before
+    assert_eq!(input(&mut state, &dup), InputAction::Retransmit);
+    assert_eq!(state.rod.unsent.bytes(), MSS as usize);
+}
+
+#[test]
+fn test_limited_transmit_respects_peer_window() {
+    let mut state = established_in_flight(4);
+    state.cong_ctrl.cwnd = 4 * MSS;
+    state.rod.snd_buf = 8192;
+    state.rod.snd_lbb = state.rod.snd_nxt;
+    state.flow_ctrl.snd_wnd = 4 * MSS;
+    tcp_write(&mut state, &[0; MSS as usize], 0).unwrap();
+    let mut dup = ack_seg(&state, state.rod.lastack);
+    dup.wnd = 4 * MSS as u16;
+
+    assert_eq!(input(&mut state, &dup), InputAction::LimitedTransmit);
+    assert!(tcp_limited_transmit(&mut state).unwrap().is_none());
+}
+
+#[test]
+fn test_dupack_not_counted_without_data_in_flight() {
+    let mut state = established_in_flight(0);
+    let dup = ack_seg(&state, state.rod.lastack);
+
+    for _ in 0..5 {
+        assert_eq!(input(&mut state, &dup), InputAction::Accept);
+    }
+    assert_eq!(state.rod.dupacks, 0);
+}
+
+// ============================================================================
+// Slow Start / Congestion Avoidance
+// ============================================================================
+
+/// ACK the in-flight data one segment at a time
+fn ack_each_segment(state: &mut TcpConnectionState, segments: u32) {
+    for _ in 0..segments {
+        let seg = ack_seg(state, state.rod.lastack.wrapping_add(MSS));
+        assert_eq!(input(state, &seg), InputAction::Accept);
+    }
+}
+
+#[test]
+fn test_slow_start_grows_one_mss_per_ack() {
+    let mut state = established_in_flight(8);
+    state.cong_ctrl.cwnd = MSS;
+    state.cong_ctrl.ssthresh = 0xFFFF;
+
+    // Round 1: one ACK doubles the window
+    ack_each_segment(&mut state, 1);
+    assert_eq!(state.cong_ctrl.cwnd, 2 * MSS);
+
+    // Round 2: two ACKs, doubles again
+    ack_each_segment(&mut state, 2);
+    assert_eq!(state.cong_ctrl.cwnd, 4 * MSS);
+
+    // Round 3: four ACKs
+    ack_each_segment(&mut state, 4);
+    assert_eq!(state.cong_ctrl.cwnd, 8 * MSS);
+}
+
+/// Slow start as it was before byte counting: one MSS per ACK
+struct PerAckSlowStart;
+
+impl CongestionControl for PerAckSlowStart {
+    fn on_ack(&mut self, win: &mut CongestionWindow, mss: u16, _acked: u32, _now: u32, _srtt: u32) {
+        win.cwnd = win.cwnd.saturating_add(mss as u32);
+    }
+
+    fn on_loss(&mut self, _win: &mut CongestionWindow, _mss: u16, _flight: u32) {}
+
+    fn on_timeout(&mut self, _win: &mut CongestionWindow, _mss: u16, _flight: u32) {}
+}
+
+/// ACK the in-flight data `per_ack` segments at a time
+fn ack_every(state: &mut TcpConnectionState, segments: u32, per_ack: u32) {
+    for _ in 0..segments / per_ack {
+        let seg = ack_seg(state, state.rod.lastack.wrapping_add(per_ack * MSS));
+        input(state, &seg);
+    }
+}
+
+#[test]
+fn test_abc_slow_start_unaffected_by_delayed_acks() {
+    let slow_start = |algorithm: Option<Box<dyn CongestionControl>>, per_ack: u32| {
+        let mut state = established_in_flight(8);
+        if let Some(algorithm) = algorithm {
+            state.cong_ctrl.set_algorithm(algorithm);
+        }
+        state.cong_ctrl.cwnd = 2 * MSS;
+        state.cong_ctrl.ssthresh = 0xFFFF;
+        ack_every(&mut state, 8, per_ack);
+        state.cong_ctrl.cwnd
+    };
+
+    // Byte counting: an ACK for every other segment grows cwnd as fast as
+    // an ACK for each
+    assert_eq!(slow_start(None, 1), 10 * MSS);
+    assert_eq!(slow_start(None, 2), 10 * MSS);
+
+    // Counting ACKs, delayed ACKs halve the growth
+    assert_eq!(slow_start(Some(Box::new(PerAckSlowStart)), 1), 10 * MSS);
+    assert_eq!(slow_start(Some(Box::new(PerAckSlowStart)), 2), 6 * MSS);
+}
+
+#[test]
+fn test_abc_caps_growth_per_stretch_ack() {
+    let mut state = established_in_flight(8);
+    state.cong_ctrl.cwnd = 2 * MSS;
+    state.cong_ctrl.ssthresh = 0xFFFF;
+
+    // One ACK for four segments still only adds TCP_ABC_LIMIT segments
+    ack_every(&mut state, 4, 4);
+    assert_eq!(state.cong_ctrl.cwnd, (2 + tcp_proto::TCP_ABC_LIMIT as u32) * MSS);
+}
+
+#[test]
+fn test_congestion_avoidance_grows_linearly() {
+    let mut state = established_in_flight(16);
+    state.cong_ctrl.cwnd = 4 * MSS;
+    state.cong_ctrl.ssthresh = 4 * MSS;
+
+    // One window's worth of ACKs adds about one MSS
+    ack_each_segment(&mut state, 4);
+    let after_one_rtt = state.cong_ctrl.cwnd;
+    assert!(after_one_rtt > 4 * MSS && after_one_rtt <= 5 * MSS, "cwnd = {}", after_one_rtt);
+
+    ack_each_segment(&mut state, 5);
+    let after_two_rtt = state.cong_ctrl.cwnd;
+    assert!(after_two_rtt > after_one_rtt && after_two_rtt <= 6 * MSS, "cwnd = {}", after_two_rtt);
+}
+
+#[test]
+fn test_cwnd_grows_past_u16_max() {
+    let mut state = established_in_flight(2);
+    state.cong_ctrl.cwnd = 0xFFFF - 10;
+    state.cong_ctrl.ssthresh = u32::MAX;
+
+    // A scaled send window can keep more than 64K in flight
+    ack_each_segment(&mut state, 2);
+    assert_eq!(state.cong_ctrl.cwnd, 0xFFFF - 10 + 2 * MSS);
+}
+
+// ============================================================================
+// Retransmission Timeout
+// ============================================================================
+
+#[test]
+fn test_rto_collapses_cwnd() {
+    let mut state = established_in_flight(8);
+    state.cong_ctrl.cwnd = 8 * MSS;
+    state.cong_ctrl.ssthresh = 0xFFFF;
+    state.rod.dupacks = 2;
+
+    let mut action = TimerAction::None;
+    while action == TimerAction::None {
+        action = tcp_slowtmr_pcb(&mut state).unwrap();
+    }
+    assert_eq!(action, TimerAction::Retransmit);
+
+    // ssthresh = FlightSize/2, cwnd back to one segment
+    assert_eq!(state.cong_ctrl.ssthresh, 4 * MSS);
+    assert_eq!(state.cong_ctrl.cwnd, MSS);
+    assert_eq!(state.rod.dupacks, 0);
+}
+
+#[test]
+fn test_rto_ssthresh_floor_is_two_mss() {
+    let mut state = established_in_flight(1);
+    state.cong_ctrl.cwnd = 4 * MSS;
+
+    let mut action = TimerAction::None;
+    while action == TimerAction::None {
+        action = tcp_slowtmr_pcb(&mut state).unwrap();
+    }
+
+    assert_eq!(state.cong_ctrl.ssthresh, 2 * MSS);
+    assert_eq!(state.cong_ctrl.cwnd, MSS);
+}
+
+// ============================================================================
+// Pluggable Algorithm
+// ============================================================================
+
+/// Never moves the window off a fixed size
+struct FixedWindow(u32);
+
+impl CongestionControl for FixedWindow {
+    fn initial_cwnd(&self, _mss: u16) -> u32 {
+        self.0
+    }
+
+    fn on_ack(&mut self, win: &mut CongestionWindow, _mss: u16, _acked: u32, _now: u32, _srtt: u32) {
+        win.cwnd = self.0;
+    }
+
+    fn on_loss(&mut self, win: &mut CongestionWindow, _mss: u16, _flight: u32) {
+        win.cwnd = self.0;
+    }
+
+    fn on_dupack(&mut self, win: &mut CongestionWindow, _mss: u16) {
+        win.cwnd = self.0;
+    }
+
+    fn on_recovery_exit(&mut self, win: &mut CongestionWindow) {
+        win.cwnd = self.0;
+    }
+
+    fn on_timeout(&mut self, win: &mut CongestionWindow, _mss: u16, _flight: u32) {
+        win.cwnd = self.0;
+    }
+
+    fn cwnd(&self, _win: &CongestionWindow) -> u32 {
+        self.0
+    }
+}
+
+#[test]
+fn test_fixed_window_algorithm_ignores_congestion_events() {
+    let mut state = established_in_flight(8);
+    state.cong_ctrl.set_algorithm(Box::new(FixedWindow(2 * MSS)));
+    state.cong_ctrl.cwnd = 2 * MSS;
+
+    ack_each_segment(&mut state, 2);
+    assert_eq!(state.cong_ctrl.cwnd, 2 * MSS);
+
+    // Fast retransmit still fires, the window stays put
+    let dup = ack_seg(&state, state.rod.lastack);
+    input(&mut state, &dup);
+    input(&mut state, &dup);
+    assert_eq!(input(&mut state, &dup), InputAction::Retransmit);
+    input(&mut state, &dup);
+    assert_eq!(state.cong_ctrl.cwnd, 2 * MSS);
+
+    let mut action = TimerAction::None;
+    while action == TimerAction::None {
+        action = tcp_slowtmr_pcb(&mut state).unwrap();
+    }
+    assert_eq!(state.cong_ctrl.cwnd, 2 * MSS);
+}
+
+#[test]
+fn test_output_uses_algorithm_window() {
+    let mut state = established_in_flight(0);
+    state.rod.snd_buf = 8192;
+    state.flow_ctrl.snd_wnd = 8192;
+    state.cong_ctrl.cwnd = 0xFFFF;
+    state.cong_ctrl.set_algorithm(Box::new(FixedWindow(MSS)));
+
+    tcp_write(&mut state, &[0x5A; 2000], 0).unwrap();
+    let segs = tcp_output(&mut state).unwrap();
+
+    assert_eq!(segs.len(), 1);
+    assert_eq!(segs[0].data.len(), MSS as usize);
+}
+
+// ============================================================================
+// CUBIC
+// ============================================================================
+
+/// Window after a third duplicate ACK at `cwnd`, once fast recovery ends
+fn after_loss(cc: &mut dyn CongestionControl, cwnd: u32) -> CongestionWindow {
+    let mut win = CongestionWindow { cwnd, ssthresh: 0xFFFF };
+    cc.on_loss(&mut win, MSS as u16, cwnd);
+    cc.on_recovery_exit(&mut win);
+    win
+}
+
+/// One round trip, one slow timer tick long: an ACK per segment in cwnd
+fn ack_round(cc: &mut dyn CongestionControl, win: &mut CongestionWindow, now: u32) {
+    for _ in 0..win.cwnd / MSS {
+        cc.on_ack(win, MSS as u16, MSS, now, tcp_proto::TCP_SLOW_INTERVAL);
+    }
+}
+
+#[test]
+fn test_cubic_backs_off_by_beta() {
+    let mut cubic = CubicCongestionControl::new();
+    let win = after_loss(&mut cubic, 40 * MSS);
+
+    assert_eq!(win.ssthresh, (40.0 * MSS as f64 * 0.7) as u32);
+    assert_eq!(win.cwnd, win.ssthresh);
+    assert_eq!(cubic.w_max, 40.0);
+    assert!(cubic.epoch_start.is_none());
+}
+
+#[test]
+fn test_cubic_growth_is_concave_below_w_max() {
+    let mut cubic = CubicCongestionControl::new();
+    let mut win = after_loss(&mut cubic, 40 * MSS);
+
+    let mut last = win.cwnd;
+    let mut increments = Vec::new();
+    for now in 0..6 {
+        ack_round(&mut cubic, &mut win, now);
+        increments.push(win.cwnd - last);
+        last = win.cwnd;
+    }
+
+    // Fast at first, flattening out as cwnd approaches w_max. Per-ACK
+    // steps only close part of the gap to W(t+RTT) within the first round,
+    // so the shape shows from the second round on.
+    assert!(increments[1..].windows(2).all(|w| w[1] <= w[0]), "{:?}", increments);
+    assert!(increments[1] > 4 * increments[5]);
+    assert!(win.cwnd <= 40 * MSS);
+}
+
+#[test]
+fn test_cubic_outgrows_reno_after_loss() {
+    let mut cubic = CubicCongestionControl::new();
+    let mut reno = RenoCongestionControl;
+    let mut cubic_win = after_loss(&mut cubic, 40 * MSS);
+    let mut reno_win = after_loss(&mut reno, 40 * MSS);
+
+    for now in 0..12 {
+        ack_round(&mut cubic, &mut cubic_win, now);
+        ack_round(&mut reno, &mut reno_win, now);
+    }
+
+    assert!(cubic_win.cwnd > reno_win.cwnd, "cubic {} reno {}", cubic_win.cwnd, reno_win.cwnd);
+}
+
+#[test]
+fn test_cubic_fast_convergence_lowers_w_max() {
+    let mut cubic = CubicCongestionControl::new();
+    after_loss(&mut cubic, 40 * MSS);
+
+    // Second loss before regaining the old maximum
+    after_loss(&mut cubic, 30 * MSS);
+    assert_eq!(cubic.w_max, 30.0 * 1.7 / 2.0);
+}
+
+#[test]
+fn test_cubic_selected_at_creation() {
+    let mut state = TcpConnectionState::with_congestion_algorithm(tcp_proto::TCP_CC_CUBIC).unwrap();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    for _ in 0..8 {
+        let seg = UnackedSegment::new(state.rod.snd_nxt, tcp_proto::TCP_ACK, vec![0; MSS as usize]);
+        state.rod.on_segment_sent(seg, 1).unwrap();
+    }
+    state.cong_ctrl.cwnd = 40 * MSS;
+
+    let dup = ack_seg(&state, state.rod.lastack);
+    for _ in 0..3 {
+        input(&mut state, &dup);
+    }
+
+    // Reno would halve the flight; CUBIC keeps 70% of cwnd
+    assert_eq!(state.cong_ctrl.ssthresh, (40.0 * MSS as f64 * 0.7) as u32);
+}
+
+// ============================================================================
+// ECN (RFC 3168)
+// ============================================================================
+
+#[test]
+fn test_ece_halves_window_once_per_rtt_and_sends_cwr() {
+    let mut state = established_in_flight(4);
+    state.conn_mgmt.ecn_enabled = true;
+    state.cong_ctrl.cwnd = 8 * MSS;
+    state.rod.snd_buf = 8192;
+
+    let mut seg = ack_seg(&state, state.rod.lastack.wrapping_add(MSS));
+    seg.flags.ece = true;
+    assert_eq!(input(&mut state, &seg), InputAction::Accept);
+    assert_eq!(state.cong_ctrl.cwnd, 4 * MSS);
+    assert_eq!(state.cong_ctrl.ssthresh, 4 * MSS);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_ECN_CWR, 0);
+
+    // Still echoing for data sent before the reduction: no second cut
+    let mut seg = ack_seg(&state, state.rod.lastack.wrapping_add(MSS));
+    seg.flags.ece = true;
+    input(&mut state, &seg);
+    assert!(state.cong_ctrl.cwnd >= 4 * MSS);
+
+    // The next new data carries CWR, once
+    tcp_write(&mut state, &[0x5A; 2 * MSS as usize], 0).unwrap();
+    let segs = tcp_output(&mut state).unwrap();
+    assert_eq!(segs.len(), 2);
+    assert_ne!(segs[0].flags & tcp_proto::TCP_CWR, 0);
+    assert_eq!(segs[1].flags & tcp_proto::TCP_CWR, 0);
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_ECN_CWR, 0);
+}
+
+#[test]
+fn test_ce_echoed_until_cwr() {
+    let mut state = established_in_flight(0);
+    state.conn_mgmt.ecn_enabled = true;
+
+    let mut data = ack_seg(&state, state.rod.lastack);
+    data.payload_len = 100;
+    data.payload = vec![0; 100];
+    data.ce = true;
+    assert_eq!(input(&mut state, &data), InputAction::SendAck);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_ECN_ECHO, 0);
+
+    let ack = TcpTx::build_segment(&state, state.rod.snd_nxt, state.rod.rcv_nxt, tcp_proto::TCP_ACK, &[], &[]).unwrap();
+    assert_eq!(ack[13], tcp_proto::TCP_ACK | tcp_proto::TCP_ECE);
+
+    // Sender reduced its window: stop echoing
+    let mut cwr = ack_seg(&state, state.rod.lastack);
+    cwr.flags.cwr = true;
+    input(&mut state, &cwr);
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_ECN_ECHO, 0);
+
+    let ack = TcpTx::build_segment(&state, state.rod.snd_nxt, state.rod.rcv_nxt, tcp_proto::TCP_ACK, &[], &[]).unwrap();
+    assert_eq!(ack[13], tcp_proto::TCP_ACK);
+}
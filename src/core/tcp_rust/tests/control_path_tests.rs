@@ -8,10 +8,11 @@ mod test_helpers;
 use test_helpers::*;
 use lwip_tcp_rust::{
     TcpFlags, TcpSegment,
-    RstValidation, AckValidation, InputAction,
-    tcp_bind, tcp_listen, tcp_connect, tcp_abort, initiate_close, tcp_input
+    RstValidation, AckValidation, InputAction, TcpError, CloseAction,
+    tcp_bind, tcp_ephemeral_port, tcp_listen, tcp_connect, tcp_connect_over_timewait, tcp_abort, initiate_close, tcp_shutdown, tcp_input
 };
-use lwip_tcp_rust::state::{TcpConnectionState, TcpState};
+use lwip_tcp_rust::state::{TcpConnectionState, TcpEvent, TcpState};
+use lwip_tcp_rust::components::UnackedSegment;
 use lwip_tcp_rust::tcp_proto;
 use lwip_tcp_rust::ffi;
 
@@ -49,21 +50,24 @@ fn test_tcp_connect_active_open() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Process SYN-ACK (should transition to ESTABLISHED)
     // Use component methods
     let result = state.rod.on_synack_in_synsent(&synack_seg);
     assert!(result.is_ok());
-    let result = state.flow_ctrl.on_synack_in_synsent(&synack_seg);
+    let result = state.flow_ctrl.on_synack_in_synsent(&synack_seg, &state.conn_mgmt);
     assert!(result.is_ok());
     let result = state.cong_ctrl.on_synack_in_synsent(&state.conn_mgmt);
     assert!(result.is_ok());
-    let result = state.conn_mgmt.on_synack_in_synsent();
+    let result = state.conn_mgmt.on_synack_in_synsent(&synack_seg);
     assert!(result.is_ok());
 
     assert_eq!(state.conn_mgmt.state, TcpState::Established);
@@ -89,7 +93,7 @@ fn test_tcp_active_close() {
     // Close from ESTABLISHED should transition to FIN_WAIT_1
     let result = initiate_close(&mut state);
     assert!(result.is_ok());
-    assert_eq!(result.unwrap(), true); // Should send FIN
+    assert_eq!(result.unwrap(), CloseAction::SendFin);
     assert_eq!(state.conn_mgmt.state, TcpState::FinWait1);
 
     // Receive ACK of our FIN -> FIN_WAIT_2
@@ -103,10 +107,13 @@ fn test_tcp_active_close() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Process ACK in FIN_WAIT_1 - use component methods
@@ -131,10 +138,13 @@ fn test_tcp_active_close() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Process FIN in FIN_WAIT_2 - use component methods
@@ -184,10 +194,13 @@ fn test_tcp_simultaneous_close() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Process FIN in FIN_WAIT_1 (crossing FINs) - use component methods
@@ -212,10 +225,13 @@ fn test_tcp_simultaneous_close() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Process ACK in CLOSING - use component methods
@@ -339,17 +355,19 @@ fn test_tcp_process_rst_seqno() {
             rst: true,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
-    // Process RST with bad seqno (to be implemented)
-    // Should NOT abort connection
-    // TODO: Implement sequence number validation in process_rst
-    // For now, we skip this test
-    // let result = ControlPath::process_rst(&mut state);
+    // Process RST with bad seqno: dropped, should NOT abort connection
+    assert_eq!(state.rod.validate_rst(&bad_rst, state.flow_ctrl.rcv_wnd), RstValidation::Invalid);
+    let action = tcp_input(&mut state, &bad_rst, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+    assert!(matches!(action, Ok(InputAction::Drop)));
 
     // Connection should still be ESTABLISHED
     assert_eq!(state.conn_mgmt.state, TcpState::Established);
@@ -365,13 +383,17 @@ fn test_tcp_process_rst_seqno() {
             rst: true,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Process RST with correct seqno - use component methods
+    assert_eq!(state.rod.validate_rst(&good_rst, state.flow_ctrl.rcv_wnd), RstValidation::Valid);
     let _ = state.rod.on_rst();
     let _ = state.flow_ctrl.on_rst();
     let _ = state.cong_ctrl.on_rst();
@@ -403,10 +425,13 @@ fn test_tcp_gen_rst_in_syn_sent_ackseq() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Should reject and send RST - use component methods
@@ -438,10 +463,13 @@ fn test_tcp_gen_rst_in_syn_sent_non_syn_ack() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Should reject (SYN_SENT expects SYN+ACK, not just ACK)
@@ -474,10 +502,13 @@ fn test_tcp_gen_rst_in_syn_rcvd() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Should send RST due to out-of-window seqno
@@ -506,14 +537,17 @@ fn test_tcp_receive_rst_syn_rcvd_to_listen() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Use component methods
-    let result = state.rod.on_syn_in_listen(&syn_seg);
+    let result = state.rod.on_syn_in_listen(&syn_seg, next_iss());
     assert!(result.is_ok());
     let result = state.flow_ctrl.on_syn_in_listen(&syn_seg, &state.conn_mgmt);
     assert!(result.is_ok());
@@ -522,6 +556,7 @@ fn test_tcp_receive_rst_syn_rcvd_to_listen() {
     let result = state.conn_mgmt.on_syn_in_listen(
         crate::ffi::ip_addr_t { addr: TEST_REMOTE_IP },
         TEST_REMOTE_PORT,
+        &syn_seg,
     );
 
     assert!(result.is_ok());
@@ -538,15 +573,104 @@ fn test_tcp_receive_rst_syn_rcvd_to_listen() {
             rst: true,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
+    };
+
+    // Passively opened: back to LISTEN, peer forgotten
+    let action = tcp_input(&mut state, &rst_seg, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+    assert!(matches!(action, Ok(InputAction::Drop)));
+    assert_eq!(state.conn_mgmt.state, TcpState::Listen);
+    assert_eq!(state.conn_mgmt.remote_ip.addr, 0);
+    assert_eq!(state.conn_mgmt.remote_port, 0);
+    assert!(!state.conn_mgmt.opened_passively);
+}
+
+#[test]
+fn test_tcp_receive_rst_syn_rcvd_active_to_closed() {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::SynRcvd,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    state.rod.rcv_nxt = 2001;
+    state.flow_ctrl.rcv_wnd = 8192;
+    // Reached SYN_RCVD by simultaneous open, not from LISTEN
+    assert!(!state.conn_mgmt.opened_passively);
+
+    let rst_seg = TcpSegment {
+        seqno: state.rod.rcv_nxt,
+        ackno: 0,
+        flags: TcpFlags {
+            syn: false,
+            ack: false,
+            fin: false,
+            rst: true,
+            psh: false,
+            urg: false,
+            ece: false,
+            cwr: false,
+        },
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    };
+
+    let action = tcp_input(&mut state, &rst_seg, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+    assert!(matches!(action, Ok(InputAction::Abort)));
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+}
+
+#[test]
+fn test_tcp_receive_rst_syn_rcvd_needs_exact_seqno() {
+    let mut state = create_test_state();
+    tcp_bind(&mut state, ffi::ip_addr_t { addr: TEST_LOCAL_IP }, TEST_LOCAL_PORT).unwrap();
+    tcp_listen(&mut state).unwrap();
+    let remote_ip = ffi::ip_addr_t { addr: TEST_REMOTE_IP };
+    let syn = TcpSegment {
+        seqno: 1000,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_SYN),
+        wnd: 8192,
+        tcphdr_len: 20,
+        ..Default::default()
+    };
+    tcp_input(&mut state, &syn, remote_ip, TEST_REMOTE_PORT).unwrap();
+    assert_eq!(state.conn_mgmt.state, TcpState::SynRcvd);
+
+    let rst = |seqno: u32| TcpSegment {
+        seqno,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_RST),
+        tcphdr_len: 20,
+        ..Default::default()
     };
 
-    // Process RST (to be implemented)
-    // For listening PCB, should return to LISTEN
-    // For non-listening PCB, should go to CLOSED
+    // Outside the window: ignored
+    let action = tcp_input(&mut state, &rst(1001u32.wrapping_sub(100000)), remote_ip, TEST_REMOTE_PORT);
+    assert_eq!(action, Ok(InputAction::Drop));
+    assert_eq!(state.conn_mgmt.state, TcpState::SynRcvd);
+
+    // In the window but not rcv_nxt: a challenge ACK (RFC 5961). Start a
+    // fresh window so the budget shared with other tests has room.
+    unsafe { lwip_tcp_rust::tcp_ticks += tcp_proto::TCP_CHALLENGE_ACK_WINDOW };
+    assert_eq!(state.rod.last_challenge_tick, None);
+    let action = tcp_input(&mut state, &rst(1101), remote_ip, TEST_REMOTE_PORT).unwrap();
+    assert_eq!(action, InputAction::SendChallengeAck);
+    assert_eq!(state.conn_mgmt.state, TcpState::SynRcvd);
+    assert_eq!(state.conn_mgmt.remote_port, TEST_REMOTE_PORT);
+
+    // Exactly rcv_nxt: back to LISTEN
+    assert_eq!(tcp_input(&mut state, &rst(1001), remote_ip, TEST_REMOTE_PORT), Ok(InputAction::Drop));
+    assert_eq!(state.conn_mgmt.state, TcpState::Listen);
 }
 
 // ============================================================================
@@ -576,10 +700,13 @@ fn test_tcp_passive_close() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Process FIN in ESTABLISHED -> CLOSE_WAIT - use component methods
@@ -597,7 +724,7 @@ fn test_tcp_passive_close() {
     // Application calls tcp_close() -> LAST_ACK
     let result = initiate_close(&mut state);
     assert!(result.is_ok());
-    assert_eq!(result.unwrap(), true); // Should send FIN
+    assert_eq!(result.unwrap(), CloseAction::SendFin);
     assert_eq!(state.conn_mgmt.state, TcpState::LastAck);
 
     // Receive ACK of our FIN -> CLOSED
@@ -611,10 +738,13 @@ fn test_tcp_passive_close() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Process ACK in LAST_ACK -> CLOSED - use component methods
@@ -654,17 +784,46 @@ fn test_tcp_bind_wrong_state() {
     // Cannot bind in non-CLOSED state
     let result = tcp_bind(&mut state, ffi::ip_addr_t { addr: TEST_LOCAL_IP }, 8080);
     assert!(result.is_err());
-    assert_eq!(result.unwrap_err(), "Can only bind in CLOSED state");
+    assert_eq!(result.unwrap_err(), TcpError::AlreadyConnected);
 }
 
 #[test]
 fn test_tcp_bind_port_zero() {
     let mut state = create_test_state();
 
-    // Port 0 not yet supported (needs port allocation)
+    // Port 0 is resolved with tcp_ephemeral_port before binding
     let result = tcp_bind(&mut state, ffi::ip_addr_t { addr: TEST_LOCAL_IP }, 0);
     assert!(result.is_err());
-    assert_eq!(result.unwrap_err(), "Port 0 not yet supported - provide explicit port");
+    assert_eq!(result.unwrap_err(), TcpError::InvalidArgument);
+}
+
+#[test]
+fn test_ephemeral_ports_rotate() {
+    let mut next = 0xc000;
+    let first = tcp_ephemeral_port(&mut next, 0xc000..=0xffff, |_| false).unwrap();
+    let second = tcp_ephemeral_port(&mut next, 0xc000..=0xffff, |_| false).unwrap();
+    assert_ne!(first, second);
+    assert!((0xc000..=0xffff).contains(&first));
+    assert!((0xc000..=0xffff).contains(&second));
+}
+
+#[test]
+fn test_ephemeral_port_skips_used_and_wraps() {
+    // From the end of the range, past a taken port at its start
+    let mut next = 0xffff;
+    assert_eq!(tcp_ephemeral_port(&mut next, 0xc000..=0xffff, |port| port == 0xffff || port == 0xc000), Some(0xc001));
+    assert_eq!(next, 0xc002);
+
+    // A cursor outside the range starts over at its beginning
+    let mut next = 80;
+    assert_eq!(tcp_ephemeral_port(&mut next, 0xc000..=0xffff, |_| false), Some(0xc000));
+}
+
+#[test]
+fn test_ephemeral_range_exhausted() {
+    let mut next = 50000;
+    assert_eq!(tcp_ephemeral_port(&mut next, 50000..=50003, |_| true), None);
+    assert_eq!(next, 50000);
 }
 
 // ============================================================================
@@ -694,7 +853,7 @@ fn test_tcp_listen_without_bind() {
     // Cannot listen without binding to port
     let result = tcp_listen(&mut state);
     assert!(result.is_err());
-    assert_eq!(result.unwrap_err(), "Must bind to port before listening");
+    assert_eq!(result.unwrap_err(), TcpError::NotBound);
 }
 
 #[test]
@@ -706,7 +865,25 @@ fn test_tcp_listen_wrong_state() {
     // Cannot listen from non-CLOSED state
     let result = tcp_listen(&mut state);
     assert!(result.is_err());
-    assert_eq!(result.unwrap_err(), "Can only listen from CLOSED state");
+    assert_eq!(result.unwrap_err(), TcpError::BadState);
+}
+
+#[test]
+fn test_backlog_counter_stays_in_range() {
+    let mut state = create_test_state();
+    tcp_bind(&mut state, ffi::ip_addr_t { addr: TEST_LOCAL_IP }, 8080).unwrap();
+    tcp_listen(&mut state).unwrap();
+
+    // Releasing more than was held does not wrap around
+    state.conn_mgmt.on_backlog_release().unwrap();
+    assert_eq!(state.conn_mgmt.accepts_pending, 0);
+
+    // Delayed accepts count past the backlog, but never overflow
+    state.conn_mgmt.accepts_pending = u8::MAX - 1;
+    assert!(state.conn_mgmt.on_backlog_delayed().is_ok());
+    assert!(state.conn_mgmt.on_backlog_delayed().is_err());
+    assert_eq!(state.conn_mgmt.accepts_pending, u8::MAX);
+    assert!(state.conn_mgmt.on_backlog_reserve().is_err());
 }
 
 // ============================================================================
@@ -743,6 +920,36 @@ fn test_tcp_connect_success() {
     assert!(state.cong_ctrl.cwnd > 0);
 }
 
+#[test]
+fn test_tcp_connect_over_timewait_keeps_fresh_iss() {
+    let mut tw = create_test_state();
+    tw.conn_mgmt.state = TcpState::TimeWait;
+    tw.rod.snd_nxt = 1000;
+
+    let mut state = create_test_state();
+    tcp_bind(&mut state, ffi::ip_addr_t { addr: TEST_LOCAL_IP }, 12345).unwrap();
+    tcp_connect_over_timewait(&mut state, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, 80, &tw).unwrap();
+    assert_eq!(state.conn_mgmt.state, TcpState::SynSent);
+
+    // Whatever the clock said, the ISS lies past the old sequence space
+    let iss = state.rod.iss;
+    assert!(iss.wrapping_sub(tw.rod.snd_nxt) as i32 > 0);
+
+    // One behind the old snd_nxt moves past it, one ahead is kept
+    state.rod.on_connect_over_timewait(iss).unwrap();
+    assert_eq!(state.rod.iss, iss.wrapping_add(tcp_proto::TCP_ISS_INCR));
+    assert_eq!(state.rod.snd_nxt, state.rod.iss);
+    state.rod.on_connect_over_timewait(iss).unwrap();
+    assert_eq!(state.rod.iss, iss.wrapping_add(tcp_proto::TCP_ISS_INCR));
+
+    // Only a connection in TIME_WAIT gives up its tuple
+    let mut other = create_test_state();
+    tcp_bind(&mut other, ffi::ip_addr_t { addr: TEST_LOCAL_IP }, 12345).unwrap();
+    tw.conn_mgmt.state = TcpState::Established;
+    let result = tcp_connect_over_timewait(&mut other, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, 80, &tw);
+    assert_eq!(result.unwrap_err(), TcpError::PortInUse);
+}
+
 #[test]
 fn test_tcp_connect_wrong_state() {
     let mut state = create_test_state();
@@ -756,7 +963,7 @@ fn test_tcp_connect_wrong_state() {
         80,
     );
     assert!(result.is_err());
-    assert_eq!(result.unwrap_err(), "Can only connect from CLOSED state");
+    assert_eq!(result.unwrap_err(), TcpError::AlreadyConnected);
 }
 
 // ============================================================================
@@ -782,6 +989,35 @@ fn test_tcp_abort_established() {
     assert_eq!(state.conn_mgmt.state, TcpState::Closed);
 }
 
+#[test]
+fn test_tcp_abort_frees_queued_data() {
+    let mut state = established_state();
+    state.flow_ctrl.snd_wnd = 500;
+    state.flow_ctrl.rcv_wnd = tcp_proto::TCP_WND as u32;
+
+    // Some data in flight, more waiting behind the window
+    lwip_tcp_rust::tcp_write(&mut state, &[1; 500], 0).unwrap();
+    lwip_tcp_rust::tcp_output(&mut state).unwrap();
+    lwip_tcp_rust::tcp_write(&mut state, &[2; 500], 0).unwrap();
+    assert!(!state.rod.unacked.is_empty());
+    assert!(!state.rod.unsent.is_empty());
+
+    // Received data past a hole, and in-order data not yet delivered
+    let remote_ip = ffi::ip_addr_t { addr: TEST_REMOTE_IP };
+    tcp_input(&mut state, &data_segment(2101, 100), remote_ip, TEST_REMOTE_PORT).unwrap();
+    tcp_input(&mut state, &data_segment(2001, 50), remote_ip, TEST_REMOTE_PORT).unwrap();
+    assert!(!state.rod.ooseq.is_empty());
+    assert!(!state.rod.rcv_ready.is_empty());
+
+    assert_eq!(tcp_abort(&mut state), Ok(true));
+    assert!(state.rod.unacked.is_empty());
+    assert!(state.rod.unsent.is_empty());
+    assert!(state.rod.ooseq.is_empty());
+    assert!(state.rod.rcv_ready.is_empty());
+    assert_eq!(state.rod.snd_buf, tcp_proto::TCP_SND_BUF);
+    assert_eq!(state.rod.snd_queuelen, 0);
+}
+
 #[test]
 fn test_tcp_abort_listen() {
     let mut state = create_test_state();
@@ -794,6 +1030,16 @@ fn test_tcp_abort_listen() {
     assert_eq!(state.conn_mgmt.state, TcpState::Closed);
 }
 
+#[test]
+fn test_tcp_abort_time_wait() {
+    let mut state = create_test_state();
+    state.conn_mgmt.state = TcpState::TimeWait;
+
+    // The peer has closed already; lwIP resets nobody
+    assert_eq!(tcp_abort(&mut state), Ok(false));
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+}
+
 #[test]
 fn test_tcp_abort_closed() {
     let mut state = create_test_state();
@@ -835,14 +1081,17 @@ fn test_full_server_lifecycle() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Use component methods
-    let result = state.rod.on_syn_in_listen(&syn_seg);
+    let result = state.rod.on_syn_in_listen(&syn_seg, next_iss());
     assert!(result.is_ok());
     let result = state.flow_ctrl.on_syn_in_listen(&syn_seg, &state.conn_mgmt);
     assert!(result.is_ok());
@@ -851,6 +1100,7 @@ fn test_full_server_lifecycle() {
     let result = state.conn_mgmt.on_syn_in_listen(
         ffi::ip_addr_t { addr: TEST_REMOTE_IP },
         TEST_REMOTE_PORT,
+        &syn_seg,
     );
     assert!(result.is_ok());
     assert_eq!(state.conn_mgmt.state, TcpState::SynRcvd);
@@ -866,10 +1116,13 @@ fn test_full_server_lifecycle() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Use component methods
@@ -918,20 +1171,23 @@ fn test_full_client_lifecycle() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Use component methods
     let result = state.rod.on_synack_in_synsent(&synack_seg);
     assert!(result.is_ok());
-    let result = state.flow_ctrl.on_synack_in_synsent(&synack_seg);
+    let result = state.flow_ctrl.on_synack_in_synsent(&synack_seg, &state.conn_mgmt);
     assert!(result.is_ok());
     let result = state.cong_ctrl.on_synack_in_synsent(&state.conn_mgmt);
     assert!(result.is_ok());
-    let result = state.conn_mgmt.on_synack_in_synsent();
+    let result = state.conn_mgmt.on_synack_in_synsent(&synack_seg);
     assert!(result.is_ok());
     assert_eq!(state.conn_mgmt.state, TcpState::Established);
 
@@ -972,10 +1228,13 @@ fn test_validate_sequence_number_in_window() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 100,
+        ..Default::default()
     };
 
     assert!(state.rod.validate_sequence_number(&seg, state.flow_ctrl.rcv_wnd));
@@ -988,6 +1247,7 @@ fn test_validate_sequence_number_in_window() {
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 100,
+        ..Default::default()
     };
 
     assert!(state.rod.validate_sequence_number(&seg2, state.flow_ctrl.rcv_wnd));
@@ -1000,6 +1260,7 @@ fn test_validate_sequence_number_in_window() {
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 1,
+        ..Default::default()
     };
 
     assert!(state.rod.validate_sequence_number(&seg3, state.flow_ctrl.rcv_wnd));
@@ -1031,10 +1292,13 @@ fn test_validate_sequence_number_out_of_window() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 100,
+        ..Default::default()
     };
 
     assert!(!state.rod.validate_sequence_number(&seg, state.flow_ctrl.rcv_wnd));
@@ -1047,6 +1311,7 @@ fn test_validate_sequence_number_out_of_window() {
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 100,
+        ..Default::default()
     };
 
     assert!(!state.rod.validate_sequence_number(&seg2, state.flow_ctrl.rcv_wnd));
@@ -1078,10 +1343,13 @@ fn test_validate_sequence_number_zero_window() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     assert!(state.rod.validate_sequence_number(&seg_exact, state.flow_ctrl.rcv_wnd));
@@ -1094,11 +1362,211 @@ fn test_validate_sequence_number_zero_window() {
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     assert!(!state.rod.validate_sequence_number(&seg_off, state.flow_ctrl.rcv_wnd));
 }
 
+#[test]
+fn test_validate_sequence_number_wraparound() {
+    let mut state = create_test_state();
+    state.rod.rcv_nxt = u32::MAX - 99;
+    state.flow_ctrl.rcv_wnd = 1000;
+
+    // Window spans the wrap: [2^32 - 100, 900)
+    let seg = TcpSegment {
+        seqno: 500,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        tcphdr_len: 20,
+        payload_len: 100,
+        ..Default::default()
+    };
+    assert!(state.rod.validate_sequence_number(&seg, state.flow_ctrl.rcv_wnd));
+
+    let beyond = TcpSegment { seqno: 900, ..seg };
+    assert!(!state.rod.validate_sequence_number(&beyond, state.flow_ctrl.rcv_wnd));
+
+    // Old data whose last octet crosses into the window
+    let overlap = TcpSegment {
+        seqno: u32::MAX - 149,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        tcphdr_len: 20,
+        payload_len: 100,
+        ..Default::default()
+    };
+    assert!(state.rod.validate_sequence_number(&overlap, state.flow_ctrl.rcv_wnd));
+}
+
+#[test]
+fn test_validate_sequence_number_counts_fin() {
+    let mut state = create_test_state();
+    state.rod.rcv_nxt = 1000;
+    state.flow_ctrl.rcv_wnd = 8192;
+
+    // 10 bytes ending just before rcv_nxt, plus a FIN sitting on it
+    let fin = TcpSegment {
+        seqno: 990,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_FIN),
+        tcphdr_len: 20,
+        payload_len: 10,
+        ..Default::default()
+    };
+    assert!(state.rod.validate_sequence_number(&fin, state.flow_ctrl.rcv_wnd));
+
+    let data_only = TcpSegment {
+        seqno: 990,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        tcphdr_len: 20,
+        payload_len: 10,
+        ..Default::default()
+    };
+    assert!(!state.rod.validate_sequence_number(&data_only, state.flow_ctrl.rcv_wnd));
+}
+
+#[test]
+fn test_unacceptable_segment_is_acked() {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+
+    // Retransmission of data we already have
+    let old = TcpSegment {
+        seqno: state.rod.rcv_nxt.wrapping_sub(100),
+        ackno: state.rod.snd_nxt,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 100,
+        ..Default::default()
+    };
+    let action = tcp_input(&mut state, &old, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+    assert!(matches!(action, Ok(InputAction::SendAck)));
+    assert_eq!(state.rod.rcv_nxt, 2001);
+}
+
+#[test]
+fn test_retransmitted_fin_in_time_wait_is_acked() {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    // Peer's FIN at 2001 was consumed before we got here
+    state.rod.rcv_nxt = 2002;
+    state.conn_mgmt.state = TcpState::TimeWait;
+
+    let fin = TcpSegment {
+        seqno: 2001,
+        ackno: state.rod.snd_nxt,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_FIN),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    };
+    assert!(state.rod.on_fin_in_timewait(&fin).is_ok());
+
+    let action = tcp_input(&mut state, &fin, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+    assert!(matches!(action, Ok(InputAction::SendAck)));
+    assert_eq!(state.conn_mgmt.state, TcpState::TimeWait);
+
+    // A FIN anywhere else is not a retransmission
+    let stray = TcpSegment { seqno: 2500, ..fin };
+    assert!(state.rod.on_fin_in_timewait(&stray).is_err());
+}
+
+fn time_wait_state() -> TcpConnectionState {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    // Both FINs exchanged: ours at 1001, the peer's at 2001
+    state.rod.snd_nxt = 1002;
+    state.rod.lastack = 1002;
+    state.rod.rcv_nxt = 2002;
+    state.conn_mgmt.flags |= tcp_proto::TF_FIN | tcp_proto::TF_TIMESTAMP;
+    state.conn_mgmt.state = TcpState::TimeWait;
+    state
+}
+
+fn syn_at(seqno: u32) -> TcpSegment {
+    TcpSegment {
+        seqno,
+        ackno: 0,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_SYN),
+        wnd: 16384,
+        tcphdr_len: 24,
+        payload_len: 0,
+        options: lwip_tcp_rust::TcpOptions { mss: Some(1460), ..Default::default() },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_new_syn_reopens_time_wait() {
+    let mut state = time_wait_state();
+    let remote_ip = ffi::ip_addr_t { addr: TEST_REMOTE_IP };
+
+    let action = tcp_input(&mut state, &syn_at(90000), remote_ip, TEST_REMOTE_PORT);
+    assert!(matches!(action, Ok(InputAction::SendSynAck)));
+    assert_eq!(state.conn_mgmt.state, TcpState::SynRcvd);
+
+    // A new incarnation: peer's sequence space, ours beyond the old one
+    assert_eq!(state.rod.irs, 90000);
+    assert_eq!(state.rod.rcv_nxt, 90001);
+    assert_eq!(state.rod.iss, 1002 + tcp_proto::TCP_ISS_INCR);
+    assert_eq!(state.rod.snd_nxt, state.rod.iss);
+    assert_eq!(state.flow_ctrl.snd_wnd, 16384);
+    assert_eq!(state.flow_ctrl.rcv_wnd, state.flow_ctrl.rcv_buf);
+
+    // Nothing negotiated before carries over
+    assert_eq!(state.conn_mgmt.flags & (tcp_proto::TF_FIN | tcp_proto::TF_TIMESTAMP), 0);
+    assert_eq!(state.conn_mgmt.mss, 536);
+
+    let ack = TcpSegment {
+        seqno: 90001,
+        ackno: state.rod.iss.wrapping_add(1),
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        wnd: 16384,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    };
+    assert!(tcp_input(&mut state, &ack, remote_ip, TEST_REMOTE_PORT).is_ok());
+    assert_eq!(state.conn_mgmt.state, TcpState::Established);
+}
+
+#[test]
+fn test_stale_syn_in_time_wait_is_reset() {
+    let mut state = time_wait_state();
+    let remote_ip = ffi::ip_addr_t { addr: TEST_REMOTE_IP };
+
+    // Within the old incarnation's sequence space
+    for seqno in [2002, 1500] {
+        let action = tcp_input(&mut state, &syn_at(seqno), remote_ip, TEST_REMOTE_PORT);
+        assert!(matches!(action, Ok(InputAction::SendRst)));
+        assert_eq!(state.conn_mgmt.state, TcpState::TimeWait);
+        assert_eq!(state.rod.rcv_nxt, 2002);
+        assert_eq!(state.rod.snd_nxt, 1002);
+    }
+}
+
 // ============================================================================
 // Test 19: RST Validation (RFC 5961)
 // ============================================================================
@@ -1118,7 +1586,7 @@ fn test_validate_rst_in_window() {
     state.rod.rcv_nxt = 1000;
     state.flow_ctrl.rcv_wnd = 8192;
 
-    // RST with sequence number in window
+    // RST with sequence number in window, but not at rcv_nxt
     let seg = TcpSegment {
         seqno: 5000, // In window
         ackno: 0,
@@ -1129,14 +1597,21 @@ fn test_validate_rst_in_window() {
             rst: true,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = state.rod.validate_rst(&seg, state.flow_ctrl.rcv_wnd);
-    assert_eq!(result, RstValidation::Valid);
+    assert_eq!(result, RstValidation::Challenge);
+
+    // Only an exact match resets the connection
+    let exact = TcpSegment { seqno: 1000, ..seg };
+    assert_eq!(state.rod.validate_rst(&exact, state.flow_ctrl.rcv_wnd), RstValidation::Valid);
 }
 
 #[test]
@@ -1165,14 +1640,17 @@ fn test_validate_rst_out_of_window() {
             rst: true,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = state.rod.validate_rst(&seg, state.flow_ctrl.rcv_wnd);
-    assert_eq!(result, RstValidation::Challenge);
+    assert_eq!(result, RstValidation::Invalid);
 }
 
 // ============================================================================
@@ -1205,10 +1683,13 @@ fn test_validate_ack_valid() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = state.rod.validate_ack(&seg);
@@ -1241,10 +1722,13 @@ fn test_validate_ack_duplicate() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = state.rod.validate_ack(&seg);
@@ -1277,10 +1761,13 @@ fn test_validate_ack_future() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = state.rod.validate_ack(&seg);
@@ -1313,10 +1800,13 @@ fn test_validate_ack_old() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = state.rod.validate_ack(&seg);
@@ -1343,10 +1833,13 @@ fn test_tcp_input_dispatcher_listen() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = tcp_input(
@@ -1384,10 +1877,13 @@ fn test_tcp_input_dispatcher_established_with_fin() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = tcp_input(
@@ -1402,6 +1898,28 @@ fn test_tcp_input_dispatcher_established_with_fin() {
     assert_eq!(state.conn_mgmt.state, TcpState::CloseWait);
 }
 
+#[test]
+fn test_tcp_input_dispatcher_synack_is_acked() {
+    let mut state = create_test_state();
+    tcp_connect(&mut state, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT).unwrap();
+
+    let synack = TcpSegment {
+        seqno: 5000,
+        ackno: state.rod.iss.wrapping_add(1),
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_SYN | tcp_proto::TCP_ACK),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    };
+    let result = tcp_input(&mut state, &synack, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+
+    // The peer waits in SYN_RCVD for this ACK
+    assert_eq!(result, Ok(InputAction::SendAck));
+    assert_eq!(state.conn_mgmt.state, TcpState::Established);
+    assert_eq!(state.rod.rcv_nxt, 5001);
+}
+
 #[test]
 fn test_tcp_input_dispatcher_rst_in_window() {
     let mut state = create_test_state();
@@ -1425,10 +1943,13 @@ fn test_tcp_input_dispatcher_rst_in_window() {
             rst: true,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = tcp_input(
@@ -1466,10 +1987,13 @@ fn test_tcp_input_dispatcher_rst_out_of_window() {
             rst: true,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let result = tcp_input(
@@ -1480,9 +2004,60 @@ fn test_tcp_input_dispatcher_rst_out_of_window() {
     );
 
     assert!(result.is_ok());
-    assert_eq!(result.unwrap(), InputAction::SendChallengeAck);
+    assert_eq!(result.unwrap(), InputAction::Drop);
     // State should NOT change to Closed
     assert_eq!(state.conn_mgmt.state, TcpState::Established);
+
+    // In window but not at rcv_nxt: challenge the peer instead
+    let in_window = TcpSegment { seqno: state.rod.rcv_nxt.wrapping_add(100), ..rst_seg };
+    let result = tcp_input(
+        &mut state,
+        &in_window,
+        ffi::ip_addr_t { addr: TEST_REMOTE_IP },
+        TEST_REMOTE_PORT,
+    );
+    assert_eq!(result.unwrap(), InputAction::SendChallengeAck);
+    assert_eq!(state.conn_mgmt.state, TcpState::Established);
+}
+
+#[test]
+fn test_one_challenge_ack_per_window_per_connection() {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    let rst = TcpSegment {
+        seqno: state.rod.rcv_nxt.wrapping_add(100),
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_RST),
+        tcphdr_len: 20,
+        ..Default::default()
+    };
+    let remote_ip = ffi::ip_addr_t { addr: TEST_REMOTE_IP };
+
+    // Two in-window RSTs back to back: the second is not challenged
+    let actions = [
+        tcp_input(&mut state, &rst, remote_ip, TEST_REMOTE_PORT).unwrap(),
+        tcp_input(&mut state, &rst, remote_ip, TEST_REMOTE_PORT).unwrap(),
+    ];
+    assert_eq!(actions.iter().filter(|a| **a == InputAction::SendChallengeAck).count(), 1);
+    assert_eq!(actions[1], InputAction::Drop);
+    assert_eq!(state.conn_mgmt.state, TcpState::Established);
+}
+
+#[test]
+fn test_challenge_ack_limiter_per_connection() {
+    let mut state = create_test_state();
+    assert!(state.rod.challenge_ack_due(100));
+    state.rod.on_challenge_ack(100);
+    assert!(!state.rod.challenge_ack_due(100));
+    assert!(!state.rod.challenge_ack_due(100 + tcp_proto::TCP_CHALLENGE_ACK_WINDOW - 1));
+    assert!(state.rod.challenge_ack_due(100 + tcp_proto::TCP_CHALLENGE_ACK_WINDOW));
+    assert_eq!(state.rod.last_challenge_tick, Some(100));
 }
 
 // ============================================================================
@@ -1506,14 +2081,17 @@ fn test_tcp_passive_open_handshake() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Use component methods
-    let result = state.rod.on_syn_in_listen(&syn_seg);
+    let result = state.rod.on_syn_in_listen(&syn_seg, next_iss());
     assert!(result.is_ok());
     let result = state.flow_ctrl.on_syn_in_listen(&syn_seg, &state.conn_mgmt);
     assert!(result.is_ok());
@@ -1522,6 +2100,7 @@ fn test_tcp_passive_open_handshake() {
     let result = state.conn_mgmt.on_syn_in_listen(
         ffi::ip_addr_t { addr: TEST_REMOTE_IP },
         TEST_REMOTE_PORT,
+        &syn_seg,
     );
 
     assert!(result.is_ok());
@@ -1539,10 +2118,13 @@ fn test_tcp_passive_open_handshake() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Use component methods
@@ -1557,3 +2139,301 @@ fn test_tcp_passive_open_handshake() {
     assert!(result.is_ok());
     assert_eq!(state.conn_mgmt.state, TcpState::Established);
 }
+
+// ============================================================================
+// Test 23: RST During Loss Recovery
+// ============================================================================
+
+#[test]
+fn test_tcp_rst_during_fast_recovery() {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+
+    // Two segments in flight, third dupack received, retransmission pending
+    for i in 0..2u32 {
+        state.rod.unacked.push(UnackedSegment::new(1001 + i * 536, tcp_proto::TCP_ACK, vec![0; 536]));
+    }
+    state.rod.snd_nxt = 1001 + 2 * 536;
+    state.rod.dupacks = 3;
+    state.rod.nrtx = 1;
+    state.rod.rtime = 6;
+    state.flow_ctrl.persist_backoff = 1;
+    state.cong_ctrl.ssthresh = 536;
+    state.cong_ctrl.cwnd = 536 + 3 * 536;
+    state.conn_mgmt.flags |= tcp_proto::TF_INFR;
+
+    let rst_seg = TcpSegment {
+        seqno: state.rod.rcv_nxt,
+        ackno: 0,
+        flags: TcpFlags {
+            syn: false,
+            ack: false,
+            fin: false,
+            rst: true,
+            psh: false,
+            urg: false,
+            ece: false,
+            cwr: false,
+        },
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    };
+
+    let result = tcp_input(&mut state, &rst_seg, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+    assert_eq!(result, Ok(InputAction::Abort));
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+
+    // Queue freed
+    assert!(state.rod.unacked.is_empty());
+
+    // Timers cancelled, recovery state gone
+    assert_eq!(state.rod.rtime, 0);
+    assert_eq!(state.rod.nrtx, 0);
+    assert_eq!(state.rod.dupacks, 0);
+    assert_eq!(state.flow_ctrl.persist_backoff, 0);
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_INFR, 0);
+
+    // Congestion control back to defaults
+    assert_eq!(state.cong_ctrl.cwnd, 0);
+    assert_eq!(state.cong_ctrl.ssthresh, 0xFFFF);
+}
+
+// ============================================================================
+// Test 24: tcp_shutdown() Half-Close
+// ============================================================================
+
+fn established_state() -> TcpConnectionState {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    state
+}
+
+fn data_segment(seqno: u32, len: u16) -> TcpSegment {
+    TcpSegment {
+        seqno,
+        ackno: 1001,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload: vec![0xAB; len as usize],
+        payload_len: len,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_tcp_shutdown_tx_only() {
+    let mut state = established_state();
+
+    assert_eq!(tcp_shutdown(&mut state, false, true), Ok(CloseAction::SendFin));
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait1);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_FIN, 0);
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_RXCLOSED, 0);
+
+    // The FIN goes out; nothing more may be written
+    let segs = lwip_tcp_rust::tcp_output(&mut state).unwrap();
+    assert_eq!(segs.len(), 1);
+    assert_ne!(segs[0].flags & tcp_proto::TCP_FIN, 0);
+    assert_eq!(lwip_tcp_rust::tcp_write(&mut state, &[1, 2, 3], 0), Err(TcpError::Closed));
+
+    // The receive side stays open
+    state.flow_ctrl.rcv_wnd = tcp_proto::TCP_WND as u32;
+    let result = tcp_input(&mut state, &data_segment(2001, 100), ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+    assert!(result.is_ok());
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait1);
+    assert_eq!(state.rod.rcv_ready.len(), 100);
+}
+
+#[test]
+fn test_tcp_shutdown_tx_queues_fin_behind_data() {
+    let mut state = established_state();
+    state.flow_ctrl.snd_wnd = 200;
+    lwip_tcp_rust::tcp_write(&mut state, &[1; 300], 0).unwrap();
+
+    assert_eq!(tcp_shutdown(&mut state, false, true), Ok(CloseAction::FinQueued));
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait1);
+
+    // The window takes part of the data; the FIN stays behind the rest
+    let segs = lwip_tcp_rust::tcp_output(&mut state).unwrap();
+    assert_eq!(segs.iter().map(|s| s.data.len()).sum::<usize>(), 200);
+    assert!(segs.iter().all(|s| s.flags & tcp_proto::TCP_FIN == 0));
+
+    // Acking it opens the window for the last 100 bytes and the FIN
+    let ack = TcpSegment { ackno: 1201, wnd: 200, ..data_segment(2001, 0) };
+    tcp_input(&mut state, &ack, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT).unwrap();
+    let segs = lwip_tcp_rust::tcp_output(&mut state).unwrap();
+    let last = segs.last().unwrap();
+    assert_eq!(last.seqno + last.data.len() as u32, 1301);
+    assert_ne!(last.flags & tcp_proto::TCP_FIN, 0);
+}
+
+#[test]
+fn test_tcp_shutdown_rx_only() {
+    let mut state = established_state();
+    state.flow_ctrl.rcv_wnd = tcp_proto::TCP_WND as u32;
+
+    assert_eq!(tcp_shutdown(&mut state, true, false), Ok(CloseAction::None));
+    assert_eq!(state.conn_mgmt.state, TcpState::Established);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_RXCLOSED, 0);
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_FIN, 0);
+
+    // Data is acknowledged, then thrown away with its window space
+    let result = tcp_input(&mut state, &data_segment(2001, 100), ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT);
+    assert!(result.is_ok());
+    assert_eq!(state.rod.rcv_nxt, 2101);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_ACK_DELAY, 0);
+    assert!(state.rod.rcv_ready.is_empty());
+    assert_eq!(state.flow_ctrl.rcv_wnd, tcp_proto::TCP_WND as u32);
+
+    // Sending still works
+    assert!(lwip_tcp_rust::tcp_write(&mut state, &[1, 2, 3], 0).is_ok());
+}
+
+#[test]
+fn test_tcp_shutdown_both_is_full_close() {
+    let mut shut = established_state();
+    let mut closed = established_state();
+
+    assert_eq!(tcp_shutdown(&mut shut, true, true), initiate_close(&mut closed));
+    assert_eq!(shut.conn_mgmt.state, closed.conn_mgmt.state);
+    assert_eq!(shut.rod.snd_lbb, closed.rod.snd_lbb);
+    assert!(shut.rod.fin_pending);
+    assert_ne!(shut.conn_mgmt.flags & tcp_proto::TF_RXCLOSED, 0);
+    assert_eq!(lwip_tcp_rust::tcp_write(&mut shut, &[1, 2, 3], 0), Err(TcpError::Closed));
+}
+
+#[test]
+fn test_tcp_shutdown_listen_rejected() {
+    let mut state = create_test_state();
+    tcp_bind(&mut state, ffi::ip_addr_t { addr: TEST_LOCAL_IP }, TEST_LOCAL_PORT).unwrap();
+    tcp_listen(&mut state).unwrap();
+
+    assert_eq!(tcp_shutdown(&mut state, true, true), Err(TcpError::NotConnected));
+    assert_eq!(state.conn_mgmt.state, TcpState::Listen);
+}
+
+#[test]
+fn test_tcp_shutdown_tx_needs_connection() {
+    let mut state = create_test_state();
+    assert_eq!(tcp_shutdown(&mut state, false, true), Err(TcpError::NotConnected));
+
+    tcp_connect(&mut state, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT).unwrap();
+    assert_eq!(tcp_shutdown(&mut state, false, true), Err(TcpError::NotConnected));
+    assert_eq!(state.conn_mgmt.state, TcpState::SynSent);
+
+    // Once the FIN is out there is nothing left to shut down
+    set_tcp_state(&mut state, TcpState::FinWait1, TEST_LOCAL_IP, TEST_REMOTE_IP, TEST_LOCAL_PORT, TEST_REMOTE_PORT);
+    assert_eq!(tcp_shutdown(&mut state, false, true), Err(TcpError::NotConnected));
+}
+
+#[test]
+fn test_tcp_error_maps_to_err_t() {
+    assert_eq!(TcpError::BadState.to_err_t(), -6);         // ERR_VAL
+    assert_eq!(TcpError::NoMemory.to_err_t(), -1);         // ERR_MEM
+    assert_eq!(TcpError::PortInUse.to_err_t(), -8);        // ERR_USE
+    assert_eq!(TcpError::AlreadyConnected.to_err_t(), -10); // ERR_ISCONN
+    assert_eq!(TcpError::NotConnected.to_err_t(), -11);    // ERR_CONN
+    assert_eq!(TcpError::Closed.to_err_t(), -15);          // ERR_CLSD
+    assert_eq!(TcpError::InvalidArgument.to_err_t(), -16); // ERR_ARG
+
+    // Component messages survive the conversion
+    let err: TcpError = "Can only close from SYN_SENT, SYN_RCVD, ESTABLISHED, or CLOSE_WAIT".into();
+    assert_eq!(err.to_err_t(), -6);
+    assert_eq!(err.to_string(), "Can only close from SYN_SENT, SYN_RCVD, ESTABLISHED, or CLOSE_WAIT");
+}
+
+// ============================================================================
+// Transition Table
+// ============================================================================
+
+#[test]
+fn test_transition_table_matches_rfc793_diagram() {
+    use TcpEvent as E;
+    use TcpState as S;
+
+    // Every legal (from, event, to); anything else must be refused
+    let legal = [
+        (S::Closed, E::Bind, S::Closed),
+        (S::Closed, E::PassiveOpen, S::Listen),
+        (S::Closed, E::ActiveOpen, S::SynSent),
+        (S::Listen, E::RcvSyn, S::SynRcvd),
+        (S::TimeWait, E::RcvSyn, S::SynRcvd),
+        (S::SynSent, E::RcvSyn, S::SynRcvd),
+        (S::SynSent, E::RcvSynAck, S::Established),
+        (S::SynRcvd, E::RcvAckOfSyn, S::Established),
+        (S::Listen, E::Close, S::Closed),
+        (S::SynSent, E::Close, S::Closed),
+        (S::SynRcvd, E::Close, S::Closed),
+        (S::Established, E::Close, S::FinWait1),
+        (S::CloseWait, E::Close, S::LastAck),
+        (S::Established, E::RcvFin, S::CloseWait),
+        (S::FinWait1, E::RcvFin, S::Closing),
+        (S::FinWait2, E::RcvFin, S::TimeWait),
+        (S::TimeWait, E::RcvFin, S::TimeWait),
+        (S::FinWait1, E::RcvAckOfFin, S::FinWait2),
+        (S::Closing, E::RcvAckOfFin, S::TimeWait),
+        (S::LastAck, E::RcvAckOfFin, S::Closed),
+        (S::TimeWait, E::TimeWaitTimeout, S::Closed),
+    ];
+
+    for from in S::ALL {
+        for event in E::ALL {
+            let expected = match event {
+                E::Abort | E::RcvRst => Some(S::Closed),
+                _ => legal.iter().find(|(f, e, _)| *f == from && *e == event).map(|(_, _, to)| *to),
+            };
+            assert_eq!(from.next_state(event), expected, "{:?} on {:?}", from, event);
+            assert_eq!(TcpState::can_transition(from, event), expected.is_some());
+        }
+    }
+}
+
+#[test]
+fn test_illegal_event_leaves_state_untouched() {
+    let mut state = create_test_state();
+    state.conn_mgmt.state = TcpState::FinWait2;
+
+    assert_eq!(state.conn_mgmt.on_ack_in_lastack(), Err("Invalid state transition"));
+    assert_eq!(state.conn_mgmt.on_close_in_established(), Err("Invalid state transition"));
+    assert_eq!(state.conn_mgmt.on_timewait_timeout(), Err("Invalid state transition"));
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait2);
+
+    state.conn_mgmt.on_fin_in_finwait2().unwrap();
+    state.conn_mgmt.on_timewait_timeout().unwrap();
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+}
+
+#[test]
+fn test_root_types_are_tcp_types() {
+    // One definition only: the crate-root names must be the tcp_types ones.
+    // Each binding fails to compile if the two paths ever diverge.
+    fn same<T>(x: T) -> T { x }
+
+    let _: lwip_tcp_rust::tcp_types::TcpFlags = same::<TcpFlags>(TcpFlags::default());
+    let seg: lwip_tcp_rust::tcp_types::TcpSegment = same::<TcpSegment>(TcpSegment::default());
+    let _: lwip_tcp_rust::tcp_types::RstValidation = same::<RstValidation>(RstValidation::Valid);
+    let _: lwip_tcp_rust::tcp_types::AckValidation = same::<AckValidation>(AckValidation::Valid);
+    let _: lwip_tcp_rust::tcp_types::InputAction = same::<InputAction>(InputAction::Accept);
+
+    assert_eq!(
+        core::any::TypeId::of::<TcpSegment>(),
+        core::any::TypeId::of::<lwip_tcp_rust::tcp_types::TcpSegment>()
+    );
+    assert_eq!(seg.payload_len, 0);
+}
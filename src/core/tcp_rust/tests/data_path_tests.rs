@@ -0,0 +1,1124 @@
+//! TCP Data Path Tests
+//!
+//! Queue data with tcp_write and drain it with tcp_output on an established
+//! connection, checking segmentation and that the congestion and receive
+//! windows bound what is put on the wire, including sender-side silly
+//! window avoidance and Nagle, and that a graceful close sends its FIN after the
+//! queued data.
+
+mod test_helpers;
+
+use test_helpers::*;
+use lwip_tcp_rust::{initiate_close, CloseAction, tcp_fasttmr_pcb, tcp_input, tcp_output, tcp_recved, tcp_write, InputAction, TcpFlags, TcpOptions, TcpSegment, TimerAction};
+use lwip_tcp_rust::state::{TcpConnectionState, TcpState};
+use lwip_tcp_rust::tcp_out::TcpTx;
+use lwip_tcp_rust::tcp_proto;
+use lwip_tcp_rust::ffi;
+
+fn established() -> TcpConnectionState {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    state.rod.snd_buf = 8192;
+    state
+}
+
+#[test]
+fn test_output_respects_receive_window() {
+    let mut state = established();
+    state.flow_ctrl.snd_wnd = 1000;
+    state.cong_ctrl.cwnd = 0xFFFF;
+
+    tcp_write(&mut state, &[0x5A; 2000], 0).unwrap();
+    let segs = tcp_output(&mut state).unwrap();
+
+    // One full MSS plus the rest of the window
+    let lens: Vec<usize> = segs.iter().map(|s| s.data.len()).collect();
+    assert_eq!(lens, vec![536, 464]);
+    assert_eq!(segs[0].seqno, 1001);
+    assert_eq!(segs[1].seqno, 1537);
+
+    assert_eq!(state.rod.snd_nxt, 2001);
+    assert_eq!(state.rod.unacked.len(), 2);
+    assert_eq!(state.rod.unsent.bytes(), 1000);
+
+    // Window is full, nothing more goes out
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+}
+
+#[test]
+fn test_output_respects_congestion_window() {
+    let mut state = established();
+    state.flow_ctrl.snd_wnd = 8192;
+    state.cong_ctrl.cwnd = 600;
+
+    tcp_write(&mut state, &[0; 2000], 0).unwrap();
+    let segs = tcp_output(&mut state).unwrap();
+
+    let sent: usize = segs.iter().map(|s| s.data.len()).sum();
+    assert_eq!(sent, 600);
+    assert_eq!(state.rod.snd_nxt.wrapping_sub(state.rod.lastack), 600);
+}
+
+#[test]
+fn test_ack_opens_window_for_more_data() {
+    let mut state = established();
+    state.flow_ctrl.snd_wnd = 1000;
+    state.cong_ctrl.cwnd = 0xFFFF;
+
+    tcp_write(&mut state, &[0; 2000], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+
+    // Acknowledge the first segment
+    let ack = TcpSegment {
+        seqno: state.rod.rcv_nxt,
+        ackno: 1537,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        wnd: 1000,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    };
+    state.rod.on_ack_in_established(&ack, 2).unwrap();
+
+    let segs = tcp_output(&mut state).unwrap();
+    let sent: usize = segs.iter().map(|s| s.data.len()).sum();
+    assert_eq!(sent, 536);
+    assert_eq!(state.rod.snd_nxt, 2537);
+}
+
+#[test]
+fn test_acked_data_frees_send_buffer() {
+    let mut state = established();
+    state.rod.snd_buf = tcp_proto::TCP_SND_BUF;
+    state.flow_ctrl.snd_wnd = 8192;
+    state.cong_ctrl.cwnd = 0xFFFF;
+
+    tcp_write(&mut state, &[0; 1000], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+    assert_eq!(state.rod.snd_buf, tcp_proto::TCP_SND_BUF - 1000);
+
+    // Space comes back as it is acknowledged, not when it is sent
+    input(&mut state, &window_ack(2001, 1537, 8192));
+    assert_eq!(state.rod.snd_buf, tcp_proto::TCP_SND_BUF - 464);
+    // Nagle held the tail back until now
+    tcp_output(&mut state).unwrap();
+    input(&mut state, &window_ack(2001, 2001, 8192));
+    assert_eq!(state.rod.snd_buf, tcp_proto::TCP_SND_BUF);
+}
+
+#[test]
+fn test_segmentize_splits_write_at_mss() {
+    let mut state = established();
+    state.conn_mgmt.mss = 1460;
+    state.conn_mgmt.eff_mss = 1460;
+    state.conn_mgmt.flags |= tcp_proto::TF_NODELAY;
+    state.cong_ctrl.cwnd = 0xFFFF;
+
+    tcp_write(&mut state, &[0; 4000], 0).unwrap();
+    let plan = TcpTx::segmentize(&state, usize::MAX);
+
+    let lens: Vec<usize> = plan.iter().map(|d| d.len).collect();
+    assert_eq!(lens, vec![1460, 1460, 1080]);
+    assert_eq!(plan[1].seqno, 1001 + 1460);
+    assert_eq!(plan[2].seqno, 1001 + 2 * 1460);
+
+    // PSH only on the last segment
+    assert_eq!(plan[0].flags, tcp_proto::TCP_ACK);
+    assert_eq!(plan[1].flags, tcp_proto::TCP_ACK);
+    assert_eq!(plan[2].flags, tcp_proto::TCP_ACK | tcp_proto::TCP_PSH);
+
+    // Planning does not consume the queue
+    assert_eq!(state.rod.unsent.bytes(), 4000);
+}
+
+#[test]
+fn test_segmentize_stops_at_window_and_budget() {
+    let mut state = established();
+    state.conn_mgmt.mss = 1460;
+    state.conn_mgmt.eff_mss = 1460;
+    state.cong_ctrl.cwnd = 2000;
+
+    tcp_write(&mut state, &[0; 4000], 0).unwrap();
+
+    let lens: Vec<usize> = TcpTx::segmentize(&state, usize::MAX).iter().map(|d| d.len).collect();
+    assert_eq!(lens, vec![1460, 540]);
+
+    let lens: Vec<usize> = TcpTx::segmentize(&state, 1000).iter().map(|d| d.len).collect();
+    assert_eq!(lens, vec![1000]);
+}
+
+#[test]
+fn test_small_window_defers_output_until_it_grows() {
+    let mut state = established();
+    state.conn_mgmt.flags |= tcp_proto::TF_NODELAY;
+    state.conn_mgmt.mss = 1460;
+    state.conn_mgmt.eff_mss = 1460;
+    state.cong_ctrl.cwnd = 0xFFFF;
+    state.flow_ctrl.snd_wnd_max = 8192;
+    state.flow_ctrl.snd_wnd = 600;
+
+    tcp_write(&mut state, &[0; 2000], 0).unwrap();
+
+    // 600 bytes is neither a full MSS nor half the peer's largest window
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+    assert_eq!(state.rod.unsent.bytes(), 2000);
+    // Nothing in flight to bring a window update: probe for it
+    assert_eq!(state.flow_ctrl.persist_backoff, 1);
+
+    state.flow_ctrl.snd_wnd = 4096;
+    let lens: Vec<usize> = tcp_output(&mut state).unwrap().iter().map(|s| s.data.len()).collect();
+    assert_eq!(lens, vec![1460, 540]);
+}
+
+#[test]
+fn test_half_of_max_window_is_worth_sending() {
+    let mut state = established();
+    state.conn_mgmt.mss = 1460;
+    state.conn_mgmt.eff_mss = 1460;
+    state.cong_ctrl.cwnd = 0xFFFF;
+    state.flow_ctrl.snd_wnd_max = 1000;
+    state.flow_ctrl.snd_wnd = 600;
+
+    tcp_write(&mut state, &[0; 2000], 0).unwrap();
+
+    let lens: Vec<usize> = tcp_output(&mut state).unwrap().iter().map(|s| s.data.len()).collect();
+    assert_eq!(lens, vec![600]);
+}
+
+#[test]
+fn test_pending_fin_flushes_small_segment() {
+    let mut state = established();
+    state.conn_mgmt.mss = 1460;
+    state.conn_mgmt.eff_mss = 1460;
+    state.cong_ctrl.cwnd = 0xFFFF;
+    state.flow_ctrl.snd_wnd_max = 8192;
+    state.flow_ctrl.snd_wnd = 600;
+
+    tcp_write(&mut state, &[0; 2000], 0).unwrap();
+    assert!(TcpTx::segmentize(&state, usize::MAX).is_empty());
+
+    state.conn_mgmt.flags |= tcp_proto::TF_FIN;
+    let lens: Vec<usize> = TcpTx::segmentize(&state, usize::MAX).iter().map(|d| d.len).collect();
+    assert_eq!(lens, vec![600]);
+}
+
+#[test]
+fn test_no_output_before_established() {
+    let mut state = create_test_state();
+    state.conn_mgmt.state = TcpState::SynSent;
+    state.rod.unsent.push(state.rod.snd_lbb, &[0; 100]);
+
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+    assert!(state.rod.unacked.is_empty());
+}
+
+// ============================================================================
+// Delayed ACK
+// ============================================================================
+
+fn data_seg(seqno: u32, len: u16) -> TcpSegment {
+    TcpSegment {
+        seqno,
+        ackno: 1001,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: len,
+        ..Default::default()
+    }
+}
+
+fn input(state: &mut TcpConnectionState, seg: &TcpSegment) -> InputAction {
+    tcp_input(state, seg, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT).unwrap()
+}
+
+#[test]
+fn test_single_segment_ack_is_delayed() {
+    let mut state = established();
+
+    assert_eq!(input(&mut state, &data_seg(2001, 536)), InputAction::Accept);
+    assert_eq!(state.rod.rcv_nxt, 2537);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_ACK_DELAY, 0);
+
+    // The fast timer sends it, once
+    assert_eq!(tcp_fasttmr_pcb(&mut state).unwrap(), TimerAction::SendAck);
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_ACK_DELAY, 0);
+    assert_eq!(tcp_fasttmr_pcb(&mut state).unwrap(), TimerAction::None);
+}
+
+#[test]
+fn test_second_segment_acked_immediately() {
+    let mut state = established();
+
+    assert_eq!(input(&mut state, &data_seg(2001, 536)), InputAction::Accept);
+    assert_eq!(input(&mut state, &data_seg(2537, 536)), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, 3073);
+
+    // The immediate ACK covered the delayed one
+    let ack_flags = tcp_proto::TF_ACK_DELAY | tcp_proto::TF_ACK_NOW;
+    assert_eq!(state.conn_mgmt.flags & ack_flags, 0);
+    assert_eq!(tcp_fasttmr_pcb(&mut state).unwrap(), TimerAction::None);
+}
+
+#[test]
+fn test_ack_window_shrink_and_data_in_one_segment() {
+    let mut state = established();
+    state.flow_ctrl.snd_wnd = 1000;
+    state.cong_ctrl.cwnd = 0xFFFF;
+    tcp_write(&mut state, &[0x5A; 500], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+
+    // Acknowledges 300 bytes, shrinks the window to 200 and carries 100
+    // bytes of its own: ACK first, then the window, then the data
+    let seg = TcpSegment { ackno: 1301, wnd: 200, ..data_seg(2001, 100) };
+    input(&mut state, &seg);
+
+    assert_eq!(state.rod.lastack, 1301);
+    assert_eq!(state.flow_ctrl.snd_wnd, 200);
+    assert_eq!(state.flow_ctrl.snd_wl2, 1301);
+    assert_eq!(state.rod.rcv_nxt, 2101);
+    assert_eq!(state.flow_ctrl.rcv_wnd, 8192 - 100);
+
+    // The 200 bytes still in flight fill the shrunk window
+    tcp_write(&mut state, &[0x5A; 100], 0).unwrap();
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+}
+
+#[test]
+fn test_push_segment_acked_immediately() {
+    let mut state = established();
+
+    let push = TcpSegment {
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_PSH),
+        ..data_seg(2001, 100)
+    };
+    assert_eq!(input(&mut state, &push), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, 2101);
+    assert_eq!(tcp_fasttmr_pcb(&mut state).unwrap(), TimerAction::None);
+}
+
+#[test]
+fn test_delayed_ack_rides_on_outgoing_data() {
+    let mut state = established();
+    state.flow_ctrl.snd_wnd = 8192;
+    state.cong_ctrl.cwnd = 0xFFFF;
+
+    assert_eq!(input(&mut state, &data_seg(2001, 100)), InputAction::Accept);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_ACK_DELAY, 0);
+
+    // One segment carries both the data and the ACK of what came in
+    tcp_write(&mut state, &[0; 100], 0).unwrap();
+    let segs = tcp_output(&mut state).unwrap();
+    assert_eq!(segs.len(), 1);
+    assert_ne!(segs[0].flags & tcp_proto::TCP_ACK, 0);
+    let bytes = TcpTx::build_segment(&state, segs[0].seqno, state.rod.rcv_nxt, segs[0].flags, &[], &segs[0].data).unwrap();
+    assert_eq!(u32::from_be_bytes(bytes[8..12].try_into().unwrap()), 2101);
+
+    // Nothing left for the fast timer to send
+    let ack_flags = tcp_proto::TF_ACK_DELAY | tcp_proto::TF_ACK_NOW;
+    assert_eq!(state.conn_mgmt.flags & ack_flags, 0);
+    assert_eq!(tcp_fasttmr_pcb(&mut state).unwrap(), TimerAction::None);
+}
+
+#[test]
+fn test_out_of_order_segment_acked_immediately() {
+    let mut state = established();
+
+    assert_eq!(input(&mut state, &data_seg(2537, 536)), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, 2001);
+}
+
+// ============================================================================
+// Out-of-Order Reassembly
+// ============================================================================
+
+fn payload_seg(seqno: u32, data: &[u8]) -> TcpSegment {
+    TcpSegment {
+        payload: data.to_vec(),
+        ..data_seg(seqno, data.len() as u16)
+    }
+}
+
+#[test]
+fn test_reverse_order_segments_reassemble() {
+    let mut state = established();
+
+    input(&mut state, &payload_seg(2201, &[3; 100]));
+    input(&mut state, &payload_seg(2101, &[2; 100]));
+    assert_eq!(state.rod.rcv_nxt, 2001);
+    assert_eq!(state.rod.ooseq.len(), 2);
+    assert_eq!(state.rod.ooseq.front().unwrap().seqno, 2101);
+
+    // The missing first segment releases everything queued behind it
+    input(&mut state, &payload_seg(2001, &[1; 100]));
+    assert_eq!(state.rod.rcv_nxt, 2301);
+    assert!(state.rod.ooseq.is_empty());
+}
+
+#[test]
+fn test_overlapping_segments_merge() {
+    let mut state = established();
+
+    input(&mut state, &payload_seg(2101, &[0; 100]));
+    input(&mut state, &payload_seg(2051, &[0; 200]));
+    assert_eq!(state.rod.ooseq.bytes(), 200);
+
+    input(&mut state, &payload_seg(2001, &[0; 100]));
+    assert_eq!(state.rod.rcv_nxt, 2251);
+    assert!(state.rod.ooseq.is_empty());
+}
+
+#[test]
+fn test_fin_on_data_taken_after_the_data() {
+    let mut state = established();
+    let mut seg = payload_seg(2001, &[0; 100]);
+    seg.flags.fin = true;
+
+    assert_eq!(input(&mut state, &seg), InputAction::SendAck);
+    assert_eq!(state.conn_mgmt.state, TcpState::CloseWait);
+    // Past the data, plus one for the FIN
+    assert_eq!(state.rod.rcv_nxt, 2102);
+}
+
+#[test]
+fn test_fin_beyond_gap_is_not_taken() {
+    let mut state = established();
+    let mut seg = payload_seg(2101, &[0; 100]);
+    seg.flags.fin = true;
+
+    // The data waits for the gap; the peer resends the FIN
+    input(&mut state, &seg);
+    assert_eq!(state.conn_mgmt.state, TcpState::Established);
+    assert_eq!(state.rod.rcv_nxt, 2001);
+}
+
+#[test]
+fn test_gap_fill_takes_push_from_last_queued_segment() {
+    let mut state = established();
+    let pushed = |seqno, data: &[u8]| TcpSegment {
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_PSH),
+        ..payload_seg(seqno, data)
+    };
+
+    input(&mut state, &payload_seg(2101, &[2; 100]));
+    input(&mut state, &pushed(2201, &[3; 100]));
+
+    // Filling the gap releases the queue, whose last segment was pushed
+    input(&mut state, &payload_seg(2001, &[1; 100]));
+    assert_eq!(state.rod.rcv_nxt, 2301);
+    assert!(state.rod.rcv_push);
+    state.rod.on_deliver();
+    assert!(!state.rod.rcv_push);
+
+    // A pushed filler followed by unpushed queued data is not pushed
+    input(&mut state, &payload_seg(2401, &[5; 100]));
+    input(&mut state, &pushed(2301, &[4; 100]));
+    assert_eq!(state.rod.rcv_nxt, 2501);
+    assert!(!state.rod.rcv_push);
+}
+
+#[test]
+fn test_ooseq_bounded_drops_furthest() {
+    let mut state = established();
+    let max = tcp_proto::TCP_OOSEQ_MAX_BYTES as u32;
+
+    input(&mut state, &payload_seg(2101, &vec![0; max as usize]));
+    input(&mut state, &payload_seg(2101 + max, &[0; 100]));
+    assert_eq!(state.rod.ooseq.bytes(), max as usize);
+    assert_eq!(state.rod.ooseq.len(), 1);
+
+    // Nearer data displaces the furthest segment
+    input(&mut state, &payload_seg(2051, &[0; 50]));
+    assert_eq!(state.rod.ooseq.len(), 1);
+    assert_eq!(state.rod.ooseq.front().unwrap().seqno, 2051);
+}
+
+#[test]
+fn test_free_ooseq_clears_queue() {
+    let mut state = established();
+
+    input(&mut state, &payload_seg(2101, &[0; 100]));
+    state.rod.on_free_ooseq().unwrap();
+    assert!(state.rod.ooseq.is_empty());
+
+    // The gap filling now only covers the first segment
+    input(&mut state, &payload_seg(2001, &[0; 100]));
+    assert_eq!(state.rod.rcv_nxt, 2101);
+}
+
+#[test]
+fn test_gap_segment_sends_dup_ack() {
+    let mut state = established();
+    input(&mut state, &payload_seg(2001, &[0; 100]));
+    state.conn_mgmt.on_ack_sent().unwrap();
+
+    // Segment after a hole: ACK immediately, still asking for 2101
+    assert_eq!(input(&mut state, &payload_seg(2201, &[0; 100])), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, 2101);
+
+    // Every further segment past the hole repeats the same ACK
+    assert_eq!(input(&mut state, &payload_seg(2301, &[0; 100])), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, 2101);
+    assert_eq!(state.rod.ooseq.len(), 2);
+}
+
+#[test]
+fn test_data_with_stale_ack_is_delivered() {
+    let mut state = established();
+    state.flow_ctrl.snd_wnd_max = 8192;
+    state.rod.lastack = 1501;
+    state.rod.snd_nxt = 1501;
+
+    // Reordered behind a later ACK: the ACK is old, the data is not
+    let seg = TcpSegment { ackno: 1201, ..payload_seg(2001, &[7; 100]) };
+    assert_eq!(input(&mut state, &seg), InputAction::Accept);
+    assert_eq!(state.rod.rcv_nxt, 2101);
+    assert_eq!(state.rod.lastack, 1501);
+}
+
+#[test]
+fn test_data_with_ack_older_than_any_window_is_dropped() {
+    let mut state = established();
+    state.flow_ctrl.snd_wnd_max = 8192;
+    state.rod.lastack = 20001;
+    state.rod.snd_nxt = 20001;
+    // Challenged just now, so the shared challenge ACK budget is left alone
+    state.rod.on_challenge_ack(unsafe { lwip_tcp_rust::tcp_ticks });
+
+    let seg = TcpSegment { ackno: 1001, ..payload_seg(2001, &[7; 100]) };
+    assert_ne!(input(&mut state, &seg), InputAction::Accept);
+    assert_eq!(state.rod.rcv_nxt, 2001);
+}
+
+#[test]
+fn test_challenge_acks_are_rate_limited() {
+    // The budget is shared: each challenge comes from its own connection
+    let mut conns: Vec<TcpConnectionState> = (0..=tcp_proto::TCP_CHALLENGE_ACK_LIMIT).map(|_| established()).collect();
+
+    // ACK for data we never sent
+    let future_ack = TcpSegment { ackno: conns[0].rod.snd_nxt.wrapping_add(1000), ..data_seg(conns[0].rod.rcv_nxt, 0) };
+
+    let (last, first) = conns.split_last_mut().unwrap();
+    for state in first {
+        assert_eq!(input(state, &future_ack), InputAction::SendChallengeAck);
+    }
+    // Budget spent: further challenges are silently dropped, without
+    // using up the connection's own window
+    assert_eq!(input(last, &future_ack), InputAction::Drop);
+    assert_eq!(last.rod.last_challenge_tick, None);
+
+    // The next window refills the bucket
+    unsafe { lwip_tcp_rust::tcp_ticks += tcp_proto::TCP_CHALLENGE_ACK_WINDOW };
+    assert_eq!(input(last, &future_ack), InputAction::SendChallengeAck);
+}
+
+// ============================================================================
+// Receive Window
+// ============================================================================
+
+fn established_with_rcv_buf() -> TcpConnectionState {
+    let mut state = established();
+    state.flow_ctrl.rcv_wnd = tcp_proto::TCP_WND as u32;
+    state.flow_ctrl.rcv_ann_wnd = tcp_proto::TCP_WND as u32;
+    state.flow_ctrl.rcv_ann_right_edge = 2001 + tcp_proto::TCP_WND as u32;
+    state
+}
+
+/// `len` bytes counting up from the byte at `seqno`
+fn numbered_seg(seqno: u32, len: u16) -> TcpSegment {
+    let payload = (0..len as u32).map(|i| (seqno + i) as u8).collect();
+    TcpSegment { payload, ..data_seg(seqno, len) }
+}
+
+#[test]
+fn test_probe_into_zero_window_is_not_taken() {
+    let mut state = established_with_rcv_buf();
+    input(&mut state, &numbered_seg(2001, tcp_proto::TCP_WND));
+    assert_eq!(state.rod.on_deliver().0.len(), tcp_proto::TCP_WND as usize);
+    let rcv_nxt = state.rod.rcv_nxt;
+    assert_eq!(state.flow_ctrl.rcv_wnd, 0);
+
+    // No buffer for the probe byte: ACK rcv_nxt with our zero window
+    assert_eq!(input(&mut state, &numbered_seg(rcv_nxt, 1)), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, rcv_nxt);
+    assert!(state.rod.on_deliver().0.is_empty());
+    assert_eq!(state.flow_ctrl.advertised_window(), 0);
+
+    // Once the window opens the byte comes again with what follows it
+    tcp_recved(&mut state, tcp_proto::TCP_WND).unwrap();
+    input(&mut state, &numbered_seg(rcv_nxt, 100));
+    assert_eq!(state.rod.rcv_nxt, rcv_nxt + 100);
+    assert_eq!(state.rod.on_deliver().0, numbered_seg(rcv_nxt, 100).payload);
+}
+
+#[test]
+fn test_probe_into_open_window_is_data() {
+    let mut state = established_with_rcv_buf();
+
+    // A probe that finds room is just the first byte of the stream
+    assert_eq!(input(&mut state, &numbered_seg(2001, 1)), InputAction::Accept);
+    assert_eq!(state.rod.rcv_nxt, 2002);
+
+    // The full segment overlaps it: the probe byte is not delivered twice
+    input(&mut state, &numbered_seg(2001, 100));
+    assert_eq!(state.rod.rcv_nxt, 2101);
+    assert_eq!(state.rod.on_deliver().0, numbered_seg(2001, 100).payload);
+    assert_eq!(state.flow_ctrl.rcv_wnd, tcp_proto::TCP_WND as u32 - 100);
+}
+
+#[test]
+fn test_received_data_shrinks_window_not_right_edge() {
+    let mut state = established_with_rcv_buf();
+    let right_edge = state.flow_ctrl.rcv_ann_right_edge;
+
+    input(&mut state, &data_seg(2001, 1000));
+
+    assert_eq!(state.flow_ctrl.rcv_wnd, tcp_proto::TCP_WND as u32 - 1000);
+    assert_eq!(state.flow_ctrl.rcv_ann_wnd, tcp_proto::TCP_WND as u32 - 1000);
+    assert_eq!(state.flow_ctrl.rcv_ann_right_edge, right_edge);
+}
+
+#[test]
+fn test_small_reads_do_not_update_window() {
+    let mut state = established_with_rcv_buf();
+    let right_edge = state.flow_ctrl.rcv_ann_right_edge;
+    input(&mut state, &data_seg(2001, 1000));
+
+    // Below min(MSS, TCP_WND / 2): keep advertising the old edge
+    assert!(!tcp_recved(&mut state, 100).unwrap());
+    assert!(!tcp_recved(&mut state, 400).unwrap());
+    assert_eq!(state.flow_ctrl.rcv_ann_right_edge, right_edge);
+    assert_eq!(state.flow_ctrl.rcv_ann_wnd, tcp_proto::TCP_WND as u32 - 1000);
+
+    // Crossing the threshold moves the edge by everything refunded
+    assert!(tcp_recved(&mut state, 100).unwrap());
+    assert_eq!(state.flow_ctrl.rcv_ann_right_edge, right_edge + 600);
+    assert_eq!(state.flow_ctrl.rcv_ann_wnd, tcp_proto::TCP_WND as u32 - 400);
+}
+
+#[test]
+fn test_window_refund_is_capped_at_buffer_size() {
+    let mut state = established_with_rcv_buf();
+    input(&mut state, &data_seg(2001, 1000));
+
+    assert!(tcp_recved(&mut state, 5000).unwrap());
+    assert_eq!(state.flow_ctrl.rcv_wnd, tcp_proto::TCP_WND as u32);
+    assert_eq!(state.flow_ctrl.rcv_ann_right_edge, 3001 + tcp_proto::TCP_WND as u32);
+}
+
+#[test]
+fn test_refund_bounded_by_configured_rcv_buf() {
+    let mut state = established();
+    state.flow_ctrl.rcv_buf = 1500;
+    state.flow_ctrl.rcv_wnd = 1500;
+    state.flow_ctrl.rcv_ann_wnd = 1500;
+    state.flow_ctrl.rcv_ann_right_edge = 2001 + 1500;
+
+    // Fill the buffer: nothing left to advertise
+    input(&mut state, &data_seg(2001, 1500));
+    assert_eq!((state.flow_ctrl.rcv_wnd, state.flow_ctrl.rcv_ann_wnd), (0, 0));
+
+    // Over-refund is clamped to what was taken, and the peer hears of it
+    assert!(tcp_recved(&mut state, u16::MAX).unwrap());
+    assert_eq!(state.flow_ctrl.rcv_wnd, 1500);
+    assert_eq!(state.flow_ctrl.rcv_ann_wnd, 1500);
+    assert_eq!(state.flow_ctrl.rcv_ann_right_edge, 3501 + 1500);
+
+    // Nothing outstanding: a further refund changes nothing
+    assert!(!tcp_recved(&mut state, 100).unwrap());
+    assert_eq!(state.flow_ctrl.rcv_wnd, 1500);
+}
+
+#[test]
+fn test_advertised_window_respects_cap() {
+    let mut state = established_with_rcv_buf();
+    state.flow_ctrl.set_max_rcv_wnd(1000).unwrap();
+    let advertised = |state: &TcpConnectionState| {
+        let bytes = TcpTx::build_segment(state, state.rod.snd_nxt, state.rod.rcv_nxt, tcp_proto::TCP_ACK, &[], &[]).unwrap();
+        TcpSegment::parse(&bytes).unwrap().wnd
+    };
+
+    // The window already offered is honoured, not taken back
+    input(&mut state, &data_seg(2001, 4096));
+    assert_eq!(state.flow_ctrl.rcv_ann_wnd, 0);
+
+    // Buffer space for all of TCP_WND, but only the cap is offered
+    assert!(tcp_recved(&mut state, 4096).unwrap());
+    assert_eq!(state.flow_ctrl.rcv_wnd, tcp_proto::TCP_WND as u32);
+    assert_eq!(state.flow_ctrl.rcv_ann_wnd, 1000);
+    assert_eq!(advertised(&state), 1000);
+
+    let mut seqno = 6097;
+    for _ in 0..4 {
+        input(&mut state, &data_seg(seqno, 500));
+        tcp_recved(&mut state, 500).unwrap();
+        seqno += 500;
+        assert!(state.flow_ctrl.rcv_ann_wnd <= 1000);
+        assert!(advertised(&state) <= 1000);
+    }
+    assert_eq!(state.flow_ctrl.rcv_ann_wnd, 1000);
+
+    // Lifting the cap lets the next update offer the buffer again
+    state.flow_ctrl.set_max_rcv_wnd(0).unwrap();
+    input(&mut state, &data_seg(seqno, 500));
+    assert_eq!(state.flow_ctrl.rcv_ann_wnd, tcp_proto::TCP_WND as u32 - 500);
+}
+
+// ============================================================================
+// Send Window Updates
+// ============================================================================
+
+fn window_ack(seqno: u32, ackno: u32, wnd: u16) -> TcpSegment {
+    TcpSegment {
+        seqno,
+        ackno,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        wnd,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_old_segment_does_not_regress_window() {
+    let mut state = established();
+    state.cong_ctrl.cwnd = 0xFFFF;
+    tcp_write(&mut state, &[0; 1000], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+
+    // Newer segment: window recorded along with wl1/wl2
+    input(&mut state, &window_ack(2001, 1501, 6000));
+    assert_eq!(state.flow_ctrl.snd_wnd, 6000);
+    assert_eq!((state.flow_ctrl.snd_wl1, state.flow_ctrl.snd_wl2), (2001, 1501));
+
+    // Same seqno, older ackno (reordered): ignored
+    input(&mut state, &window_ack(2001, 1401, 100));
+    assert_eq!(state.flow_ctrl.snd_wnd, 6000);
+    assert_eq!((state.flow_ctrl.snd_wl1, state.flow_ctrl.snd_wl2), (2001, 1501));
+}
+
+#[test]
+fn test_older_seqno_does_not_regress_window() {
+    let mut state = established();
+    state.flow_ctrl.snd_wnd_max = 0;
+
+    // Peer's data moves wl1 on to 2011, with a large window
+    input(&mut state, &TcpSegment { payload_len: 10, ..window_ack(2001, 1001, 5000) });
+    input(&mut state, &window_ack(2011, 1001, 9000));
+    assert_eq!((state.flow_ctrl.snd_wl1, state.flow_ctrl.snd_wnd), (2011, 9000));
+
+    // A segment from before that data arrives late: its window is stale
+    input(&mut state, &window_ack(2001, 1001, 100));
+    assert_eq!((state.flow_ctrl.snd_wl1, state.flow_ctrl.snd_wnd), (2011, 9000));
+    assert_eq!(state.flow_ctrl.snd_wnd_max, 9000);
+}
+
+#[test]
+fn test_newer_segment_updates_window() {
+    let mut state = established();
+    state.conn_mgmt.flags |= tcp_proto::TF_NODELAY;
+    state.cong_ctrl.cwnd = 0xFFFF;
+    tcp_write(&mut state, &[0; 1000], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+    input(&mut state, &window_ack(2001, 1501, 6000));
+
+    // Same seqno, newer ackno
+    input(&mut state, &window_ack(2001, 2001, 3000));
+    assert_eq!(state.flow_ctrl.snd_wnd, 3000);
+
+    // Peer sent data since: its next segment has a newer seqno
+    input(&mut state, &TcpSegment { payload_len: 10, ..window_ack(2001, 2001, 5000) });
+    input(&mut state, &window_ack(2011, 2001, 7000));
+    assert_eq!(state.flow_ctrl.snd_wnd, 7000);
+    assert_eq!(state.flow_ctrl.snd_wl1, 2011);
+}
+
+#[test]
+fn test_window_shrink_then_grow_resumes_sending() {
+    let mut state = established();
+    state.conn_mgmt.flags |= tcp_proto::TF_NODELAY;
+    state.cong_ctrl.cwnd = 0xFFFF;
+    state.flow_ctrl.snd_wnd = 1000;
+    tcp_write(&mut state, &[0; 2000], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+    let lastack = state.rod.lastack;
+
+    // Peer shrinks its window below what is in flight: nothing more goes
+    // out, and a window update is no duplicate ACK
+    input(&mut state, &window_ack(2001, lastack, 200));
+    assert_eq!(state.flow_ctrl.snd_wnd, 200);
+    assert_eq!(state.rod.dupacks, 0);
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+
+    // ...then opens it again with data of its own
+    let grow = TcpSegment { payload_len: 10, ..window_ack(2001, lastack, 4000) };
+    assert_eq!(input(&mut state, &grow), InputAction::Accept);
+    assert_eq!(state.flow_ctrl.snd_wnd, 4000);
+    assert_eq!(state.rod.dupacks, 0);
+
+    // A stale copy of the shrinking ACK cannot take the window back
+    input(&mut state, &window_ack(2001, lastack, 200));
+    assert_eq!(state.flow_ctrl.snd_wnd, 4000);
+
+    let lens: Vec<usize> = tcp_output(&mut state).unwrap().iter().map(|s| s.data.len()).collect();
+    assert_eq!(lens.iter().sum::<usize>(), 1000);
+}
+
+// ============================================================================
+// Nagle
+// ============================================================================
+
+fn sent_seqnos(state: &mut TcpConnectionState) -> Vec<u32> {
+    tcp_output(state).unwrap().iter().map(|s| s.seqno).collect()
+}
+
+#[test]
+fn test_nagle_holds_small_write_while_data_in_flight() {
+    let mut state = established();
+    state.cong_ctrl.cwnd = 0xFFFF;
+
+    // Nothing in flight: the first byte goes at once
+    tcp_write(&mut state, b"a", 0).unwrap();
+    assert_eq!(sent_seqnos(&mut state), vec![1001]);
+
+    // The second waits for the first to be acknowledged
+    tcp_write(&mut state, b"b", 0).unwrap();
+    assert!(sent_seqnos(&mut state).is_empty());
+    input(&mut state, &window_ack(2001, 1002, 8192));
+    assert_eq!(sent_seqnos(&mut state), vec![1002]);
+}
+
+#[test]
+fn test_nodelay_sends_small_write_at_once() {
+    let mut state = established();
+    state.cong_ctrl.cwnd = 0xFFFF;
+    state.conn_mgmt.flags |= tcp_proto::TF_NODELAY;
+
+    tcp_write(&mut state, b"a", 0).unwrap();
+    assert_eq!(sent_seqnos(&mut state), vec![1001]);
+    tcp_write(&mut state, b"b", 0).unwrap();
+    assert_eq!(sent_seqnos(&mut state), vec![1002]);
+}
+
+#[test]
+fn test_nagle_lets_full_segments_through() {
+    let mut state = established();
+    state.cong_ctrl.cwnd = 0xFFFF;
+    tcp_write(&mut state, b"a", 0).unwrap();
+    tcp_output(&mut state).unwrap();
+
+    // Only the short tail of the write is held back
+    tcp_write(&mut state, &[0; 600], 0).unwrap();
+    let lens: Vec<usize> = tcp_output(&mut state).unwrap().iter().map(|s| s.data.len()).collect();
+    assert_eq!(lens, vec![536]);
+    assert_eq!(state.rod.unsent.bytes(), 64);
+}
+
+#[test]
+fn test_close_flushes_held_segment() {
+    let mut state = established();
+    state.cong_ctrl.cwnd = 0xFFFF;
+    tcp_write(&mut state, b"a", 0).unwrap();
+    tcp_output(&mut state).unwrap();
+    tcp_write(&mut state, b"b", 0).unwrap();
+
+    // Nothing will come to fill it: the byte goes out with the FIN
+    initiate_close(&mut state).unwrap();
+    let segs = tcp_output(&mut state).unwrap();
+    assert_eq!(segs.len(), 1);
+    assert_eq!(segs[0].data, b"b".to_vec());
+    assert_ne!(segs[0].flags & tcp_proto::TCP_FIN, 0);
+}
+
+// ============================================================================
+// Graceful Close
+// ============================================================================
+
+#[test]
+fn test_fin_follows_queued_data() {
+    let mut state = established();
+    state.cong_ctrl.cwnd = 0xFFFF;
+    state.flow_ctrl.snd_wnd = 1000;
+    state.flow_ctrl.snd_wnd_max = 1000;
+
+    tcp_write(&mut state, &[0; 1500], 0).unwrap();
+    assert_eq!(initiate_close(&mut state), Ok(CloseAction::FinQueued));
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait1);
+
+    // The window holds back part of the data, and the FIN behind it
+    let segs = tcp_output(&mut state).unwrap();
+    assert_eq!(segs.iter().map(|s| s.data.len()).sum::<usize>(), 1000);
+    assert!(segs.iter().all(|s| s.flags & tcp_proto::TCP_FIN == 0));
+
+    // Once the rest fits, the FIN rides on the last data segment
+    input(&mut state, &window_ack(2001, 2001, 1000));
+    let segs = tcp_output(&mut state).unwrap();
+    let last = segs.last().unwrap();
+    assert_eq!(last.seqno + last.data.len() as u32, 2501);
+    assert_ne!(last.flags & tcp_proto::TCP_FIN, 0);
+    assert_eq!(state.rod.snd_nxt, 2502);
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+
+    // Data acked, FIN not yet: still FIN_WAIT_1
+    input(&mut state, &window_ack(2001, 2501, 1000));
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait1);
+
+    input(&mut state, &window_ack(2001, 2502, 1000));
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait2);
+    assert!(state.rod.unacked.is_empty());
+}
+
+#[test]
+fn test_fin_carrying_data_delivers_it_first() {
+    let mut state = established();
+    let fin = TcpSegment {
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_FIN),
+        ..numbered_seg(2001, 100)
+    };
+
+    assert_eq!(input(&mut state, &fin), InputAction::SendAck);
+    assert_eq!(state.conn_mgmt.state, TcpState::CloseWait);
+    // 100 data bytes, then one for the FIN
+    assert_eq!(state.rod.rcv_nxt, 2001 + 100 + 1);
+
+    let (data, eof) = state.rod.on_deliver();
+    assert_eq!(data, fin.payload);
+    assert!(eof);
+}
+
+#[test]
+fn test_close_with_empty_queue_sends_bare_fin() {
+    let mut state = established();
+
+    assert_eq!(initiate_close(&mut state), Ok(CloseAction::SendFin));
+    let segs = tcp_output(&mut state).unwrap();
+    assert_eq!(segs.len(), 1);
+    assert_eq!(segs[0].seqno, 1001);
+    assert!(segs[0].data.is_empty());
+    assert_eq!(segs[0].flags, tcp_proto::TCP_ACK | tcp_proto::TCP_FIN);
+
+    // The FIN takes one sequence number and waits on unacked
+    assert_eq!(state.rod.snd_nxt, 1002);
+    assert_eq!(state.rod.unacked.len(), 1);
+    assert!(state.rod.rtime > 0);
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+}
+
+#[test]
+fn test_fin_in_fin_wait_reaches_application_as_eof() {
+    let mut state = established();
+    initiate_close(&mut state).unwrap();
+    tcp_output(&mut state).unwrap();
+    input(&mut state, &window_ack(2001, 1002, 8192));
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait2);
+
+    let mut fin = window_ack(2001, 1002, 8192);
+    fin.flags.fin = true;
+    assert_eq!(input(&mut state, &fin), InputAction::SendAck);
+    assert_eq!(state.conn_mgmt.state, TcpState::TimeWait);
+    assert_eq!(state.rod.on_deliver(), (Vec::new(), true));
+}
+
+#[test]
+fn test_last_ack_closes_once_fin_acked() {
+    let mut state = established();
+    let fin = TcpSegment {
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_FIN),
+        ..window_ack(2001, 1001, 8192)
+    };
+    assert_eq!(input(&mut state, &fin), InputAction::SendAck);
+    assert_eq!(state.conn_mgmt.state, TcpState::CloseWait);
+
+    initiate_close(&mut state).unwrap();
+    assert_eq!(state.conn_mgmt.state, TcpState::LastAck);
+    tcp_output(&mut state).unwrap();
+
+    // An ACK that does not cover the FIN changes nothing
+    input(&mut state, &window_ack(2002, 1001, 8192));
+    assert_eq!(state.conn_mgmt.state, TcpState::LastAck);
+
+    input(&mut state, &window_ack(2002, 1002, 8192));
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+}
+
+#[test]
+fn test_close_wait_acks_free_sent_data() {
+    let mut state = established();
+    let fin = TcpSegment {
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_FIN),
+        ..window_ack(2001, 1001, 8192)
+    };
+    input(&mut state, &fin);
+    assert_eq!(state.conn_mgmt.state, TcpState::CloseWait);
+
+    // Our direction is still open: data goes out and ACKs free it
+    state.rod.snd_buf = tcp_proto::TCP_SND_BUF;
+    tcp_write(&mut state, &[7; 100], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+    assert_eq!(state.rod.unacked.len(), 1);
+
+    assert_eq!(input(&mut state, &window_ack(2002, 1101, 4096)), InputAction::Accept);
+    assert!(state.rod.unacked.is_empty());
+    assert_eq!(state.rod.snd_buf, tcp_proto::TCP_SND_BUF);
+    assert_eq!(state.flow_ctrl.snd_wnd, 4096);
+    assert_eq!(state.conn_mgmt.state, TcpState::CloseWait);
+}
+
+#[test]
+fn test_half_closed_connection_still_receives() {
+    let mut state = established();
+    initiate_close(&mut state).unwrap();
+    tcp_output(&mut state).unwrap();
+    input(&mut state, &window_ack(2001, 1002, 8192));
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait2);
+
+    // The peer keeps sending: data is delivered and ACKed as in ESTABLISHED
+    let seg = |seqno, data: &[u8]| TcpSegment { ackno: 1002, ..payload_seg(seqno, data) };
+    assert_eq!(input(&mut state, &seg(2001, &[1; 100])), InputAction::Accept);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_ACK_DELAY, 0);
+    assert_eq!(input(&mut state, &seg(2101, &[2; 100])), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, 2201);
+    assert_eq!(state.rod.rcv_ready.len(), 200);
+    assert_eq!(state.conn_mgmt.state, TcpState::FinWait2);
+
+    // Its FIN, behind the last of its data, ends the connection
+    let fin = TcpSegment {
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK | tcp_proto::TCP_FIN),
+        ..seg(2201, &[3; 50])
+    };
+    assert_eq!(input(&mut state, &fin), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, 2252);
+    assert_eq!(state.rod.rcv_ready.len(), 250);
+    assert_eq!(state.conn_mgmt.state, TcpState::TimeWait);
+}
+
+// ============================================================================
+// Timestamps and PAWS
+// ============================================================================
+
+fn ts_seg(seqno: u32, len: u16, tsval: u32, tsecr: u32) -> TcpSegment {
+    TcpSegment {
+        options: TcpOptions { ts: Some((tsval, tsecr)), ..Default::default() },
+        ..payload_seg(seqno, &vec![0; len as usize])
+    }
+}
+
+fn established_with_ts() -> TcpConnectionState {
+    let mut state = established();
+    state.conn_mgmt.flags |= tcp_proto::TF_TIMESTAMP;
+    state.rod.ts_recent = 1000;
+    state
+}
+
+#[test]
+fn test_paws_drops_old_timestamp_in_window() {
+    let mut state = established_with_ts();
+
+    // In window, but its TSval predates ts_recent: ACKed and dropped
+    assert_eq!(input(&mut state, &ts_seg(2001, 100, 999, 0)), InputAction::SendAck);
+    assert_eq!(state.rod.rcv_nxt, 2001);
+    assert_eq!(state.rod.ts_recent, 1000);
+
+    // Same data with a current TSval goes through and is echoed from now on
+    input(&mut state, &ts_seg(2001, 100, 1005, 0));
+    assert_eq!(state.rod.rcv_nxt, 2101);
+    assert_eq!(state.rod.ts_recent, 1005);
+}
+
+#[test]
+fn test_out_of_order_segment_does_not_move_ts_recent() {
+    let mut state = established_with_ts();
+
+    input(&mut state, &ts_seg(2201, 100, 2000, 0));
+    assert_eq!(state.rod.ts_recent, 1000);
+}
+
+#[test]
+fn test_timestamp_echo_samples_rtt() {
+    let mut state = established_with_ts();
+    state.cong_ctrl.cwnd = 0xFFFF;
+    tcp_write(&mut state, &[0; 100], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+    state.rod.rttest = 0;
+
+    // Echo of a TSval sent 4 ticks ago: a 2 second sample
+    let now = unsafe { lwip_tcp_rust::tcp_ticks };
+    let ack = TcpSegment {
+        options: TcpOptions { ts: Some((1001, now.wrapping_sub(4))), ..Default::default() },
+        ..window_ack(2001, 1101, 8192)
+    };
+    input(&mut state, &ack);
+
+    // Nothing was being timed, yet the echo gave the first sample
+    assert_eq!(state.rod.sa >> 3, 2000);
+}
+
+// ============================================================================
+// SACK Scoreboard
+// ============================================================================
+
+fn sack_ack(ackno: u32, blocks: &[(u32, u32)]) -> TcpSegment {
+    let mut options = TcpOptions::default();
+    options.sack[..blocks.len()].copy_from_slice(blocks);
+    options.sack_len = blocks.len() as u8;
+    TcpSegment { options, ..window_ack(2001, ackno, 8192) }
+}
+
+#[test]
+fn test_sack_blocks_stored_and_merged() {
+    let mut state = established();
+    state.conn_mgmt.flags |= tcp_proto::TF_SACK;
+    state.cong_ctrl.cwnd = 0xFFFF;
+    tcp_write(&mut state, &[0; 2000], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+
+    // First segment lost, the two after it arrived
+    input(&mut state, &sack_ack(1001, &[(1537, 2073)]));
+    input(&mut state, &sack_ack(1001, &[(2073, 2609), (1537, 2073)]));
+    assert_eq!(state.rod.sacked_blocks(), &[(1537, 2609)]);
+    assert!(state.rod.is_sacked(1537, 536));
+    assert!(!state.rod.is_sacked(1001, 536));
+
+    // Blocks outside what we sent are ignored
+    input(&mut state, &sack_ack(1001, &[(3001, 3101)]));
+    assert_eq!(state.rod.sacked_blocks(), &[(1537, 2609)]);
+
+    // The cumulative ACK passes part of the scoreboard
+    input(&mut state, &sack_ack(2073, &[]));
+    assert_eq!(state.rod.sacked_blocks(), &[(2073, 2609)]);
+}
+
+#[test]
+fn test_sack_ignored_unless_negotiated() {
+    let mut state = established();
+    state.cong_ctrl.cwnd = 0xFFFF;
+    tcp_write(&mut state, &[0; 2000], 0).unwrap();
+    tcp_output(&mut state).unwrap();
+
+    input(&mut state, &sack_ack(1001, &[(1537, 2073)]));
+    assert!(state.rod.sacked_blocks().is_empty());
+}
+
+#[test]
+fn test_ooseq_ranges_reported_latest_first() {
+    let mut state = established();
+
+    input(&mut state, &payload_seg(2101, &[1; 100]));
+    input(&mut state, &payload_seg(2401, &[2; 100]));
+    input(&mut state, &payload_seg(2201, &[3; 100]));
+
+    // [2101, 2301) grew last, so it leads; adjacent arrivals merge
+    assert_eq!(state.rod.ooseq.sack_blocks(3), vec![(2101, 2301), (2401, 2501)]);
+
+    // Filling the first hole leaves only the far range
+    input(&mut state, &payload_seg(2001, &[0; 100]));
+    assert_eq!(state.rod.ooseq.sack_blocks(3), vec![(2401, 2501)]);
+}
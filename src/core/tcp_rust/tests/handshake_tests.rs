@@ -1,6 +1,7 @@
 //! Integration tests for TCP handshake implementation
 
-use lwip_tcp_rust::{TcpConnectionState, TcpState, TcpSegment, TcpFlags};
+use lwip_tcp_rust::{TcpConnectionState, TcpState, TcpSegment, TcpFlags, TcpOptions};
+use lwip_tcp_rust::tcp_proto;
 
 #[test]
 fn test_three_way_handshake_passive() {
@@ -21,16 +22,19 @@ fn test_three_way_handshake_passive() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let remote_ip = unsafe { core::mem::zeroed() };
 
     // Use component methods
-    let result = state.rod.on_syn_in_listen(&syn_seg);
+    let result = state.rod.on_syn_in_listen(&syn_seg, 6510);
     assert!(result.is_ok(), "ROD SYN processing failed");
 
     let result = state.flow_ctrl.on_syn_in_listen(&syn_seg, &state.conn_mgmt);
@@ -39,7 +43,7 @@ fn test_three_way_handshake_passive() {
     let result = state.cong_ctrl.on_syn_in_listen(&state.conn_mgmt);
     assert!(result.is_ok(), "CongControl SYN processing failed");
 
-    let result = state.conn_mgmt.on_syn_in_listen(remote_ip, 12345);
+    let result = state.conn_mgmt.on_syn_in_listen(remote_ip, 12345, &syn_seg);
     assert!(result.is_ok(), "ConnMgmt SYN processing failed");
 
     assert_eq!(state.conn_mgmt.state, TcpState::SynRcvd);
@@ -56,10 +60,13 @@ fn test_three_way_handshake_passive() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Use component methods
@@ -98,23 +105,26 @@ fn test_three_way_handshake_active() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 16384,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     // Use component methods
     let result = state.rod.on_synack_in_synsent(&synack_seg);
     assert!(result.is_ok(), "ROD SYN+ACK processing failed");
 
-    let result = state.flow_ctrl.on_synack_in_synsent(&synack_seg);
+    let result = state.flow_ctrl.on_synack_in_synsent(&synack_seg, &state.conn_mgmt);
     assert!(result.is_ok(), "FlowControl SYN+ACK processing failed");
 
     let result = state.cong_ctrl.on_synack_in_synsent(&state.conn_mgmt);
     assert!(result.is_ok(), "CongControl SYN+ACK processing failed");
 
-    let result = state.conn_mgmt.on_synack_in_synsent();
+    let result = state.conn_mgmt.on_synack_in_synsent(&synack_seg);
     assert!(result.is_ok(), "ConnMgmt SYN+ACK processing failed");
 
     assert_eq!(state.conn_mgmt.state, TcpState::Established);
@@ -143,7 +153,7 @@ fn test_state_initialization() {
     assert_eq!(state.conn_mgmt.state, TcpState::Closed);
     assert_eq!(state.conn_mgmt.mss, 536);
     assert_eq!(state.conn_mgmt.ttl, 255);
-    assert_eq!(state.rod.rto, 3000);
+    assert_eq!(state.rod.rto, tcp_proto::TCP_RTO_INITIAL);
     assert_eq!(state.cong_ctrl.ssthresh, 0xFFFF);
 }
 
@@ -163,21 +173,295 @@ fn test_congestion_window_initialization() {
             rst: false,
             psh: false,
             urg: false,
+            ece: false,
+            cwr: false,
         },
         wnd: 8192,
         tcphdr_len: 20,
         payload_len: 0,
+        ..Default::default()
     };
 
     let remote_ip = unsafe { core::mem::zeroed() };
 
     // Use component methods
-    let _ = state.rod.on_syn_in_listen(&syn_seg);
+    let _ = state.rod.on_syn_in_listen(&syn_seg, 6510);
     let _ = state.flow_ctrl.on_syn_in_listen(&syn_seg, &state.conn_mgmt);
     let _ = state.cong_ctrl.on_syn_in_listen(&state.conn_mgmt);
-    let _ = state.conn_mgmt.on_syn_in_listen(remote_ip, 12345);
+    let _ = state.conn_mgmt.on_syn_in_listen(remote_ip, 12345, &syn_seg);
 
     // RFC 5681: IW = min(4*MSS, max(2*MSS, 4380))
     // With MSS=1460: min(5840, max(2920, 4380)) = min(5840, 4380) = 4380
     assert_eq!(state.cong_ctrl.cwnd, 4380);
 }
+
+#[test]
+fn test_passive_open_effective_send_mss() {
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.state = TcpState::Listen;
+    state.conn_mgmt.mss = 1460;
+    state.conn_mgmt.mtu = 1500;
+
+    // SYN options: MSS 1460, NOP, NOP, Timestamps
+    let opts = [
+        2, 4, 0x05, 0xB4,
+        1, 1,
+        8, 10, 0, 0, 0, 1, 0, 0, 0, 0,
+    ];
+
+    let syn_seg = TcpSegment {
+        seqno: 1000,
+        ackno: 0,
+        flags: TcpFlags {
+            syn: true,
+            ack: false,
+            fin: false,
+            rst: false,
+            psh: false,
+            urg: false,
+            ece: false,
+            cwr: false,
+        },
+        wnd: 8192,
+        tcphdr_len: 20 + opts.len() as u16,
+        payload_len: 0,
+        options: TcpOptions::parse(&opts),
+        ..Default::default()
+    };
+    assert_eq!(syn_seg.options.mss, Some(1460));
+    assert_eq!(syn_seg.options.ts, Some((1, 0)));
+
+    let remote_ip = unsafe { core::mem::zeroed() };
+    let result = state.conn_mgmt.on_syn_in_listen(remote_ip, 12345, &syn_seg);
+    assert!(result.is_ok());
+
+    // Negotiated MSS stays 1460; each segment loses 12 bytes to timestamps
+    assert_eq!(state.conn_mgmt.mss, 1460);
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_TIMESTAMP, 0);
+    assert_eq!(state.conn_mgmt.eff_mss, 1460 - 12);
+}
+
+#[test]
+fn test_sack_permitted_negotiation() {
+    let syn = |opts: &[u8], flags: u8| TcpSegment {
+        seqno: 1000,
+        ackno: 5001,
+        flags: TcpFlags::from_tcphdr(flags),
+        wnd: 8192,
+        tcphdr_len: 20 + opts.len() as u16,
+        payload_len: 0,
+        options: TcpOptions::parse(opts),
+        ..Default::default()
+    };
+    let remote_ip = unsafe { core::mem::zeroed() };
+
+    // Passive: only if the SYN offers it
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.state = TcpState::Listen;
+    state.conn_mgmt.on_syn_in_listen(remote_ip, 12345, &syn(&[1, 1, 4, 2], tcp_proto::TCP_SYN)).unwrap();
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_SACK, 0);
+
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.state = TcpState::Listen;
+    state.conn_mgmt.on_syn_in_listen(remote_ip, 12345, &syn(&[], tcp_proto::TCP_SYN)).unwrap();
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_SACK, 0);
+
+    // Active: offered, then dropped by a SYN+ACK without it
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.on_connect(remote_ip, 80).unwrap();
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_SACK, 0);
+    state.conn_mgmt.on_synack_in_synsent(&syn(&[], tcp_proto::TCP_SYN | tcp_proto::TCP_ACK)).unwrap();
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_SACK, 0);
+}
+
+#[test]
+fn test_ecn_negotiation() {
+    let syn = |flags: u8| TcpSegment {
+        seqno: 1000,
+        ackno: 5001,
+        flags: TcpFlags::from_tcphdr(flags),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    };
+    let ecn_setup = tcp_proto::TCP_ECE | tcp_proto::TCP_CWR;
+    let remote_ip = unsafe { core::mem::zeroed() };
+
+    // Passive: an ECN-setup SYN carries both ECE and CWR
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.state = TcpState::Listen;
+    state.conn_mgmt.on_syn_in_listen(remote_ip, 12345, &syn(tcp_proto::TCP_SYN | ecn_setup)).unwrap();
+    assert!(state.conn_mgmt.ecn_enabled);
+
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.state = TcpState::Listen;
+    state.conn_mgmt.on_syn_in_listen(remote_ip, 12345, &syn(tcp_proto::TCP_SYN | tcp_proto::TCP_ECE)).unwrap();
+    assert!(!state.conn_mgmt.ecn_enabled);
+
+    // Active: the SYN+ACK accepts with ECE alone
+    let synack = tcp_proto::TCP_SYN | tcp_proto::TCP_ACK;
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.on_connect(remote_ip, 80).unwrap();
+    state.conn_mgmt.on_synack_in_synsent(&syn(synack | tcp_proto::TCP_ECE)).unwrap();
+    assert!(state.conn_mgmt.ecn_enabled);
+
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.on_connect(remote_ip, 80).unwrap();
+    state.conn_mgmt.on_synack_in_synsent(&syn(synack | ecn_setup)).unwrap();
+    assert!(!state.conn_mgmt.ecn_enabled);
+
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.on_connect(remote_ip, 80).unwrap();
+    state.conn_mgmt.on_synack_in_synsent(&syn(synack)).unwrap();
+    assert!(!state.conn_mgmt.ecn_enabled);
+}
+
+#[test]
+fn test_active_open_timestamp_negotiation() {
+    let synack = |opts: &[u8]| TcpSegment {
+        seqno: 2000,
+        ackno: 5001,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_SYN | tcp_proto::TCP_ACK),
+        wnd: 16384,
+        tcphdr_len: 20 + opts.len() as u16,
+        payload_len: 0,
+        options: TcpOptions::parse(opts),
+        ..Default::default()
+    };
+    let remote_ip = unsafe { core::mem::zeroed() };
+
+    // Our SYN offers timestamps; a SYN+ACK without them turns them off
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.on_connect(remote_ip, 80).unwrap();
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_TIMESTAMP, 0);
+    state.conn_mgmt.on_synack_in_synsent(&synack(&[])).unwrap();
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_TIMESTAMP, 0);
+
+    // Echoed: they stay on and the peer's TSval is kept for echoing
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.on_connect(remote_ip, 80).unwrap();
+    state.rod.iss = 5000;
+    let seg = synack(&[1, 1, 8, 10, 0, 0, 0x12, 0x34, 0, 0, 0, 9]);
+    state.rod.on_synack_in_synsent(&seg).unwrap();
+    state.conn_mgmt.on_synack_in_synsent(&seg).unwrap();
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_TIMESTAMP, 0);
+    assert_eq!(state.rod.ts_recent, 0x1234);
+}
+
+#[test]
+fn test_window_scale_negotiation() {
+    let seg = |flags: u8, wnd: u16, opts: &[u8]| TcpSegment {
+        seqno: 1000,
+        ackno: 5001,
+        flags: TcpFlags::from_tcphdr(flags),
+        wnd,
+        tcphdr_len: 20 + opts.len() as u16,
+        payload_len: 0,
+        options: TcpOptions::parse(opts),
+        ..Default::default()
+    };
+    let ws7 = [1, 3, 3, 7];
+
+    // Passive: the SYN's own window is taken as is, later ones are scaled
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.state = TcpState::Listen;
+    let syn = seg(tcp_proto::TCP_SYN, 0xFFFF, &ws7);
+    state.flow_ctrl.on_syn_in_listen(&syn, &state.conn_mgmt).unwrap();
+    assert_eq!((state.flow_ctrl.snd_scale, state.flow_ctrl.snd_wnd), (7, 0xFFFF));
+
+    let ack = TcpSegment { seqno: 1001, ..seg(tcp_proto::TCP_ACK, 0xFFFF, &[]) };
+    state.flow_ctrl.on_ack_in_synrcvd(&ack).unwrap();
+    assert_eq!(state.flow_ctrl.effective_snd_wnd(), 0xFFFF << 7); // Just under 8MB
+    assert_eq!(state.flow_ctrl.snd_wnd_max, 0xFFFF << 7);
+
+    // Active: offered, then dropped by a SYN+ACK without it
+    let remote_ip = unsafe { core::mem::zeroed() };
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.on_connect(remote_ip, 80).unwrap();
+    assert_ne!(state.conn_mgmt.flags & tcp_proto::TF_WND_SCALE, 0);
+    let synack = seg(tcp_proto::TCP_SYN | tcp_proto::TCP_ACK, 0xFFFF, &[]);
+    state.conn_mgmt.on_synack_in_synsent(&synack).unwrap();
+    state.flow_ctrl.on_synack_in_synsent(&synack, &state.conn_mgmt).unwrap();
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_WND_SCALE, 0);
+    assert_eq!(state.flow_ctrl.snd_scale, 0);
+}
+
+#[cfg(feature = "md5")]
+#[test]
+fn test_window_scale_off_with_md5() {
+    use lwip_tcp_rust::tcp_out::TcpTx;
+
+    // The SYN offers scaling, but our SYN+ACK has no room to answer it
+    let mut state = TcpConnectionState::new();
+    state.conn_mgmt.state = TcpState::Listen;
+    state.conn_mgmt.set_md5_key(b"secret").unwrap();
+    let syn = TcpSegment {
+        seqno: 1000,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_SYN),
+        wnd: 0xFFFF,
+        tcphdr_len: 24,
+        options: TcpOptions::parse(&[1, 3, 3, 7]),
+        ..Default::default()
+    };
+    state.flow_ctrl.on_syn_in_listen(&syn, &state.conn_mgmt).unwrap();
+    let remote_ip = unsafe { core::mem::zeroed() };
+    state.conn_mgmt.on_syn_in_listen(remote_ip, 40000, &syn).unwrap();
+
+    assert_eq!(state.conn_mgmt.flags & tcp_proto::TF_WND_SCALE, 0);
+    assert_eq!((state.flow_ctrl.snd_scale, state.flow_ctrl.rcv_scale), (0, 0));
+    let opts = TcpOptions::parse(&TcpTx::syn_options(&state));
+    assert_eq!(opts.ws, None);
+}
+
+#[test]
+fn test_simultaneous_open() {
+    use lwip_tcp_rust::{ffi, tcp_connect, tcp_input, InputAction};
+
+    let a_ip = ffi::ip_addr_t { addr: 0x0100a8c0 };
+    let b_ip = ffi::ip_addr_t { addr: 0x0200a8c0 };
+    let open = |local: ffi::ip_addr_t, local_port: u16, remote: ffi::ip_addr_t, remote_port: u16| {
+        let mut state = TcpConnectionState::new();
+        state.conn_mgmt.local_ip = local;
+        state.conn_mgmt.local_port = local_port;
+        tcp_connect(&mut state, remote, remote_port).unwrap();
+        state
+    };
+    let mut a = open(a_ip, 4000, b_ip, 5000);
+    let mut b = open(b_ip, 5000, a_ip, 4000);
+    let (a_iss, b_iss) = (a.rod.iss, b.rod.iss);
+
+    let seg = |seqno: u32, ackno: u32, flags: u8| TcpSegment {
+        seqno,
+        ackno,
+        flags: TcpFlags::from_tcphdr(flags),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    };
+
+    // The SYNs cross: each side answers the other's with SYN+ACK
+    let action = tcp_input(&mut a, &seg(b_iss, 0, tcp_proto::TCP_SYN), b_ip, 5000);
+    assert_eq!(action, Ok(InputAction::SendSynAck));
+    let action = tcp_input(&mut b, &seg(a_iss, 0, tcp_proto::TCP_SYN), a_ip, 4000);
+    assert_eq!(action, Ok(InputAction::SendSynAck));
+    for state in [&a, &b] {
+        assert_eq!(state.conn_mgmt.state, TcpState::SynRcvd);
+        assert!(!state.conn_mgmt.opened_passively);
+    }
+    assert_eq!((a.rod.irs, a.rod.rcv_nxt), (b_iss, b_iss.wrapping_add(1)));
+    assert_eq!((b.rod.irs, b.rod.rcv_nxt), (a_iss, a_iss.wrapping_add(1)));
+
+    // Each SYN+ACK acknowledges the other side's SYN
+    let synack = tcp_proto::TCP_SYN | tcp_proto::TCP_ACK;
+    let action = tcp_input(&mut a, &seg(b_iss, a_iss.wrapping_add(1), synack), b_ip, 5000);
+    assert_eq!(action, Ok(InputAction::Accept));
+    let action = tcp_input(&mut b, &seg(a_iss, b_iss.wrapping_add(1), synack), a_ip, 4000);
+    assert_eq!(action, Ok(InputAction::Accept));
+    for state in [&a, &b] {
+        assert_eq!(state.conn_mgmt.state, TcpState::Established);
+    }
+    assert_eq!((a.rod.snd_nxt, a.rod.lastack), (a_iss.wrapping_add(1), a_iss.wrapping_add(1)));
+    assert_eq!((b.rod.snd_nxt, b.rod.lastack), (b_iss.wrapping_add(1), b_iss.wrapping_add(1)));
+}
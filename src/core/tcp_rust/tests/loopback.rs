@@ -0,0 +1,413 @@
+//! Loopback Transport for End-to-End Tests
+//!
+//! Connects two `TcpConnectionState`s back to back. Whatever one endpoint
+//! sends is built by the `TcpTx` functions lib.rs sends with, carried over
+//! an in-memory wire and run through the other endpoint's `tcp_input`. The
+//! link does what lib.rs does for a real PCB - acting on the returned
+//! actions, handing received data to the application and running the
+//! timers - so whole connections can be tested without C.
+
+#![allow(dead_code)]
+
+use std::collections::{BTreeSet, VecDeque};
+use std::ops::{Deref, DerefMut};
+
+use lwip_tcp_rust::state::{TcpConnectionState, TcpState};
+use lwip_tcp_rust::tcp_out::TcpTx;
+use lwip_tcp_rust::{
+    ffi, initiate_close, tcp_bind, tcp_connect, tcp_fasttmr_pcb, tcp_input, tcp_limited_transmit,
+    tcp_listen, tcp_output, tcp_recved, tcp_slowtmr_pcb, tcp_write, InputAction, TcpSegment, TimerAction,
+};
+
+/// One end of the link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    A, // Active opener
+    B, // Passive opener
+}
+
+impl Side {
+    pub fn peer(self) -> Side {
+        match self {
+            Side::A => Side::B,
+            Side::B => Side::A,
+        }
+    }
+}
+
+/// A segment in flight
+#[derive(Debug, Clone)]
+pub struct Packet {
+    pub to: Side,
+    pub bytes: Vec<u8>,
+    pub due: u32, // Link step at which it arrives
+}
+
+/// Scripted faults, by transmission number
+///
+/// Segments are numbered from 1 in the order they are transmitted, both
+/// directions counted together.
+#[derive(Debug, Default)]
+pub struct FaultPlan {
+    pub drop: BTreeSet<usize>,
+    pub duplicate: BTreeSet<usize>,
+    pub reorder: Vec<(usize, usize)>, // (a, b): a is held back until b has gone out
+}
+
+/// A connection endpoint and the application sitting on it
+pub struct Endpoint {
+    pub state: TcpConnectionState,
+    pub ip: u32,
+    pub port: u16,
+    pub received: Vec<u8>, // Data handed to the application, in order
+    pub eof: bool,         // The peer's FIN reached the application
+    outbox: VecDeque<u8>,  // Written by the application, not yet taken by tcp_write
+    close_pending: bool,   // Close once the outbox has drained
+}
+
+impl Endpoint {
+    fn new(ip: u32, port: u16) -> Self {
+        let mut state = TcpConnectionState::new();
+        tcp_bind(&mut state, ffi::ip_addr_t { addr: ip }, port).unwrap();
+        Self {
+            state,
+            ip,
+            port,
+            received: Vec::new(),
+            eof: false,
+            outbox: VecDeque::new(),
+            close_pending: false,
+        }
+    }
+
+    /// Nothing left to send or to be acknowledged
+    fn is_idle(&self) -> bool {
+        self.outbox.is_empty()
+            && !self.close_pending
+            && self.state.rod.unsent.is_empty()
+            && self.state.rod.unacked.is_empty()
+    }
+}
+
+/// Two endpoints joined by a wire with a fixed latency
+///
+/// Time advances in steps of TCP_FAST_INTERVAL: every step runs the fast
+/// timer, every second one the slow timer too. A segment sent during a
+/// step arrives `latency` steps later, 0 meaning within the same step.
+pub struct LoopbackLink {
+    pub a: Endpoint,
+    pub b: Endpoint,
+    pub now: u32,
+    pub latency: u32,
+    pub wire: VecDeque<Packet>,
+    pub transmitted: usize,   // Segments transmitted so far
+    pub retransmitted: usize, // Of which retransmissions
+    pub faults: FaultPlan,
+    held: Vec<(usize, Packet)>, // Reordered segments waiting for their partner
+}
+
+impl Default for LoopbackLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoopbackLink {
+    pub fn new() -> Self {
+        Self::with_latency(0)
+    }
+
+    pub fn with_latency(latency: u32) -> Self {
+        Self {
+            a: Endpoint::new(0xC0A80001, 0x101),
+            b: Endpoint::new(0xC0A80002, 0x100),
+            now: 0,
+            latency,
+            wire: VecDeque::new(),
+            transmitted: 0,
+            retransmitted: 0,
+            faults: FaultPlan::default(),
+            held: Vec::new(),
+        }
+    }
+
+    pub fn endpoint(&mut self, side: Side) -> &mut Endpoint {
+        match side {
+            Side::A => &mut self.a,
+            Side::B => &mut self.b,
+        }
+    }
+
+    pub fn state(&self, side: Side) -> TcpState {
+        match side {
+            Side::A => self.a.state.conn_mgmt.state,
+            Side::B => self.b.state.conn_mgmt.state,
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    // Application
+    // ------------------------------------------------------------------------
+
+    /// B listens, A connects and sends its SYN
+    pub fn connect(&mut self) {
+        tcp_listen(&mut self.b.state).unwrap();
+
+        let remote = ffi::ip_addr_t { addr: self.b.ip };
+        tcp_connect(&mut self.a.state, remote, self.b.port).unwrap();
+
+        let bytes = TcpTx::syn_segment(&self.a.state).unwrap();
+        self.emit(Side::A, bytes);
+    }
+
+    /// Queue `data` for sending; it is written as send buffer space frees up
+    pub fn send(&mut self, side: Side, data: &[u8]) {
+        self.endpoint(side).outbox.extend(data);
+    }
+
+    /// Close once everything queued by `send` has been written
+    pub fn close(&mut self, side: Side) {
+        self.endpoint(side).close_pending = true;
+    }
+
+    // ------------------------------------------------------------------------
+    // Running
+    // ------------------------------------------------------------------------
+
+    /// Advance the link by one step
+    pub fn step(&mut self) {
+        for side in [Side::A, Side::B] {
+            self.pump(side);
+            self.flush(side);
+        }
+
+        while let Some(pos) = self.wire.iter().position(|pkt| pkt.due <= self.now) {
+            let pkt = self.wire.remove(pos).unwrap();
+            self.deliver(pkt);
+        }
+
+        for side in [Side::A, Side::B] {
+            let action = tcp_fasttmr_pcb(&mut self.endpoint(side).state).unwrap();
+            self.on_timer(side, action);
+            if self.now % 2 == 1 {
+                let action = tcp_slowtmr_pcb(&mut self.endpoint(side).state).unwrap();
+                self.on_timer(side, action);
+            }
+        }
+
+        self.now += 1;
+    }
+
+    /// Nothing in flight and nothing left to send on either side
+    pub fn is_idle(&self) -> bool {
+        self.wire.is_empty() && self.held.is_empty() && self.a.is_idle() && self.b.is_idle()
+    }
+
+    /// Step until idle; panics if that takes more than `max_steps`
+    pub fn run(&mut self, max_steps: u32) -> u32 {
+        for steps in 0..max_steps {
+            self.step();
+            if self.is_idle() {
+                return steps + 1;
+            }
+        }
+        panic!("link not idle after {} steps", max_steps);
+    }
+
+    // ------------------------------------------------------------------------
+    // Plumbing (what lib.rs does for a PCB)
+    // ------------------------------------------------------------------------
+
+    /// Hand the application's pending data to tcp_write, then close
+    fn pump(&mut self, side: Side) {
+        let ep = self.endpoint(side);
+        if !ep.state.conn_mgmt.state.can_send() {
+            return;
+        }
+
+        loop {
+            let len = ep.outbox.len().min(ep.state.rod.snd_buf as usize);
+            if len == 0 {
+                break;
+            }
+            let chunk: Vec<u8> = ep.outbox.drain(..len).collect();
+            tcp_write(&mut ep.state, &chunk, 0).unwrap();
+        }
+
+        if ep.close_pending && ep.outbox.is_empty() {
+            ep.close_pending = false;
+            initiate_close(&mut ep.state).unwrap();
+        }
+    }
+
+    /// Send what tcp_output releases
+    fn flush(&mut self, side: Side) {
+        let segs = tcp_output(&mut self.endpoint(side).state).unwrap();
+        for seg in segs {
+            let bytes = TcpTx::data_segment(&self.endpoint(side).state, &seg).unwrap();
+            self.emit(side, bytes);
+        }
+    }
+
+    /// Run a packet through its receiver and act on the outcome
+    fn deliver(&mut self, pkt: Packet) {
+        let seg = TcpSegment::parse(&pkt.bytes).unwrap();
+        let from = self.endpoint(pkt.to.peer());
+        let (remote_ip, remote_port) = (ffi::ip_addr_t { addr: from.ip }, from.port);
+
+        let action = tcp_input(&mut self.endpoint(pkt.to).state, &seg, remote_ip, remote_port).unwrap();
+        match action {
+            InputAction::SendAck | InputAction::SendChallengeAck => self.send_ack(pkt.to),
+            InputAction::SendSynAck => self.send_synack(pkt.to),
+            InputAction::Retransmit => self.retransmit(pkt.to),
+            InputAction::LimitedTransmit => self.limited_transmit(pkt.to),
+            _ => {}
+        }
+
+        self.drain(pkt.to);
+    }
+
+    /// Pass received data to the application, reopening the window
+    fn drain(&mut self, side: Side) {
+        let ep = self.endpoint(side);
+        let (data, fin) = ep.state.rod.on_deliver();
+        ep.received.extend_from_slice(&data);
+        ep.eof |= fin;
+
+        let mut update = false;
+        for chunk in data.chunks(u16::MAX as usize) {
+            update |= tcp_recved(&mut ep.state, chunk.len() as u16).unwrap();
+        }
+        if update {
+            self.send_ack(side);
+        }
+    }
+
+    fn on_timer(&mut self, side: Side, action: TimerAction) {
+        match action {
+            TimerAction::SendAck => self.send_ack(side),
+            TimerAction::Retransmit => self.retransmit(side),
+            TimerAction::Probe => self.send_probe(side),
+            _ => {}
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    // Segments (built by TcpTx, as lib.rs sends them)
+    // ------------------------------------------------------------------------
+
+    fn send_ack(&mut self, side: Side) {
+        let bytes = TcpTx::ack_segment(&self.endpoint(side).state).unwrap();
+        self.emit(side, bytes);
+    }
+
+    fn send_synack(&mut self, side: Side) {
+        let bytes = TcpTx::synack_segment(&self.endpoint(side).state).unwrap();
+        self.emit(side, bytes);
+    }
+
+    fn limited_transmit(&mut self, side: Side) {
+        if let Some(seg) = tcp_limited_transmit(&mut self.endpoint(side).state).unwrap() {
+            let bytes = TcpTx::data_segment(&self.endpoint(side).state, &seg).unwrap();
+            self.emit(side, bytes);
+        }
+    }
+
+    fn retransmit(&mut self, side: Side) {
+        if let Some(bytes) = TcpTx::rexmit_segment(&self.endpoint(side).state).unwrap() {
+            self.retransmitted += 1;
+            self.emit(side, bytes);
+        }
+    }
+
+    fn send_probe(&mut self, side: Side) {
+        if let Some(bytes) = TcpTx::probe_segment(&self.endpoint(side).state).unwrap() {
+            self.emit(side, bytes);
+        }
+    }
+
+    /// Put a segment from `side` on the wire, as far as the fault plan
+    /// lets it
+    fn emit(&mut self, side: Side, bytes: Vec<u8>) {
+        self.transmitted += 1;
+        let n = self.transmitted;
+        let pkt = Packet { to: side.peer(), bytes, due: self.now + self.latency };
+
+        if self.faults.drop.contains(&n) {
+            return;
+        }
+        if let Some(&(_, after)) = self.faults.reorder.iter().find(|&&(a, _)| a == n) {
+            self.held.push((after, pkt));
+            return;
+        }
+
+        if self.faults.duplicate.contains(&n) {
+            self.wire.push_back(pkt.clone());
+        }
+        self.wire.push_back(pkt);
+
+        // Release whatever was waiting for this one
+        let due = self.now + self.latency;
+        let (released, held): (Vec<_>, Vec<_>) = self.held.drain(..).partition(|&(after, _)| after == n);
+        self.held = held;
+        for (_, pkt) in released {
+            self.wire.push_back(Packet { due, ..pkt });
+        }
+    }
+}
+
+/// `len` bytes of test data that make a misplaced byte show up
+pub fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Loopback link that loses, duplicates and reorders segments on cue
+///
+/// Everything else is the plain `LoopbackLink`, reachable through Deref.
+#[derive(Default)]
+pub struct FaultyLink {
+    link: LoopbackLink,
+}
+
+impl FaultyLink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_latency(latency: u32) -> Self {
+        Self { link: LoopbackLink::with_latency(latency) }
+    }
+
+    /// Lose the `n`th transmitted segment
+    pub fn drop_segment(&mut self, n: usize) -> &mut Self {
+        self.link.faults.drop.insert(n);
+        self
+    }
+
+    /// Deliver the `n`th transmitted segment twice
+    pub fn duplicate(&mut self, n: usize) -> &mut Self {
+        self.link.faults.duplicate.insert(n);
+        self
+    }
+
+    /// Deliver segment `a` right after segment `b`, for `a` < `b`
+    pub fn reorder(&mut self, a: usize, b: usize) -> &mut Self {
+        assert!(a < b, "segment {} cannot wait for an earlier one", a);
+        self.link.faults.reorder.push((a, b));
+        self
+    }
+}
+
+impl Deref for FaultyLink {
+    type Target = LoopbackLink;
+
+    fn deref(&self) -> &LoopbackLink {
+        &self.link
+    }
+}
+
+impl DerefMut for FaultyLink {
+    fn deref_mut(&mut self) -> &mut LoopbackLink {
+        &mut self.link
+    }
+}
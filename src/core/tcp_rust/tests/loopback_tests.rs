@@ -0,0 +1,63 @@
+//! End-to-End Tests over the Loopback Transport
+//!
+//! Two connection states talk to each other through `LoopbackLink`:
+//! handshake, bulk transfer and close, with no component called by hand.
+
+mod loopback;
+
+use loopback::{pattern, LoopbackLink, Side};
+use lwip_tcp_rust::state::TcpState;
+
+#[test]
+fn test_handshake_over_loopback() {
+    let mut link = LoopbackLink::new();
+    link.connect();
+    link.run(10);
+
+    assert_eq!(link.state(Side::A), TcpState::Established);
+    assert_eq!(link.state(Side::B), TcpState::Established);
+    // SYN, SYN+ACK, ACK
+    assert_eq!(link.transmitted, 3);
+}
+
+#[test]
+fn test_transfer_10kb_and_close() {
+    let mut link = LoopbackLink::new();
+    link.connect();
+
+    let data = pattern(10 * 1024);
+    link.send(Side::A, &data);
+    link.close(Side::A);
+    link.run(1000);
+
+    assert_eq!(link.b.received, data);
+    assert!(link.b.eof);
+    assert_eq!(link.state(Side::A), TcpState::FinWait2);
+    assert_eq!(link.state(Side::B), TcpState::CloseWait);
+
+    link.close(Side::B);
+    link.run(100);
+
+    assert!(link.a.eof);
+    assert!(link.a.received.is_empty());
+    assert_eq!(link.state(Side::A), TcpState::TimeWait);
+    assert_eq!(link.state(Side::B), TcpState::Closed);
+    assert_eq!(link.a.state.rod.nrtx, 0);
+}
+
+#[test]
+fn test_bidirectional_transfer_with_latency() {
+    let mut link = LoopbackLink::with_latency(1);
+    link.connect();
+
+    let to_b = pattern(10 * 1024);
+    let to_a = pattern(3000);
+    link.send(Side::A, &to_b);
+    link.send(Side::B, &to_a);
+    link.run(1000);
+
+    assert_eq!(link.b.received, to_b);
+    assert_eq!(link.a.received, to_a);
+    assert_eq!(link.a.state.rod.nrtx, 0);
+    assert_eq!(link.b.state.rod.nrtx, 0);
+}
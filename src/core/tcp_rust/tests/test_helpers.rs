@@ -177,12 +177,13 @@ pub fn set_tcp_state(
         state.rod.iss = 1000;
         state.rod.snd_nxt = 1001;
         state.rod.lastack = 1001;
+        state.rod.snd_lbb = 1001;
         state.rod.irs = 2000;
         state.rod.rcv_nxt = 2001;
         
         state.flow_ctrl.snd_wnd = 8192;
         state.flow_ctrl.rcv_wnd = 8192;
-        state.cong_ctrl.cwnd = 4 * state.conn_mgmt.mss as u16;
+        state.cong_ctrl.cwnd = 4 * state.conn_mgmt.mss as u32;
     }
 }
 
@@ -0,0 +1,579 @@
+//! TCP Timer Tests
+//!
+//! Drive the slow timer on a single connection and verify retransmission
+//! timeout handling: resend, exponential backoff and the retransmit limit,
+//! plus the RTT estimator that drives the RTO, the zero window persist
+//! timer, keep-alive probing and the 2MSL TIME_WAIT timer.
+
+mod test_helpers;
+
+use test_helpers::*;
+use lwip_tcp_rust::{tcp_input, tcp_output, tcp_slowtmr_pcb, tcp_write, TcpFlags, TcpSegment, TimerAction};
+use lwip_tcp_rust::components::UnackedSegment;
+use lwip_tcp_rust::state::{TcpConnectionState, TcpState};
+use lwip_tcp_rust::tcp_proto;
+use lwip_tcp_rust::ffi;
+
+fn established_with_unacked() -> TcpConnectionState {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+
+    let seg = UnackedSegment::new(state.rod.snd_nxt, tcp_proto::TCP_ACK | tcp_proto::TCP_PSH, vec![0xAB; 100]);
+    assert!(state.rod.on_segment_sent(seg, 1).is_ok());
+    state
+}
+
+/// Tick until the timer fires, returning the action and the ticks it took
+fn run_until_expiry(state: &mut TcpConnectionState) -> (TimerAction, u32) {
+    for tick in 1..=1000 {
+        let action = tcp_slowtmr_pcb(state).unwrap();
+        if action != TimerAction::None {
+            return (action, tick);
+        }
+    }
+    panic!("retransmission timer never fired");
+}
+
+#[test]
+fn test_segment_sent_arms_timer() {
+    let state = established_with_unacked();
+
+    assert_eq!(state.rod.snd_nxt, 1101);
+    assert_eq!(state.rod.unacked.len(), 1);
+    // 1000ms initial RTO at 500ms per slow tick
+    assert_eq!(state.rod.rtime, 2);
+}
+
+#[test]
+fn test_rto_expiry_triggers_retransmit() {
+    let mut state = established_with_unacked();
+
+    let (action, ticks) = run_until_expiry(&mut state);
+    assert_eq!(action, TimerAction::Retransmit);
+    assert_eq!(ticks, 2);
+
+    // Oldest segment is still queued for the resend
+    assert_eq!(state.rod.unacked.front().unwrap().seqno, 1001);
+    assert_eq!(state.rod.nrtx, 1);
+}
+
+#[test]
+fn test_rto_backs_off() {
+    let mut state = established_with_unacked();
+
+    let (_, first) = run_until_expiry(&mut state);
+    assert_eq!(state.rod.rto, 2000);
+
+    let (_, second) = run_until_expiry(&mut state);
+    assert_eq!(state.rod.rto, 4000);
+    assert_eq!(second, 2 * first);
+    assert_eq!(state.rod.nrtx, 2);
+}
+
+#[test]
+fn test_rto_backoff_stops_at_ceiling() {
+    let mut state = established_with_unacked();
+
+    // 1s doubled five times is 32s; the sixth doubling hits the ceiling
+    for _ in 0..5 {
+        run_until_expiry(&mut state);
+    }
+    assert_eq!(state.rod.rto, 32000);
+    run_until_expiry(&mut state);
+    assert_eq!(state.rod.rto, tcp_proto::TCP_RTO_MAX);
+
+    let (_, ticks) = run_until_expiry(&mut state);
+    assert_eq!(state.rod.rto, tcp_proto::TCP_RTO_MAX);
+    assert_eq!(ticks, tcp_proto::TCP_RTO_MAX / tcp_proto::TCP_SLOW_INTERVAL);
+}
+
+#[test]
+fn test_no_retransmit_without_unacked_data() {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+
+    for _ in 0..100 {
+        assert_eq!(tcp_slowtmr_pcb(&mut state).unwrap(), TimerAction::None);
+    }
+    assert_eq!(state.rod.nrtx, 0);
+}
+
+#[test]
+fn test_abort_after_max_retransmits() {
+    let mut state = established_with_unacked();
+
+    for _ in 0..tcp_proto::TCP_MAXRTX {
+        let (action, _) = run_until_expiry(&mut state);
+        assert_eq!(action, TimerAction::Retransmit);
+    }
+
+    let (action, _) = run_until_expiry(&mut state);
+    assert_eq!(action, TimerAction::Abort);
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+    assert!(state.rod.unacked.is_empty());
+}
+
+// ============================================================================
+// Retransmission Queue
+// ============================================================================
+
+/// Established with three 100-byte segments in flight, sent at tick 1
+fn established_with_three_unacked() -> TcpConnectionState {
+    let mut state = established_with_unacked();
+    for _ in 0..2 {
+        let seg = UnackedSegment::new(state.rod.snd_nxt, tcp_proto::TCP_ACK, vec![0; 100]);
+        state.rod.on_segment_sent(seg, 1).unwrap();
+    }
+    state
+}
+
+fn unacked_seqnos(state: &TcpConnectionState) -> Vec<u32> {
+    state.rod.unacked.iter().map(|seg| seg.seqno).collect()
+}
+
+#[test]
+fn test_full_ack_removes_one_segment() {
+    let mut state = established_with_three_unacked();
+    state.rod.rttest = 0; // keep the RTO where it is
+    state.rod.rtime = 1;
+
+    state.rod.on_ack_in_established(&ack(1101), 3).unwrap();
+    assert_eq!(unacked_seqnos(&state), vec![1101, 1201]);
+    assert_eq!(state.rod.acked, 100);
+    // New data acked: the timer starts over
+    assert_eq!(state.rod.rtime, 2);
+}
+
+#[test]
+fn test_partial_ack_trims_segment() {
+    let mut state = established_with_three_unacked();
+
+    state.rod.on_ack_in_established(&ack(1041), 3).unwrap();
+    assert_eq!(unacked_seqnos(&state), vec![1041, 1101, 1201]);
+    let front = state.rod.unacked.front().unwrap();
+    assert_eq!(front.data.len(), 60);
+    assert_eq!(front.seq_len(), 60);
+    assert_eq!(state.rod.acked, 40);
+
+    // Only the unacknowledged rest is resent
+    assert_eq!(state.rod.retransmit_candidate().unwrap().seqno, 1041);
+}
+
+#[test]
+fn test_cumulative_ack_spans_segments() {
+    let mut state = established_with_three_unacked();
+
+    state.rod.on_ack_in_established(&ack(1251), 3).unwrap();
+    assert_eq!(unacked_seqnos(&state), vec![1251]);
+    assert_eq!(state.rod.unacked.front().unwrap().data.len(), 50);
+    assert_eq!(state.rod.acked, 250);
+
+    state.rod.on_ack_in_established(&ack(1301), 4).unwrap();
+    assert!(state.rod.unacked.is_empty());
+    assert_eq!(state.rod.rtime, 0);
+}
+
+#[test]
+fn test_duplicate_ack_does_not_restart_timer() {
+    let mut state = established_with_three_unacked();
+    state.rod.rtime = 2;
+
+    state.rod.on_ack_in_established(&ack(1001), 3).unwrap();
+    assert_eq!(state.rod.rtime, 2);
+    assert_eq!(state.rod.unacked.len(), 3);
+}
+
+#[test]
+fn test_retransmission_recorded_on_segment() {
+    let mut state = established_with_three_unacked();
+
+    state.rod.on_rto_timeout(7).unwrap();
+    state.rod.on_rto_timeout(19).unwrap();
+    let front = state.rod.unacked.front().unwrap();
+    assert_eq!((front.seqno, front.nrtx, front.sent), (1001, 2, 19));
+
+    // The others were sent once, at tick 1
+    let rest: Vec<(u8, u32)> = state.rod.unacked.iter().skip(1).map(|seg| (seg.nrtx, seg.sent)).collect();
+    assert_eq!(rest, vec![(0, 1), (0, 1)]);
+}
+
+// ============================================================================
+// RTT Estimation
+// ============================================================================
+
+fn ack(ackno: u32) -> TcpSegment {
+    TcpSegment {
+        seqno: 2001,
+        ackno,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_ACK),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    }
+}
+
+/// Send one 100-byte segment at `sent`, acknowledge it at `acked`
+fn round_trip(state: &mut TcpConnectionState, sent: u32, acked: u32) {
+    let seqno = state.rod.snd_nxt;
+    let seg = UnackedSegment::new(seqno, tcp_proto::TCP_ACK, vec![0; 100]);
+    state.rod.on_segment_sent(seg, sent).unwrap();
+    state.rod.on_ack_in_established(&ack(seqno.wrapping_add(100)), acked).unwrap();
+}
+
+#[test]
+fn test_first_rtt_sample_initializes_estimators() {
+    let mut state = established_with_unacked();
+
+    // Sent at tick 1, acked at tick 5: a 2000ms sample
+    state.rod.on_ack_in_established(&ack(1101), 5).unwrap();
+
+    // SRTT = R, RTTVAR = R/2 (RFC 6298, 2.2), so RTO = R + 4 * R/2
+    assert_eq!(state.rod.sa >> 3, 2000);
+    assert_eq!(state.rod.sv >> 2, 1000);
+    assert_eq!(state.rod.rto, 6000);
+
+    // Later samples are smoothed in, not taken outright
+    round_trip(&mut state, 10, 12);
+    assert_eq!(state.rod.sa >> 3, 1875);
+}
+
+#[test]
+fn test_rto_converges_to_stable_rtt() {
+    let mut state = established_with_unacked();
+    state.rod.on_ack_in_established(&ack(1101), 1).unwrap();
+
+    // Constant 2-tick (1000ms) round trips
+    let mut now = 10;
+    for _ in 0..50 {
+        round_trip(&mut state, now, now + 2);
+        now += 5;
+    }
+
+    // SRTT settles on the sample and the deviation decays away
+    let srtt = state.rod.sa >> 3;
+    assert!((990..=1000).contains(&srtt), "srtt = {}", srtt);
+    assert!(state.rod.rto >= 990 && state.rod.rto < 1100, "rto = {}", state.rod.rto);
+    assert!(state.rod.unacked.is_empty());
+    assert_eq!(state.rod.rtime, 0);
+}
+
+#[test]
+fn test_rto_tracks_rtt_increase() {
+    let mut state = established_with_unacked();
+    state.rod.on_ack_in_established(&ack(1101), 1).unwrap();
+
+    let mut now = 10;
+    for _ in 0..30 {
+        round_trip(&mut state, now, now + 1);
+        now += 5;
+    }
+    let fast_rto = state.rod.rto;
+
+    for _ in 0..30 {
+        round_trip(&mut state, now, now + 4);
+        now += 10;
+    }
+    assert!(state.rod.rto > fast_rto);
+    assert!(state.rod.rto >= 2000);
+}
+
+#[test]
+fn test_rto_clamped_to_minimum() {
+    let mut state = established_with_unacked();
+
+    // Same-tick ACKs measure 0ms
+    let mut now = 10;
+    for _ in 0..50 {
+        round_trip(&mut state, now, now);
+        now += 1;
+    }
+    assert_eq!(state.rod.rto, tcp_proto::TCP_RTO_MIN);
+}
+
+#[test]
+fn test_karn_skips_retransmitted_segment() {
+    let mut state = established_with_unacked();
+    let (sa, sv) = (state.rod.sa, state.rod.sv);
+
+    let (action, _) = run_until_expiry(&mut state);
+    assert_eq!(action, TimerAction::Retransmit);
+    assert_eq!(state.rod.rttest, 0);
+
+    // ACK for the retransmitted data must not produce a sample
+    state.rod.on_ack_in_established(&ack(1101), 40).unwrap();
+    assert_eq!((state.rod.sa, state.rod.sv), (sa, sv));
+    assert_eq!(state.rod.nrtx, 0);
+}
+
+/// Established, peer window closed, 100 bytes waiting in unsent
+fn zero_window_with_data() -> TcpConnectionState {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    state.rod.snd_buf = 8192;
+    state.flow_ctrl.snd_wnd = 0;
+
+    tcp_write(&mut state, &[0x42; 100], 0).unwrap();
+    assert!(tcp_output(&mut state).unwrap().is_empty());
+    state
+}
+
+#[test]
+fn test_zero_window_starts_persist_timer() {
+    let state = zero_window_with_data();
+
+    assert_eq!(state.flow_ctrl.persist_backoff, 1);
+    assert_eq!(state.rod.unacked.len(), 0);
+    assert_eq!(state.rod.rtime, 0);
+}
+
+#[test]
+fn test_persist_timer_sends_probes_with_backoff() {
+    let mut state = zero_window_with_data();
+
+    // Ticks between probes follow TCP_PERSIST_BACKOFF
+    let mut gaps = Vec::new();
+    for _ in 0..3 {
+        let (action, ticks) = run_until_expiry(&mut state);
+        assert_eq!(action, TimerAction::Probe);
+        gaps.push(ticks as u8);
+    }
+    assert_eq!(gaps, tcp_proto::TCP_PERSIST_BACKOFF[..3].to_vec());
+
+    // Every probe carries the same single byte
+    assert_eq!(state.rod.unacked.len(), 1);
+    assert_eq!(state.rod.unacked.front().unwrap().data, vec![0x42]);
+    assert_eq!(state.rod.snd_nxt, 1002);
+    assert_eq!(state.rod.unsent.bytes(), 99);
+    assert_eq!(state.flow_ctrl.persist_probe, 3);
+}
+
+#[test]
+fn test_persist_backoff_is_capped() {
+    let mut state = zero_window_with_data();
+    let last = *tcp_proto::TCP_PERSIST_BACKOFF.last().unwrap();
+
+    for _ in 0..tcp_proto::TCP_PERSIST_BACKOFF.len() {
+        run_until_expiry(&mut state);
+    }
+    let (_, ticks) = run_until_expiry(&mut state);
+    assert_eq!(ticks, last as u32);
+    assert_eq!(state.flow_ctrl.persist_backoff as usize, tcp_proto::TCP_PERSIST_BACKOFF.len());
+}
+
+#[test]
+fn test_window_update_stops_persist_timer() {
+    let mut state = zero_window_with_data();
+    run_until_expiry(&mut state);
+
+    // Peer took the probe byte and opened its window
+    let ack = TcpSegment { wnd: 4096, ..ack(1002) };
+    tcp_input(&mut state, &ack, ffi::ip_addr_t { addr: TEST_REMOTE_IP }, TEST_REMOTE_PORT).unwrap();
+
+    assert_eq!(state.flow_ctrl.persist_backoff, 0);
+    let segs = tcp_output(&mut state).unwrap();
+    let sent: usize = segs.iter().map(|s| s.data.len()).sum();
+    assert_eq!(sent, 99);
+}
+
+#[test]
+fn test_abort_after_max_probes() {
+    let mut state = zero_window_with_data();
+
+    for _ in 0..tcp_proto::TCP_MAXRTX {
+        assert_eq!(run_until_expiry(&mut state).0, TimerAction::Probe);
+    }
+    assert_eq!(tcp_slowtmr_pcb(&mut state).unwrap(), TimerAction::Abort);
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+}
+
+#[test]
+fn test_peer_answering_probes_with_zero_window_is_aborted() {
+    let mut state = zero_window_with_data();
+    let remote_ip = ffi::ip_addr_t { addr: TEST_REMOTE_IP };
+
+    // The peer is alive but never takes the probe byte
+    let zero_wnd = TcpSegment { wnd: 0, ..ack(1001) };
+    let mut probes = 0;
+    let action = loop {
+        let action = tcp_slowtmr_pcb(&mut state).unwrap();
+        match action {
+            TimerAction::None => continue,
+            TimerAction::Probe => probes += 1,
+            _ => break action,
+        }
+        tcp_input(&mut state, &zero_wnd, remote_ip, TEST_REMOTE_PORT).unwrap();
+        assert_eq!(state.flow_ctrl.persist_probe, probes);
+    };
+
+    assert_eq!(action, TimerAction::Abort);
+    assert_eq!(probes, tcp_proto::TCP_MAXRTX);
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+}
+
+// ============================================================================
+// Keep-Alive
+// ============================================================================
+
+/// Idle established connection probed after 1s, then every 0.5s, 3 times
+fn idle_connection(keepalive: bool) -> TcpConnectionState {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    state.conn_mgmt.keep_idle = 1000;
+    state.conn_mgmt.keep_intvl = 500;
+    state.conn_mgmt.keep_cnt = 3;
+    state.conn_mgmt.set_so_option(tcp_proto::SOF_KEEPALIVE, keepalive).unwrap();
+    state
+}
+
+#[test]
+fn test_no_keepalive_without_option() {
+    let mut state = idle_connection(false);
+
+    for now in 0..100 {
+        assert_eq!(state.conn_mgmt.on_keepalive_tmr(now), TimerAction::None);
+    }
+    assert_eq!(state.conn_mgmt.keep_cnt_sent, 0);
+}
+
+#[test]
+fn test_keepalive_probes_then_aborts() {
+    let mut state = idle_connection(true);
+
+    // Idle for 2 ticks (1s) is not yet enough
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(2), TimerAction::None);
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(3), TimerAction::KeepAlive);
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(3), TimerAction::None);
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(4), TimerAction::KeepAlive);
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(5), TimerAction::KeepAlive);
+    assert_eq!(state.conn_mgmt.keep_cnt_sent, 3);
+
+    // keep_cnt probes unanswered
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(6), TimerAction::Abort);
+}
+
+#[test]
+fn test_keepalive_answer_restarts_idle_clock() {
+    let mut state = idle_connection(true);
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(3), TimerAction::KeepAlive);
+
+    // Peer answers at tick 4
+    state.conn_mgmt.on_segment_received(4).unwrap();
+    assert_eq!(state.conn_mgmt.keep_cnt_sent, 0);
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(6), TimerAction::None);
+    assert_eq!(state.conn_mgmt.on_keepalive_tmr(7), TimerAction::KeepAlive);
+}
+
+#[test]
+fn test_slowtmr_reports_keepalive_and_abort() {
+    let mut state = idle_connection(true);
+    state.conn_mgmt.on_segment_received(unsafe { lwip_tcp_rust::tcp_ticks }).unwrap();
+
+    let mut probes = 0;
+    loop {
+        // tcp_slowtmr advances the clock before walking the PCBs
+        unsafe { lwip_tcp_rust::tcp_ticks += 1 };
+        match tcp_slowtmr_pcb(&mut state).unwrap() {
+            TimerAction::KeepAlive => probes += 1,
+            TimerAction::Abort => break,
+            _ => {}
+        }
+    }
+    assert_eq!(probes, 3);
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+}
+
+// ============================================================================
+// TIME_WAIT (2MSL)
+// ============================================================================
+
+/// FIN_WAIT_2 connection that has just received the peer's FIN
+fn time_wait() -> TcpConnectionState {
+    let mut state = create_test_state();
+    set_tcp_state(
+        &mut state,
+        TcpState::Established,
+        TEST_LOCAL_IP,
+        TEST_REMOTE_IP,
+        TEST_LOCAL_PORT,
+        TEST_REMOTE_PORT,
+    );
+    state.conn_mgmt.state = TcpState::FinWait2;
+
+    let remote_ip = ffi::ip_addr_t { addr: TEST_REMOTE_IP };
+    tcp_input(&mut state, &fin(2001), remote_ip, TEST_REMOTE_PORT).unwrap();
+    assert_eq!(state.conn_mgmt.state, TcpState::TimeWait);
+    state
+}
+
+fn fin(seqno: u32) -> TcpSegment {
+    TcpSegment {
+        seqno,
+        ackno: 1001,
+        flags: TcpFlags::from_tcphdr(tcp_proto::TCP_FIN | tcp_proto::TCP_ACK),
+        wnd: 8192,
+        tcphdr_len: 20,
+        payload_len: 0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_time_wait_closes_after_2msl() {
+    let mut state = time_wait();
+    assert_eq!(tcp_proto::TCP_TIME_WAIT_TICKS, 240);
+
+    for _ in 1..tcp_proto::TCP_TIME_WAIT_TICKS {
+        assert_eq!(tcp_slowtmr_pcb(&mut state).unwrap(), TimerAction::None);
+    }
+    assert_eq!(state.conn_mgmt.state, TcpState::TimeWait);
+
+    assert_eq!(tcp_slowtmr_pcb(&mut state).unwrap(), TimerAction::Expired);
+    assert_eq!(state.conn_mgmt.state, TcpState::Closed);
+}
+
+#[test]
+fn test_retransmitted_fin_restarts_2msl() {
+    let mut state = time_wait();
+    for _ in 0..100 {
+        tcp_slowtmr_pcb(&mut state).unwrap();
+    }
+
+    // Our last ACK was lost; the peer's FIN comes again
+    let remote_ip = ffi::ip_addr_t { addr: TEST_REMOTE_IP };
+    tcp_input(&mut state, &fin(2001), remote_ip, TEST_REMOTE_PORT).unwrap();
+    assert_eq!(state.conn_mgmt.time_wait_ticks, tcp_proto::TCP_TIME_WAIT_TICKS);
+
+    for _ in 0..200 {
+        assert_eq!(tcp_slowtmr_pcb(&mut state).unwrap(), TimerAction::None);
+    }
+    assert_eq!(state.conn_mgmt.state, TcpState::TimeWait);
+}